@@ -0,0 +1,613 @@
+use std::{fmt, fs, ops::Range, path::Path};
+
+use chrono::{Local, TimeZone};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    asset::Asset,
+    config::Config,
+    read_only::is_read_only,
+    report::{DailyPrices, Transaction, TransactionKind},
+};
+
+/// How the lots a send is taken out of are picked, which decides the basis of what's sent.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LotMethod {
+    #[default]
+    /// First in, first out: the oldest lots first.
+    Fifo,
+    /// Last in, first out: the newest lots first.
+    Lifo,
+    /// Highest in, first out: the lots with the highest basis per unit first.
+    Hifo,
+    /// Average cost basis: every lot of the asset is pooled at its average basis.
+    #[serde(alias = "avg")]
+    #[value(alias = "avg")]
+    Acb,
+}
+
+impl LotMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Fifo => "FIFO",
+            Self::Lifo => "LIFO",
+            Self::Hifo => "HIFO",
+            Self::Acb => "average cost",
+        }
+    }
+
+    pub fn strategy(self) -> Box<dyn BasisStrategy> {
+        match self {
+            Self::Fifo => Box::new(Fifo),
+            Self::Lifo => Box::new(Lifo),
+            Self::Hifo => Box::new(Hifo),
+            Self::Acb => Box::new(Acb),
+        }
+    }
+}
+
+/// How a [`LotTracker`] adds lots received and picks the lot a send is taken out of next,
+/// which is all a cost basis method decides.
+pub trait BasisStrategy: fmt::Debug + Send + Sync {
+    /// Adds the lot to the asset's lots, which are in the order they were received.
+    fn receive(&self, lots: &mut Vec<Lot>, lot: Lot) {
+        lots.push(lot);
+    }
+
+    /// The index of the lot to take out of next, out of lots that aren't empty.
+    fn next(&self, lots: &[Lot]) -> usize;
+}
+
+#[derive(Debug)]
+pub struct Fifo;
+
+impl BasisStrategy for Fifo {
+    fn next(&self, _lots: &[Lot]) -> usize {
+        0
+    }
+}
+
+#[derive(Debug)]
+pub struct Lifo;
+
+impl BasisStrategy for Lifo {
+    fn next(&self, lots: &[Lot]) -> usize {
+        lots.len() - 1
+    }
+}
+
+#[derive(Debug)]
+pub struct Hifo;
+
+impl BasisStrategy for Hifo {
+    fn next(&self, lots: &[Lot]) -> usize {
+        (0..lots.len())
+            .max_by(|a, b| {
+                // An empty lot has no basis left to take, rather than NaN.
+                let per_unit = |lot: &Lot| match lot.amount {
+                    0 => 0.0,
+                    amount => lot.basis / amount as f64,
+                };
+                per_unit(&lots[*a]).total_cmp(&per_unit(&lots[*b]))
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Keeps a single lot per asset, so every send is taken out at the average basis.
+#[derive(Debug)]
+pub struct Acb;
+
+impl BasisStrategy for Acb {
+    fn receive(&self, lots: &mut Vec<Lot>, lot: Lot) {
+        match lots.first_mut() {
+            Some(pool) => {
+                pool.amount += lot.amount;
+                pool.basis += lot.basis;
+                pool.priced &= lot.priced;
+            }
+            None => lots.push(lot),
+        }
+    }
+
+    fn next(&self, _lots: &[Lot]) -> usize {
+        0
+    }
+}
+
+/// The gain realized by sending an asset, from what it was worth when sent less the basis of
+/// the lots it was taken out of.
+#[derive(Debug, Default, Clone)]
+pub struct RealizedGains {
+    pub disposals: usize,
+    pub proceeds: f64,
+    pub basis: f64,
+    pub gain: f64,
+    /// Disposals whose value or a lot of whose basis couldn't be priced, which count as zero.
+    pub unpriced: usize,
+}
+
+/// A lot left to take sends out of, where the basis is counted as zero if it wasn't priced.
+#[derive(Debug, Clone)]
+pub struct Lot {
+    /// When and at what height it was received. A pooled lot keeps its first receive's.
+    pub acquired: u64,
+    pub height: u32,
+    pub amount: u64,
+    pub basis: f64,
+    pub priced: bool,
+}
+
+/// The lots of every asset received, which sends are taken out of as the strategy says.
+#[derive(Debug)]
+pub struct LotTracker {
+    strategy: Box<dyn BasisStrategy>,
+    lots: IndexMap<Asset, Vec<Lot>>,
+}
+
+/// The part of the lots a send was taken out of.
+#[derive(Debug, Clone, Copy)]
+pub struct Taken {
+    pub amount: u64,
+    pub basis: f64,
+    /// Whether every lot taken out of was priced.
+    pub priced: bool,
+    /// When the earliest lot taken out of was received, or `None` if none were.
+    pub acquired: Option<u64>,
+}
+
+/// A send or fee, with the gain it realized against the lots it was taken out of.
+#[derive(Debug, Clone)]
+pub struct Disposal {
+    pub asset: Asset,
+    pub height: u32,
+    pub disposed: u64,
+    pub acquired: Option<u64>,
+    pub amount: u64,
+    /// What was sent was worth when it was sent, if it could be priced.
+    pub proceeds: Option<f64>,
+    pub basis: f64,
+    pub gain: f64,
+    /// Whether the proceeds and every lot taken out of were priced.
+    pub priced: bool,
+}
+
+impl LotTracker {
+    pub fn new(method: LotMethod) -> Self {
+        Self {
+            strategy: method.strategy(),
+            lots: IndexMap::new(),
+        }
+    }
+
+    /// Opens a lot of what was received, at its value when it was received if it's known.
+    pub fn receive(&mut self, tx: &Transaction, basis: Option<f64>) {
+        let lot = Lot {
+            acquired: tx.timestamp,
+            height: tx.height,
+            amount: tx.amount,
+            basis: basis.unwrap_or_default(),
+            priced: basis.is_some(),
+        };
+        self.strategy
+            .receive(self.lots.entry(tx.asset).or_default(), lot);
+    }
+
+    /// The lots left of every asset, oldest first. Together they add up to the holdings.
+    pub fn open_lots(&self) -> Vec<(Asset, &Lot)> {
+        let mut lots = self
+            .lots
+            .iter()
+            .flat_map(|(asset, lots)| lots.iter().map(|lot| (*asset, lot)))
+            .filter(|(_, lot)| lot.amount > 0)
+            .collect::<Vec<_>>();
+        lots.sort_by_key(|(asset, lot)| (lot.acquired, *asset));
+        lots
+    }
+
+    /// Takes the amount out of the asset's lots. Less is taken if the lots don't hold enough.
+    pub fn take(&mut self, asset: Asset, amount: u64) -> Taken {
+        let queue = self.lots.entry(asset).or_default();
+        let mut taken = Taken {
+            amount: 0,
+            basis: 0.0,
+            priced: true,
+            acquired: None,
+        };
+
+        while taken.amount < amount && !queue.is_empty() {
+            let index = self.strategy.next(queue);
+            let Some(lot) = queue.get_mut(index) else {
+                break;
+            };
+
+            let remaining = amount - taken.amount;
+            taken.priced &= lot.priced;
+            taken.acquired = Some(taken.acquired.map_or(lot.acquired, |t| t.min(lot.acquired)));
+            if lot.amount <= remaining {
+                taken.amount += lot.amount;
+                taken.basis += lot.basis;
+                queue.remove(index);
+            } else {
+                let basis = lot.basis * remaining as f64 / lot.amount as f64;
+                taken.amount += remaining;
+                taken.basis += basis;
+                lot.basis -= basis;
+                lot.amount -= remaining;
+            }
+        }
+
+        taken
+    }
+
+    /// Opens or takes out of the lots for a transaction, valued at its price if it's known,
+    /// returning the disposal if it was a send or fee.
+    pub fn apply(&mut self, tx: &Transaction, value: Option<f64>) -> Option<Disposal> {
+        match tx.kind {
+            TransactionKind::Receive => {
+                self.receive(tx, value);
+                None
+            }
+            TransactionKind::Send | TransactionKind::Fee => {
+                let taken = self.take(tx.asset, tx.amount);
+                Some(Disposal {
+                    asset: tx.asset,
+                    height: tx.height,
+                    disposed: tx.timestamp,
+                    acquired: taken.acquired,
+                    amount: tx.amount,
+                    proceeds: value,
+                    basis: taken.basis,
+                    gain: value.unwrap_or_default() - taken.basis,
+                    priced: value.is_some() && taken.priced,
+                })
+            }
+        }
+    }
+}
+
+/// Every send and fee within the bounds, with lots taken out as the method says. Fees are
+/// disposals too, since they spend XCH that had a basis. Sends beyond the lots received, like
+/// of coins from before the cache starts, have no basis.
+pub fn disposals(
+    transactions: impl Iterator<Item = Transaction>,
+    bounds: Range<i64>,
+    method: LotMethod,
+    daily_prices: &DailyPrices,
+    config: &Config,
+) -> Vec<Disposal> {
+    let mut lots = LotTracker::new(method);
+    let mut disposals = Vec::new();
+
+    for tx in transactions.filter(|tx| (tx.timestamp as i64) < bounds.end) {
+        let value = tx
+            .price(daily_prices, config)
+            .map(|price| price * tx.asset.display_amount(tx.amount));
+        let Some(disposal) = lots.apply(&tx, value) else {
+            continue;
+        };
+        if bounds.contains(&(tx.timestamp as i64)) {
+            disposals.push(disposal);
+        }
+    }
+
+    disposals
+}
+
+/// What every asset sent within the bounds realized, totalled from its disposals.
+pub fn realized_gains(
+    transactions: impl Iterator<Item = Transaction>,
+    bounds: Range<i64>,
+    method: LotMethod,
+    daily_prices: &DailyPrices,
+    config: &Config,
+) -> IndexMap<Asset, RealizedGains> {
+    let mut gains = IndexMap::<Asset, RealizedGains>::new();
+
+    for disposal in disposals(transactions, bounds, method, daily_prices, config) {
+        let gains = gains.entry(disposal.asset).or_default();
+        gains.disposals += 1;
+        gains.proceeds += disposal.proceeds.unwrap_or_default();
+        gains.basis += disposal.basis;
+        gains.gain = gains.proceeds - gains.basis;
+        if !disposal.priced {
+            gains.unpriced += 1;
+        }
+    }
+
+    gains.sort_keys();
+    gains
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DisposalRow {
+    pub height: u32,
+    pub disposed: String,
+    /// When the earliest lot it was taken out of was received, empty if it had no lots.
+    pub acquired: Option<String>,
+    pub asset: String,
+    pub amount: String,
+    pub proceeds: Option<f64>,
+    pub basis: f64,
+    pub gain: f64,
+    pub currency: String,
+}
+
+pub fn disposal_rows(disposals: &[Disposal], currency: &str, config: &Config) -> Vec<DisposalRow> {
+    let date = |timestamp: u64| {
+        Local
+            .timestamp_opt(timestamp as i64, 0)
+            .single()
+            .map(|date| date.date_naive().to_string())
+            .unwrap_or_default()
+    };
+
+    disposals
+        .iter()
+        .map(|disposal| DisposalRow {
+            height: disposal.height,
+            disposed: date(disposal.disposed),
+            acquired: disposal.acquired.map(date),
+            asset: disposal.asset.name(config),
+            amount: disposal.asset.format_amount(disposal.amount),
+            proceeds: disposal.proceeds,
+            basis: disposal.basis,
+            gain: disposal.gain,
+            currency: currency.to_uppercase(),
+        })
+        .collect()
+}
+
+pub fn write_disposal_report(path: &Path, rows: &[DisposalRow]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// The net realized gain of each year reported with `--carry-losses`, kept next to the cache
+/// in one file per currency so the losses of earlier years can be carried into later ones.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Carryforward {
+    /// By entity, with the whole wallet under an empty name, then by year.
+    pub years: IndexMap<String, IndexMap<i32, f64>>,
+}
+
+impl Carryforward {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// A read-only run doesn't record the year, which is still carried into in memory.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        if is_read_only() {
+            return Ok(());
+        }
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Records the year's net realized gain, replacing what an earlier report recorded.
+    pub fn record(&mut self, entity: &str, year: i32, gain: f64) {
+        let years = self.years.entry(entity.to_string()).or_default();
+        years.insert(year, gain);
+        years.sort_keys();
+    }
+
+    /// The losses carried into the year from the recorded years before it, and those it
+    /// carries into the next. Each year deducts up to `deduction_limit` of its net loss from
+    /// other income, and a gain absorbs the losses carried into it. A year that isn't recorded
+    /// yet carries on what was carried into it.
+    pub fn carried(&self, entity: &str, year: i32, deduction_limit: f64) -> (f64, f64) {
+        let Some(years) = self.years.get(entity) else {
+            return (0.0, 0.0);
+        };
+
+        let carry = |carried: f64, gain: f64| {
+            let net = gain - carried;
+            if net < 0.0 {
+                (-net - deduction_limit).max(0.0)
+            } else {
+                0.0
+            }
+        };
+
+        let carried_in = years
+            .iter()
+            .filter(|(recorded, _)| **recorded < year)
+            .fold(0.0, |carried, (_, gain)| carry(carried, *gain));
+        let carried_out = years
+            .get(&year)
+            .map_or(carried_in, |gain| carry(carried_in, *gain));
+
+        (carried_in, carried_out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(kind: TransactionKind, timestamp: u64, amount: u64) -> Transaction {
+        Transaction {
+            height: timestamp as u32,
+            timestamp,
+            kind,
+            asset: Asset::Xch,
+            amount,
+            coin_ids: Vec::new(),
+            change_coin_ids: Vec::new(),
+            change: 0,
+            cost: None,
+            counterparty: None,
+            label: None,
+            tags: Vec::new(),
+            evidence: Vec::new(),
+        }
+    }
+
+    /// Three lots of 100 received with a basis of 100, 300 and 200, then a send of 150.
+    fn send_from_three_lots(method: LotMethod) -> (Disposal, LotTracker) {
+        let mut lots = LotTracker::new(method);
+        for (timestamp, basis) in [(1, 100.0), (2, 300.0), (3, 200.0)] {
+            lots.apply(&tx(TransactionKind::Receive, timestamp, 100), Some(basis));
+        }
+        let disposal = lots
+            .apply(&tx(TransactionKind::Send, 4, 150), Some(500.0))
+            .unwrap();
+        (disposal, lots)
+    }
+
+    fn left(lots: &LotTracker) -> Vec<(u64, f64)> {
+        lots.open_lots()
+            .into_iter()
+            .map(|(_, lot)| (lot.amount, lot.basis))
+            .collect()
+    }
+
+    #[test]
+    fn fifo_takes_the_oldest_lots_first() {
+        let (disposal, lots) = send_from_three_lots(LotMethod::Fifo);
+        assert_eq!(disposal.basis, 250.0);
+        assert_eq!(disposal.gain, 250.0);
+        assert_eq!(disposal.acquired, Some(1));
+        assert_eq!(left(&lots), [(50, 150.0), (100, 200.0)]);
+    }
+
+    #[test]
+    fn lifo_takes_the_newest_lots_first() {
+        let (disposal, lots) = send_from_three_lots(LotMethod::Lifo);
+        assert_eq!(disposal.basis, 350.0);
+        assert_eq!(disposal.acquired, Some(2));
+        assert_eq!(left(&lots), [(100, 100.0), (50, 150.0)]);
+    }
+
+    #[test]
+    fn hifo_takes_the_highest_basis_first() {
+        let (disposal, lots) = send_from_three_lots(LotMethod::Hifo);
+        assert_eq!(disposal.basis, 400.0);
+        assert_eq!(disposal.acquired, Some(2));
+        assert_eq!(left(&lots), [(100, 100.0), (50, 100.0)]);
+    }
+
+    #[test]
+    fn acb_takes_the_average_basis() {
+        let (disposal, lots) = send_from_three_lots(LotMethod::Acb);
+        assert_eq!(disposal.basis, 300.0);
+        assert_eq!(disposal.acquired, Some(1));
+        assert_eq!(left(&lots), [(150, 300.0)]);
+    }
+
+    #[test]
+    fn hifo_skips_past_empty_lots() {
+        let lots = [
+            Lot {
+                acquired: 1,
+                height: 1,
+                amount: 0,
+                basis: 0.0,
+                priced: true,
+            },
+            Lot {
+                acquired: 2,
+                height: 2,
+                amount: 10,
+                basis: 5.0,
+                priced: true,
+            },
+        ];
+        assert_eq!(Hifo.next(&lots), 1);
+    }
+
+    #[test]
+    fn sends_beyond_the_lots_have_no_basis() {
+        for method in [
+            LotMethod::Fifo,
+            LotMethod::Lifo,
+            LotMethod::Hifo,
+            LotMethod::Acb,
+        ] {
+            let mut lots = LotTracker::new(method);
+            lots.apply(&tx(TransactionKind::Receive, 1, 100), Some(100.0));
+
+            let taken = lots.take(Asset::Xch, 250);
+            assert_eq!(taken.amount, 100, "{method:?}");
+            assert_eq!(taken.basis, 100.0, "{method:?}");
+            assert!(lots.open_lots().is_empty(), "{method:?}");
+
+            let taken = lots.take(Asset::Xch, 10);
+            assert_eq!(taken.amount, 0, "{method:?}");
+            assert_eq!(taken.acquired, None, "{method:?}");
+        }
+    }
+
+    #[test]
+    fn unpriced_receives_count_as_zero_basis() {
+        for method in [
+            LotMethod::Fifo,
+            LotMethod::Lifo,
+            LotMethod::Hifo,
+            LotMethod::Acb,
+        ] {
+            let mut lots = LotTracker::new(method);
+            lots.apply(&tx(TransactionKind::Receive, 1, 100), None);
+
+            let disposal = lots
+                .apply(&tx(TransactionKind::Fee, 2, 40), Some(50.0))
+                .unwrap();
+            assert_eq!(disposal.basis, 0.0, "{method:?}");
+            assert_eq!(disposal.gain, 50.0, "{method:?}");
+            assert!(!disposal.priced, "{method:?}");
+        }
+    }
+
+    #[test]
+    fn losses_carry_forward_beyond_the_deduction_limit() {
+        let mut carryforward = Carryforward::default();
+        carryforward.record("", 2023, -10_000.0);
+        carryforward.record("", 2025, 2_000.0);
+        carryforward.record("", 2024, 1_000.0);
+
+        // Each year's gain absorbs what it can, and 3,000 of the net loss left is deducted.
+        assert_eq!(carryforward.carried("", 2023, 3_000.0), (0.0, 7_000.0));
+        assert_eq!(carryforward.carried("", 2024, 3_000.0), (7_000.0, 3_000.0));
+        assert_eq!(carryforward.carried("", 2025, 3_000.0), (3_000.0, 0.0));
+        assert_eq!(carryforward.carried("", 2025, 0.0), (9_000.0, 7_000.0));
+        assert_eq!(carryforward.carried("business", 2025, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn realized_gains_only_count_disposals_within_the_bounds() {
+        let transactions = [
+            tx(TransactionKind::Receive, 1, 100),
+            tx(TransactionKind::Send, 2, 10),
+            tx(TransactionKind::Send, 5, 20),
+            tx(TransactionKind::Send, 9, 30),
+        ];
+        let gains = realized_gains(
+            transactions.into_iter(),
+            2..9,
+            LotMethod::Fifo,
+            &DailyPrices::new(),
+            &Config::default(),
+        );
+
+        // Nothing is priced without daily prices, so every disposal is unpriced.
+        let xch = &gains[&Asset::Xch];
+        assert_eq!(xch.disposals, 2);
+        assert_eq!(xch.unpriced, 2);
+        assert_eq!(xch.gain, 0.0);
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fs, path::Path};
+
+use chia::{
+    bls::{DerivableKey, PublicKey},
+    protocol::Bytes32,
+    puzzles::{standard::StandardArgs, DeriveSynthetic},
+};
+use chia_wallet_sdk::encode_address;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
+
+use crate::{config::Config, read_only::check_writable};
+
+/// A receive address handed out with `thyme address`, and what it was for.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedAddress {
+    /// The address and its puzzle hash are left out of the file with `private_cache`, and are
+    /// derived again from the index when it's loaded.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub address: String,
+    #[serde_as(as = "Hex")]
+    #[serde(default, skip_serializing_if = "is_unset")]
+    pub puzzle_hash: [u8; 32],
+    pub purpose: Option<String>,
+    pub amount: Option<String>,
+    pub memo: Option<String>,
+    /// The local date the address was issued on.
+    pub issued: String,
+}
+
+/// The receive addresses issued for a wallet, keyed by derivation index. Coins later received
+/// to these addresses are labeled with the recorded purpose in reports.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AddressBook {
+    pub addresses: IndexMap<u32, IssuedAddress>,
+    /// Labels for the addresses of people and services that pay the wallet, added with
+    /// `thyme label`, which apply to the whole cluster each address belongs to.
+    #[serde(default)]
+    pub counterparties: IndexMap<String, String>,
+}
+
+impl AddressBook {
+    pub fn load(
+        path: impl AsRef<Path>,
+        config: &Config,
+        intermediate_pk: &PublicKey,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        let mut book: Self = serde_json::from_str(&contents)?;
+
+        for (index, issued) in &mut book.addresses {
+            if is_unset(&issued.puzzle_hash) {
+                issued.puzzle_hash = derive_puzzle_hash(intermediate_pk, *index).to_bytes();
+            }
+            if issued.address.is_empty() {
+                issued.address = address(config, issued.puzzle_hash.into())?;
+            }
+        }
+
+        Ok(book)
+    }
+
+    /// Writes the book to a partial file first, so it's never left half written.
+    pub fn save(&self, path: impl AsRef<Path>, config: &Config) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        check_writable(path)?;
+
+        let mut book = self.clone();
+        if config.private_cache {
+            for issued in book.addresses.values_mut() {
+                issued.address.clear();
+                issued.puzzle_hash = [0; 32];
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = serde_json::to_string_pretty(&book)?;
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, contents)?;
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// The label for coins received to each issued address, which is its purpose, or else its memo.
+    pub fn labels(&self) -> IndexMap<Bytes32, String> {
+        self.addresses
+            .values()
+            .filter_map(|issued| {
+                let label = issued.purpose.clone().or_else(|| issued.memo.clone())?;
+                Some((issued.puzzle_hash.into(), label))
+            })
+            .collect()
+    }
+}
+
+fn is_unset(puzzle_hash: &[u8; 32]) -> bool {
+    *puzzle_hash == [0; 32]
+}
+
+/// The standard puzzle hash of the unhardened derivation at `index`.
+pub fn derive_puzzle_hash(intermediate_pk: &PublicKey, index: u32) -> Bytes32 {
+    let pk = intermediate_pk.derive_unhardened(index).derive_synthetic();
+    StandardArgs::curry_tree_hash(pk).into()
+}
+
+/// Encodes a puzzle hash as an address for the configured network.
+pub fn address(config: &Config, puzzle_hash: Bytes32) -> anyhow::Result<String> {
+    let prefix = if config.network_id == "mainnet" {
+        "xch"
+    } else {
+        "txch"
+    };
+    Ok(encode_address(puzzle_hash.into(), prefix)?)
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+
+use chia::{
+    clvm_traits::FromClvm,
+    protocol::Bytes32,
+    puzzles::{
+        cat::{CatArgs, CAT_PUZZLE_HASH},
+        singleton::{SingletonArgs, SINGLETON_TOP_LAYER_PUZZLE_HASH},
+    },
+};
+use chia_wallet_sdk::Puzzle;
+use clvmr::{Allocator, NodePtr};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Asset {
+    Xch,
+    Cat(Bytes32),
+    Nft(Bytes32),
+}
+
+impl Asset {
+    /// Identifies the asset held by the outer layer of a puzzle.
+    /// Anything that isn't a CAT or singleton is treated as XCH.
+    pub fn from_puzzle(allocator: &Allocator, puzzle: NodePtr) -> Self {
+        let Some(curried) = Puzzle::parse(allocator, puzzle).as_curried() else {
+            return Self::Xch;
+        };
+
+        if curried.mod_hash == CAT_PUZZLE_HASH {
+            if let Ok(args) = CatArgs::<NodePtr>::from_clvm(allocator, curried.args) {
+                return Self::Cat(args.asset_id);
+            }
+        } else if curried.mod_hash == SINGLETON_TOP_LAYER_PUZZLE_HASH {
+            if let Ok(args) = SingletonArgs::<NodePtr>::from_clvm(allocator, curried.args) {
+                return Self::Nft(args.singleton_struct.launcher_id);
+            }
+        }
+
+        Self::Xch
+    }
+
+    /// The number of decimal places between the base unit and the display unit.
+    pub fn precision(&self) -> u32 {
+        match self {
+            Self::Xch => 12,
+            Self::Cat(..) => 3,
+            Self::Nft(..) => 0,
+        }
+    }
+
+    pub fn format_amount(&self, amount: u64) -> String {
+        let precision = self.precision();
+        if precision == 0 {
+            return amount.to_string();
+        }
+        let divisor = 10u64.pow(precision);
+        format!(
+            "{}.{:0width$}",
+            amount / divisor,
+            amount % divisor,
+            width = precision as usize
+        )
+    }
+
+    /// Parses an amount in display units, like `1.5`, into base units. Amounts with more decimal
+    /// places than the asset has are rejected rather than rounded.
+    pub fn parse_amount(&self, amount: &str) -> Option<u64> {
+        let precision = self.precision() as usize;
+        let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+        if fraction.len() > precision || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        let whole = if whole.is_empty() {
+            0
+        } else {
+            whole.parse::<u64>().ok()?
+        };
+        let fraction = format!("{fraction:0<precision$}");
+        let fraction = if fraction.is_empty() {
+            0
+        } else {
+            fraction.parse::<u64>().ok()?
+        };
+
+        whole
+            .checked_mul(10u64.pow(precision as u32))?
+            .checked_add(fraction)
+    }
+
+    /// Finds an asset by `XCH`, a CAT's name from the config, or a CAT's asset id in hex.
+    pub fn parse(name: &str, config: &Config) -> Option<Self> {
+        if name.eq_ignore_ascii_case("xch") {
+            return Some(Self::Xch);
+        }
+
+        let configured = config.assets.iter().find(|(_, asset)| {
+            asset
+                .name
+                .as_deref()
+                .is_some_and(|asset| asset.eq_ignore_ascii_case(name))
+        });
+        if let Some((asset_id, _)) = configured {
+            return Some(Self::Cat((*asset_id).into()));
+        }
+
+        let asset_id = hex::decode(name.strip_prefix("0x").unwrap_or(name)).ok()?;
+        Some(Self::Cat(<[u8; 32]>::try_from(asset_id).ok()?.into()))
+    }
+
+    pub fn display_amount(&self, amount: u64) -> f64 {
+        amount as f64 / 10f64.powi(self.precision() as i32)
+    }
+
+    /// The name configured for the asset, falling back to a shortened asset id.
+    pub fn name(&self, config: &Config) -> String {
+        match self {
+            Self::Xch => "XCH".to_string(),
+            Self::Cat(asset_id) => config
+                .assets
+                .get(&asset_id.to_bytes())
+                .and_then(|asset| asset.name.clone())
+                .unwrap_or_else(|| self.to_string()),
+            Self::Nft(..) => self.to_string(),
+        }
+    }
+
+    /// The CoinGecko id used to look up prices for the asset, if any.
+    pub fn coingecko_id(&self, config: &Config) -> Option<String> {
+        match self {
+            Self::Xch => Some("chia".to_string()),
+            Self::Cat(asset_id) => config
+                .assets
+                .get(&asset_id.to_bytes())
+                .and_then(|asset| asset.coingecko_id.clone()),
+            Self::Nft(..) => None,
+        }
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Xch => write!(f, "XCH"),
+            Self::Cat(asset_id) => write!(f, "CAT {}", &hex::encode(asset_id)[..8]),
+            Self::Nft(launcher_id) => write!(f, "NFT {}", &hex::encode(launcher_id)[..8]),
+        }
+    }
+}
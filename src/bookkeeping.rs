@@ -0,0 +1,107 @@
+use std::io::Write;
+
+use chrono::NaiveDate;
+
+use crate::{
+    config::CategoriesConfig,
+    report::{ReportRow, ReportWriter, TransactionKind},
+};
+
+/// The bookkeeping software a bank feed CSV is laid out for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BankFeedStyle {
+    QuickBooks,
+    Xero,
+}
+
+/// Writes the report as a bank statement CSV that QuickBooks Online or Xero can import,
+/// with one fiat amount per transaction, positive for receives and negative for sends.
+///
+/// Bank feeds need an amount on every line, so transactions without a price are left out
+/// and counted on stderr instead.
+pub struct BankFeedWriter {
+    writer: csv::Writer<Box<dyn Write>>,
+    style: BankFeedStyle,
+    categories: CategoriesConfig,
+    unpriced: usize,
+}
+
+impl BankFeedWriter {
+    pub fn new(
+        output: Box<dyn Write>,
+        style: BankFeedStyle,
+        categories: CategoriesConfig,
+    ) -> anyhow::Result<Self> {
+        let mut writer = csv::Writer::from_writer(output);
+        match style {
+            BankFeedStyle::QuickBooks => {
+                writer.write_record(["Date", "Description", "Amount", "Category"])?
+            }
+            BankFeedStyle::Xero => writer.write_record([
+                "Date",
+                "Amount",
+                "Payee",
+                "Description",
+                "Reference",
+                "Account Code",
+            ])?,
+        }
+        Ok(Self {
+            writer,
+            style,
+            categories,
+            unpriced: 0,
+        })
+    }
+}
+
+impl ReportWriter for BankFeedWriter {
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        let Some(value) = row.value else {
+            self.unpriced += 1;
+            return Ok(());
+        };
+
+        let date = NaiveDate::parse_from_str(row.date.get(..10).unwrap_or(&row.date), "%Y-%m-%d")?;
+        let (verb, amount, category) = match row.kind {
+            TransactionKind::Receive => ("Received", value, &self.categories.income),
+            TransactionKind::Send => ("Sent", -value, &self.categories.expenses),
+            TransactionKind::Fee => ("Network fee of", -value, &self.categories.fees),
+        };
+        let amount = format!("{amount:.2}");
+        let mut description = format!("{verb} {} {}", row.amount, row.asset);
+        if let Some(label) = &row.label {
+            description = format!("{description} - {label}");
+        }
+
+        match self.style {
+            BankFeedStyle::QuickBooks => self.writer.write_record([
+                date.format("%m/%d/%Y").to_string().as_str(),
+                &description,
+                &amount,
+                category,
+            ])?,
+            BankFeedStyle::Xero => self.writer.write_record([
+                date.format("%d/%m/%Y").to_string().as_str(),
+                &amount,
+                row.counterparty.as_deref().unwrap_or_default(),
+                &description,
+                &row.height.to_string(),
+                category,
+            ])?,
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        if self.unpriced > 0 {
+            eprintln!(
+                "Left {} transactions without a price out of the bank feed",
+                self.unpriced
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::slice;
+
+use anyhow::bail;
+use chia::protocol::{RejectCoinState, RejectStateReason, RequestCoinState, RespondCoinState};
+use indexmap::IndexMap;
+
+use crate::{
+    cache::{CoinStateJson, Derivations, PuzzleInfo},
+    config::Config,
+    custom::CustomPuzzles,
+    indexer::Indexer,
+    peers::ProbedPeer,
+    retry::{with_retries, Rejections},
+    sanity::check_coin_states,
+    verify::header_hash_at,
+};
+
+/// How far behind the indexer's peak a bootstrapped window starts paging from, so a reorg or
+/// a lagging indexer can't leave a gap before the peer takes over.
+const PEAK_MARGIN: u32 = 100;
+
+/// How many coin ids are checked with the peer in each request.
+const VERIFY_BATCH_SIZE: usize = 100;
+
+/// Fills a window that was never synced with the coins the indexer has for it, so the sync
+/// pages the peer from close to the indexer's peak instead of from genesis.
+///
+/// Every coin is checked with the peer before it's cached, and the peer's heights are the
+/// ones kept, so a wrong or forged coin fails the bootstrap. A coin the indexer leaves out
+/// can't be caught this way, which is the trade for the faster first sync.
+pub async fn bootstrap_window(
+    derivations: &mut Derivations,
+    config: &Config,
+    peer: &ProbedPeer,
+    indexer: &Indexer,
+) -> anyhow::Result<()> {
+    if !peer.supports_puzzle_state {
+        bail!(
+            "{} can't check coins by id, so a window can't be bootstrapped",
+            peer.uri
+        );
+    }
+
+    let height = indexer.peak_height().await?.saturating_sub(PEAK_MARGIN);
+    let Some(header_hash) = header_hash_at(&peer.peer, height, &config.retry).await? else {
+        bail!(
+            "{} hasn't reached height {height} that the indexer has",
+            peer.uri
+        );
+    };
+
+    let custom_puzzle_hashes =
+        CustomPuzzles::from_config(config)?.puzzle_hashes(&derivations.puzzle_hashes);
+    let puzzle_hashes = derivations
+        .puzzle_hashes
+        .iter()
+        .chain(custom_puzzle_hashes.keys())
+        .copied()
+        .collect::<Vec<_>>();
+    let mut indexed = indexer.coins(&puzzle_hashes, false).await?;
+    if config.coin_states.include_hinted {
+        indexed.extend(indexer.coins(&puzzle_hashes, true).await?);
+    }
+
+    // Coins created after the starting height are left to the sync, which pages from there.
+    let indexed = indexed
+        .into_iter()
+        .filter(|coin| coin.created_height <= height)
+        .map(|coin| (coin.coin.coin_id(), coin.coin))
+        .collect::<IndexMap<_, _>>();
+
+    eprintln!(
+        "Bootstrapping {} coins from the indexer up to height {height}",
+        indexed.len()
+    );
+
+    let coin_ids = indexed.keys().copied().collect::<Vec<_>>();
+    let mut verified = IndexMap::new();
+
+    for batch in coin_ids.chunks(VERIFY_BATCH_SIZE) {
+        let response = with_retries(&config.retry, Rejections::Return, || {
+            peer.peer
+                .request_or_reject::<RespondCoinState, RejectCoinState, _>(RequestCoinState {
+                    coin_ids: batch.to_vec(),
+                    previous_height: None,
+                    header_hash: config.genesis_challenge.into(),
+                    subscribe: false,
+                })
+        })
+        .await?;
+
+        match response {
+            Ok(response) => verified.extend(
+                response
+                    .coin_states
+                    .into_iter()
+                    .map(|coin_state| (coin_state.coin.coin_id(), coin_state)),
+            ),
+            Err(rejection) => match rejection.reason {
+                RejectStateReason::ExceededSubscriptionLimit => {
+                    bail!("The peer rejected checking the bootstrapped coins")
+                }
+                RejectStateReason::Reorg => {
+                    bail!("Reorg detected but we didn't specify a previous height.")
+                }
+            },
+        }
+    }
+
+    let coin_states = verified.values().cloned().collect::<Vec<_>>();
+    check_coin_states(
+        &peer.uri,
+        &coin_states,
+        None,
+        slice::from_ref(&*derivations),
+    )?;
+
+    let filters = &config.coin_states;
+    let min_amount = config.dust_threshold.max(filters.min_amount);
+
+    for (coin_id, coin) in indexed {
+        let Some(coin_state) = verified.get(&coin_id) else {
+            bail!(
+                "The indexer has coin {coin_id}, but {} doesn't know it",
+                peer.uri
+            );
+        };
+        if coin_state.coin != coin || coin_state.created_height.is_none() {
+            bail!("The indexer and {} disagree about coin {coin_id}", peer.uri);
+        }
+
+        let wanted = if coin_state.spent_height.is_some() {
+            filters.include_spent
+        } else {
+            filters.include_unspent
+        };
+        if !wanted || coin.amount < min_amount {
+            continue;
+        }
+
+        let coin_id = coin_id.to_bytes();
+        let puzzle_hash = coin.puzzle_hash.to_bytes();
+        let custom = custom_puzzle_hashes.get(&puzzle_hash);
+        if !derivations.puzzle_hashes.contains(&puzzle_hash) && custom.is_none() {
+            derivations.pending.insert(coin_id);
+        }
+        derivations.coin_states.insert(
+            coin_id,
+            CoinStateJson {
+                coin: coin.into(),
+                parent_puzzle: custom.cloned().map(PuzzleInfo::Custom),
+                parent_spend: None,
+                created_height: coin_state.created_height,
+                spent_height: coin_state.spent_height,
+            },
+        );
+    }
+
+    derivations.previous_height = Some(height);
+    derivations.header_hash = header_hash.to_bytes();
+
+    Ok(())
+}
@@ -1,24 +1,123 @@
-use std::{fs, path::Path};
+use std::{
+    fs::{self, File, TryLockError},
+    io,
+    path::{Path, PathBuf},
+};
 
+use anyhow::{anyhow, bail};
 use chia::{
-    protocol::Coin,
-    puzzles::{EveProof, LineageProof, Proof},
+    bls::PublicKey,
+    protocol::{Bytes32, Coin},
+    puzzles::{nft::NftMetadata, EveProof, LineageProof, Proof},
 };
-use chia_wallet_sdk::Cat;
+use chia_wallet_sdk::{Cat, Nft};
+use clvmr::sha2::Sha256;
 use indexmap::{IndexMap, IndexSet};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
 use serde_with::{hex::Hex, serde_as};
 
+use crate::{
+    asset::Asset,
+    privacy::restore_puzzle_hashes,
+    read_only::{check_writable, is_read_only},
+};
+
+/// The magic bytes at the start of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+const ZSTD_LEVEL: i32 = 3;
+
 #[serde_as]
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Derivations {
     pub previous_height: Option<u32>,
     #[serde_as(as = "Hex")]
     pub header_hash: [u8; 32],
+    /// The height the window was first paged from, if not genesis.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_height: Option<u32>,
+    /// Left out of the file when the window has a key fingerprint.
+    #[serde(default)]
     #[serde_as(as = "IndexSet<Hex>")]
     pub puzzle_hashes: IndexSet<[u8; 32]>,
+    /// The fingerprint of the key the puzzle hashes are derived from, set for windows saved
+    /// with `private_cache`, which are derived again whenever the window is read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_fingerprint: Option<u32>,
     #[serde_as(as = "IndexMap<Hex, _>")]
     pub coin_states: IndexMap<[u8; 32], CoinStateJson>,
+    /// Coins whose parent puzzle still has to be looked up, which a sync does after paging
+    /// the coin states of every window.
+    #[serde(default)]
+    #[serde_as(as = "IndexSet<Hex>")]
+    pub pending: IndexSet<[u8; 32]>,
+    /// The timestamps of the heights the coins were created and spent at, so reports don't
+    /// look them up from the peer again.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub timestamps: IndexMap<u32, u64>,
+    /// The `coin_states` filters the window is synced with, if they leave out any coins.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filters: Option<SyncFilters>,
+    /// Set when coins of the window were set aside because they couldn't be read, so the next
+    /// sync pages it again from its start height and fetches them again.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub resync: bool,
+}
+
+/// Which coins a window is synced with. Coins a narrower sync left out are never fetched once
+/// the window's height moves past them, so a window keeps the filters it was first synced with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncFilters {
+    pub include_spent: bool,
+    pub include_unspent: bool,
+    pub include_hinted: bool,
+    /// The smallest amount synced, which is the dust threshold if that's higher.
+    pub min_amount: u64,
+}
+
+impl Default for SyncFilters {
+    fn default() -> Self {
+        Self {
+            include_spent: true,
+            include_unspent: true,
+            include_hinted: true,
+            min_amount: 0,
+        }
+    }
+}
+
+impl Derivations {
+    /// Fails if the window was synced with other filters than these, since syncing it on would
+    /// leave it missing coins or with some coins it shouldn't have.
+    pub fn check_filters(&self, filters: SyncFilters) -> anyhow::Result<()> {
+        let recorded = self.filters.unwrap_or_default();
+        if recorded != filters {
+            bail!(
+                "The cache was synced with {recorded:?}, but the config's `coin_states` and \
+                 `dust_threshold` are now {filters:?}. Sync with --reset to fetch every coin \
+                 again with the new filters, or set them back"
+            );
+        }
+        Ok(())
+    }
+
+    /// Records the timestamps of the heights the coins were created or spent at, returning
+    /// whether any weren't recorded yet.
+    pub fn record_timestamps(&mut self, timestamps: &IndexMap<u32, u64>) -> bool {
+        let before = self.timestamps.len();
+        for coin_state in self.coin_states.values() {
+            for height in [coin_state.created_height, coin_state.spent_height]
+                .into_iter()
+                .flatten()
+            {
+                if let Some(timestamp) = timestamps.get(&height) {
+                    self.timestamps.entry(height).or_insert(*timestamp);
+                }
+            }
+        }
+        self.timestamps.len() > before
+    }
 }
 
 #[serde_as]
@@ -60,14 +159,37 @@ pub struct CoinJson {
 pub struct CoinStateJson {
     pub coin: CoinJson,
     pub parent_puzzle: Option<PuzzleInfo>,
+    /// What the parent spend did, for parents no driver recognized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_spend: Option<SpendSummaryJson>,
     pub created_height: Option<u32>,
     pub spent_height: Option<u32>,
 }
 
+/// The conditions of a parent spend, from running its puzzle with its solution. Even without
+/// knowing the puzzle, this tells how much of the parent's value went where.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpendSummaryJson {
+    pub parent_amount: u64,
+    /// How many coins the spend created, and their total amount.
+    pub created_coins: usize,
+    pub created_amount: u64,
+    /// The part of the created amount sent back to the parent's own puzzle hash, which is
+    /// usually change.
+    pub change_amount: u64,
+    /// The public keys whose signatures the spend required.
+    #[serde_as(as = "Vec<Hex>")]
+    pub signers: Vec<[u8; 48]>,
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PuzzleInfo {
     Cat(CatJson),
+    Nft(NftJson),
+    /// XCH locked by one of the custom puzzles from the config.
+    Custom(CustomJson),
     Unknown,
 }
 
@@ -82,28 +204,397 @@ pub struct CatJson {
     pub lineage_proof: Option<LineageProofJson>,
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+/// Only what's needed to identify an NFT and look up its collection is kept,
+/// rather than the full on-chain metadata.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NftJson {
+    #[serde_as(as = "Hex")]
+    pub launcher_id: [u8; 32],
+    #[serde_as(as = "Hex")]
+    pub p2_puzzle_hash: [u8; 32],
+    pub metadata_uris: Vec<String>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomJson {
+    /// The puzzle's name in the config.
+    pub name: String,
+    #[serde_as(as = "Hex")]
+    pub p2_puzzle_hash: [u8; 32],
+}
+
+/// The cache is stored as a directory with one file per derivation window,
+/// so windows can be written individually during a sync and loaded lazily when reporting.
+#[derive(Debug, Default, Clone)]
 pub struct Cache {
     pub derivations: Vec<Derivations>,
 }
 
 impl Cache {
-    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+    pub fn load(
+        path: impl AsRef<Path>,
+        intermediate_pk: Option<&PublicKey>,
+    ) -> anyhow::Result<Self> {
         let path = path.as_ref();
+        if path.is_file() {
+            return Self::load_legacy(path);
+        }
         if !path.exists() {
-            let cache = Self::default();
-            cache.save(path)?;
-            return Ok(cache);
+            if let Some(cache) = Self::migrate_legacy(path)? {
+                return Ok(cache);
+            }
+            check_writable(path)?;
+            fs::create_dir_all(path)?;
+            return Ok(Self::default());
         }
-        let contents = fs::read_to_string(path)?;
-        Ok(serde_json::from_str(&contents)?)
+        Ok(Self {
+            derivations: CacheWindows::open(path, intermediate_pk)?
+                .iter()
+                .collect::<anyhow::Result<_>>()?,
+        })
     }
 
     pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
-        let contents = serde_json::to_string_pretty(self)?;
-        fs::write(path, contents)?;
+        for index in 0..self.derivations.len() {
+            self.save_window(path.as_ref(), index)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a single derivation window, which is all a sync needs after updating it.
+    pub fn save_window(&self, path: impl AsRef<Path>, index: usize) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        check_writable(path)?;
+        fs::create_dir_all(path)?;
+        let derivations = &self.derivations[index];
+        if derivations.key_fingerprint.is_none() {
+            return write_json(path.join(window_file_name(index)), derivations);
+        }
+
+        let stripped = Derivations {
+            puzzle_hashes: IndexSet::new(),
+            ..derivations.clone()
+        };
+        write_json(path.join(window_file_name(index)), &stripped)
+    }
+
+    /// Converts a cache saved as a single `{path}.json` file into the window directory format.
+    fn migrate_legacy(path: &Path) -> anyhow::Result<Option<Self>> {
+        let legacy_path = path.with_extension("json");
+        if !legacy_path.exists() {
+            return Ok(None);
+        }
+
+        let cache = Self::load_legacy(&legacy_path)?;
+        cache.save(path)?;
+        fs::remove_file(legacy_path)?;
+
+        Ok(Some(cache))
+    }
+
+    /// Reads a cache saved as a single file, which is how older versions stored it.
+    fn load_legacy(path: &Path) -> anyhow::Result<Self> {
+        #[derive(Deserialize)]
+        struct LegacyCache {
+            derivations: Vec<Derivations>,
+        }
+
+        let legacy: LegacyCache = read_json(path)?;
+        Ok(Self {
+            derivations: legacy.derivations,
+        })
+    }
+
+    pub fn coin_states(&self) -> impl Iterator<Item = &CoinStateJson> {
+        self.derivations
+            .iter()
+            .flat_map(|derivations| derivations.coin_states.values())
+    }
+
+    pub fn coin_state(&self, coin_id: &[u8; 32]) -> Option<&CoinStateJson> {
+        self.derivations
+            .iter()
+            .find_map(|derivations| derivations.coin_states.get(coin_id))
+    }
+}
+
+/// An exclusive lock on a cache, which is held until this is dropped, so two runs can't write
+/// to the same cache at once. The lock is taken on a `.lock` file next to the cache directory
+/// and is released by the OS if thyme exits, so a crashed run never leaves it stuck.
+///
+/// Runs that only read the cache share the lock, so any number of them only wait for a run
+/// that's writing, and a writer only waits for them to finish reading.
+#[derive(Debug)]
+pub struct CacheLock {
+    _file: Option<File>,
+}
+
+impl CacheLock {
+    /// Takes the lock, failing if another run holds it unless `wait` is set. A read-only run
+    /// can only take it shared.
+    pub fn acquire(path: impl AsRef<Path>, wait: bool) -> anyhow::Result<Self> {
+        Self::lock(path.as_ref(), wait, is_read_only())
+    }
+
+    /// Takes the lock shared with the other runs reading the cache.
+    pub fn acquire_shared(path: impl AsRef<Path>, wait: bool) -> anyhow::Result<Self> {
+        Self::lock(path.as_ref(), wait, true)
+    }
+
+    fn lock(path: &Path, wait: bool, shared: bool) -> anyhow::Result<Self> {
+        let lock_path = path.with_extension("lock");
+        let file = if is_read_only() {
+            // The lock file can't be created, but without one no run is writing to the cache.
+            match File::open(&lock_path) {
+                Ok(file) => file,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    return Ok(Self { _file: None })
+                }
+                Err(error) => return Err(error.into()),
+            }
+        } else {
+            File::create(&lock_path)?
+        };
+
+        let locked = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+        match locked {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) if wait => {
+                eprintln!(
+                    "Waiting for another thyme run to release {}",
+                    lock_path.display()
+                );
+                if shared {
+                    file.lock_shared()?;
+                } else {
+                    file.lock()?;
+                }
+            }
+            Err(TryLockError::WouldBlock) => bail!(
+                "Another thyme run is using the cache at {}, pass --wait to run after it finishes",
+                path.display()
+            ),
+            Err(TryLockError::Error(error)) => return Err(error.into()),
+        }
+
+        Ok(Self { _file: Some(file) })
+    }
+}
+
+/// The window files of a cache directory, which can be loaded one at a time.
+#[derive(Debug, Clone)]
+pub struct CacheWindows {
+    paths: Vec<PathBuf>,
+    /// The wallet's intermediate key, which windows saved with `private_cache` derive their
+    /// puzzle hashes from again as they're read.
+    intermediate_pk: Option<PublicKey>,
+}
+
+impl CacheWindows {
+    pub fn open(
+        path: impl AsRef<Path>,
+        intermediate_pk: Option<&PublicKey>,
+    ) -> anyhow::Result<Self> {
+        let mut windows = Vec::new();
+
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            let Some(index) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(window_index)
+            else {
+                continue;
+            };
+            windows.push((index, path));
+        }
+
+        windows.sort();
+
+        for (expected, (index, path)) in windows.iter().enumerate() {
+            if *index != expected {
+                bail!(
+                    "Cache window {expected} is missing before {}",
+                    path.display()
+                );
+            }
+        }
+
+        Ok(Self {
+            paths: windows.into_iter().map(|(_, path)| path).collect(),
+            intermediate_pk: intermediate_pk.cloned(),
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = anyhow::Result<Derivations>> + '_ {
+        self.paths.iter().enumerate().map(|(index, path)| {
+            let mut derivations = read_window(path)?;
+            restore_puzzle_hashes(&mut derivations, index, self.intermediate_pk.as_ref())
+                .map_err(|error| anyhow!("{error} in {}", path.display()))?;
+            Ok(derivations)
+        })
+    }
+
+    /// Links every window file into the directory, which takes no space until the cache
+    /// writes the window again, since windows are replaced rather than written over.
+    /// Windows are copied instead where links aren't supported.
+    pub fn snapshot(&self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        let dir = dir.as_ref();
+        check_writable(dir)?;
+        fs::create_dir_all(dir)?;
+
+        for path in &self.paths {
+            let Some(file_name) = path.file_name() else {
+                continue;
+            };
+            let target = dir.join(file_name);
+            if fs::hard_link(path, &target).is_err() {
+                fs::copy(path, &target)?;
+            }
+        }
+
         Ok(())
     }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// A hash of every window file as it's stored, which changes whenever a sync changes any.
+    pub fn content_hash(&self, hasher: &mut Sha256) -> anyhow::Result<()> {
+        for path in &self.paths {
+            hasher.update(fs::read(path)?);
+        }
+        Ok(())
+    }
+}
+
+/// Reads a cache file, which is zstd compressed unless it was written by an older version.
+fn read_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> anyhow::Result<T> {
+    Ok(serde_json::from_slice(&read_contents(path.as_ref())?)?)
+}
+
+fn read_contents(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let contents = fs::read(path)?;
+    if contents.starts_with(&ZSTD_MAGIC) {
+        Ok(zstd::decode_all(contents.as_slice())?)
+    } else {
+        Ok(contents)
+    }
+}
+
+/// Reads a window, setting aside the coins that can't be read instead of failing the whole
+/// window over them, such as coins written by a newer version before a downgrade. They're
+/// written to a `.quarantine.json` file next to the window like the window itself, and the
+/// window is marked to be synced again from its start so they're fetched again.
+fn read_window(path: &Path) -> anyhow::Result<Derivations> {
+    let contents = read_contents(path)?;
+    let error = match serde_json::from_slice(&contents) {
+        Ok(derivations) => return Ok(derivations),
+        Err(error) => error,
+    };
+
+    let mut value: Value = serde_json::from_slice(&contents)?;
+    let Some(coin_states) = value.get_mut("coin_states").and_then(Value::as_object_mut) else {
+        return Err(error.into());
+    };
+
+    let mut quarantined = serde_json::Map::new();
+    for (coin_id, coin_state) in std::mem::take(coin_states) {
+        let readable = hex::decode(&coin_id).is_ok_and(|bytes| bytes.len() == 32)
+            && CoinStateJson::deserialize(&coin_state).is_ok();
+        if readable {
+            coin_states.insert(coin_id, coin_state);
+        } else {
+            quarantined.insert(coin_id, coin_state);
+        }
+    }
+
+    // The problem is somewhere other than the coins.
+    if quarantined.is_empty() {
+        return Err(error.into());
+    }
+    let mut derivations: Derivations = serde_json::from_value(value)?;
+    derivations.resync = true;
+
+    let quarantine_path = path.with_extension("quarantine.json");
+    let count = quarantined.len();
+    if is_read_only() {
+        eprintln!(
+            "Warning: left out {count} coins of {} that can't be read ({error}). \
+             They weren't set aside with --read-only.",
+            path.display()
+        );
+        return Ok(derivations);
+    }
+
+    // Coins set aside by an earlier load stay, in case the window was saved without them since.
+    let mut previous = if quarantine_path.exists() {
+        read_json::<serde_json::Map<String, Value>>(&quarantine_path)?
+    } else {
+        serde_json::Map::new()
+    };
+    previous.extend(quarantined);
+    write_json(&quarantine_path, &previous)?;
+
+    eprintln!(
+        "Warning: set aside {count} coins of {} that can't be read ({error}) in {}",
+        path.display(),
+        quarantine_path.display()
+    );
+    Ok(derivations)
+}
+
+/// Writes the file next to where it goes and moves it into place, so a run reading the cache
+/// without the lock sees either the old window or the new one, never half of one.
+fn write_json(path: impl AsRef<Path>, value: &impl Serialize) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let contents = serde_json::to_vec(value)?;
+    let partial = path.with_extension("json.partial");
+    fs::write(&partial, zstd::encode_all(contents.as_slice(), ZSTD_LEVEL)?)?;
+    fs::rename(partial, path)?;
+    Ok(())
+}
+
+fn window_file_name(index: usize) -> String {
+    format!("window-{index:06}.json")
+}
+
+fn window_index(file_name: &str) -> Option<usize> {
+    file_name
+        .strip_prefix("window-")?
+        .strip_suffix(".json")?
+        .parse()
+        .ok()
+}
+
+impl CoinStateJson {
+    /// The asset held by the coin, or [`None`] if its puzzle wasn't recognized.
+    pub fn asset(&self) -> Option<Asset> {
+        match &self.parent_puzzle {
+            None => Some(Asset::Xch),
+            Some(PuzzleInfo::Cat(cat)) => Some(Asset::Cat(cat.asset_id.into())),
+            Some(PuzzleInfo::Nft(nft)) => Some(Asset::Nft(nft.launcher_id.into())),
+            Some(PuzzleInfo::Custom(_)) => Some(Asset::Xch),
+            Some(PuzzleInfo::Unknown) => None,
+        }
+    }
+
+    /// The inner puzzle hash the coin is locked to, which is how it's tied to a derivation.
+    pub fn p2_puzzle_hash(&self) -> Bytes32 {
+        match &self.parent_puzzle {
+            Some(PuzzleInfo::Cat(cat)) => cat.p2_puzzle_hash.into(),
+            Some(PuzzleInfo::Nft(nft)) => nft.p2_puzzle_hash.into(),
+            Some(PuzzleInfo::Custom(custom)) => custom.p2_puzzle_hash.into(),
+            _ => self.coin.puzzle_hash.into(),
+        }
+    }
 }
 
 impl From<Coin> for CoinJson {
@@ -192,3 +683,13 @@ impl From<Cat> for CatJson {
         }
     }
 }
+
+impl From<Nft<NftMetadata>> for NftJson {
+    fn from(value: Nft<NftMetadata>) -> Self {
+        Self {
+            launcher_id: value.info.launcher_id.into(),
+            p2_puzzle_hash: value.info.p2_puzzle_hash.into(),
+            metadata_uris: value.info.metadata.metadata_uris,
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+use chia::ssl::ChiaCertificate;
+use clvmr::sha2::Sha256;
+use native_tls::Certificate;
+
+use crate::read_only::check_writable;
+
+const CERT_FILE: &str = "thyme.crt";
+const KEY_FILE: &str = "thyme.key";
+
+/// Where the TLS identity presented to full nodes is kept.
+#[derive(Debug, Clone)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertPaths {
+    /// The certificate files in the `ssl` directory of the data dir.
+    pub fn new() -> anyhow::Result<Self> {
+        let dir = data_dir()?.join("ssl");
+        Ok(Self {
+            cert: dir.join(CERT_FILE),
+            key: dir.join(KEY_FILE),
+        })
+    }
+
+    /// Where older versions kept the certificate, in the working directory.
+    pub fn legacy() -> Self {
+        Self {
+            cert: PathBuf::from(CERT_FILE),
+            key: PathBuf::from(KEY_FILE),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.cert.exists() && self.key.exists()
+    }
+
+    pub fn load(&self) -> anyhow::Result<ChiaCertificate> {
+        Ok(ChiaCertificate {
+            cert_pem: fs::read_to_string(&self.cert)?,
+            key_pem: fs::read_to_string(&self.key)?,
+        })
+    }
+
+    /// Writes the certificate, with the key only readable by the current user.
+    pub fn save(&self, cert: &ChiaCertificate) -> anyhow::Result<()> {
+        check_writable(&self.cert)?;
+        if let Some(dir) = self.cert.parent() {
+            fs::create_dir_all(dir)?;
+            restrict(dir, 0o700)?;
+        }
+        write_private(&self.key, &cert.key_pem)?;
+        fs::write(&self.cert, &cert.cert_pem)?;
+        Ok(())
+    }
+
+    /// Loads the certificate, generating one the first time. A certificate left in the
+    /// working directory by older versions is moved into the data dir instead.
+    pub fn load_or_generate(&self) -> anyhow::Result<ChiaCertificate> {
+        if self.exists() {
+            return self.load();
+        }
+
+        let legacy = Self::legacy();
+
+        let cert = if legacy.exists() {
+            eprintln!(
+                "Moving {CERT_FILE} and {KEY_FILE} to {}",
+                self.cert.parent().unwrap_or(Path::new(".")).display()
+            );
+            let cert = legacy.load()?;
+            self.save(&cert)?;
+            fs::remove_file(legacy.cert)?;
+            fs::remove_file(legacy.key)?;
+            cert
+        } else {
+            let cert = ChiaCertificate::generate()?;
+            self.save(&cert)?;
+            cert
+        };
+
+        Ok(cert)
+    }
+}
+
+/// The directory for files that belong to the user rather than to a working directory,
+/// which is `THYME_DATA_DIR` if it's set, or else the platform's data directory.
+pub fn data_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = env::var_os("THYME_DATA_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let home = || env::var_os("HOME").map(PathBuf::from);
+
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library").join("Application Support"))
+    } else {
+        env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".local").join("share")))
+    };
+
+    base.map(|base| base.join("thyme"))
+        .ok_or_else(|| anyhow!("Could not find a data directory, set THYME_DATA_DIR"))
+}
+
+/// The SHA-256 fingerprint of the certificate, as colon separated hex like `openssl` prints.
+pub fn fingerprint(cert: &ChiaCertificate) -> anyhow::Result<String> {
+    let der = Certificate::from_pem(cert.cert_pem.as_bytes())?.to_der()?;
+    let mut hasher = Sha256::new();
+    hasher.update(der);
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+/// Creates a new certificate, failing if one exists unless `replace` is set.
+pub fn generate(paths: &CertPaths, replace: bool) -> anyhow::Result<ChiaCertificate> {
+    if paths.exists() && !replace {
+        bail!(
+            "A certificate already exists at {}, use `thyme cert rotate` to replace it",
+            paths.cert.display()
+        );
+    }
+
+    let cert = ChiaCertificate::generate()?;
+    paths.save(&cert)?;
+    Ok(cert)
+}
+
+fn write_private(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(contents.as_bytes())?;
+
+    // The mode only applies when the file is created, so tighten an existing one too.
+    restrict(path, 0o600)
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn restrict(path: &Path, mode: u32) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
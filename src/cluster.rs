@@ -0,0 +1,222 @@
+use std::{fs, path::Path};
+
+use anyhow::anyhow;
+use chia::{client::Peer, protocol::Bytes32};
+use chia_wallet_sdk::decode_address;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    addresses::address,
+    cache::{CoinStateJson, PuzzleInfo},
+    config::{Config, RetryConfig},
+    parents::fetch_parent_spends,
+    read_only::is_read_only,
+    timings::Timings,
+};
+
+/// Groups of counterparty addresses that are likely controlled by the same wallet,
+/// keyed by a cluster id derived from the lowest puzzle hash in each group.
+///
+/// Senders are only clustered together on evidence that their coins were spent in the same
+/// spend bundle, which is when the spend of one parent asserts an announcement created by the
+/// spend of another. The ids stay the same between reports unless a cluster is merged with
+/// another one.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Clusters {
+    pub clusters: IndexMap<String, Vec<String>>,
+}
+
+impl Clusters {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// A read-only run keeps what it looked up in memory, since this is only a cache.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        if is_read_only() {
+            return Ok(());
+        }
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Clusters the senders of the received coins, joining those that were spent together.
+    pub fn from_senders(config: &Config, senders: &Senders) -> anyhow::Result<Self> {
+        // Each puzzle hash points towards the lowest one in its cluster, which is the root.
+        let mut roots = IndexMap::<Bytes32, Bytes32>::new();
+        let root = |roots: &IndexMap<Bytes32, Bytes32>, mut puzzle_hash: Bytes32| {
+            while roots[&puzzle_hash] != puzzle_hash {
+                puzzle_hash = roots[&puzzle_hash];
+            }
+            puzzle_hash
+        };
+
+        for sender in senders.coins.values() {
+            roots.entry(*sender).or_insert(*sender);
+        }
+        for [a, b] in &senders.co_spent {
+            let (a, b) = (root(&roots, *a), root(&roots, *b));
+            roots.insert(a.max(b), a.min(b));
+        }
+
+        let mut members = IndexMap::<Bytes32, Vec<Bytes32>>::new();
+        for puzzle_hash in roots.keys() {
+            members
+                .entry(root(&roots, *puzzle_hash))
+                .or_default()
+                .push(*puzzle_hash);
+        }
+        members.sort_keys();
+
+        let mut clusters = IndexMap::new();
+        for (root, mut puzzle_hashes) in members {
+            puzzle_hashes.sort();
+            let addresses = puzzle_hashes
+                .into_iter()
+                .map(|puzzle_hash| address(config, puzzle_hash))
+                .collect::<anyhow::Result<_>>()?;
+            clusters.insert(cluster_id(root), addresses);
+        }
+
+        Ok(Self { clusters })
+    }
+
+    /// The cluster an address belongs to.
+    pub fn cluster_of(&self, address: &str) -> Option<&str> {
+        self.clusters
+            .iter()
+            .find(|(_, addresses)| addresses.iter().any(|member| member == address))
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// The name of each clustered puzzle hash, which is the label of the first address in its
+    /// cluster that has one in the address book, or else the cluster id.
+    pub fn names(
+        &self,
+        labels: &IndexMap<String, String>,
+    ) -> anyhow::Result<IndexMap<Bytes32, String>> {
+        let mut names = IndexMap::new();
+
+        for (id, addresses) in &self.clusters {
+            let name = addresses
+                .iter()
+                .find_map(|address| labels.get(address))
+                .unwrap_or(id);
+
+            for address in addresses {
+                let (puzzle_hash, _prefix) = decode_address(address)
+                    .map_err(|error| anyhow!("Invalid address `{address}` in {id}: {error}"))?;
+                names.insert(Bytes32::from(puzzle_hash), name.clone());
+            }
+        }
+
+        Ok(names)
+    }
+}
+
+/// The senders of the received coins, and which of them were spent in the same spend bundle.
+#[derive(Debug, Default)]
+pub struct Senders {
+    /// The puzzle hash each received coin was sent from, keyed by coin id.
+    pub coins: IndexMap<[u8; 32], Bytes32>,
+    /// Pairs of senders whose parent coins were spent in the same spend bundle.
+    pub co_spent: Vec<[Bytes32; 2]>,
+}
+
+/// Looks up the puzzle hash each received coin was sent from, which for CATs is the inner
+/// puzzle hash of the parent. NFTs and coins without a parent spend, such as farming rewards,
+/// have no sender.
+pub async fn coin_senders(
+    peer: &Peer,
+    genesis_challenge: Bytes32,
+    coins: &[([u8; 32], CoinStateJson)],
+    concurrency: usize,
+    retry: &RetryConfig,
+    timings: &Timings,
+) -> anyhow::Result<Senders> {
+    let parents = coins
+        .iter()
+        .filter(|(_, coin_state)| {
+            !matches!(
+                coin_state.parent_puzzle,
+                Some(PuzzleInfo::Nft(_) | PuzzleInfo::Unknown)
+            )
+        })
+        .filter_map(|(_, coin_state)| {
+            Some((
+                Bytes32::from(coin_state.coin.parent_coin_info),
+                coin_state.created_height?,
+            ))
+        })
+        .collect::<IndexMap<_, _>>();
+
+    let parent_spends = fetch_parent_spends(
+        peer,
+        genesis_challenge,
+        parents,
+        concurrency,
+        retry,
+        timings,
+    )
+    .await?;
+
+    let mut senders = Senders::default();
+    let mut parent_senders = IndexMap::new();
+
+    for (coin_id, coin_state) in coins {
+        let parent_id = Bytes32::from(coin_state.coin.parent_coin_info);
+        let parent_spend = parent_spends.get(&parent_id).and_then(Option::as_ref);
+        let sender = match &coin_state.parent_puzzle {
+            None | Some(PuzzleInfo::Custom(_)) => {
+                parent_spend.map(|parent_spend| parent_spend.coin.puzzle_hash)
+            }
+            Some(PuzzleInfo::Cat(cat)) => cat
+                .lineage_proof
+                .as_ref()
+                .map(|proof| proof.parent_inner_puzzle_hash.into()),
+            Some(PuzzleInfo::Nft(_) | PuzzleInfo::Unknown) => None,
+        };
+
+        if let Some(sender) = sender {
+            senders.coins.insert(*coin_id, sender);
+            if let Some(parent_spend) = parent_spend {
+                parent_senders.insert(parent_id, (sender, parent_spend));
+            }
+        }
+    }
+
+    // A parent whose puzzle fails to run just isn't linked to any other, since it was spent on
+    // chain regardless.
+    let mut announcers = IndexMap::new();
+    let mut assertions = Vec::new();
+    for (parent_id, (sender, parent_spend)) in &parent_senders {
+        let Ok((created, asserted)) = parent_spend.announcements() else {
+            continue;
+        };
+        for announcement_id in created {
+            announcers.insert(announcement_id, (*parent_id, *sender));
+        }
+        assertions.extend(asserted.into_iter().map(|id| (*parent_id, *sender, id)));
+    }
+
+    for (parent_id, sender, announcement_id) in assertions {
+        if let Some((announcer, announcer_sender)) = announcers.get(&announcement_id) {
+            if *announcer != parent_id {
+                senders.co_spent.push([sender, *announcer_sender]);
+            }
+        }
+    }
+
+    Ok(senders)
+}
+
+fn cluster_id(root: Bytes32) -> String {
+    format!("cluster-{}", &hex::encode(root)[..8])
+}
@@ -1,20 +1,680 @@
 use std::{fs, path::Path};
 
+use anyhow::anyhow;
+use chia::protocol::CoinStateFilters;
+use chrono::{Datelike, NaiveDate};
 use hex_literal::hex;
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use serde_with::{hex::Hex, serde_as};
 
+use crate::{accounting::LotMethod, cache::SyncFilters, read_only::check_writable};
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub full_node_uri: String,
+    pub peers: Vec<String>,
+    pub peer_reprobe_interval: u64,
+    /// How many of the configured peers a sync is split between, each syncing its own
+    /// derivation windows.
+    pub sync_peers: usize,
+    /// Seconds between the small requests that keep the connection alive during a sync,
+    /// or 0 to send none.
+    pub heartbeat_interval: u64,
+    pub min_peak_height: Option<u32>,
+    pub allow_unsynced_peer: bool,
+    /// Check the synced headers against a second peer on every report, like `--verify-headers`.
+    pub verify_headers: bool,
     #[serde_as(as = "Hex")]
     pub genesis_challenge: [u8; 32],
     pub network_id: String,
     pub dust_threshold: u64,
+    pub coin_states: CoinStateConfig,
+    pub concurrency: usize,
+    pub retry: RetryConfig,
+    pub rate_limit: RateLimitConfig,
+    pub currency: String,
+    /// Falls back to the key stored with `thyme config set-secret coingecko_api_key`.
+    pub coingecko_api_key: Option<String>,
+    pub prices: PricesConfig,
+    #[serde_as(as = "IndexMap<Hex, _>")]
+    pub assets: IndexMap<[u8; 32], AssetConfig>,
+    pub entities: IndexMap<String, EntityConfig>,
+    pub tags: IndexMap<String, TagConfig>,
+    /// Files backing up the transactions of these coins, such as invoice PDFs or screenshots of
+    /// exchange trades, by coin id. Reports list them next to each transaction.
+    #[serde_as(as = "IndexMap<Hex, _>")]
+    pub evidence: IndexMap<[u8; 32], Vec<String>>,
+    /// Counterparty labels from `thyme label` that are exchanges' hot wallets. Coins received
+    /// from them are tagged as `withdrawal from <label>` with --counterparties.
+    pub exchanges: Vec<String>,
+    pub exchange_export: ExchangeExportConfig,
+    /// In-house puzzles that lock coins to the wallet, by name. Coins are only found for
+    /// puzzles that were configured when their window was synced, so adding one later needs
+    /// a sync with `--reset` to pick up the coins already locked by it.
+    pub puzzles: IndexMap<String, CustomPuzzleConfig>,
+    /// How NFTs received without a payment, like gifts, are valued with --nft-collections,
+    /// by collection id or name. They have no basis otherwise.
+    pub nft_valuations: IndexMap<String, NftValuationConfig>,
+    pub accounts: AccountsConfig,
+    pub categories: CategoriesConfig,
+    pub notifications: NotificationsConfig,
+    pub wealth_tax: WealthTaxConfig,
+    pub vat: VatConfig,
+    pub estimated_tax: EstimatedTaxConfig,
+    pub loss_carryforward: LossCarryforwardConfig,
+    /// How `thyme simulate` picks the lots a sale is taken out of: `fifo`, `lifo`, `hifo` or
+    /// `acb` for the average cost. `--cost-basis` overrides it for a run.
+    pub lot_method: LotMethod,
+    /// The method from `--cost-basis`, which overrides `lot_method` and each residency's for
+    /// the run. It's never read from or written to the config file.
+    #[serde(skip)]
+    pub cost_basis: Option<LotMethod>,
+    /// The countries lived in, by name, for `report --split-residency`. Each applies from its
+    /// `from` day until the next one's.
+    pub residencies: IndexMap<String, ResidencyConfig>,
+    pub self_spends: SelfSpendConfig,
+    pub pool: PoolConfig,
+    pub indexer: IndexerConfig,
+    pub dexie: DexieConfig,
+    /// Leave the derived puzzle hashes out of the cache, keeping only the fingerprint of the
+    /// key they're derived from, so a copy of the cache doesn't list every address of the
+    /// wallet. They're derived again from `--key` whenever a window is read. The coins are
+    /// still cached, with the puzzle hashes of the addresses that received them. The address
+    /// book keeps only the derivation index of each address issued.
+    pub private_cache: bool,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AssetConfig {
+    pub name: Option<String>,
+    pub coingecko_id: Option<String>,
+}
+
+/// Which coin states are requested from peers while syncing.
+///
+/// Reports net the coins spent at each height against those created, so leaving out spent
+/// coins is only useful for a quick balance check against a constrained peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoinStateConfig {
+    pub include_spent: bool,
+    pub include_unspent: bool,
+    /// Whether to include coins hinted to the wallet, which is how CATs and NFTs are found.
+    pub include_hinted: bool,
+    /// The smallest coin amount the peer returns, in mojos. The dust threshold is used instead
+    /// when it's higher.
+    pub min_amount: u64,
+    /// The most puzzle hashes sent in one request, which is paged through on its own.
+    /// Defaults to a whole derivation window at once. Peers that reject a request as too
+    /// large are sent fewer, growing back to this while they answer in a single page.
+    pub puzzle_hashes_per_request: Option<usize>,
+}
+
+impl Default for CoinStateConfig {
+    fn default() -> Self {
+        Self {
+            include_spent: true,
+            include_unspent: true,
+            include_hinted: true,
+            min_amount: 0,
+            puzzle_hashes_per_request: None,
+        }
+    }
 }
 
+/// A public indexer that windows are filled from on their first sync, which then only pages
+/// the peer from near the indexer's peak. Every coin from the indexer is checked with the peer,
+/// but one it leaves out is missed until the cache is synced again with `--reset`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexerConfig {
+    /// The base URL of an indexer serving the full node's RPC API, like
+    /// `https://api.coinset.org`, or unset to sync from genesis.
+    pub url: Option<String>,
+    pub puzzle_hashes_per_request: usize,
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            puzzle_hashes_per_request: 100,
+        }
+    }
+}
+
+/// The columns of the exchange exports given to `report --exchange-export`, which list the
+/// withdrawals the exchange sent to the wallet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExchangeExportConfig {
+    pub time_column: String,
+    /// How the times are written, as a `chrono` format like `%Y-%m-%d %H:%M:%S`, in UTC.
+    /// RFC 3339 times are read if it isn't set.
+    pub time_format: Option<String>,
+    /// The amount in the display unit of the asset. A minus sign is ignored.
+    pub amount_column: String,
+    pub address_column: String,
+    /// How far the time in the export can be from the block the coin was created in.
+    pub window_minutes: u32,
+}
+
+impl Default for ExchangeExportConfig {
+    fn default() -> Self {
+        Self {
+            time_column: "Time".to_string(),
+            time_format: None,
+            amount_column: "Amount".to_string(),
+            address_column: "Address".to_string(),
+            window_minutes: 60,
+        }
+    }
+}
+
+/// Where `thyme offer status` looks up offers given by their Dexie id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DexieConfig {
+    pub url: String,
+}
+
+impl Default for DexieConfig {
+    fn default() -> Self {
+        Self {
+            url: "https://api.dexie.space".to_string(),
+        }
+    }
+}
+
+/// Limits on requests to the price API, whose free tier only allows a few calls a minute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PricesConfig {
+    /// The most price requests sent a minute, or unlimited if unset, like with a paid plan.
+    pub requests_per_minute: Option<f64>,
+    /// How many price requests a run can send before asking to go ahead, since going over
+    /// the plan's monthly calls costs money or gets the key blocked.
+    pub budget: u64,
+}
+
+impl Default for PricesConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: Some(30.0),
+            budget: 200,
+        }
+    }
+}
+
+/// How requests to peers are retried when they time out or are rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// How many times a request is sent before giving up, including the first time.
+    pub max_attempts: u32,
+    /// Seconds to wait for an answer to each attempt.
+    pub timeout: u64,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            timeout: 60,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+/// A cap on how fast requests are sent to peers, so community nodes and farms sharing the
+/// network aren't slowed down or trip their own rate limits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// The average number of requests sent per second, or unlimited if unset.
+    pub requests_per_second: Option<f64>,
+    /// How many requests can be sent at once after being idle, before the cap applies.
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: None,
+            burst: 10,
+        }
+    }
+}
+
+/// The accounts used by journal exports, where `{asset}` is replaced with the asset's commodity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// Where each asset is held.
+    pub assets: String,
+    /// The other side of everything received.
+    pub income: String,
+    /// The other side of everything sent.
+    pub expenses: String,
+    /// The other side of network fees.
+    pub fees: String,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            assets: "Assets:Chia:{asset}".to_string(),
+            income: "Income:Chia".to_string(),
+            expenses: "Expenses:Chia".to_string(),
+            fees: "Expenses:Chia:Fees".to_string(),
+        }
+    }
+}
+
+/// The categories used by bank feed exports for QuickBooks and Xero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CategoriesConfig {
+    pub income: String,
+    pub expenses: String,
+    pub fees: String,
+}
+
+impl Default for CategoriesConfig {
+    fn default() -> Self {
+        Self {
+            income: "Uncategorized Income".to_string(),
+            expenses: "Uncategorized Expense".to_string(),
+            fees: "Bank Fees".to_string(),
+        }
+    }
+}
+
+/// Where `thyme watch` and `thyme serve` post a message about each new transaction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    /// Refused here, since anyone who can read the config could post with them. A Discord
+    /// channel webhook is stored with `thyme config set-secret discord_webhook_url`, and a
+    /// Telegram bot's token with `thyme config set-secret telegram_bot_token`, or given in
+    /// `THYME_DISCORD_WEBHOOK_URL` and `THYME_TELEGRAM_BOT_TOKEN`.
+    #[serde(skip_serializing)]
+    pub discord_webhook_url: Option<String>,
+    #[serde(skip_serializing)]
+    pub telegram_bot_token: Option<String>,
+    /// The chat the Telegram bot posts to.
+    pub telegram_chat_id: Option<String>,
+    /// Whether to post about coins received and sent.
+    pub receive: bool,
+    pub send: bool,
+    /// Turns posts on or off by category, which is the label of the address a transaction was
+    /// received to, like `pool payout`, or `unlabeled`. Categories left out are posted.
+    pub categories: IndexMap<String, bool>,
+    /// Gains realized so far in the year to post about once they're passed, in the report's
+    /// currency, like `[10000, 25000]`. Gains are worked out with the `lot_method`.
+    pub gain_thresholds: Vec<f64>,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        Self {
+            discord_webhook_url: None,
+            telegram_bot_token: None,
+            telegram_chat_id: None,
+            receive: true,
+            send: true,
+            categories: IndexMap::new(),
+            gain_thresholds: Vec::new(),
+        }
+    }
+}
+
+/// How `report --wealth-tax` values the holdings of the year.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WealthTaxConfig {
+    /// `average` for the average of what the holdings were worth on each day of the year,
+    /// or a day of the year like `12-31` to value them on that day.
+    pub valuation: String,
+}
+
+impl Default for WealthTaxConfig {
+    fn default() -> Self {
+        Self {
+            valuation: "12-31".to_string(),
+        }
+    }
+}
+
+/// How holdings are valued for a wealth tax.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Valuation {
+    Average,
+    Day { month: u32, day: u32 },
+}
+
+impl Valuation {
+    /// The valuation day in the year, unless it's averaged or the day doesn't exist that year.
+    pub fn date(self, year: i32) -> Option<NaiveDate> {
+        match self {
+            Self::Average => None,
+            Self::Day { month, day } => NaiveDate::from_ymd_opt(year, month, day),
+        }
+    }
+}
+
+impl WealthTaxConfig {
+    pub fn valuation(&self) -> Result<Valuation, String> {
+        if self.valuation == "average" {
+            return Ok(Valuation::Average);
+        }
+
+        let invalid = || {
+            format!(
+                "should be `average` or a day like 12-31, not {:?}",
+                self.valuation
+            )
+        };
+        let (month, day) = self.valuation.split_once('-').ok_or_else(invalid)?;
+        let (month, day) = (
+            month.parse().map_err(|_| invalid())?,
+            day.parse().map_err(|_| invalid())?,
+        );
+
+        // A leap year has every day there is.
+        if NaiveDate::from_ymd_opt(2024, month, day).is_none() {
+            return Err(invalid());
+        }
+        Ok(Valuation::Day { month, day })
+    }
+}
+
+/// How `report --vat` totals the sales received into VAT or GST periods.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VatConfig {
+    pub period: VatPeriod,
+    /// The standard rate as a percentage, like `20`. Payments are taken to include it.
+    pub rate: f64,
+    /// The purposes of the addresses customers pay to, from `thyme address --purpose`.
+    /// Nothing is a sale if this is empty, so `--vat` needs it set.
+    pub sales_labels: Vec<String>,
+}
+
+impl Default for VatConfig {
+    fn default() -> Self {
+        Self {
+            period: VatPeriod::Quarter,
+            rate: 0.0,
+            sales_labels: Vec::new(),
+        }
+    }
+}
+
+/// A country lived in, whose part of the year is reported on its own.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResidencyConfig {
+    /// The first day lived there, like `2024-07-01`.
+    pub from: String,
+    /// The currency its part of the year is reported in, instead of `currency`.
+    pub currency: Option<String>,
+    /// How its gains take sends out of lots, instead of `lot_method`.
+    pub lot_method: Option<LotMethod>,
+}
+
+impl ResidencyConfig {
+    pub fn start_date(&self) -> Option<NaiveDate> {
+        self.from.parse().ok()
+    }
+}
+
+/// How `report --estimated-tax` works out the payments of estimated tax on the year's income.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EstimatedTaxConfig {
+    /// The marginal rate as a percentage, like `24`.
+    pub rate: f64,
+    /// The days each payment is due, like `04-15`. Each covers the income up to the end of
+    /// the month before, and one earlier in the year than the last is due the next year.
+    /// Defaults to the US quarterly estimated tax dates.
+    pub due_dates: Vec<String>,
+}
+
+impl Default for EstimatedTaxConfig {
+    fn default() -> Self {
+        Self {
+            rate: 0.0,
+            due_dates: ["04-15", "06-15", "09-15", "01-15"]
+                .map(str::to_string)
+                .to_vec(),
+        }
+    }
+}
+
+/// How `report --carry-losses` carries net realized losses into the following years.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LossCarryforwardConfig {
+    /// How much of a year's net loss can be deducted from other income that year, in the
+    /// report's currency, like `3000` in the US. The rest is carried forward, which is all of
+    /// it by default.
+    pub deduction_limit: f64,
+}
+
+impl EstimatedTaxConfig {
+    /// The due dates of the payments for the year's income, in order.
+    pub fn due_dates(&self, year: i32) -> Result<Vec<NaiveDate>, String> {
+        let mut dates = Vec::<NaiveDate>::new();
+
+        for due_date in &self.due_dates {
+            let invalid = || format!("should have days like 04-15, not {due_date:?}");
+            let (month, day) = due_date.split_once('-').ok_or_else(invalid)?;
+            let (month, day): (u32, u32) = (
+                month.parse().map_err(|_| invalid())?,
+                day.parse().map_err(|_| invalid())?,
+            );
+
+            if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+                return Err(invalid());
+            }
+
+            let mut year = dates.last().map_or(year, |last| last.year());
+            if dates
+                .last()
+                .is_some_and(|last| (last.month(), last.day()) > (month, day))
+            {
+                year += 1;
+            }
+            // A day that doesn't exist this year, like 02-29, is due on the last day of the month.
+            let date = (1..=day)
+                .rev()
+                .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+                .ok_or_else(invalid)?;
+            dates.push(date);
+        }
+
+        Ok(dates)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VatPeriod {
+    Month,
+    Quarter,
+    Year,
+}
+
+/// How coin splitting and merging is told apart from payments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelfSpendConfig {
+    /// The most XCH in mojos that a spend whose outputs all return to the wallet can lose and
+    /// still be a self-spend, since that's what it paid in fees rather than a payment.
+    pub max_fee: u64,
+}
+
+impl Default for SelfSpendConfig {
+    fn default() -> Self {
+        Self {
+            max_fee: 10_000_000,
+        }
+    }
+}
+
+/// The plot NFTs whose pool payouts `report --pool-payouts` attributes to the pool they were
+/// paid for.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// The launcher ids of the plot NFTs, by name, as `chia plotnft show` lists them.
+    #[serde_as(as = "IndexMap<_, Hex>")]
+    pub plot_nfts: IndexMap<String, [u8; 32]>,
+    /// The address purposes that pools pay out to, from `thyme address --purpose`.
+    pub payout_labels: Vec<String>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            plot_nfts: IndexMap::new(),
+            payout_labels: vec!["pool payout".to_string()],
+        }
+    }
+}
+
+/// The derivations and addresses that belong to a named entity, such as a business.
+/// Derivations are indices like `7` or inclusive ranges like `0-499`.
+///
+/// Rules are coin filters like `asset=XCH and amount=0.25`, and coins are annotated coin ids,
+/// which assign individual transactions to the entity wherever they were received.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EntityConfig {
+    pub derivations: Vec<String>,
+    pub addresses: Vec<String>,
+    pub rules: Vec<String>,
+    pub coins: Vec<String>,
+}
+
+/// A free-form tag, such as a project or cost center, attached to the transactions of coins
+/// that match any of its rules or are annotated with it. Unlike entities, one coin can have
+/// several tags.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TagConfig {
+    pub rules: Vec<String>,
+    pub coins: Vec<String>,
+}
+
+/// A puzzle curried with one of the wallet's puzzle hashes as its owner, such as an in-house
+/// vault. Coins locked by it hold XCH of the owner's derivation rather than a counterparty's.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CustomPuzzleConfig {
+    /// The tree hash of the uncurried puzzle, in hex.
+    pub mod_hash: Option<String>,
+    /// The serialized uncurried puzzle, as CLVM hex, for when its tree hash isn't at hand.
+    pub puzzle: Option<String>,
+    /// The curried arguments in order, each as serialized CLVM hex, with [`OWNER_ARG`] where
+    /// the owner's puzzle hash goes.
+    pub args: Vec<String>,
+}
+
+/// How the NFTs of a collection are valued when received without a payment.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NftValuationConfig {
+    pub method: NftValuationMethod,
+    /// The collection's `col1...` id on Dexie, whose sales there give its floor price, and
+    /// whose NFTs' own sales there give their last sale.
+    pub dexie_collection: Option<String>,
+    /// The collection's floor price in XCH by the day it was seen, like `"2024-03-01" = 1.5`,
+    /// which overrides the floor from Dexie. An NFT is valued at the latest floor on or before
+    /// the day it was received.
+    pub floor_prices: IndexMap<String, f64>,
+    /// The value of each NFT in the report's currency, for the `manual` method.
+    pub value: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NftValuationMethod {
+    /// The collection's floor price from `floor_prices`, or else from Dexie.
+    Floor,
+    /// What the NFT last sold for in the wallet's history, such as before it was gifted back,
+    /// or else on Dexie, or the floor price if it never sold and there is one.
+    #[default]
+    LastSale,
+    /// The fixed `value`.
+    Manual,
+}
+
+impl NftValuationConfig {
+    /// Whether there's anywhere to find a floor price, in the config or on Dexie.
+    pub fn has_floor(&self) -> bool {
+        !self.floor_prices.is_empty() || self.dexie_collection.is_some()
+    }
+
+    /// The latest floor price on or before the day in `floor_prices`, and the day it's from.
+    pub fn floor_price(&self, date: NaiveDate) -> Option<(NaiveDate, f64)> {
+        self.floor_prices
+            .iter()
+            .filter_map(|(day, price)| Some((day.parse::<NaiveDate>().ok()?, *price)))
+            .filter(|(day, _)| *day <= date)
+            .max_by_key(|(day, _)| *day)
+    }
+}
+
+/// The curried argument of a custom puzzle that's the owner's puzzle hash.
+pub const OWNER_ARG: &str = "p2_puzzle_hash";
+
 impl Config {
+    /// The filters for coin state requests while syncing.
+    ///
+    /// Coins under the dust threshold are never cached, so the peer can leave them out of its
+    /// responses rather than sending them only to be dropped. Coins are still checked against
+    /// the threshold after they arrive, in case a peer ignores the minimum amount.
+    pub fn coin_state_filters(&self) -> CoinStateFilters {
+        let filters = self.sync_filters();
+        CoinStateFilters::new(
+            filters.include_spent,
+            filters.include_unspent,
+            filters.include_hinted,
+            filters.min_amount,
+        )
+    }
+
+    /// The filters recorded with each window, which every later sync of it has to match.
+    pub fn sync_filters(&self) -> SyncFilters {
+        SyncFilters {
+            include_spent: self.coin_states.include_spent,
+            include_unspent: self.coin_states.include_unspent,
+            include_hinted: self.coin_states.include_hinted,
+            min_amount: self.coin_states.min_amount.max(self.dust_threshold),
+        }
+    }
+
+    /// The primary full node followed by any additional peers, without duplicates.
+    pub fn full_node_uris(&self) -> Vec<String> {
+        let mut uris = vec![self.full_node_uri.clone()];
+        for uri in &self.peers {
+            if !uris.contains(uri) {
+                uris.push(uri.clone());
+            }
+        }
+        uris
+    }
+
     pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
         let path = path.as_ref();
         if !path.exists() {
@@ -23,25 +683,400 @@ impl Config {
             return Ok(config);
         }
         let contents = fs::read_to_string(path)?;
-        Ok(toml::from_str(&contents)?)
+        let config: Self = toml::from_str(&contents)
+            .map_err(|error| anyhow!("Invalid config in {}: {error}", path.display()))?;
+        config.validate(path, &contents)?;
+        Ok(config)
+    }
+
+    /// The `lot_method`, unless `--cost-basis` overrides it.
+    pub fn lot_method(&self) -> LotMethod {
+        self.cost_basis.unwrap_or(self.lot_method)
+    }
+
+    /// Checks the values that parse fine but would only fail later, often in confusing ways,
+    /// like a genesis challenge from the wrong network getting every request rejected.
+    fn validate(&self, path: &Path, contents: &str) -> anyhow::Result<()> {
+        let fail = |table: Option<&str>, key: &str, message: String| {
+            let location = match line_of(contents, table, key) {
+                Some(line) => format!("{}:{line}", path.display()),
+                None => path.display().to_string(),
+            };
+            let key = match table {
+                Some(table) => format!("{table}.{key}"),
+                None => key.to_string(),
+            };
+            Err(anyhow!("Invalid config at {location}: `{key}` {message}"))
+        };
+
+        match NETWORKS.iter().find(|(id, _)| *id == self.network_id) {
+            Some((id, genesis_challenge)) if *genesis_challenge != self.genesis_challenge => {
+                return fail(
+                    None,
+                    "genesis_challenge",
+                    format!(
+                        "isn't the one for {id}, which is {}",
+                        hex::encode(genesis_challenge)
+                    ),
+                );
+            }
+            Some(_) => {}
+            None => eprintln!(
+                "Warning: `network_id` {:?} isn't one of {}, so the genesis challenge can't be checked",
+                self.network_id,
+                NETWORKS.map(|(id, _)| id).join(", ")
+            ),
+        }
+
+        if let Err(problem) = check_uri(&self.full_node_uri) {
+            return fail(None, "full_node_uri", problem);
+        }
+        for uri in &self.peers {
+            if let Err(problem) = check_uri(uri) {
+                return fail(None, "peers", format!("has {uri:?}, which {problem}"));
+            }
+        }
+
+        if self.sync_peers == 0 {
+            return fail(None, "sync_peers", "must be at least 1".to_string());
+        }
+        if self.concurrency == 0 {
+            return fail(None, "concurrency", "must be at least 1".to_string());
+        }
+        if self.retry.max_attempts == 0 {
+            return fail(
+                Some("retry"),
+                "max_attempts",
+                "must be at least 1".to_string(),
+            );
+        }
+        if self.retry.initial_backoff_ms > self.retry.max_backoff_ms {
+            return fail(
+                Some("retry"),
+                "initial_backoff_ms",
+                "can't be more than `max_backoff_ms`".to_string(),
+            );
+        }
+        if let Some(rate) = self.rate_limit.requests_per_second {
+            if !(rate > 0.0 && rate.is_finite()) {
+                return fail(
+                    Some("rate_limit"),
+                    "requests_per_second",
+                    "must be a positive number, or left out for no limit".to_string(),
+                );
+            }
+        }
+        if let Some(rate) = self.prices.requests_per_minute {
+            if !(rate > 0.0 && rate.is_finite()) {
+                return fail(
+                    Some("prices"),
+                    "requests_per_minute",
+                    "must be a positive number, or left out for no limit".to_string(),
+                );
+            }
+        }
+        if self.coin_states.puzzle_hashes_per_request == Some(0) {
+            return fail(
+                Some("coin_states"),
+                "puzzle_hashes_per_request",
+                "must be at least 1".to_string(),
+            );
+        }
+        if self.currency.is_empty() || !self.currency.chars().all(|c| c.is_ascii_alphabetic()) {
+            return fail(
+                None,
+                "currency",
+                format!(
+                    "should be a currency code like usd, not {:?}",
+                    self.currency
+                ),
+            );
+        }
+        let notifications = &self.notifications;
+        for (key, value) in [
+            ("discord_webhook_url", &notifications.discord_webhook_url),
+            ("telegram_bot_token", &notifications.telegram_bot_token),
+        ] {
+            if value.is_some() {
+                return fail(
+                    Some("notifications"),
+                    key,
+                    format!(
+                        "is a secret, store it with `thyme config set-secret {key}` or set \
+                         THYME_{} instead",
+                        key.to_uppercase()
+                    ),
+                );
+            }
+        }
+        if let Some(threshold) = notifications
+            .gain_thresholds
+            .iter()
+            .find(|threshold| !threshold.is_finite())
+        {
+            return fail(
+                Some("notifications"),
+                "gain_thresholds",
+                format!("should be amounts like 10000, not {threshold}"),
+            );
+        }
+        if let Err(problem) = self.wealth_tax.valuation() {
+            return fail(Some("wealth_tax"), "valuation", problem);
+        }
+        if self.indexer.puzzle_hashes_per_request == 0 {
+            return fail(
+                Some("indexer"),
+                "puzzle_hashes_per_request",
+                "must be at least 1".to_string(),
+            );
+        }
+        if let Some(url) = &self.indexer.url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return fail(
+                    Some("indexer"),
+                    "url",
+                    format!("should start with https://, not {url:?}"),
+                );
+            }
+        }
+        if !self.dexie.url.starts_with("https://") && !self.dexie.url.starts_with("http://") {
+            return fail(
+                Some("dexie"),
+                "url",
+                format!("should start with https://, not {:?}", self.dexie.url),
+            );
+        }
+        for (name, puzzle) in &self.puzzles {
+            let table = format!("puzzles.{name}");
+            if puzzle.mod_hash.is_some() == puzzle.puzzle.is_some() {
+                return fail(
+                    Some(&table),
+                    "mod_hash",
+                    "needs either `mod_hash` or `puzzle` to be set, but not both".to_string(),
+                );
+            }
+            if !puzzle.args.iter().any(|arg| arg == OWNER_ARG) {
+                return fail(
+                    Some(&table),
+                    "args",
+                    format!("needs {OWNER_ARG:?} as one of the arguments, to tell the owner"),
+                );
+            }
+        }
+        for (collection, valuation) in &self.nft_valuations {
+            let table = format!("nft_valuations.{collection}");
+            if let Some(id) = &valuation.dexie_collection {
+                if !id.starts_with("col1") {
+                    return fail(
+                        Some(&table),
+                        "dexie_collection",
+                        format!("should be a collection id like \"col1...\", not {id:?}"),
+                    );
+                }
+            }
+            for (day, price) in &valuation.floor_prices {
+                if day.parse::<NaiveDate>().is_err() {
+                    return fail(
+                        Some(&table),
+                        "floor_prices",
+                        format!("should be keyed by days like \"2024-03-01\", not {day:?}"),
+                    );
+                }
+                if !(*price >= 0.0 && price.is_finite()) {
+                    return fail(
+                        Some(&table),
+                        "floor_prices",
+                        format!("has {price} for {day}, which isn't a price in XCH"),
+                    );
+                }
+            }
+            match valuation.method {
+                NftValuationMethod::Floor if !valuation.has_floor() => {
+                    return fail(
+                        Some(&table),
+                        "floor_prices",
+                        "needs at least one day for the `floor` method, or `dexie_collection` \
+                         to look the floor up"
+                            .to_string(),
+                    );
+                }
+                NftValuationMethod::Manual if valuation.value.is_none() => {
+                    return fail(
+                        Some(&table),
+                        "value",
+                        "needs to be set for the `manual` method".to_string(),
+                    );
+                }
+                _ => {}
+            }
+        }
+        for (name, residency) in &self.residencies {
+            let table = format!("residencies.{name}");
+            if residency.start_date().is_none() {
+                return fail(
+                    Some(&table),
+                    "from",
+                    format!("should be a day like 2024-07-01, not {:?}", residency.from),
+                );
+            }
+            if let Some(currency) = &residency.currency {
+                if currency.is_empty() || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+                    return fail(
+                        Some(&table),
+                        "currency",
+                        format!("should be a currency code like eur, not {currency:?}"),
+                    );
+                }
+            }
+        }
+        if !(0.0..100.0).contains(&self.estimated_tax.rate) {
+            return fail(
+                Some("estimated_tax"),
+                "rate",
+                format!(
+                    "should be a percentage like 24, not {}",
+                    self.estimated_tax.rate
+                ),
+            );
+        }
+        if let Err(problem) = self.estimated_tax.due_dates(2024) {
+            return fail(Some("estimated_tax"), "due_dates", problem);
+        }
+        if !(self.loss_carryforward.deduction_limit >= 0.0
+            && self.loss_carryforward.deduction_limit.is_finite())
+        {
+            return fail(
+                Some("loss_carryforward"),
+                "deduction_limit",
+                format!(
+                    "should be an amount like 3000, not {}",
+                    self.loss_carryforward.deduction_limit
+                ),
+            );
+        }
+        if !(0.0..100.0).contains(&self.vat.rate) {
+            return fail(
+                Some("vat"),
+                "rate",
+                format!("should be a percentage like 20, not {}", self.vat.rate),
+            );
+        }
+        for (coin_id, files) in &self.evidence {
+            for file in files.iter().filter(|file| !Path::new(file).exists()) {
+                eprintln!(
+                    "Warning: evidence file {file:?} for coin {} doesn't exist",
+                    hex::encode(coin_id)
+                );
+            }
+        }
+
+        Ok(())
     }
 
     pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        check_writable(&path)?;
         let contents = toml::to_string_pretty(self)?;
         fs::write(path, contents)?;
         Ok(())
     }
 }
 
+/// The networks whose genesis challenge is known, by network id.
+const NETWORKS: [(&str, [u8; 32]); 2] = [
+    (
+        "mainnet",
+        hex!("ccd5bb71183532bff220ba46c268991a3ff07eb358e8255a65c30a2dce0e5fbb"),
+    ),
+    (
+        "testnet11",
+        hex!("37a90eb5185a9c4439a91ddc98bbadce7b4feba060d50116a067de66bf236615"),
+    ),
+];
+
+/// Peers are given as `host:port`, without a scheme.
+fn check_uri(uri: &str) -> Result<(), String> {
+    if uri.contains("://") {
+        return Err("should be `host:port`, without a scheme like wss://".to_string());
+    }
+    let Some((host, port)) = uri.rsplit_once(':') else {
+        return Err("is missing the port, like localhost:8444".to_string());
+    };
+    if host.is_empty() {
+        return Err("is missing the host".to_string());
+    }
+    if port.parse::<u16>().is_err() {
+        return Err(format!("has an invalid port {port:?}"));
+    }
+    Ok(())
+}
+
+/// The line a key is set on, within a table like `retry` or at the top level.
+fn line_of(contents: &str, table: Option<&str>, key: &str) -> Option<usize> {
+    let mut current = None;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            current = header.split(']').next().map(str::trim);
+            continue;
+        }
+        let Some(rest) = line.strip_prefix(key) else {
+            continue;
+        };
+        if current == table && rest.trim_start().starts_with('=') {
+            return Some(index + 1);
+        }
+    }
+
+    None
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             full_node_uri: "localhost:8444".to_string(),
+            peers: Vec::new(),
+            peer_reprobe_interval: 600,
+            sync_peers: 1,
+            heartbeat_interval: 30,
+            min_peak_height: None,
+            allow_unsynced_peer: false,
+            verify_headers: false,
             genesis_challenge: hex!(
                 "ccd5bb71183532bff220ba46c268991a3ff07eb358e8255a65c30a2dce0e5fbb"
             ),
             network_id: "mainnet".to_string(),
             dust_threshold: 0,
+            coin_states: CoinStateConfig::default(),
+            concurrency: 8,
+            retry: RetryConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            currency: "usd".to_string(),
+            coingecko_api_key: None,
+            prices: PricesConfig::default(),
+            assets: IndexMap::new(),
+            entities: IndexMap::new(),
+            tags: IndexMap::new(),
+            evidence: IndexMap::new(),
+            exchanges: Vec::new(),
+            exchange_export: ExchangeExportConfig::default(),
+            puzzles: IndexMap::new(),
+            nft_valuations: IndexMap::new(),
+            accounts: AccountsConfig::default(),
+            categories: CategoriesConfig::default(),
+            notifications: NotificationsConfig::default(),
+            wealth_tax: WealthTaxConfig::default(),
+            vat: VatConfig::default(),
+            estimated_tax: EstimatedTaxConfig::default(),
+            loss_carryforward: LossCarryforwardConfig::default(),
+            lot_method: LotMethod::default(),
+            cost_basis: None,
+            residencies: IndexMap::new(),
+            self_spends: SelfSpendConfig::default(),
+            pool: PoolConfig::default(),
+            indexer: IndexerConfig::default(),
+            dexie: DexieConfig::default(),
+            private_cache: false,
         }
     }
 }
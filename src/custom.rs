@@ -0,0 +1,108 @@
+use anyhow::anyhow;
+use chia::clvm_utils::{curry_tree_hash, tree_hash_atom, tree_hash_from_bytes, TreeHash};
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    cache::CustomJson,
+    config::{Config, OWNER_ARG},
+};
+
+/// A puzzle from the config, with its curried arguments hashed ahead of time.
+#[derive(Debug, Clone)]
+struct CustomPuzzle {
+    name: String,
+    mod_hash: TreeHash,
+    /// The tree hash of each argument, or `None` for the owner's puzzle hash.
+    args: Vec<Option<TreeHash>>,
+}
+
+/// The custom puzzles from the config, which are curried with each of the wallet's puzzle
+/// hashes to find the coins locked by them.
+#[derive(Debug, Clone, Default)]
+pub struct CustomPuzzles {
+    puzzles: Vec<CustomPuzzle>,
+}
+
+impl CustomPuzzles {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let mut puzzles = Vec::new();
+
+        for (name, puzzle) in &config.puzzles {
+            let mod_hash = match (&puzzle.mod_hash, &puzzle.puzzle) {
+                (Some(mod_hash), _) => {
+                    let bytes = decode_hex(mod_hash)
+                        .ok()
+                        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                        .ok_or_else(|| {
+                            anyhow!("Puzzle `{name}`: `mod_hash` should be 32 bytes of hex")
+                        })?;
+                    TreeHash::new(bytes)
+                }
+                (None, Some(program)) => tree_hash_of(program)
+                    .map_err(|error| anyhow!("Puzzle `{name}`: `puzzle` {error}"))?,
+                (None, None) => {
+                    return Err(anyhow!("Puzzle `{name}` needs `mod_hash` or `puzzle`"))
+                }
+            };
+
+            let args = puzzle
+                .args
+                .iter()
+                .map(|arg| {
+                    if arg == OWNER_ARG {
+                        return Ok(None);
+                    }
+                    tree_hash_of(arg)
+                        .map(Some)
+                        .map_err(|error| anyhow!("Puzzle `{name}`: argument {arg:?} {error}"))
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            puzzles.push(CustomPuzzle {
+                name: name.clone(),
+                mod_hash,
+                args,
+            });
+        }
+
+        Ok(Self { puzzles })
+    }
+
+    /// The puzzle hash of every custom puzzle curried with each of the owners, and which
+    /// puzzle and owner it is.
+    pub fn puzzle_hashes(&self, owners: &IndexSet<[u8; 32]>) -> IndexMap<[u8; 32], CustomJson> {
+        let mut puzzle_hashes = IndexMap::new();
+
+        for puzzle in &self.puzzles {
+            for owner in owners {
+                let owner_hash = tree_hash_atom(owner);
+                let args = puzzle
+                    .args
+                    .iter()
+                    .map(|arg| arg.unwrap_or(owner_hash))
+                    .collect::<Vec<_>>();
+                let puzzle_hash = curry_tree_hash(puzzle.mod_hash, &args);
+
+                puzzle_hashes.insert(
+                    puzzle_hash.to_bytes(),
+                    CustomJson {
+                        name: puzzle.name.clone(),
+                        p2_puzzle_hash: *owner,
+                    },
+                );
+            }
+        }
+
+        puzzle_hashes
+    }
+}
+
+/// The tree hash of a serialized CLVM program given as hex.
+fn tree_hash_of(program: &str) -> Result<TreeHash, String> {
+    let bytes = decode_hex(program).map_err(|_| "isn't valid hex".to_string())?;
+    tree_hash_from_bytes(&bytes).map_err(|_| "isn't a serialized CLVM program".to_string())
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, hex::FromHexError> {
+    hex::decode(hex.strip_prefix("0x").unwrap_or(hex))
+}
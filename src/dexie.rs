@@ -0,0 +1,168 @@
+use std::fmt;
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, NaiveDate};
+use serde_json::Value;
+
+use crate::config::DexieConfig;
+
+/// The Dexie offer exchange, which keeps the offers posted to it by id.
+pub struct Dexie {
+    client: reqwest::Client,
+    url: String,
+}
+
+/// An offer as Dexie has it, with what Dexie last saw happen to it.
+pub struct DexieOffer {
+    /// The `offer1...` string, which is checked against the cache like a local offer file.
+    pub offer: String,
+    pub status: DexieStatus,
+}
+
+/// The number of offers asked for in each page of a search, which is the most Dexie allows.
+const SEARCH_PAGE_SIZE: usize = 100;
+
+/// An offer that was taken for XCH, which is a sale of what it offered.
+#[derive(Debug, Clone, Copy)]
+pub struct DexieSale {
+    /// The XCH paid for each of what was offered.
+    pub price: f64,
+    pub completed: NaiveDate,
+}
+
+/// Dexie's status codes for an offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DexieStatus {
+    Open,
+    Pending,
+    Cancelling,
+    Cancelled,
+    Completed,
+    Unknown,
+    Expired,
+}
+
+impl DexieStatus {
+    fn from_code(code: u64) -> Self {
+        match code {
+            0 => Self::Open,
+            1 => Self::Pending,
+            2 => Self::Cancelling,
+            3 => Self::Cancelled,
+            4 => Self::Completed,
+            6 => Self::Expired,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for DexieStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Self::Open => "open",
+            Self::Pending => "pending",
+            Self::Cancelling => "cancelling",
+            Self::Cancelled => "cancelled",
+            Self::Completed => "completed",
+            Self::Unknown => "unknown",
+            Self::Expired => "expired",
+        };
+        f.write_str(status)
+    }
+}
+
+impl Dexie {
+    pub fn new(config: &DexieConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: config.url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Looks up an offer by its Dexie id.
+    pub async fn offer(&self, id: &str) -> anyhow::Result<DexieOffer> {
+        let response: Value = self
+            .client
+            .get(format!("{}/v1/offers/{id}", self.url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if response["success"].as_bool() != Some(true) {
+            bail!(
+                "Dexie couldn't find the offer {id}: {}",
+                response["error_message"]
+                    .as_str()
+                    .unwrap_or("no reason given")
+            );
+        }
+
+        let offer = &response["offer"];
+        Ok(DexieOffer {
+            offer: offer["offer"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Dexie didn't return the offer string for {id}"))?
+                .to_string(),
+            status: DexieStatus::from_code(offer["status"].as_u64().unwrap_or(5)),
+        })
+    }
+
+    /// The sales of an asset, a collection by its `col1...` id or an NFT by its `nft1...` id,
+    /// completed on or after the day, with the latest first.
+    pub async fn sales(&self, offered: &str, since: NaiveDate) -> anyhow::Result<Vec<DexieSale>> {
+        let mut sales = Vec::new();
+
+        for page in 1.. {
+            let response: Value = self
+                .client
+                .get(format!("{}/v1/offers", self.url))
+                .query(&[
+                    ("offered", offered),
+                    ("requested", "xch"),
+                    ("status", "4"),
+                    ("sort", "date_completed"),
+                    ("page", &page.to_string()),
+                    ("page_size", &SEARCH_PAGE_SIZE.to_string()),
+                ])
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            if response["success"].as_bool() != Some(true) {
+                bail!(
+                    "Dexie couldn't search the sales of {offered}: {}",
+                    response["error_message"]
+                        .as_str()
+                        .unwrap_or("no reason given")
+                );
+            }
+
+            let offers = response["offers"].as_array().cloned().unwrap_or_default();
+            for offer in &offers {
+                let (Some(price), Some(completed)) = (
+                    offer["price"].as_f64(),
+                    offer["date_completed"]
+                        .as_str()
+                        .and_then(|date| DateTime::parse_from_rfc3339(date).ok()),
+                ) else {
+                    continue;
+                };
+                let completed = completed.date_naive();
+                if completed < since {
+                    return Ok(sales);
+                }
+                sales.push(DexieSale { price, completed });
+            }
+
+            if offers.len() < SEARCH_PAGE_SIZE {
+                break;
+            }
+        }
+
+        Ok(sales)
+    }
+}
@@ -0,0 +1,40 @@
+use crate::cache::{Cache, CoinStateJson};
+
+/// The coins that changed between two snapshots of the same cache.
+#[derive(Debug, Default, Clone)]
+pub struct CacheDiff<'a> {
+    pub added: Vec<(&'a [u8; 32], &'a CoinStateJson)>,
+    pub spent: Vec<(&'a [u8; 32], &'a CoinStateJson)>,
+    pub reclassified: Vec<(&'a [u8; 32], &'a CoinStateJson, &'a CoinStateJson)>,
+}
+
+impl CacheDiff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.spent.is_empty() && self.reclassified.is_empty()
+    }
+}
+
+pub fn diff_caches<'a>(old: &'a Cache, new: &'a Cache) -> CacheDiff<'a> {
+    let mut diff = CacheDiff::default();
+
+    for (coin_id, coin_state) in new
+        .derivations
+        .iter()
+        .flat_map(|derivations| &derivations.coin_states)
+    {
+        let Some(existing) = old.coin_state(coin_id) else {
+            diff.added.push((coin_id, coin_state));
+            continue;
+        };
+
+        if existing.spent_height.is_none() && coin_state.spent_height.is_some() {
+            diff.spent.push((coin_id, coin_state));
+        }
+
+        if existing.asset() != coin_state.asset() {
+            diff.reclassified.push((coin_id, existing, coin_state));
+        }
+    }
+
+    diff
+}
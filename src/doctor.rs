@@ -0,0 +1,318 @@
+use std::{fmt::Display, fs, path::Path, time::Duration};
+
+use anyhow::bail;
+use chia::ssl::ChiaCertificate;
+use chia_wallet_sdk::create_tls_connector;
+use tokio::{net::lookup_host, time::timeout};
+
+use crate::{
+    cache::CacheWindows,
+    cert::{self, CertPaths},
+    config::Config,
+    peers::{check_synced, probe},
+    price::{PriceCache, PriceProvider},
+};
+
+/// How long each network check waits before it's reported as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Tallies the checks as they're printed.
+#[derive(Debug, Default)]
+struct Checks {
+    failed: usize,
+}
+
+impl Checks {
+    fn pass(&self, name: &str, detail: impl Display) {
+        println!("PASS  {name}: {detail}");
+    }
+
+    fn fail(&mut self, name: &str, error: impl Display, hint: impl Display) {
+        self.failed += 1;
+        println!("FAIL  {name}: {error}");
+        println!("      hint: {hint}");
+    }
+
+    fn skip(&self, name: &str, reason: impl Display) {
+        println!("SKIP  {name}: {reason}");
+    }
+}
+
+/// Checks everything a report depends on, one step at a time, so a failing run can be traced
+/// back to the config, the certificate, the network, a peer, the cache or the price API.
+pub async fn doctor(config_path: &Path, cache_dir: &Path) -> anyhow::Result<()> {
+    let mut checks = Checks::default();
+
+    let config = if !config_path.exists() {
+        checks.fail(
+            "config",
+            format!("there's no {}", config_path.display()),
+            "run thyme once from this directory to write the default config, then edit it",
+        );
+        None
+    } else {
+        match Config::load(config_path) {
+            Ok(config) => {
+                checks.pass("config", format!("{} is valid", config_path.display()));
+                Some(config)
+            }
+            Err(error) => {
+                checks.fail(
+                    "config",
+                    error,
+                    "fix the key it points at and run doctor again",
+                );
+                None
+            }
+        }
+    };
+
+    let cert = match CertPaths::new() {
+        Ok(paths) if !paths.exists() && CertPaths::legacy().exists() => {
+            let legacy = CertPaths::legacy();
+            checks.pass(
+                "certificate",
+                format!(
+                    "{} is moved to {} on the next sync",
+                    legacy.cert.display(),
+                    paths.cert.display()
+                ),
+            );
+            legacy.load().ok()
+        }
+        Ok(paths) if !paths.exists() => {
+            checks.fail(
+                "certificate",
+                format!("there's no certificate at {}", paths.cert.display()),
+                "run `thyme cert generate`",
+            );
+            None
+        }
+        Ok(paths) => match paths.load().and_then(|cert| {
+            let fingerprint = cert::fingerprint(&cert)?;
+            Ok((cert, fingerprint))
+        }) {
+            Ok((cert, fingerprint)) => {
+                checks.pass(
+                    "certificate",
+                    format!("{} ({fingerprint})", paths.cert.display()),
+                );
+                Some(cert)
+            }
+            Err(error) => {
+                checks.fail(
+                    "certificate",
+                    error,
+                    "replace the unreadable certificate with `thyme cert rotate`",
+                );
+                None
+            }
+        },
+        Err(error) => {
+            checks.fail(
+                "certificate",
+                error,
+                "set THYME_DATA_DIR to a writable directory",
+            );
+            None
+        }
+    };
+
+    match &config {
+        Some(config) => {
+            for uri in config.full_node_uris() {
+                check_peer(&mut checks, config, cert.as_ref(), &uri).await;
+            }
+        }
+        None => checks.skip("peers", "the config couldn't be loaded"),
+    }
+
+    check_caches(&mut checks, cache_dir);
+
+    let config = config.unwrap_or_default();
+    let prices = PriceProvider::new(&config, PriceCache::default());
+    match timeout(CHECK_TIMEOUT, prices.ping()).await {
+        Ok(Ok(())) => checks.pass("price API", "CoinGecko answered"),
+        Ok(Err(error)) => checks.fail(
+            "price API",
+            error,
+            "check the internet connection, and that coingecko_api_key is a valid demo key",
+        ),
+        Err(_) => checks.fail(
+            "price API",
+            "CoinGecko didn't answer in time",
+            "check the internet connection or try again later",
+        ),
+    }
+
+    println!();
+    if checks.failed == 0 {
+        println!("Everything looks fine");
+        Ok(())
+    } else {
+        bail!("{} checks failed", checks.failed);
+    }
+}
+
+/// Resolves the peer, handshakes with it and checks that it's synced.
+async fn check_peer(
+    checks: &mut Checks,
+    config: &Config,
+    cert: Option<&ChiaCertificate>,
+    uri: &str,
+) {
+    let name = format!("peer {uri}");
+
+    match timeout(CHECK_TIMEOUT, lookup_host(uri)).await {
+        Ok(Ok(mut addresses)) => match addresses.next() {
+            Some(address) => checks.pass(&format!("{name} DNS"), format!("resolves to {address}")),
+            None => {
+                checks.fail(
+                    &format!("{name} DNS"),
+                    "no addresses",
+                    "check the host name",
+                );
+                return;
+            }
+        },
+        Ok(Err(error)) => {
+            checks.fail(
+                &format!("{name} DNS"),
+                error,
+                "check the host name, and the port after it like localhost:8444",
+            );
+            return;
+        }
+        Err(_) => {
+            checks.fail(
+                &format!("{name} DNS"),
+                "lookup timed out",
+                "check the DNS setup",
+            );
+            return;
+        }
+    }
+
+    let Some(cert) = cert else {
+        checks.skip(
+            &format!("{name} handshake"),
+            "there's no certificate to connect with",
+        );
+        return;
+    };
+
+    let probed = match create_tls_connector(cert) {
+        Ok(tls_connector) => timeout(CHECK_TIMEOUT, probe(uri, config, tls_connector)).await,
+        Err(error) => {
+            checks.fail(
+                &format!("{name} handshake"),
+                error,
+                "run `thyme cert rotate`",
+            );
+            return;
+        }
+    };
+
+    let probed = match probed {
+        Ok(Ok(probed)) => {
+            checks.pass(
+                &format!("{name} handshake"),
+                format!(
+                    "at height {} ({} ms)",
+                    probed.peak_height,
+                    probed.latency.as_millis()
+                ),
+            );
+            probed
+        }
+        Ok(Err(error)) => {
+            checks.fail(
+                &format!("{name} handshake"),
+                error,
+                format!(
+                    "make sure the full node is running and reachable on that port, \
+                     and that network_id {:?} is the network it's on",
+                    config.network_id
+                ),
+            );
+            return;
+        }
+        Err(_) => {
+            checks.fail(
+                &format!("{name} handshake"),
+                "the peer didn't answer in time",
+                "check for a firewall between here and the node",
+            );
+            return;
+        }
+    };
+
+    match timeout(CHECK_TIMEOUT, check_synced(&probed, config)).await {
+        Ok(Ok(())) => checks.pass(&format!("{name} sync"), "the peer is synced"),
+        Ok(Err(error)) => checks.fail(
+            &format!("{name} sync"),
+            error,
+            "wait for the node to finish syncing, or use another peer",
+        ),
+        Err(_) => checks.fail(
+            &format!("{name} sync"),
+            "the peer didn't answer in time",
+            "the node may be overloaded, try again later",
+        ),
+    }
+}
+
+/// Reads every window of every cache, so a corrupt file shows up before a report trips on it.
+fn check_caches(checks: &mut Checks, cache_dir: &Path) {
+    let entries = match fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(_) => {
+            checks.skip(
+                "caches",
+                format!("there's no {} directory yet", cache_dir.display()),
+            );
+            return;
+        }
+    };
+
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("cache-") && !name.ends_with(".lock"))
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    if paths.is_empty() {
+        checks.skip("caches", "nothing has been synced yet");
+    }
+
+    for path in paths {
+        let name = format!("cache {}", path.display());
+
+        if path.is_file() {
+            checks.pass(
+                &name,
+                "saved by an older version, it's converted on the next report",
+            );
+            continue;
+        }
+
+        let windows = CacheWindows::open(&path, None).and_then(|windows| {
+            windows
+                .iter()
+                .try_fold(0, |count, window| window.map(|_| count + 1))
+        });
+
+        match windows {
+            Ok(count) => checks.pass(&name, format!("{count} windows readable")),
+            Err(error) => checks.fail(
+                &name,
+                error,
+                "rebuild it by running the report with --reset",
+            ),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use chia::protocol::Coin;
+use chia_wallet_sdk::Puzzle;
+use clvmr::{Allocator, NodePtr};
+
+use crate::cache::PuzzleInfo;
+
+mod cat;
+mod nft;
+
+/// Recognizes the children of one kind of parent puzzle, such as CATs, from the parent's spend.
+///
+/// Supporting a new primitive only takes a module implementing this and an entry in
+/// [`DriverRegistry::default`].
+pub trait PuzzleDriver: Send + Sync {
+    /// Whether the parent puzzle is this driver's, which is usually a check of its mod hash.
+    /// Only the first driver that matches a puzzle hash is asked about its children, and the
+    /// match is remembered by puzzle hash.
+    fn matches(&self, puzzle: Puzzle) -> bool;
+
+    /// What the child is, or `None` if the spend didn't create it as this kind of coin.
+    fn child_info(
+        &self,
+        allocator: &mut Allocator,
+        parent_coin: Coin,
+        parent_puzzle: Puzzle,
+        parent_solution: NodePtr,
+        child: Coin,
+    ) -> Option<PuzzleInfo>;
+}
+
+/// The drivers parent puzzles are tried against, in order.
+pub struct DriverRegistry {
+    drivers: Vec<Box<dyn PuzzleDriver>>,
+}
+
+impl Default for DriverRegistry {
+    fn default() -> Self {
+        Self {
+            drivers: vec![Box::new(cat::CatDriver), Box::new(nft::NftDriver)],
+        }
+    }
+}
+
+impl DriverRegistry {
+    /// The index of the first driver that matches the puzzle.
+    pub fn find(&self, puzzle: Puzzle) -> Option<usize> {
+        self.drivers
+            .iter()
+            .position(|driver| driver.matches(puzzle))
+    }
+
+    pub fn get(&self, index: usize) -> &dyn PuzzleDriver {
+        self.drivers[index].as_ref()
+    }
+}
@@ -0,0 +1,34 @@
+use chia::{protocol::Coin, puzzles::cat::CAT_PUZZLE_HASH};
+use chia_wallet_sdk::{Cat, Primitive, Puzzle};
+use clvmr::{Allocator, NodePtr};
+
+use super::PuzzleDriver;
+use crate::cache::PuzzleInfo;
+
+pub struct CatDriver;
+
+impl PuzzleDriver for CatDriver {
+    fn matches(&self, puzzle: Puzzle) -> bool {
+        puzzle.mod_hash() == CAT_PUZZLE_HASH
+    }
+
+    fn child_info(
+        &self,
+        allocator: &mut Allocator,
+        parent_coin: Coin,
+        parent_puzzle: Puzzle,
+        parent_solution: NodePtr,
+        child: Coin,
+    ) -> Option<PuzzleInfo> {
+        Cat::from_parent_spend(
+            allocator,
+            parent_coin,
+            parent_puzzle,
+            parent_solution,
+            child,
+        )
+        .ok()
+        .flatten()
+        .map(|cat| PuzzleInfo::Cat(cat.into()))
+    }
+}
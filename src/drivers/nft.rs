@@ -0,0 +1,39 @@
+use chia::{
+    protocol::Coin,
+    puzzles::{nft::NftMetadata, singleton::SINGLETON_TOP_LAYER_PUZZLE_HASH},
+};
+use chia_wallet_sdk::{Nft, Primitive, Puzzle};
+use clvmr::{Allocator, NodePtr};
+
+use super::PuzzleDriver;
+use crate::cache::PuzzleInfo;
+
+/// Matches every singleton, but only recognizes NFTs with standard metadata among them.
+/// Other singletons, such as DIDs, aren't recognized.
+pub struct NftDriver;
+
+impl PuzzleDriver for NftDriver {
+    fn matches(&self, puzzle: Puzzle) -> bool {
+        puzzle.mod_hash() == SINGLETON_TOP_LAYER_PUZZLE_HASH
+    }
+
+    fn child_info(
+        &self,
+        allocator: &mut Allocator,
+        parent_coin: Coin,
+        parent_puzzle: Puzzle,
+        parent_solution: NodePtr,
+        child: Coin,
+    ) -> Option<PuzzleInfo> {
+        Nft::<NftMetadata>::from_parent_spend(
+            allocator,
+            parent_coin,
+            parent_puzzle,
+            parent_solution,
+            child,
+        )
+        .ok()
+        .flatten()
+        .map(|nft| PuzzleInfo::Nft(nft.into()))
+    }
+}
@@ -0,0 +1,187 @@
+use std::ops::RangeInclusive;
+
+use anyhow::{anyhow, bail};
+use chia::protocol::Bytes32;
+use chia_wallet_sdk::decode_address;
+use indexmap::IndexSet;
+
+use crate::{
+    cache::{CoinStateJson, Derivations},
+    config::Config,
+    query::Query,
+};
+
+/// The number of derivations each cache window starts after the previous one.
+pub const WINDOW_SIZE: u32 = 1000;
+
+/// The entity of coins that don't belong to any configured entity, such as personal holdings
+/// on the same key as a business.
+pub const UNASSIGNED: &str = "unassigned";
+
+/// The name of the report combining the entities given to `report --consolidate`.
+pub const CONSOLIDATED: &str = "consolidated";
+
+/// A named group of derivations and addresses, such as a profile tied to a DID,
+/// which can be reported on separately from the rest of the wallet.
+#[derive(Debug, Clone)]
+struct Entity {
+    name: String,
+    derivations: Vec<RangeInclusive<u32>>,
+    puzzle_hashes: IndexSet<Bytes32>,
+    /// Filters over coins, in the same syntax as `thyme cache query`.
+    rules: Vec<Query>,
+    /// Coins annotated as belonging to the entity, regardless of where they were received.
+    coin_ids: IndexSet<[u8; 32]>,
+}
+
+/// The entities from the config, which coins are matched against in order.
+#[derive(Debug, Clone, Default)]
+pub struct Entities {
+    entities: Vec<Entity>,
+}
+
+impl Entities {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let mut entities = Vec::new();
+
+        for (name, entity) in &config.entities {
+            if name == UNASSIGNED {
+                bail!("`{UNASSIGNED}` is reserved for coins outside every entity");
+            }
+
+            let derivations = entity
+                .derivations
+                .iter()
+                .map(|range| {
+                    parse_range(range).map_err(|error| anyhow!("Entity `{name}`: {error}"))
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            let puzzle_hashes = entity
+                .addresses
+                .iter()
+                .map(|address| {
+                    let (puzzle_hash, _prefix) = decode_address(address).map_err(|error| {
+                        anyhow!("Entity `{name}`: invalid address `{address}`: {error}")
+                    })?;
+                    anyhow::Ok(Bytes32::from(puzzle_hash))
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            let rules = entity
+                .rules
+                .iter()
+                .map(|rule| {
+                    let query = rule.parse::<Query>()?;
+                    query.validate()?;
+                    anyhow::Ok(query)
+                })
+                .collect::<anyhow::Result<_>>()
+                .map_err(|error| anyhow!("Entity `{name}`: {error}"))?;
+
+            let coin_ids = entity
+                .coins
+                .iter()
+                .map(|coin_id| {
+                    parse_coin_id(coin_id)
+                        .ok_or_else(|| anyhow!("Entity `{name}`: invalid coin id `{coin_id}`"))
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            entities.push(Entity {
+                name: name.clone(),
+                derivations,
+                puzzle_hashes,
+                rules,
+                coin_ids,
+            });
+        }
+
+        Ok(Self { entities })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        name == UNASSIGNED || self.entities.iter().any(|entity| entity.name == name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entities
+            .iter()
+            .map(|entity| entity.name.as_str())
+            .chain([UNASSIGNED])
+    }
+
+    /// The entity a coin belongs to, where `window` is the index of the cache window it was
+    /// found in.
+    ///
+    /// Annotated coins take precedence, then rules, then the derivations and addresses that
+    /// own the coin, each checked in config order. Anything else is [`UNASSIGNED`].
+    pub fn entity_of(
+        &self,
+        config: &Config,
+        window: usize,
+        derivations: &Derivations,
+        coin_id: &[u8; 32],
+        coin_state: &CoinStateJson,
+    ) -> &str {
+        let puzzle_hash = coin_state.p2_puzzle_hash();
+        let index = derivations
+            .puzzle_hashes
+            .get_index_of(&puzzle_hash.to_bytes())
+            .map(|offset| window as u32 * WINDOW_SIZE + offset as u32);
+
+        let owns = |entity: &Entity| {
+            entity.puzzle_hashes.contains(&puzzle_hash)
+                || index.is_some_and(|index| {
+                    entity
+                        .derivations
+                        .iter()
+                        .any(|range| range.contains(&index))
+                })
+        };
+
+        self.entities
+            .iter()
+            .find(|entity| entity.coin_ids.contains(coin_id))
+            .or_else(|| {
+                self.entities.iter().find(|entity| {
+                    entity
+                        .rules
+                        .iter()
+                        .any(|rule| rule.matches(config, coin_id, coin_state))
+                })
+            })
+            .or_else(|| self.entities.iter().find(|entity| owns(entity)))
+            .map_or(UNASSIGNED, |entity| entity.name.as_str())
+    }
+}
+
+/// Parses an annotated coin id, with or without a `0x` prefix.
+pub fn parse_coin_id(coin_id: &str) -> Option<[u8; 32]> {
+    let bytes = hex::decode(coin_id.strip_prefix("0x").unwrap_or(coin_id)).ok()?;
+    bytes.try_into().ok()
+}
+
+/// Parses a derivation index like `7` or an inclusive range like `0-499`.
+fn parse_range(range: &str) -> anyhow::Result<RangeInclusive<u32>> {
+    let parse = |index: &str| {
+        index
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| anyhow!("invalid derivation index `{index}`"))
+    };
+
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => {
+            let index = parse(range)?;
+            (index, index)
+        }
+    };
+
+    if start > end {
+        bail!("derivation range `{range}` is empty");
+    }
+
+    Ok(start..=end)
+}
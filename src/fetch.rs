@@ -1,3 +1,5 @@
+use std::{fmt, sync::atomic::Ordering, time::Instant};
+
 use anyhow::bail;
 use chia::{
     client::Peer,
@@ -7,36 +9,183 @@ use chia::{
     },
 };
 
+use crate::{
+    config::RetryConfig,
+    peers::ProbedPeer,
+    retry::{with_retries, Rejections},
+    timings::{Phase, Timings},
+};
+
+/// The peer rejected a request for having too many puzzle hashes.
+#[derive(Debug)]
+struct ExceededSubscriptionLimit {
+    subscribe: bool,
+}
+
+impl fmt::Display for ExceededSubscriptionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.subscribe {
+            write!(
+                f,
+                "The peer won't subscribe to any more puzzle hashes, raise its \
+                 max_subscribe_items or use a peer with a higher limit"
+            )
+        } else {
+            write!(
+                f,
+                "Exceeded subscription limit even though we didn't subscribe."
+            )
+        }
+    }
+}
+
+impl std::error::Error for ExceededSubscriptionLimit {}
+
+/// Fetches the coin states of the puzzle hashes since the previous height, paging through
+/// the responses until the peer is caught up.
+///
+/// The puzzle hashes are requested at most `batch_size` at a time, or all at once without one.
+/// Each batch can finish at a different height, so the lowest one is returned, which a later
+/// sync can resume from without missing anything.
+///
+/// The batch size is tuned to the peer as it goes. A peer that rejects a batch as too large is
+/// asked again with half as many puzzle hashes, down to one. A batch answered in a single page
+/// means the peer had room to spare, so the next one is twice as large, up to `batch_size`.
+/// The size the peer ends up with is where its next window starts.
+///
+/// With `subscribe`, the peer keeps sending updates for the puzzle hashes once each batch is
+/// caught up, as `CoinStateUpdate` events.
+///
+/// Peers that don't support `RequestPuzzleState` are asked with `RegisterForPhUpdates`,
+/// which returns everything since the previous height at once, and the filters other than
+/// the minimum amount are applied after it arrives. It always subscribes.
+#[allow(clippy::too_many_arguments)]
 pub async fn fetch_coin_states(
-    peer: &Peer,
+    peer: &ProbedPeer,
     genesis_challenge: Bytes32,
-    mut start_previous_height: Option<u32>,
+    start_previous_height: Option<u32>,
     start_header_hash: Bytes32,
     puzzle_hashes: impl IntoIterator<Item = impl Into<Bytes32>>,
+    filters: CoinStateFilters,
+    batch_size: Option<usize>,
+    subscribe: bool,
     dust_threshold: u64,
+    retry: &RetryConfig,
+    timings: &Timings,
 ) -> anyhow::Result<(Vec<CoinState>, u32, Bytes32)> {
-    let mut previous_height = start_previous_height;
-    let mut header_hash = start_header_hash;
-    let mut coin_states = Vec::new();
-
     let puzzle_hashes = puzzle_hashes
         .into_iter()
         .map(Into::into)
         .collect::<Vec<_>>();
+    let max_batch_size = batch_size.unwrap_or(puzzle_hashes.len()).max(1);
+    let mut batch_size = match peer.puzzle_hashes_per_request.load(Ordering::Relaxed) {
+        0 => max_batch_size,
+        tuned => tuned.min(max_batch_size),
+    };
+
+    let mut coin_states = Vec::new();
+    let mut finished: Option<(u32, Bytes32)> = None;
+    let mut start = 0;
+
+    while start < puzzle_hashes.len() {
+        let batch = &puzzle_hashes[start..puzzle_hashes.len().min(start + batch_size)];
+        let result = if peer.supports_puzzle_state {
+            fetch_batch(
+                &peer.peer,
+                genesis_challenge,
+                start_previous_height,
+                start_header_hash,
+                batch.to_vec(),
+                filters.clone(),
+                subscribe,
+                retry,
+                timings,
+            )
+            .await
+        } else {
+            fetch_batch_legacy(
+                &peer.peer,
+                start_previous_height,
+                start_header_hash,
+                batch.to_vec(),
+                &filters,
+                retry,
+                timings,
+            )
+            .await
+        };
+
+        let (batch_states, height, header_hash, pages) = match result {
+            Err(error) if error.is::<ExceededSubscriptionLimit>() && batch.len() > 1 => {
+                batch_size = batch.len() / 2;
+                peer.puzzle_hashes_per_request
+                    .store(batch_size, Ordering::Relaxed);
+                eprintln!(
+                    "The peer rejected {} puzzle hashes at once, retrying {batch_size} at a time",
+                    batch.len()
+                );
+                continue;
+            }
+            result => result?,
+        };
+
+        start += batch.len();
+        if pages == 1 && batch.len() == batch_size && batch_size < max_batch_size {
+            batch_size = (batch_size * 2).min(max_batch_size);
+        }
+        peer.puzzle_hashes_per_request
+            .store(batch_size, Ordering::Relaxed);
+        coin_states.extend(batch_states);
+        if finished.is_none_or(|(lowest, _)| height < lowest) {
+            finished = Some((height, header_hash));
+        }
+    }
+
+    let coin_states = coin_states
+        .into_iter()
+        .filter(|cs| cs.coin.amount >= dust_threshold)
+        .collect();
+
+    let (height, header_hash) =
+        finished.unwrap_or((start_previous_height.unwrap_or_default(), start_header_hash));
+
+    Ok((coin_states, height, header_hash))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn fetch_batch(
+    peer: &Peer,
+    genesis_challenge: Bytes32,
+    mut start_previous_height: Option<u32>,
+    start_header_hash: Bytes32,
+    puzzle_hashes: Vec<Bytes32>,
+    filters: CoinStateFilters,
+    subscribe: bool,
+    retry: &RetryConfig,
+    timings: &Timings,
+) -> anyhow::Result<(Vec<CoinState>, u32, Bytes32, usize)> {
+    let mut previous_height = start_previous_height;
+    let mut header_hash = start_header_hash;
+    let mut coin_states = Vec::new();
+    let mut pages = 0;
 
     loop {
-        let response: Result<RespondPuzzleState, chia::client::Error<RejectPuzzleState>> = peer
-            .request_or_reject(RequestPuzzleState {
+        let started = Instant::now();
+        let response = with_retries(retry, Rejections::Return, || {
+            peer.request_or_reject::<RespondPuzzleState, RejectPuzzleState, _>(RequestPuzzleState {
                 puzzle_hashes: puzzle_hashes.clone(),
                 previous_height,
                 header_hash,
-                filters: CoinStateFilters::new(true, true, true, 0),
-                subscribe_when_finished: false,
+                filters: filters.clone(),
+                subscribe_when_finished: subscribe,
             })
-            .await;
+        })
+        .await?;
+        timings.record(Phase::CoinStatePaging, started, 1);
 
         match response {
             Ok(response) => {
+                pages += 1;
                 coin_states.extend(response.coin_states);
                 previous_height = Some(response.height);
                 header_hash = response.header_hash;
@@ -44,9 +193,9 @@ pub async fn fetch_coin_states(
                     break;
                 }
             }
-            Err(chia::client::Error::Rejection(rejection)) => match rejection.reason {
+            Err(rejection) => match rejection.reason {
                 RejectStateReason::ExceededSubscriptionLimit => {
-                    bail!("Exceeded subscription limit even though we didn't subscribe.");
+                    return Err(ExceededSubscriptionLimit { subscribe }.into());
                 }
                 RejectStateReason::Reorg => {
                     if start_previous_height.is_none() {
@@ -58,14 +207,61 @@ pub async fn fetch_coin_states(
                     coin_states.clear();
                 }
             },
-            Err(error) => bail!(error),
         }
     }
 
+    Ok((coin_states, previous_height.unwrap(), header_hash, pages))
+}
+
+async fn fetch_batch_legacy(
+    peer: &Peer,
+    start_previous_height: Option<u32>,
+    start_header_hash: Bytes32,
+    puzzle_hashes: Vec<Bytes32>,
+    filters: &CoinStateFilters,
+    retry: &RetryConfig,
+    timings: &Timings,
+) -> anyhow::Result<(Vec<CoinState>, u32, Bytes32, usize)> {
+    let min_height = start_previous_height.unwrap_or_default();
+
+    let started = Instant::now();
+    let coin_states = with_retries(retry, Rejections::Retry, || {
+        peer.register_for_ph_updates(puzzle_hashes.clone(), min_height)
+    })
+    .await?
+    .map_err(|()| anyhow::anyhow!("Peer rejected RegisterForPhUpdates"))?;
+    timings.record(Phase::CoinStatePaging, started, 1);
+
     let coin_states = coin_states
         .into_iter()
-        .filter(|cs| cs.coin.amount >= dust_threshold)
-        .collect();
+        .filter(|cs| {
+            if cs.spent_height.is_some() {
+                filters.include_spent
+            } else {
+                filters.include_unspent
+            }
+        })
+        .filter(|cs| cs.coin.amount >= filters.min_amount)
+        .collect::<Vec<_>>();
+
+    // The response doesn't say how far the peer got, so the sync resumes from the last activity.
+    let height = coin_states
+        .iter()
+        .flat_map(|cs| [cs.created_height, cs.spent_height])
+        .flatten()
+        .max()
+        .filter(|height| *height > min_height);
+
+    let Some(height) = height else {
+        return Ok((coin_states, min_height, start_header_hash, 1));
+    };
+
+    let header_hash = with_retries(retry, Rejections::Retry, || {
+        peer.request_block_header(height)
+    })
+    .await?
+    .map_err(|_| anyhow::anyhow!("Peer rejected the header request for height {height}"))?
+    .header_hash();
 
-    Ok((coin_states, previous_height.unwrap(), header_hash))
+    Ok((coin_states, height, header_hash, 1))
 }
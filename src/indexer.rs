@@ -0,0 +1,145 @@
+use anyhow::{anyhow, bail};
+use chia::protocol::{Bytes32, Coin};
+use serde_json::{json, Value};
+
+use crate::config::IndexerConfig;
+
+/// A public indexer that serves the full node's RPC API, like coinset.org.
+pub struct Indexer {
+    client: reqwest::Client,
+    url: String,
+    puzzle_hashes_per_request: usize,
+}
+
+/// A coin as the indexer reported it, before the peer has confirmed it.
+pub struct IndexedCoin {
+    pub coin: Coin,
+    pub created_height: u32,
+}
+
+/// A spend bundle waiting in the indexer's mempool, which may never be confirmed.
+pub struct MempoolItem {
+    pub id: String,
+    pub additions: Vec<Coin>,
+    pub removals: Vec<Coin>,
+}
+
+impl Indexer {
+    /// The configured indexer, or `None` if there isn't one.
+    pub fn new(config: &IndexerConfig) -> Option<Self> {
+        Some(Self {
+            client: reqwest::Client::new(),
+            url: config.url.as_ref()?.trim_end_matches('/').to_string(),
+            puzzle_hashes_per_request: config.puzzle_hashes_per_request.max(1),
+        })
+    }
+
+    async fn post(&self, endpoint: &str, body: Value) -> anyhow::Result<Value> {
+        let response: Value = self
+            .client
+            .post(format!("{}/{endpoint}", self.url))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if response["success"].as_bool() != Some(true) {
+            bail!(
+                "The indexer's {endpoint} failed: {}",
+                response["error"].as_str().unwrap_or("no reason given")
+            );
+        }
+
+        Ok(response)
+    }
+
+    pub async fn peak_height(&self) -> anyhow::Result<u32> {
+        let response = self.post("get_blockchain_state", json!({})).await?;
+        response["blockchain_state"]["peak"]["height"]
+            .as_u64()
+            .map(|height| height as u32)
+            .ok_or_else(|| anyhow!("The indexer didn't say what its peak height is"))
+    }
+
+    /// Every coin the indexer has for the puzzle hashes, or hinted to them with `hints`.
+    pub async fn coins(
+        &self,
+        puzzle_hashes: &[[u8; 32]],
+        hints: bool,
+    ) -> anyhow::Result<Vec<IndexedCoin>> {
+        let (endpoint, key) = if hints {
+            ("get_coin_records_by_hints", "hints")
+        } else {
+            ("get_coin_records_by_puzzle_hashes", "puzzle_hashes")
+        };
+
+        let mut coins = Vec::new();
+
+        for batch in puzzle_hashes.chunks(self.puzzle_hashes_per_request) {
+            let batch = batch
+                .iter()
+                .map(|puzzle_hash| format!("0x{}", hex::encode(puzzle_hash)))
+                .collect::<Vec<_>>();
+            let response = self
+                .post(endpoint, json!({ key: batch, "include_spent_coins": true }))
+                .await?;
+
+            for record in response["coin_records"].as_array().into_iter().flatten() {
+                let coin = coin(&record["coin"]).zip(record["confirmed_block_index"].as_u64());
+                let Some((coin, created_height)) = coin else {
+                    bail!("The indexer sent a malformed coin record");
+                };
+                coins.push(IndexedCoin {
+                    coin,
+                    created_height: created_height as u32,
+                });
+            }
+        }
+
+        Ok(coins)
+    }
+
+    /// Every spend bundle in the indexer's mempool. The whole mempool is fetched, so the
+    /// indexer isn't told which coins are the wallet's.
+    pub async fn mempool_items(&self) -> anyhow::Result<Vec<MempoolItem>> {
+        let response = self.post("get_all_mempool_items", json!({})).await?;
+        let coins = |coins: &Value| {
+            coins
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(coin)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| anyhow!("The indexer sent a malformed mempool item"))
+        };
+
+        let mut items = Vec::new();
+
+        for (id, item) in response["mempool_items"].as_object().into_iter().flatten() {
+            items.push(MempoolItem {
+                id: id.clone(),
+                additions: coins(&item["additions"])?,
+                removals: coins(&item["removals"])?,
+            });
+        }
+
+        Ok(items)
+    }
+}
+
+/// A coin in the RPC's JSON, with its hashes as `0x` prefixed hex.
+fn coin(value: &Value) -> Option<Coin> {
+    let bytes32 = |value: &Value| -> Option<Bytes32> {
+        let hex = value.as_str()?;
+        let bytes = hex::decode(hex.strip_prefix("0x").unwrap_or(hex)).ok()?;
+        Some(<[u8; 32]>::try_from(bytes).ok()?.into())
+    };
+
+    Some(Coin {
+        parent_coin_info: bytes32(&value["parent_coin_info"])?,
+        puzzle_hash: bytes32(&value["puzzle_hash"])?,
+        amount: value["amount"].as_u64()?,
+    })
+}
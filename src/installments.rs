@@ -0,0 +1,116 @@
+use std::ops::Range;
+
+use anyhow::anyhow;
+use chrono::{Datelike, Local, NaiveDate};
+
+use crate::{
+    accounting::realized_gains,
+    config::Config,
+    report::{DailyPrices, Transaction},
+    summary::{day_end, summarize_year},
+};
+
+/// A payment of estimated tax, with the income received and the gains realized up to the end
+/// of its period.
+#[derive(Debug, Clone)]
+pub struct Installment {
+    pub due: NaiveDate,
+    /// The last day of income the installment covers.
+    pub covers_to: NaiveDate,
+    /// The income received from the start of the year to the end of the period.
+    pub income: f64,
+    /// The gains realized over the same days, before the losses carried into the year.
+    pub gains: f64,
+    pub payment: f64,
+    /// Whether the period hasn't ended yet, so its income is projected from the year so far.
+    pub projected: bool,
+}
+
+/// The estimated tax payments for the year from the income received and gains realized so far,
+/// at the rate and due dates in the config.
+///
+/// Each installment covers the income and realized gains up to the end of the month before
+/// it's due, less what the earlier ones paid. Gains are realized with the configured lot method,
+/// and absorb the losses carried into the year first, while a net loss is deducted from income
+/// up to the carryforward's deduction limit. Periods that haven't ended are projected by
+/// assuming the rest of the year earns and realizes at the same daily rate as the year so far.
+pub fn installments(
+    year: i32,
+    bounds: Range<i64>,
+    transactions: impl Iterator<Item = Transaction>,
+    carried_in: f64,
+    daily_prices: &DailyPrices,
+    config: &Config,
+) -> anyhow::Result<Vec<Installment>> {
+    let transactions = transactions.collect::<Vec<_>>();
+    let totals_to = |date: NaiveDate| {
+        let bounds = bounds.start..day_end(date)?.min(bounds.end);
+        let income = summarize_year(
+            year,
+            bounds.clone(),
+            transactions.iter().cloned(),
+            Default::default(),
+            daily_prices,
+            config,
+        )
+        .income;
+        let gains = realized_gains(
+            transactions.iter().cloned(),
+            bounds,
+            config.lot_method(),
+            daily_prices,
+            config,
+        )
+        .values()
+        .map(|gains| gains.gain)
+        .sum::<f64>();
+        anyhow::Ok((income, gains))
+    };
+
+    let first = NaiveDate::from_ymd_opt(year, 1, 1).unwrap();
+    let last = NaiveDate::from_ymd_opt(year, 12, 31).unwrap();
+    let today = Local::now().date_naive();
+    let elapsed = (today.min(last) - first).num_days() + 1;
+    let (daily_income, daily_gains) = if today < first {
+        (0.0, 0.0)
+    } else {
+        let (income, gains) = totals_to(today.min(last))?;
+        (income / elapsed as f64, gains / elapsed as f64)
+    };
+
+    let rate = config.estimated_tax.rate / 100.0;
+    let mut paid = 0.0;
+    let mut installments = Vec::new();
+
+    let due_dates = config
+        .estimated_tax
+        .due_dates(year)
+        .map_err(|problem| anyhow!("`estimated_tax.due_dates` {problem}"))?;
+
+    for due in due_dates {
+        let period_start = NaiveDate::from_ymd_opt(due.year(), due.month(), 1).unwrap();
+        let covers_to = period_start.pred_opt().unwrap().min(last);
+        let projected = covers_to >= today;
+
+        let (income, gains) = if projected {
+            let days = ((covers_to - first).num_days() + 1) as f64;
+            (daily_income * days, daily_gains * days)
+        } else {
+            totals_to(covers_to)?
+        };
+        let net_gains = (gains - carried_in).max(-config.loss_carryforward.deduction_limit);
+        let payment = ((income + net_gains) * rate - paid).max(0.0);
+        paid += payment;
+
+        installments.push(Installment {
+            due,
+            covers_to,
+            income,
+            gains,
+            payment,
+            projected,
+        });
+    }
+
+    Ok(installments)
+}
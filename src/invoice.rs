@@ -0,0 +1,167 @@
+use std::path::Path;
+
+use anyhow::bail;
+use chia::{client::Peer, protocol::Bytes32};
+use indexmap::{IndexMap, IndexSet};
+use serde::Deserialize;
+
+use crate::{
+    asset::Asset,
+    cache::{CacheWindows, CoinStateJson},
+    config::RetryConfig,
+    parents::fetch_parent_spends,
+    timings::Timings,
+};
+
+/// A row of the invoice CSV, whose reference clients put in the memo of their payment.
+/// The amount, in the display unit of whatever is paid, is optional and only used for checking.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Invoice {
+    pub reference: String,
+    #[serde(default)]
+    pub amount: Option<f64>,
+    #[serde(default)]
+    pub client: Option<String>,
+}
+
+/// A coin received from outside the wallet, with the text memos its sender attached.
+#[derive(Debug, Clone)]
+pub struct Payment {
+    pub coin_id: Bytes32,
+    pub asset: Asset,
+    pub amount: u64,
+    pub height: u32,
+    pub memos: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct InvoiceMatches {
+    /// Each invoice with the payments referencing it, which can be several for partial payments.
+    pub matched: Vec<(Invoice, Vec<Payment>)>,
+    pub unmatched: Vec<Invoice>,
+    /// Payments with memos that don't reference any invoice.
+    pub unreferenced: Vec<Payment>,
+}
+
+pub fn load_invoices(path: impl AsRef<Path>) -> anyhow::Result<Vec<Invoice>> {
+    let mut invoices = Vec::new();
+
+    for (line, invoice) in csv::Reader::from_path(path)?.deserialize().enumerate() {
+        let invoice: Invoice = invoice?;
+        if invoice.reference.trim().is_empty() {
+            bail!("Invoice on line {} has an empty reference", line + 2);
+        }
+        invoices.push(invoice);
+    }
+
+    Ok(invoices)
+}
+
+/// The cached coins that were received from outside the wallet, leaving out change,
+/// which is any coin whose parent is also in the cache.
+pub fn incoming_coins(windows: &CacheWindows) -> anyhow::Result<Vec<([u8; 32], CoinStateJson)>> {
+    let mut coin_ids = IndexSet::new();
+    let mut coins = Vec::new();
+
+    for derivations in windows.iter() {
+        for (coin_id, coin_state) in derivations?.coin_states {
+            coin_ids.insert(coin_id);
+            if coin_state.created_height.is_some() {
+                coins.push((coin_id, coin_state));
+            }
+        }
+    }
+
+    coins.retain(|(_, coin_state)| !coin_ids.contains(&coin_state.coin.parent_coin_info));
+    Ok(coins)
+}
+
+/// Looks up the memos of each coin from its parent's spend. Coins without a parent spend,
+/// such as farming rewards, have no memos.
+pub async fn payment_memos(
+    peer: &Peer,
+    genesis_challenge: Bytes32,
+    coins: Vec<([u8; 32], CoinStateJson)>,
+    concurrency: usize,
+    retry: &RetryConfig,
+) -> anyhow::Result<Vec<Payment>> {
+    let parents = coins
+        .iter()
+        .filter_map(|(_, coin_state)| {
+            Some((
+                Bytes32::from(coin_state.coin.parent_coin_info),
+                coin_state.created_height?,
+            ))
+        })
+        .collect::<IndexMap<_, _>>();
+
+    let parent_spends = fetch_parent_spends(
+        peer,
+        genesis_challenge,
+        parents,
+        concurrency,
+        retry,
+        &Timings::default(),
+    )
+    .await?;
+
+    let mut payments = Vec::new();
+
+    for (coin_id, coin_state) in coins {
+        let (Some(asset), Some(height)) = (coin_state.asset(), coin_state.created_height) else {
+            continue;
+        };
+
+        let memos = match parent_spends.get(&Bytes32::from(coin_state.coin.parent_coin_info)) {
+            Some(Some(parent_spend)) => parent_spend.child_memos(coin_state.coin.clone().into())?,
+            _ => Vec::new(),
+        };
+
+        payments.push(Payment {
+            coin_id: coin_id.into(),
+            asset,
+            amount: coin_state.coin.amount,
+            height,
+            memos,
+        });
+    }
+
+    Ok(payments)
+}
+
+/// Links payments to the invoices their memos reference. References are compared to each word
+/// of a memo, ignoring case, so `INV-1` doesn't match a memo of `INV-10`.
+pub fn match_invoices(invoices: Vec<Invoice>, payments: Vec<Payment>) -> InvoiceMatches {
+    let mut matches = InvoiceMatches::default();
+    let mut referenced = IndexSet::new();
+
+    for invoice in invoices {
+        let reference = invoice.reference.trim().to_lowercase();
+        let paid = payments
+            .iter()
+            .filter(|payment| {
+                payment.memos.iter().any(|memo| {
+                    memo.trim().to_lowercase() == reference
+                        || memo
+                            .split(|c: char| c.is_whitespace() || matches!(c, ',' | ';'))
+                            .any(|word| word.to_lowercase() == reference)
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if paid.is_empty() {
+            matches.unmatched.push(invoice);
+        } else {
+            referenced.extend(paid.iter().map(|payment| payment.coin_id));
+            matches.matched.push((invoice, paid));
+        }
+    }
+
+    matches.unreferenced = payments
+        .into_iter()
+        .filter(|payment| !payment.memos.is_empty() && !referenced.contains(&payment.coin_id))
+        .collect();
+
+    matches
+}
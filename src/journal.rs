@@ -0,0 +1,180 @@
+use std::io::{BufWriter, Write};
+
+use indexmap::IndexSet;
+
+use crate::{
+    config::AccountsConfig,
+    report::{ReportRow, ReportWriter, TransactionKind},
+};
+
+/// The plain-text accounting syntax a journal is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalStyle {
+    Beancount,
+    Ledger,
+}
+
+/// Writes each transaction as a double-entry journal entry, which moves the asset between
+/// its asset account and the income or expense account from the config.
+///
+/// Transactions are valued with a per-unit price in the report's currency when one is known,
+/// and the other posting is left for the tool to balance. Beancount requires accounts to be
+/// opened, so an `open` directive is written the first time each one is used.
+pub struct JournalWriter {
+    writer: BufWriter<Box<dyn Write>>,
+    style: JournalStyle,
+    accounts: AccountsConfig,
+    opened: IndexSet<String>,
+}
+
+impl JournalWriter {
+    pub fn new(output: Box<dyn Write>, style: JournalStyle, accounts: AccountsConfig) -> Self {
+        Self {
+            writer: BufWriter::new(output),
+            style,
+            accounts,
+            opened: IndexSet::new(),
+        }
+    }
+
+    fn open(&mut self, date: &str, account: &str) -> anyhow::Result<()> {
+        if self.style == JournalStyle::Beancount && self.opened.insert(account.to_string()) {
+            writeln!(self.writer, "{date} open {account}")?;
+        }
+        Ok(())
+    }
+}
+
+impl ReportWriter for JournalWriter {
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        let date = row.date.get(..10).unwrap_or(&row.date);
+        let commodity = commodity(&row.asset);
+        let asset_account = self
+            .accounts
+            .assets
+            .replace("{asset}", &account_component(&commodity));
+        let (narration, sign, other_account) = match row.kind {
+            TransactionKind::Receive => ("Receive", "", &self.accounts.income),
+            TransactionKind::Send => ("Send", "-", &self.accounts.expenses),
+            TransactionKind::Fee => ("Fee", "-", &self.accounts.fees),
+        };
+        let other_account = other_account.replace("{asset}", &account_component(&commodity));
+
+        self.open(date, &asset_account)?;
+        self.open(date, &other_account)?;
+
+        let price = row
+            .price
+            .map(|price| format!(" @ {price} {}", row.currency))
+            .unwrap_or_default();
+
+        match self.style {
+            JournalStyle::Beancount => {
+                match &row.counterparty {
+                    Some(payee) => writeln!(
+                        self.writer,
+                        "{date} * {payee:?} \"{narration} {}\"",
+                        row.asset
+                    )?,
+                    None => writeln!(self.writer, "{date} * \"{narration} {}\"", row.asset)?,
+                }
+                writeln!(self.writer, "  height: {}", row.height)?;
+                writeln!(self.writer, "  coin_ids: \"{}\"", row.coin_ids)?;
+                if !row.change_coin_ids.is_empty() {
+                    writeln!(
+                        self.writer,
+                        "  change_coin_ids: \"{}\"",
+                        row.change_coin_ids
+                    )?;
+                }
+                if let Some(label) = &row.label {
+                    writeln!(self.writer, "  label: {label:?}")?;
+                }
+                if let Some(evidence) = &row.evidence {
+                    writeln!(self.writer, "  evidence: {evidence:?}")?;
+                }
+                writeln!(
+                    self.writer,
+                    "  {asset_account}  {sign}{} {commodity}{price}",
+                    row.amount
+                )?;
+                writeln!(self.writer, "  {other_account}")?;
+            }
+            JournalStyle::Ledger => {
+                let commodity = if commodity.chars().all(|c| c.is_ascii_alphabetic()) {
+                    commodity
+                } else {
+                    format!("\"{commodity}\"")
+                };
+                writeln!(self.writer, "{date} {narration} {}", row.asset)?;
+                if let Some(payee) = &row.counterparty {
+                    writeln!(self.writer, "    ; Payee: {payee}")?;
+                }
+                writeln!(self.writer, "    ; height: {}", row.height)?;
+                writeln!(self.writer, "    ; coin_ids: {}", row.coin_ids)?;
+                if !row.change_coin_ids.is_empty() {
+                    writeln!(
+                        self.writer,
+                        "    ; change_coin_ids: {}",
+                        row.change_coin_ids
+                    )?;
+                }
+                if let Some(label) = &row.label {
+                    writeln!(self.writer, "    ; label: {label}")?;
+                }
+                if let Some(evidence) = &row.evidence {
+                    writeln!(self.writer, "    ; evidence: {evidence}")?;
+                }
+                writeln!(
+                    self.writer,
+                    "    {asset_account}  {sign}{} {commodity}{price}",
+                    row.amount
+                )?;
+                writeln!(self.writer, "    {other_account}")?;
+            }
+        }
+        writeln!(self.writer)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Turns an asset name like `CAT 1a2b3c4d` into a commodity both tools accept, like `CAT-1A2B3C4D`.
+fn commodity(asset: &str) -> String {
+    let mut commodity = asset
+        .to_uppercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '\'') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .take(24)
+        .collect::<String>();
+
+    if !commodity.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        commodity.insert(0, 'X');
+        commodity.truncate(24);
+    }
+
+    while commodity.ends_with(|c: char| !c.is_ascii_alphanumeric()) {
+        commodity.pop();
+    }
+
+    commodity
+}
+
+/// Account names only allow letters, digits and dashes in each component.
+fn account_component(commodity: &str) -> String {
+    commodity
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
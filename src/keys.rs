@@ -0,0 +1,103 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail};
+use chia::bls::PublicKey;
+use indexmap::IndexSet;
+use serde_json::Value;
+
+use crate::secrets::{get_secret, Secret};
+
+/// Reads the master public key from `--key`, which is either the key itself, `-` for stdin,
+/// or a file. The contents can be the hex key, the output of `chia keys show`, or a wallet's
+/// JSON export such as Goby's or Sage's, and the format is detected from them.
+/// Without `--key`, the key stored in the keyring is used.
+pub fn parse_pk(key: Option<&str>) -> anyhow::Result<PublicKey> {
+    let Some(key) = key else {
+        let Some(key) = get_secret(Secret::MasterPublicKey)? else {
+            bail!("Pass --key, or store it with `thyme config set-secret master_public_key`");
+        };
+        return parse_hex(&key);
+    };
+
+    let contents = if key == "-" {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents)?;
+        contents
+    } else if Path::new(key).is_file() {
+        fs::read_to_string(key)?
+    } else {
+        key.to_string()
+    };
+
+    let trimmed = contents.trim();
+
+    if trimmed.starts_with(['{', '[']) {
+        let json: Value = serde_json::from_str(trimmed)
+            .map_err(|error| anyhow!("The key export isn't valid JSON: {error}"))?;
+        let mut keys = IndexSet::new();
+        json_keys(&json, false, &mut keys);
+        return only_key(keys, "JSON export");
+    }
+
+    if trimmed.contains("Master public key") {
+        // `chia keys show` prints a block per key, with a line like `Master public key (m): 8f1a...`.
+        let keys = trimmed
+            .lines()
+            .filter(|line| line.trim_start().starts_with("Master public key"))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(_, key)| parse_hex(key))
+            .collect::<anyhow::Result<_>>()?;
+        return only_key(keys, "`chia keys show` output");
+    }
+
+    parse_hex(trimmed)
+}
+
+/// Collects the public keys in a JSON export. Only strings under a field whose name mentions
+/// a public key are considered, so that other hex fields, like fingerprints, are left alone.
+fn json_keys(value: &Value, in_key_field: bool, keys: &mut IndexSet<PublicKey>) {
+    match value {
+        Value::String(string) if in_key_field => {
+            if let Ok(pk) = parse_hex(string) {
+                keys.insert(pk);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                json_keys(value, in_key_field, keys);
+            }
+        }
+        Value::Object(fields) => {
+            for (name, value) in fields {
+                let name = name.to_lowercase().replace(['_', '-'], "");
+                let is_key_field = name.contains("publickey") || name.ends_with("pk");
+                json_keys(value, is_key_field, keys);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn only_key(keys: IndexSet<PublicKey>, format: &str) -> anyhow::Result<PublicKey> {
+    match keys.len() {
+        0 => bail!("Couldn't find a master public key in the {format}"),
+        1 => Ok(keys[0]),
+        count => {
+            bail!("The {format} has {count} different public keys, pass the one to use as --key")
+        }
+    }
+}
+
+fn parse_hex(key: &str) -> anyhow::Result<PublicKey> {
+    let trimmed = key.trim();
+    let stripped = trimmed.strip_prefix("0x").unwrap_or(trimmed);
+    let bytes = hex::decode(stripped)?;
+    let array = bytes
+        .try_into()
+        .map_err(|_| anyhow!("Public key is not 48 bytes long"))?;
+    Ok(PublicKey::from_bytes(&array)?)
+}
@@ -1,44 +1,312 @@
 use std::{
-    fs,
+    fs::{self, File},
+    io::{self, Write},
+    iter,
+    net::SocketAddr,
+    ops::Range,
     path::{Path, PathBuf},
+    slice,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
+use accounting::{
+    disposal_rows, disposals, realized_gains, write_disposal_report, Carryforward, LotMethod,
+    LotTracker, RealizedGains,
+};
+use addresses::{address, derive_puzzle_hash, AddressBook, IssuedAddress};
 use anyhow::{anyhow, bail};
-use cache::{Cache, CoinStateJson, Derivations, PuzzleInfo};
+use asset::Asset;
+use bootstrap::bootstrap_window;
+use cache::{Cache, CacheLock, CacheWindows, CoinStateJson, Derivations, PuzzleInfo, SyncFilters};
+use cert::CertPaths;
 use chia::{
-    bls::{master_to_wallet_unhardened_intermediate, DerivableKey, PublicKey},
-    client::Peer,
-    clvm_traits::ToClvm,
-    protocol::{
-        NodeType, PuzzleSolutionResponse, RejectCoinState, RejectPuzzleSolution, RequestCoinState,
-        RespondCoinState,
-    },
-    puzzles::{standard::StandardArgs, DeriveSynthetic},
+    bls::{master_to_wallet_unhardened_intermediate, PublicKey},
+    client::{Peer, PeerEvent},
+    protocol::{Bytes32, CoinState},
 };
-use chia_wallet_sdk::{connect_peer, create_tls_connector, load_ssl_cert, Cat, Primitive, Puzzle};
-use chrono::{Local, TimeZone};
-use clap::Parser;
-use clvmr::Allocator;
-use config::Config;
+use chia_wallet_sdk::decode_address;
+use chrono::{Datelike, Local, NaiveDate, TimeZone};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::{generate, Shell};
+use cluster::{coin_senders, Clusters};
+use clvmr::sha2::Sha256;
+use config::{Config, Valuation};
+use custom::CustomPuzzles;
+use dexie::Dexie;
+use diff::diff_caches;
+use doctor::doctor;
+use entity::{Entities, CONSOLIDATED, WINDOW_SIZE};
 use fetch::fetch_coin_states;
-use indexmap::IndexMap;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use futures_util::future::join_all;
+use indexer::Indexer;
+use indexmap::{IndexMap, IndexSet};
+use installments::{installments, Installment};
+use invoice::{incoming_coins, load_invoices, match_invoices, payment_memos};
+use keys::parse_pk;
+use mints::detect_mints;
+use nft::{
+    collection_rows, market_sales, nft_trades, resolve_collections, valuation_rows,
+    write_collection_report, write_valuation_report, CollectionCache,
+};
+use notify::{GainAlerts, Notifier};
+use offer::{is_maker, load_offer, parse_offer, summarize_offer, OfferStatus};
+use parents::{fetch_parent_spends, parent_fetch_requests, PuzzleCache};
+use peers::{connect, connect_many, with_heartbeat, PeerDisconnected, ProbedPeer};
+use pool::{payout_tags, pool_history, PoolPeriod};
+use price::{PriceCache, PriceProvider};
+use privacy::window_puzzle_hashes;
+use query::Query;
+use reconcile::reconcile;
+use report::{
+    collapse_self_spends, daily_prices, fetch_prices, report_file_name, report_row, write_report,
+    Commitment, DailyPrices, ReportCompression, ReportFormat, ReportOutput, ReportWriter,
+    SplitReportWriter, Transaction, TransactionGrouper, TransactionKind,
+};
+use reuse::{reuse_rows, write_reuse_report};
+use sanity::{check_coin_states, check_parent_spends};
+use secrets::{delete_secret, set_secret, Secret};
+use serve::serve_transactions;
+use summary::{
+    daily_balances, day_end, holdings_at, summarize_groups, summarize_year, year_end, GroupBy,
+    YearSummary,
+};
+use tags::Tags;
+use timestamps::{block_timestamp, resolve_timestamps};
+use timings::{Phase, Timings};
+use tokio::{
+    net::TcpListener,
+    sync::broadcast::{self, error::RecvError, Receiver},
+    time::sleep,
+};
+use update::self_update;
+use vat::{vat_rows, write_vat_report};
+use verify::{check_consistency, header_hash_at, verify_headers};
+use watch::{
+    apply_coin_states, coin_events, snapshot, subscribe_coins, CoinEvent, CoinEventKind,
+    Subscriptions,
+};
+use withdrawals::{load_exchange_export, match_withdrawals, Withdrawal};
 
+mod accounting;
+mod addresses;
+mod asset;
+mod bookkeeping;
+mod bootstrap;
 mod cache;
+mod cert;
+mod cluster;
 mod config;
+mod custom;
+mod dexie;
+mod diff;
+mod doctor;
+mod drivers;
+mod entity;
 mod fetch;
+mod indexer;
+mod installments;
+mod invoice;
+mod journal;
+mod keys;
+mod mints;
+mod nft;
+mod notify;
+mod offer;
+mod parents;
+mod peers;
+mod pool;
+mod price;
+mod privacy;
+mod query;
+mod read_only;
+mod reconcile;
+mod report;
+mod retry;
+mod reuse;
+mod sanity;
+mod secrets;
+mod serve;
+mod summary;
+mod tags;
+mod timestamps;
+mod timings;
+mod update;
+mod vat;
+mod verify;
+mod watch;
+mod withdrawals;
+
+const CONFIG_PATH: &str = "config.toml";
+
+/// How many times a sync reconnects to retry the same window before giving up.
+const MAX_RECONNECTS: usize = 3;
+
+/// The longest a watch waits before trying to reconnect again.
+const MAX_WATCH_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How often the mempool is checked for pending spend bundles with `watch --mempool`.
+const MEMPOOL_POLL_INTERVAL: Duration = Duration::from_secs(15);
+const PRICE_CACHE_FILE: &str = "prices.json";
+const COLLECTION_CACHE_FILE: &str = "nft-collections.json";
 
 /// Generates a CSV file with observer key Chia transaction info for a given tax year.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// The master public key of the wallet to lookup transactions for.
+    /// Fail instead of writing the cache, config, address book or certificates, so a report
+    /// can run against a cache that another process syncs. Prices and other lookups are only
+    /// cached in memory, and reports don't sync, as with --skip-sync.
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// How sends are taken out of lots for every command of the run, instead of `lot_method`
+    /// and each residency's method in the config.
+    #[arg(long, global = true, value_enum, value_name = "METHOD")]
+    cost_basis: Option<LotMethod>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Syncs the cache and generates the report for a given tax year.
+    Report(Box<ReportArgs>),
+
+    /// Keeps the cache of a year synced as coins are received and spent, printing each one.
+    /// The peer pushes changes to the wallet's puzzle hashes and coins as they're confirmed,
+    /// so nothing has to be paged again to see them.
+    Watch {
+        #[command(flatten)]
+        wallet: WalletArgs,
+
+        /// Also print the wallet's coins in spend bundles waiting in the mempool of
+        /// `indexer.url` from the config, as pending. They're never cached or reported,
+        /// since they may never be confirmed.
+        #[arg(long)]
+        mempool: bool,
+    },
+
+    /// Watches the wallet like `watch`, and streams each new transaction to WebSocket clients
+    /// connected to `ws://<listen>/transactions`, as a JSON report row.
+    Serve {
+        #[command(flatten)]
+        wallet: WalletArgs,
+
+        /// The address to listen on.
+        #[arg(long, default_value = "127.0.0.1:8787")]
+        listen: SocketAddr,
+    },
+
+    /// Works with offer files.
+    #[command(subcommand)]
+    Offer(OfferCommand),
+
+    /// Inspects cache files.
+    #[command(subcommand)]
+    Cache(CacheCommand),
+
+    /// Manages the TLS certificate used to connect to full nodes, which is kept in the data dir.
+    #[command(subcommand)]
+    Cert(CertCommand),
+
+    /// Stores secrets in the OS keyring instead of config.toml.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Checks the config, certificate, peers, caches and price API, with hints for what fails.
+    Doctor,
+
+    /// Prints a completion script for bash, zsh, fish, elvish or PowerShell, such as
+    /// `thyme completions bash > /etc/bash_completion.d/thyme`.
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+
+    /// Updates thyme to the latest signed release on GitHub.
+    SelfUpdate {
+        /// Only print whether a newer release is available.
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Derives a receive address, recording what it's for so coins received to it are labeled.
+    Address(AddressArgs),
+
+    /// Matches payments received during the year to invoices by the reference in their memo.
+    Invoices {
+        #[command(flatten)]
+        wallet: WalletArgs,
+
+        /// A CSV with a `reference` column, and optionally `amount` and `client` columns.
+        invoices: PathBuf,
+
+        /// Print the matched coins to add to this entity's `coins` in the config, such as
+        /// `business`. The config is left as it was, so its comments and layout are kept.
+        #[arg(long)]
+        tag: Option<String>,
+    },
+
+    /// Works out the gain a sale would realize from the lots held on the date, with the
+    /// `lot_method` from the config, such as `thyme simulate -y 2024 --sell 100 XCH`.
+    /// The cache is read as it is, without syncing, and nothing is written.
+    Simulate(SimulateArgs),
+
+    /// Labels a counterparty in the address book, which names every address clustered with it
+    /// in reports with --counterparties.
+    Label {
+        /// The master public key of the wallet whose address book to update, in any format
+        /// `--key` of `report` accepts.
+        #[arg(short, long)]
+        key: Option<String>,
+
+        /// One of the counterparty's addresses, or a cluster id from a report like `cluster-1a2b3c4d`.
+        counterparty: String,
+
+        /// The name to report the counterparty as, such as `Exchange`.
+        label: String,
+    },
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct WalletArgs {
+    /// The master public key of the wallet to lookup transactions for. This is the hex key,
+    /// or a file, or `-` for stdin, with the output of `chia keys show` or a wallet's JSON export.
+    /// Defaults to the key stored with `thyme config set-secret master_public_key`.
     #[arg(short, long)]
-    key: String,
+    key: Option<String>,
 
     /// The year you are interested in, from Jan 1st to Dec 31st, inclusive.
     #[arg(short, long)]
     year: i32,
+}
+
+#[derive(clap::Args, Debug)]
+struct SimulateArgs {
+    #[command(flatten)]
+    wallet: WalletArgs,
+
+    /// An amount and asset to sell, like `--sell 100 XCH`, which can be given more than once.
+    /// Assets are XCH, or a CAT by its name in the config or its asset id.
+    #[arg(long, num_args = 2, value_names = ["AMOUNT", "ASSET"], required = true)]
+    sell: Vec<String>,
+
+    /// The day of the sale, which defaults to today. Sales today or later are priced at the
+    /// current price.
+    #[arg(long)]
+    date: Option<NaiveDate>,
+
+    /// The price per unit to sell at instead of the day's, in the config's currency.
+    #[arg(long)]
+    price: Option<f64>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ReportArgs {
+    #[command(flatten)]
+    wallet: WalletArgs,
 
     /// Whether to reset the cache before running.
     #[arg(short, long)]
@@ -47,211 +315,4213 @@ struct Args {
     /// The dust threshold to filter out small transactions. Defaults to 0.
     #[arg(short, long)]
     dust_threshold: Option<u64>,
+
+    /// The block the wallet's first coin was received after, so new windows page the peer from
+    /// there instead of from genesis. Without it, the block before the first coin of the first
+    /// window is used once that window is synced. The height is kept in the cache for windows
+    /// added by later syncs. Windows already in the cache keep their progress, so this is meant
+    /// for a new cache or one synced with --reset.
+    #[arg(long)]
+    start_height: Option<u32>,
+
+    /// Report from the existing cache without syncing it first.
+    #[arg(long)]
+    skip_sync: bool,
+
+    /// Report from a snapshot taken with `thyme cache snapshot` instead of the cache, which
+    /// is never synced.
+    #[arg(long, conflicts_with_all = ["reset", "dry_run"])]
+    snapshot: Option<String>,
+
+    /// Only write the reports that are out of date with the cache, without syncing it first.
+    /// Each report records a hash of the cache, config, address book and arguments it was
+    /// written from in a `.fingerprint` file next to it, which is what's compared against.
+    #[arg(long, conflicts_with_all = ["reset", "dry_run", "compare", "as_of", "wealth_tax"])]
+    check: bool,
+
+    /// Page the new coin states of every window and estimate how long the sync would take,
+    /// then exit without fetching parents, writing the cache or making a report.
+    #[arg(long, conflicts_with = "skip_sync")]
+    dry_run: bool,
+
+    /// If another run is using the cache, wait for it to finish instead of failing.
+    /// Reports that don't sync share the cache with other readers, so they only ever wait for
+    /// a run writing to it, like a watch while it saves.
+    #[arg(long)]
+    wait: bool,
+
+    /// The format of the report file.
+    #[arg(short, long, value_enum, default_value_t)]
+    format: ReportFormat,
+
+    /// Value the report in this currency, like `eur`, instead of `currency` from the config.
+    /// Prices are cached per currency, so one cache can be reported in several. Add
+    /// `{currency}` to --name-template to keep each currency's report.
+    #[arg(long)]
+    currency: Option<String>,
+
+    /// Where to write the report, or `-` for stdout. Progress is always printed to stderr.
+    /// Overrides --output-dir and --name-template.
+    #[arg(short, long, conflicts_with_all = ["output_dir", "name_template"])]
+    output: Option<PathBuf>,
+
+    /// The directory to write the report to, which is created if needed.
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
+
+    /// The report file name, with `{fingerprint}`, `{year}`, `{entity}`, `{currency}` and
+    /// `{format}` replaced.
+    #[arg(long, default_value = "report-{fingerprint}-{year}.{format}")]
+    name_template: String,
+
+    /// Compress the report, which adds the matching extension to templated file names.
+    #[arg(long, value_enum)]
+    compress: Option<ReportCompression>,
+
+    /// Print a side-by-side summary of these years and --year instead of writing a report.
+    /// Every year is summarized from the same sync of --year's cache.
+    #[arg(long, num_args = 1..)]
+    compare: Vec<i32>,
+
+    /// Print the holdings and open lots at the end of this date instead of writing a report,
+    /// valued at the day's prices, like for a wealth tax declaration or a collateral statement.
+    /// Sends are taken out of the lots as `--cost-basis` or `lot_method` says. Any date the
+    /// cache covers can be used.
+    #[arg(long, value_name = "DATE", conflicts_with_all = ["compare", "nft_collections", "counterparties"])]
+    as_of: Option<NaiveDate>,
+
+    /// Offer files whose coins are committed while the offer is open, which --as-of lists
+    /// along with coins hinted to the wallet that haven't been claimed, like pending clawbacks.
+    #[arg(long, num_args = 1.., requires = "as_of")]
+    offers: Vec<PathBuf>,
+
+    /// Print the holdings of the year valued for a wealth tax instead of writing a report,
+    /// either on one day or as the average of their value over the year, as set by
+    /// `wealth_tax.valuation` in the config.
+    #[arg(long, conflicts_with_all = ["compare", "as_of", "nft_collections", "counterparties"])]
+    wealth_tax: bool,
+
+    /// Also write a separate report for each asset next to the combined one, such as
+    /// `report-{fingerprint}-{year}-XCH.csv`. NFTs all go in one `-NFT` report.
+    #[arg(long)]
+    per_asset: bool,
+
+    /// Detect NFTs minted from the wallet during the year, and report each as received
+    /// with the XCH spent minting it as its cost. This adds a request per coin spent in the year.
+    #[arg(long)]
+    capitalize_mints: bool,
+
+    /// Also write the proceeds, basis, net profit and holding period of NFTs sold during the year,
+    /// grouped by collection, next to the report as `{name}-nft-collections.csv`.
+    /// Collections are looked up from each NFT's off-chain metadata. NFTs valued by
+    /// `nft_valuations` in the config are listed with how in `{name}-nft-valuations.csv`.
+    #[arg(long, conflicts_with = "compare")]
+    nft_collections: bool,
+
+    /// Look up who sent each coin received during the year, and report them by their label
+    /// from `thyme label` or by cluster id. Senders whose coins were spent in the same spend
+    /// bundle are clustered together. This adds a request per sending coin.
+    #[arg(long, conflicts_with = "compare")]
+    counterparties: bool,
+
+    /// Match the coins tagged as withdrawals from an exchange with --counterparties against
+    /// the withdrawals in the exchange's own export, by address, amount and time. The columns
+    /// are set in the `exchange_export` table of the config. Each coin matched lists its line
+    /// of the export as evidence.
+    #[arg(long, requires = "counterparties")]
+    exchange_export: Vec<PathBuf>,
+
+    /// Also write every derivation that received payments at more than one height, over the
+    /// whole history in the cache, next to the report as `{name}-address-reuse.csv`.
+    /// The senders of the payments received during the year are included with --counterparties.
+    #[arg(long, conflicts_with_all = ["compare", "as_of", "wealth_tax"])]
+    address_reuse: bool,
+
+    /// Also write the sales received during each VAT or GST period of the year, valued when
+    /// they were received, next to the report as `{name}-vat.csv`. The period, rate and which
+    /// address purposes are sales are set in the `vat` table of the config.
+    #[arg(long, conflicts_with_all = ["compare", "as_of", "wealth_tax"])]
+    vat: bool,
+
+    /// Also write every send and fee of the year with the gain it realized, next to the report
+    /// as `{name}-disposals.csv`. Lots are taken out of as `--cost-basis` or `lot_method` says,
+    /// and each disposal lists when the earliest lot it was taken out of was received.
+    #[arg(long, conflicts_with_all = ["compare", "as_of", "wealth_tax"])]
+    disposals: bool,
+
+    /// Record the year's net realized gain next to the cache, and print it less the losses
+    /// carried forward from the years recorded before it. Each year has to be reported with
+    /// this once for its losses to carry, and how much of a loss is deducted each year instead
+    /// is set with `loss_carryforward.deduction_limit` in the config.
+    #[arg(long, conflicts_with_all = ["compare", "as_of", "wealth_tax"])]
+    carry_losses: bool,
+
+    /// Look up where each plot NFT in the `pool` table of the config paid its pool rewards
+    /// over time, print when that changed, and tag each payout received during the year with
+    /// the period it was received in. Payouts are the XCH received to addresses whose purpose
+    /// is one of `pool.payout_labels`. This adds a request per spend of each plot NFT.
+    #[arg(long, conflicts_with_all = ["compare", "as_of", "wealth_tax"])]
+    pool_payouts: bool,
+
+    /// Also print the year's totals for each tag from the config, such as projects or cost
+    /// centers, or for each address purpose. A transaction with several tags counts toward each.
+    #[arg(long, value_enum, conflicts_with_all = ["compare", "as_of", "wealth_tax"])]
+    group_by: Option<GroupBy>,
+
+    /// Also print the estimated tax payments due on the year's income and realized gains, at
+    /// the rate and due dates in the `estimated_tax` table of the config. Losses recorded with
+    /// --carry-losses are carried in. Payments for periods that haven't ended are projected
+    /// from the year so far.
+    #[arg(long, conflicts_with_all = ["compare", "as_of", "wealth_tax"])]
+    estimated_tax: bool,
+
+    /// Also print the gains realized during the year under each of these ways of picking the
+    /// lots sends are taken out of, like `fifo,lifo,hifo,acb`, to compare them before choosing one.
+    /// Every receive before the end of the year is priced for the basis of its lot.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "METHODS",
+        conflicts_with_all = ["compare", "as_of", "wealth_tax"]
+    )]
+    compare_methods: Vec<LotMethod>,
+
+    /// Also write the part of the year lived in each of the `residencies` in the config as its
+    /// own report, like `{name}-germany.csv`, in that residency's currency. Lots carry over
+    /// a move at their basis, worked out in each residency's currency and lot method.
+    #[arg(long, conflicts_with_all = ["compare", "as_of", "wealth_tax"])]
+    split_residency: bool,
+
+    /// Merge the self-spends of a day, like those of a coin splitting or merging bot, into one
+    /// fee row and print their count and total fees as maintenance. A self-spend sends all of
+    /// its outputs back to the wallet and loses no more than `self_spends.max_fee` from the
+    /// config, and is reported as a fee either way.
+    #[arg(long)]
+    collapse_self_spends: bool,
+
+    /// Write a separate report and summary for each of these entities from the config, covering
+    /// only their coins. `unassigned` covers the coins outside every entity.
+    /// The entity name is added to each file name.
+    #[arg(long, num_args = 1..)]
+    entity: Vec<String>,
+
+    /// Also write a report combining the --entity reports, where transfers between the
+    /// entities are netted like any other self-spend, and print each entity's figures next to
+    /// the eliminated and consolidated ones.
+    #[arg(long, requires = "entity")]
+    consolidate: bool,
+
+    /// After syncing, check the block each window was synced up to against a second peer,
+    /// and warn if they disagree. Needs another synced peer in the config.
+    #[arg(long, conflicts_with = "skip_sync")]
+    verify_headers: bool,
+
+    /// Print the wall-clock time and number of requests spent in each phase.
+    #[arg(long)]
+    timings: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct AddressArgs {
+    /// The master public key of the wallet to derive from, in any format `--key` of `report` accepts.
+    #[arg(short, long)]
+    key: Option<String>,
+
+    /// The derivation index of the address.
+    #[arg(
+        long,
+        required_unless_present = "next_unused",
+        conflicts_with = "next_unused"
+    )]
+    index: Option<u32>,
+
+    /// Use the lowest derivation that hasn't received a coin or been issued before,
+    /// according to the synced cache for --year.
+    #[arg(long)]
+    next_unused: bool,
+
+    /// The year of the cache checked by --next-unused. Defaults to the current year.
+    #[arg(short, long)]
+    year: Option<i32>,
+
+    /// What the address is for, which labels the coins received to it in reports.
+    #[arg(long)]
+    purpose: Option<String>,
+
+    /// The amount to request, in the display unit, for a payment request.
+    #[arg(long)]
+    amount: Option<String>,
+
+    /// The memo the payer should attach, such as an invoice reference.
+    #[arg(long)]
+    memo: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum OfferCommand {
+    /// Shows whether each offer is still open, was executed, or was cancelled.
+    Status {
+        #[command(flatten)]
+        wallet: WalletArgs,
+
+        /// The offer files to check, each containing an `offer1...` string, or the ids of
+        /// offers posted to Dexie, which are looked up there and shown with Dexie's status.
+        #[arg(required = true)]
+        offers: Vec<String>,
+    },
+
+    /// Shows both sides of an offer, valued at current and execution-time prices.
+    Inspect {
+        #[command(flatten)]
+        wallet: WalletArgs,
+
+        /// The offer file to inspect, containing an `offer1...` string.
+        offer: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Shows coins added, newly spent, and reclassified between two cache snapshots.
+    Diff {
+        /// The older cache directory.
+        old: PathBuf,
+
+        /// The newer cache directory.
+        new: PathBuf,
+    },
+
+    /// Compares unspent cached balances against a fresh query of the peer.
+    Reconcile {
+        #[command(flatten)]
+        wallet: WalletArgs,
+    },
+
+    /// Prints the cached coins matching a filter, such as `asset=SBX and amount>1000`.
+    ///
+    /// Fields are asset, coin_id, parent_coin_id, puzzle_hash, amount, created_height and spent_height.
+    /// Comparisons are joined with `and` and `or`, and heights can be compared against `none`.
+    Query {
+        #[command(flatten)]
+        wallet: WalletArgs,
+
+        /// The filter to apply.
+        query: String,
+
+        /// Print the matching coins as JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Saves the cache as it is now under a name, so a report can be written from it with
+    /// `report --snapshot` after the cache has synced further. Snapshots share the window files
+    /// with the cache until it changes them, and can be compared with `cache diff`.
+    Snapshot {
+        #[command(flatten)]
+        wallet: WalletArgs,
+
+        /// The snapshot's name, which defaults to the height the cache is synced to, like
+        /// `height-6123456`.
+        name: Option<String>,
+    },
+
+    /// Writes every puzzle hash the cache scans as CSV, with its derivation index, address
+    /// and how many cached coins were received to it, to check coverage against other tools.
+    PuzzleHashes {
+        #[command(flatten)]
+        wallet: WalletArgs,
+
+        /// Where to write the CSV, instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CertCommand {
+    /// Creates the certificate, unless one already exists.
+    Generate,
+
+    /// Prints where the certificate is stored and its SHA-256 fingerprint.
+    Show,
+
+    /// Replaces the certificate with a newly generated one.
+    Rotate,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Stores a secret in the keyring, which is used when it isn't set elsewhere.
+    SetSecret {
+        #[arg(value_enum)]
+        name: Secret,
+
+        /// The value, which is read from stdin if left out so it stays out of the shell history.
+        value: Option<String>,
+    },
+
+    /// Removes a secret from the keyring.
+    DeleteSecret {
+        #[arg(value_enum)]
+        name: Secret,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Parse arguments and setup timezone and key info.
     let args = Args::parse();
-    let local_timezone = Local::now().timezone();
-    let master_pk = parse_pk(&args.key)?;
+    if args.read_only {
+        read_only::set_read_only();
+    }
+    let cost_basis = args.cost_basis;
+
+    match args.command {
+        Command::Report(args) => report(*args, cost_basis).await,
+        Command::Watch { wallet, mempool } => watch(wallet, None, mempool, cost_basis).await,
+        Command::Serve { wallet, listen } => watch(wallet, Some(listen), false, cost_basis).await,
+        Command::Offer(OfferCommand::Status { wallet, offers }) => {
+            offer_status(wallet, offers).await
+        }
+        Command::Offer(OfferCommand::Inspect { wallet, offer }) => {
+            offer_inspect(wallet, offer).await
+        }
+        Command::Cache(CacheCommand::Diff { old, new }) => cache_diff(old, new),
+        Command::Cache(CacheCommand::Reconcile { wallet }) => cache_reconcile(wallet).await,
+        Command::Cache(CacheCommand::Query {
+            wallet,
+            query,
+            json,
+        }) => cache_query(wallet, &query, json),
+        Command::Cache(CacheCommand::Snapshot { wallet, name }) => cache_snapshot(wallet, name),
+        Command::Cache(CacheCommand::PuzzleHashes { wallet, output }) => {
+            cache_puzzle_hashes(wallet, output)
+        }
+        Command::Cert(command) => cert_command(command),
+        Command::Config(command) => config_command(command),
+        Command::Completions { shell } => {
+            let mut command = Args::command();
+            let name = command.get_name().to_string();
+            generate(shell, &mut command, name, &mut io::stdout());
+            Ok(())
+        }
+        Command::SelfUpdate { check } => self_update(check).await,
+        Command::Doctor => doctor(Path::new(CONFIG_PATH), Path::new("cache")).await,
+        Command::Address(args) => issue_address(args),
+        Command::Invoices {
+            wallet,
+            invoices,
+            tag,
+        } => invoices_match(wallet, invoices, tag).await,
+        Command::Simulate(args) => simulate(args, cost_basis).await,
+        Command::Label {
+            key,
+            counterparty,
+            label,
+        } => label_counterparty(key.as_deref(), &counterparty, label),
+    }
+}
+
+async fn report(args: ReportArgs, cost_basis: Option<LotMethod>) -> anyhow::Result<()> {
+    // Setup key info.
+    let master_pk = parse_pk(args.wallet.key.as_deref())?;
     let intermediate_pk = master_to_wallet_unhardened_intermediate(&master_pk);
-    let fingerprint = master_pk.get_fingerprint();
 
     // Load the config and cache.
-    let cache_dir = PathBuf::from("cache");
-    if !cache_dir.try_exists()? {
-        fs::create_dir_all(cache_dir.as_path())?;
+    let mut config = Config::load(CONFIG_PATH)?;
+    config.cost_basis = cost_basis;
+    if let Some(currency) = &args.currency {
+        if currency.is_empty() || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+            bail!("--currency should be a currency code like eur, not {currency:?}");
+        }
+        config.currency = currency.to_lowercase();
+    }
+    let cache_path = match &args.snapshot {
+        Some(name) => existing_snapshot_path(&master_pk, args.wallet.year, name)?,
+        None => cache_path(&master_pk, args.wallet.year)?,
+    };
+    let entities = Entities::from_config(&config)?;
+    let tags = Tags::from_config(&config)?;
+    // A report written to stdout leaves nowhere for the output written next to it.
+    if args.output.as_deref() == Some(Path::new("-")) {
+        let conflicts = [
+            (args.per_asset, "--per-asset writes one file per asset"),
+            (
+                args.nft_collections,
+                "--nft-collections writes a file next to the report",
+            ),
+            (
+                args.group_by.is_some(),
+                "--group-by prints a summary to stdout",
+            ),
+            (
+                args.address_reuse,
+                "--address-reuse writes a file next to the report",
+            ),
+            (args.vat, "--vat writes a file next to the report"),
+            (
+                args.disposals,
+                "--disposals writes a file next to the report",
+            ),
+            (
+                args.split_residency,
+                "--split-residency writes files next to the report",
+            ),
+        ];
+        if let Some((_, conflict)) = conflicts.iter().find(|(conflicts, _)| *conflicts) {
+            bail!("{conflict}, so it can't be used with --output -");
+        }
+    }
+    if args.vat && config.vat.sales_labels.is_empty() {
+        bail!("--vat needs the sales purposes in `vat.sales_labels` of the config");
     }
-    let cache_path = cache_dir.join(format!("cache-{fingerprint}-{}.json", args.year));
-    let config_path = "config.toml";
+    if args.pool_payouts && config.pool.plot_nfts.is_empty() {
+        bail!("--pool-payouts needs launcher ids in the `pool.plot_nfts` table of the config");
+    }
+    // A report from the cache as it is only holds the lock while reading it, so a watch keeping
+    // the cache synced isn't held up by the rest of the report.
+    let reads_only =
+        args.skip_sync || args.check || args.snapshot.is_some() || read_only::is_read_only();
+    let mut lock = if reads_only {
+        None
+    } else {
+        Some(CacheLock::acquire(&cache_path, args.wait)?)
+    };
 
-    let config = Config::load(config_path)?;
-    let mut cache = Cache::load(cache_path.as_path())?;
+    for name in &args.entity {
+        if !entities.contains(name) {
+            bail!(
+                "Unknown entity `{name}`, expected one of: {}",
+                entities.names().collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
 
-    // Setup January 1st of the year and the next year.
-    let start_date = local_timezone
-        .with_ymd_and_hms(args.year, 1, 1, 0, 0, 0)
-        .unwrap()
-        .timestamp();
-
-    let end_date = local_timezone
-        .with_ymd_and_hms(args.year + 1, 1, 1, 0, 0, 0)
-        .unwrap()
-        .timestamp();
-
-    // Create and load an SSL certificate and connect to the peer.
-    let cert = load_ssl_cert("thyme.crt", "thyme.key")?;
-    let tls_connector = create_tls_connector(&cert)?;
-    let peer = connect_peer(&config.full_node_uri, tls_connector).await?;
-    peer.send_handshake(config.network_id.clone(), NodeType::Wallet)
-        .await?;
+    if args.entity.len() > 1 && args.output.as_deref() == Some(Path::new("-")) {
+        bail!("Only one --entity can be written to --output -");
+    }
 
-    update_cache(&mut cache, cache_path, &config, &peer, &intermediate_pk).await?;
+    if args.consolidate {
+        if args.entity.len() < 2 {
+            bail!("--consolidate needs at least two --entity to combine");
+        }
+        if entities.contains(CONSOLIDATED) {
+            bail!("--consolidate writes a report named `{CONSOLIDATED}`, which is also an entity");
+        }
+    }
 
-    // Do something with the cached and saved coin data.
+    let mut probed = connect(&config).await?;
+    let timings = Timings::default();
 
-    Ok(())
-}
+    // Without --entity there's a single report covering the whole wallet.
+    let address_book = AddressBook::load(address_book_path(&master_pk), &config, &intermediate_pk)?;
+    let labels = address_book.labels();
+    let mut offered = IndexSet::new();
+    for path in &args.offers {
+        let offer = load_offer(path)?;
+        offered.extend(
+            offer
+                .offered_coin_spends()
+                .iter()
+                .map(|coin_spend| coin_spend.coin.coin_id()),
+        );
+    }
+    let evidence = config
+        .evidence
+        .iter()
+        .map(|(coin_id, files)| (Bytes32::from(*coin_id), files.clone()))
+        .collect::<IndexMap<_, _>>();
+    let mut groupers = if args.entity.is_empty() {
+        vec![(None, TransactionGrouper::with_labels(labels))]
+    } else {
+        args.entity
+            .iter()
+            .map(String::as_str)
+            .chain(args.consolidate.then_some(CONSOLIDATED))
+            .map(|name| (Some(name), TransactionGrouper::with_labels(labels.clone())))
+            .collect()
+    };
+    for (_, grouper) in &mut groupers {
+        grouper.set_offered(offered.clone());
+        grouper.set_evidence(evidence.clone());
+    }
 
-async fn update_cache(
-    cache: &mut Cache,
-    cache_path: impl AsRef<Path>,
-    config: &Config,
-    peer: &Peer,
-    intermediate_pk: &PublicKey,
-) -> anyhow::Result<()> {
-    let cache_path = cache_path.as_ref();
-    let mut index = 0;
+    let mut coin_tags = IndexMap::<Bytes32, Vec<String>>::new();
+    let mut derivation_indices = IndexMap::new();
+    let mut cached_timestamps = IndexMap::new();
+    let mut add_window = |index: usize, derivations: &Derivations| {
+        cached_timestamps.extend(&derivations.timestamps);
 
-    loop {
-        println!(
-            "Fetching coin states starting from derivation {}",
-            index * 1000
-        );
-
-        if cache.derivations.len() <= index {
-            let start = index as u32 * 1000;
-            cache.derivations.push(Derivations {
-                previous_height: None,
-                header_hash: config.genesis_challenge,
-                puzzle_hashes: (start..=start + 1000)
-                    .into_par_iter()
-                    .map(|i| {
-                        let pk = intermediate_pk.derive_unhardened(i).derive_synthetic();
-                        StandardArgs::curry_tree_hash(pk).to_bytes()
+        if args.address_reuse {
+            for (offset, puzzle_hash) in derivations.puzzle_hashes.iter().enumerate() {
+                derivation_indices.insert(
+                    Bytes32::from(*puzzle_hash),
+                    index as u32 * WINDOW_SIZE + offset as u32,
+                );
+            }
+        }
+
+        if !tags.is_empty() {
+            for (coin_id, coin_state) in &derivations.coin_states {
+                let names = tags
+                    .tags_of(&config, coin_id, coin_state)
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                if !names.is_empty() {
+                    coin_tags.insert((*coin_id).into(), names);
+                }
+            }
+        }
+
+        for (entity, grouper) in &mut groupers {
+            match entity {
+                None => grouper.add(derivations),
+                // Coins moving between the entities are spent and created at the same height,
+                // so they net out of the combined report.
+                Some(CONSOLIDATED) if args.consolidate => {
+                    grouper.add_matching(derivations, |coin_id, coin_state| {
+                        let entity =
+                            entities.entity_of(&config, index, derivations, coin_id, coin_state);
+                        args.entity.iter().any(|name| name == entity)
                     })
-                    .collect::<Vec<_>>()
-                    .into_iter()
-                    .collect(),
-                coin_states: IndexMap::new(),
-            });
+                }
+                Some(name) => grouper.add_matching(derivations, |coin_id, coin_state| {
+                    entities.entity_of(&config, index, derivations, coin_id, coin_state) == *name
+                }),
+            }
+        }
+    };
 
-            cache.save(cache_path)?;
+    if args.dry_run {
+        let cache = if cache_path.try_exists()? {
+            Cache::load(&cache_path, Some(&intermediate_pk))?
+        } else {
+            Cache::default()
+        };
+        estimate_sync(
+            &cache,
+            &config,
+            &probed,
+            &intermediate_pk,
+            args.start_height,
+            &timings,
+        )
+        .await?;
+        if args.timings {
+            timings.print();
         }
+        return Ok(());
+    }
 
-        let (coin_states, previous_height, previous_header_hash) = fetch_coin_states(
-            peer,
-            config.genesis_challenge.into(),
-            cache.derivations[index].previous_height,
-            cache.derivations[index].header_hash.into(),
-            cache.derivations[index].puzzle_hashes.clone(),
-            config.dust_threshold,
+    // The cache is kept after a sync, to record the timestamps it didn't have yet.
+    let mut synced = None;
+    if reads_only {
+        if !cache_path.try_exists()? {
+            bail!(
+                "No cache at {}, run a report without --skip-sync first",
+                cache_path.display()
+            );
+        }
+
+        // Load one window at a time, since the report only needs amounts and coin ids.
+        lock = Some(CacheLock::acquire_shared(&cache_path, args.wait)?);
+        let started = Instant::now();
+        let mut pending = 0;
+        for (index, derivations) in CacheWindows::open(&cache_path, Some(&intermediate_pk))?
+            .iter()
+            .enumerate()
+        {
+            let derivations = derivations?;
+            if derivations.resync {
+                bail!(
+                    "Window {index} of the cache had coins that couldn't be read, which have to \
+                     be fetched again. Run a report without --skip-sync first."
+                );
+            }
+            pending += derivations.pending.len();
+            add_window(index, &derivations);
+        }
+        timings.record(Phase::CacheIo, started, 0);
+
+        if pending > 0 {
+            eprintln!(
+                "Warning: {pending} coins are missing their parent puzzles, so their assets may be \
+                 wrong. Run a report without --skip-sync to finish the sync."
+            );
+        }
+    } else {
+        let started = Instant::now();
+        let mut cache = Cache::load(cache_path.as_path(), Some(&intermediate_pk))?;
+        timings.record(Phase::CacheIo, started, 0);
+
+        update_cache(
+            &mut cache,
+            &cache_path,
+            &config,
+            &mut probed,
+            &intermediate_pk,
+            args.start_height,
+            &timings,
         )
         .await?;
 
-        let len = coin_states.len();
+        if args.verify_headers || config.verify_headers {
+            verify_headers(&probed, &config, &cache.derivations).await?;
+        }
 
-        for (i, coin_state) in coin_states.into_iter().enumerate() {
-            let parent_puzzle = if cache.derivations[index]
-                .puzzle_hashes
-                .contains(&coin_state.coin.puzzle_hash.to_bytes())
-            {
-                None
-            } else {
-                if let Some(existing) = cache.derivations[index]
-                    .coin_states
-                    .get(&coin_state.coin.coin_id().to_bytes())
-                    .cloned()
-                {
-                    if existing.spent_height == coin_state.spent_height {
-                        println!("Skipping existing coin {}", coin_state.coin.coin_id());
-                        continue;
-                    }
-                }
+        for (index, derivations) in cache.derivations.iter().enumerate() {
+            add_window(index, derivations);
+        }
+        synced = Some(cache);
+    }
 
-                println!(
-                    "Fetching puzzle data for parent coin {} ({}/{})",
-                    coin_state.coin.parent_coin_info, i, len,
-                );
+    let content_hash = report_content_hash(&args, &cache_path, &master_pk, &config)?;
+    // Looking up counterparties reads the cache again, so it has to be the same.
+    if reads_only && !args.counterparties {
+        drop(lock.take());
+    }
 
-                let response: Result<
-                    PuzzleSolutionResponse,
-                    chia::client::Error<RejectPuzzleSolution>,
-                > = peer
-                    .request_puzzle_and_solution(
-                        coin_state.coin.parent_coin_info,
-                        coin_state.created_height.unwrap(),
-                    )
-                    .await;
-
-                match response {
-                    Ok(response) => {
-                        let csr: RespondCoinState = peer
-                            .request_or_reject::<_, RejectCoinState, _>(RequestCoinState {
-                                coin_ids: vec![coin_state.coin.parent_coin_info],
-                                previous_height: None,
-                                header_hash: config.genesis_challenge.into(),
-                                subscribe: false,
-                            })
-                            .await?;
-
-                        let Some(parent_coin_state) = csr.coin_states.into_iter().next() else {
-                            bail!(
-                                "Parent coin state not found with id {}",
-                                coin_state.coin.parent_coin_info
-                            );
-                        };
-
-                        let mut allocator = Allocator::new();
-                        let puzzle_ptr = response.puzzle.to_clvm(&mut allocator)?;
-                        let parent_puzzle = Puzzle::parse(&allocator, puzzle_ptr);
-                        let parent_solution = response.solution.to_clvm(&mut allocator)?;
-
-                        Cat::from_parent_spend(
-                            &mut allocator,
-                            parent_coin_state.coin,
-                            parent_puzzle,
-                            parent_solution,
-                            coin_state.coin,
-                        )
-                        .ok()
-                        .flatten()
-                        .map(|cat| PuzzleInfo::Cat(cat.into()))
-                    }
-                    Err(chia::client::Error::Rejection(_rejection)) => None,
-                    Err(error) => {
-                        return Err(error.into());
-                    }
-                }
-            };
+    let peer = probed.peer;
+
+    for (_, grouper) in &mut groupers {
+        grouper.set_max_fee(config.self_spends.max_fee);
+        if !coin_tags.is_empty() {
+            grouper.set_tags(coin_tags.clone());
+        }
+    }
+
+    // Resolve the date of every height with activity, so we can filter by the tax year.
+    let heights = groupers
+        .iter()
+        .flat_map(|(_, grouper)| grouper.heights())
+        .collect::<IndexSet<_>>();
+
+    let (cached, missing): (IndexSet<_>, IndexSet<_>) = heights
+        .into_iter()
+        .partition(|height| cached_timestamps.contains_key(height));
 
-            cache.derivations[index].coin_states.insert(
-                coin_state.coin.coin_id().into(),
-                CoinStateJson {
-                    coin: coin_state.coin.into(),
-                    parent_puzzle,
-                    created_height: coin_state.created_height,
-                    spent_height: coin_state.spent_height,
-                },
+    eprintln!(
+        "Resolving timestamps for {} heights, {} were cached",
+        missing.len(),
+        cached.len()
+    );
+
+    let started = Instant::now();
+    let height_count = missing.len() as u64;
+    let resolved = resolve_timestamps(&peer, missing, config.concurrency, &config.retry).await?;
+    timings.record(Phase::Timestamps, started, height_count);
+
+    if let Some(cache) = &mut synced {
+        for index in 0..cache.derivations.len() {
+            if cache.derivations[index].record_timestamps(&resolved) {
+                save_window(cache, &cache_path, index, &timings)?;
+            }
+        }
+    }
+    drop(synced);
+
+    let mut timestamps = cached
+        .into_iter()
+        .filter_map(|height| Some((height, *cached_timestamps.get(&height)?)))
+        .collect::<IndexMap<_, _>>();
+    timestamps.extend(resolved);
+
+    if args.counterparties {
+        let year_range = year_bounds(args.wallet.year);
+        let coins = incoming_coins(&CacheWindows::open(&cache_path, Some(&intermediate_pk))?)?
+            .into_iter()
+            .filter(|(_, coin_state)| {
+                coin_state
+                    .created_height
+                    .and_then(|height| timestamps.get(&height))
+                    .is_some_and(|timestamp| year_range.contains(&(*timestamp as i64)))
+            })
+            .collect::<Vec<_>>();
+
+        eprintln!("Looking up senders for {} received coins", coins.len());
+
+        let senders = coin_senders(
+            &peer,
+            config.genesis_challenge.into(),
+            &coins,
+            config.concurrency,
+            &config.retry,
+            &timings,
+        )
+        .await?;
+        let clusters = Clusters::from_senders(&config, &senders)?;
+        clusters.save(clusters_path(&master_pk))?;
+
+        let names = clusters.names(&address_book.counterparties)?;
+        let counterparties = senders
+            .coins
+            .iter()
+            .filter_map(|(coin_id, sender)| Some(((*coin_id).into(), names.get(sender)?.clone())))
+            .collect::<IndexMap<_, _>>();
+
+        eprintln!(
+            "Found {} senders in {} clusters",
+            names.len(),
+            clusters.clusters.len()
+        );
+
+        // An exchange's own export lists the same withdrawals, so they're flagged for matching.
+        let withdrawals = counterparties
+            .iter()
+            .filter(|(_, name)| config.exchanges.contains(name))
+            .map(|(coin_id, name)| (*coin_id, vec![format!("withdrawal from {name}")]))
+            .collect::<IndexMap<_, _>>();
+        let mut per_exchange = IndexMap::<&str, usize>::new();
+        for tag in withdrawals.values().flatten() {
+            *per_exchange.entry(tag).or_default() += 1;
+        }
+        for (tag, coins) in per_exchange {
+            eprintln!(
+                "Tagged {coins} coins as {tag}, which should match withdrawals in the exchange's export"
             );
-            cache.save(cache_path)?;
         }
 
-        cache.derivations[index].previous_height = Some(previous_height);
-        cache.derivations[index].header_hash = previous_header_hash.into();
-        cache.save(cache_path)?;
+        let mut matched = IndexMap::new();
+        if !args.exchange_export.is_empty() {
+            let mut exported = Vec::new();
+            for path in &args.exchange_export {
+                exported.extend(load_exchange_export(path, &config.exchange_export)?);
+            }
 
-        if cache.derivations[index].coin_states.is_empty() {
-            break;
+            let coins = coins
+                .iter()
+                .filter(|(coin_id, _)| withdrawals.contains_key(&Bytes32::from(*coin_id)))
+                .filter_map(|(coin_id, coin_state)| {
+                    let height = coin_state.created_height?;
+                    Some((coin_id, coin_state, height, *timestamps.get(&height)?))
+                });
+            let mut coming = Vec::new();
+            for (coin_id, coin_state, height, timestamp) in coins {
+                let Some(asset) = coin_state.asset() else {
+                    continue;
+                };
+                coming.push(Withdrawal {
+                    coin_id: (*coin_id).into(),
+                    asset,
+                    amount: coin_state.coin.amount,
+                    height,
+                    timestamp,
+                    address: address(&config, coin_state.p2_puzzle_hash())?,
+                    exchange: counterparties[&Bytes32::from(*coin_id)].clone(),
+                });
+            }
+
+            let matches =
+                match_withdrawals(coming, &exported, config.exchange_export.window_minutes);
+            eprintln!(
+                "Matched {} withdrawals to the exchange exports, which list {} more",
+                matches.matched.len(),
+                matches.unclaimed
+            );
+            for withdrawal in &matches.unmatched {
+                eprintln!(
+                    "No withdrawal from {} in the exports matches {} {} at height {}, coin {}",
+                    withdrawal.exchange,
+                    withdrawal.asset.format_amount(withdrawal.amount),
+                    withdrawal.asset.name(&config),
+                    withdrawal.height,
+                    withdrawal.coin_id
+                );
+            }
+            matched = matches
+                .matched
+                .into_iter()
+                .map(|(withdrawal, row)| {
+                    (
+                        withdrawal.coin_id,
+                        vec![format!("{} line {}", row.file, row.line)],
+                    )
+                })
+                .collect();
         }
 
-        index += 1;
+        for (_, grouper) in &mut groupers {
+            grouper.set_counterparties(counterparties.clone());
+            grouper.add_tags(&withdrawals);
+            grouper.add_evidence(&matched);
+        }
     }
 
-    Ok(())
-}
+    if args.pool_payouts {
+        let mut histories = IndexMap::new();
+        for (name, launcher_id) in &config.pool.plot_nfts {
+            eprintln!("Looking up the pool history of plot NFT {name}");
+            let periods = pool_history(&peer, (*launcher_id).into(), &config).await?;
+            histories.insert(name.clone(), periods);
+        }
+        print_pool_history(&histories, &config)?;
 
-fn parse_pk(pk: &str) -> anyhow::Result<PublicKey> {
-    let trimmed = pk.trim();
-    let stripped = if let Some(after) = trimmed.strip_prefix("0x") {
-        after
-    } else {
-        trimmed
+        for (_, grouper) in &mut groupers {
+            let tags = payout_tags(grouper.transactions(&timestamps), &histories, &config)?;
+            eprintln!("Tagged {} payout coins with their pool period", tags.len());
+            grouper.add_tags(&tags);
+        }
+    }
+
+    let context = ReportContext {
+        args: &args,
+        config: &config,
+        peer: &peer,
+        timestamps: &timestamps,
+        timings: &timings,
+        fingerprint: master_pk.get_fingerprint(),
+        derivation_indices: &derivation_indices,
+        content_hash: &content_hash,
     };
-    let bytes = hex::decode(stripped)?;
-    let array = bytes
-        .try_into()
-        .map_err(|_| anyhow!("Public key is not 48 bytes long"))?;
-    Ok(PublicKey::from_bytes(&array)?)
+
+    let mut summaries = IndexMap::new();
+    for (entity, grouper) in &mut groupers {
+        if let Some(summary) = entity_report(&context, *entity, grouper).await? {
+            summaries.insert(entity.unwrap_or_default().to_string(), summary);
+        }
+    }
+
+    // Reports skipped by --check don't have figures to compare.
+    if args.consolidate && summaries.len() == groupers.len() {
+        if let Some(consolidated) = summaries.shift_remove(CONSOLIDATED) {
+            print_consolidation(&summaries, &consolidated, &config);
+        }
+    }
+
+    if args.timings {
+        timings.print();
+    }
+
+    Ok(())
+}
+
+/// Everything shared by the reports written from a single sync.
+struct ReportContext<'a> {
+    args: &'a ReportArgs,
+    config: &'a Config,
+    peer: &'a Peer,
+    timestamps: &'a IndexMap<u32, u64>,
+    timings: &'a Timings,
+    fingerprint: u32,
+    /// The derivation of each puzzle hash, which is only filled in for --address-reuse.
+    derivation_indices: &'a IndexMap<Bytes32, u32>,
+    /// What the reports are generated from, which is recorded next to each one for --check.
+    content_hash: &'a str,
+}
+
+/// Writes the report for one entity, or the whole wallet if there's no entity, returning the
+/// entity's summary of the year.
+async fn entity_report(
+    context: &ReportContext<'_>,
+    entity: Option<&str>,
+    grouper: &mut TransactionGrouper,
+) -> anyhow::Result<Option<YearSummary>> {
+    let ReportContext {
+        args,
+        config,
+        peer,
+        timestamps,
+        timings,
+        fingerprint,
+        derivation_indices,
+        content_hash,
+    } = *context;
+
+    let report_path = match &args.output {
+        Some(output) => output.clone(),
+        None => {
+            let mut file_name = report_file_name(
+                &args.name_template,
+                fingerprint,
+                args.wallet.year,
+                entity,
+                &config.currency,
+                args.format,
+            )?;
+            if let Some(compression) = args.compress {
+                file_name = format!("{file_name}.{}", compression.extension());
+            }
+            args.output_dir.join(file_name)
+        }
+    };
+    let is_stdout = report_path.as_os_str() == "-";
+
+    // Each entity needs its own file, so its name is added unless the template already has it.
+    let report_path = match entity {
+        Some(entity)
+            if !is_stdout
+                && (args.output.is_some() || !args.name_template.contains("{entity}")) =>
+        {
+            suffixed_report_path(&report_path, entity)
+        }
+        _ => report_path,
+    };
+
+    if args.check && !is_stdout {
+        let recorded = fs::read_to_string(fingerprint_path(&report_path)).unwrap_or_default();
+        if recorded.trim() == content_hash {
+            eprintln!("{} is up to date with the cache", report_path.display());
+            return Ok(None);
+        }
+        eprintln!("{} is out of date, writing it again", report_path.display());
+    }
+
+    if let Some(entity) = entity {
+        eprintln!("Reporting on entity {entity}");
+    }
+
+    // Setup January 1st of the year and the next year.
+    let year_range = year_bounds(args.wallet.year);
+
+    let mut years = args.compare.clone();
+    years.push(args.wallet.year);
+    years.sort();
+    years.dedup();
+
+    if args.capitalize_mints {
+        // Only look for mints in the years being reported, since every spent coin costs a request.
+        let bounds = year_bounds(years[0]).start..year_bounds(years[years.len() - 1]).end;
+        let spent_coins = grouper
+            .net_sends(Asset::Xch)
+            .filter(|(height, _)| {
+                timestamps
+                    .get(height)
+                    .is_some_and(|timestamp| bounds.contains(&(*timestamp as i64)))
+            })
+            .flat_map(|(height, coin_ids)| coin_ids.iter().map(move |coin_id| (height, *coin_id)))
+            .collect::<Vec<_>>();
+
+        eprintln!("Checking {} spent coins for mints", spent_coins.len());
+
+        let mints = detect_mints(
+            peer,
+            spent_coins,
+            config.concurrency,
+            &config.retry,
+            timings,
+        )
+        .await?;
+        grouper.add_mints(&mints);
+    }
+
+    if !args.compare.is_empty() {
+        let summaries = compare_years(&years, grouper, timestamps, config, timings).await?;
+        if let Some(entity) = entity {
+            println!("Entity: {entity}");
+        }
+        print_comparison(&summaries, config);
+
+        return Ok(None);
+    }
+
+    if let Some(as_of) = args.as_of {
+        if let Some(entity) = entity {
+            println!("Entity: {entity}");
+        }
+        print_snapshot(as_of, grouper, timestamps, config, timings).await?;
+        return Ok(None);
+    }
+
+    if args.wealth_tax {
+        if let Some(entity) = entity {
+            println!("Entity: {entity}");
+        }
+        print_wealth_tax(args.wallet.year, grouper, timestamps, config, timings).await?;
+        return Ok(None);
+    }
+
+    let in_year = |tx: &Transaction| year_range.contains(&(tx.timestamp as i64));
+
+    eprintln!("Pricing transactions");
+
+    let started = Instant::now();
+    let price_cache_path = PathBuf::from("cache").join(PRICE_CACHE_FILE);
+    let prices = PriceProvider::new(config, PriceCache::load(&price_cache_path)?);
+    let transactions = grouper.transactions(timestamps).filter(in_year);
+    let mut daily_prices = daily_prices(transactions, &prices, config).await;
+    if let (true, Ok(known)) = (args.nft_collections, &mut daily_prices) {
+        let keys = nft::price_keys(grouper.transactions(timestamps), config)
+            .into_iter()
+            .filter(|key| !known.contains_key(key))
+            .collect();
+        match fetch_prices(keys, &prices, config.concurrency).await {
+            Ok(more) => known.extend(more),
+            Err(error) => daily_prices = Err(error),
+        }
+    }
+    let prices_lots = !args.compare_methods.is_empty()
+        || args.disposals
+        || args.carry_losses
+        || args.estimated_tax;
+    if let (true, Ok(known)) = (prices_lots, &mut daily_prices) {
+        let keys = grouper
+            .transactions(timestamps)
+            .filter(|tx| tx.kind == TransactionKind::Receive)
+            .filter(|tx| (tx.timestamp as i64) < year_range.end)
+            .filter_map(|tx| tx.price_key(config))
+            .filter(|key| !known.contains_key(key))
+            .collect();
+        match fetch_prices(keys, &prices, config.concurrency).await {
+            Ok(more) => known.extend(more),
+            Err(error) => daily_prices = Err(error),
+        }
+    }
+    prices.save_cache(price_cache_path)?;
+    let daily_prices = daily_prices?;
+    timings.record(Phase::Pricing, started, prices.requests());
+
+    let started = Instant::now();
+    if args.output.is_none() {
+        fs::create_dir_all(&args.output_dir)?;
+    }
+    let mut writer = open_report(&report_path, args.format, args.compress, config)?;
+    if args.per_asset {
+        let (report_path, format, compress) = (report_path.clone(), args.format, args.compress);
+        let config = config.clone();
+        writer = Box::new(SplitReportWriter::new(writer, move |asset| {
+            open_report(
+                &suffixed_report_path(&report_path, asset),
+                format,
+                compress,
+                &config,
+            )
+        }));
+    }
+    let count = write_report(
+        collapse_self_spends(
+            grouper.transactions(timestamps).filter(in_year),
+            args.collapse_self_spends,
+        ),
+        &daily_prices,
+        prices.currency(),
+        config,
+        writer.as_mut(),
+    )?;
+    timings.record(Phase::Export, started, 0);
+
+    if is_stdout {
+        eprintln!("Wrote {count} transactions to stdout");
+    } else {
+        eprintln!("Wrote {count} transactions to {}", report_path.display());
+    }
+
+    if args.split_residency {
+        write_residency_reports(
+            args,
+            &report_path,
+            grouper,
+            timestamps,
+            year_range.clone(),
+            config,
+        )
+        .await?;
+    }
+
+    if args.collapse_self_spends {
+        let (spends, fees, value) = grouper
+            .transactions(timestamps)
+            .filter(|tx| in_year(tx) && tx.kind == TransactionKind::Fee)
+            .fold((0, 0, 0.0), |(spends, fees, value), tx| {
+                let price = tx.price(&daily_prices, config).unwrap_or_default();
+                (
+                    spends + 1,
+                    fees + tx.amount,
+                    value + price * tx.asset.display_amount(tx.amount),
+                )
+            });
+        eprintln!(
+            "Maintenance: {spends} self-spends, {} XCH in fees, {value:.2} {}",
+            Asset::Xch.format_amount(fees),
+            prices.currency().to_uppercase()
+        );
+    }
+
+    let summary = entity.map(|entity| {
+        let summary = summarize_year(
+            args.wallet.year,
+            year_range.clone(),
+            grouper.transactions(timestamps),
+            IndexMap::new(),
+            &daily_prices,
+            config,
+        );
+        let currency = prices.currency().to_uppercase();
+        eprintln!(
+            "{entity}: {} receives, {} sends, {:.2} {currency} income, {:.2} {currency} proceeds, \
+             {:.2} {currency} fees, {} unpriced",
+            summary.receives,
+            summary.sends,
+            summary.income,
+            summary.proceeds,
+            summary.fees,
+            summary.unpriced
+        );
+        summary
+    });
+
+    if let Some(group_by) = args.group_by {
+        let summaries = summarize_groups(
+            args.wallet.year,
+            year_range.clone(),
+            grouper.transactions(timestamps),
+            group_by,
+            &daily_prices,
+            config,
+        );
+        if let Some(entity) = entity {
+            println!("Entity: {entity}");
+        }
+        print_groups(group_by, &summaries, config);
+    }
+
+    if args.estimated_tax {
+        // Losses are only carried in from the years recorded by earlier reports with
+        // --carry-losses, and the year itself isn't recorded until it's over.
+        let (carried_in, _) =
+            Carryforward::load(carryforward_path(fingerprint, prices.currency()))?.carried(
+                entity.unwrap_or_default(),
+                args.wallet.year,
+                config.loss_carryforward.deduction_limit,
+            );
+        let installments = installments(
+            args.wallet.year,
+            year_range.clone(),
+            grouper.transactions(timestamps),
+            carried_in,
+            &daily_prices,
+            config,
+        )?;
+        if let Some(entity) = entity {
+            println!("Entity: {entity}");
+        }
+        print_installments(&installments, config);
+    }
+
+    if !args.compare_methods.is_empty() {
+        let methods = args
+            .compare_methods
+            .iter()
+            .copied()
+            .collect::<IndexSet<_>>()
+            .into_iter()
+            .map(|method| {
+                let gains = realized_gains(
+                    grouper.transactions(timestamps),
+                    year_range.clone(),
+                    method,
+                    &daily_prices,
+                    config,
+                );
+                (method, gains)
+            })
+            .collect::<Vec<_>>();
+        if let Some(entity) = entity {
+            println!("Entity: {entity}");
+        }
+        print_method_comparison(&methods, config);
+    }
+
+    if args.nft_collections {
+        let collection_cache_path = PathBuf::from("cache").join(COLLECTION_CACHE_FILE);
+        let mut collections = CollectionCache::load(&collection_cache_path)?;
+        let resolved = resolve_collections(
+            grouper.nft_metadata_uris(),
+            &mut collections,
+            config.concurrency,
+            timings,
+        )
+        .await;
+        collections.save(&collection_cache_path)?;
+        resolved?;
+
+        let market = market_sales(
+            grouper.transactions(timestamps),
+            &collections,
+            config,
+            timings,
+        )
+        .await?;
+        let trades = nft_trades(
+            grouper.transactions(timestamps),
+            &daily_prices,
+            &collections,
+            &market,
+            config,
+        );
+        let rows = collection_rows(&trades, &collections, year_range.clone(), prices.currency());
+        let path = companion_report_path(&report_path, "nft-collections");
+        write_collection_report(&path, &rows)?;
+
+        eprintln!("Wrote {} NFT collections to {}", rows.len(), path.display());
+
+        if !config.nft_valuations.is_empty() {
+            let rows = valuation_rows(&trades, &collections, year_range.clone(), prices.currency());
+            let path = companion_report_path(&report_path, "nft-valuations");
+            write_valuation_report(&path, &rows)?;
+
+            eprintln!("Wrote {} appraised NFTs to {}", rows.len(), path.display());
+        }
+    }
+
+    if args.address_reuse {
+        let rows = reuse_rows(grouper, derivation_indices, timestamps, config)?;
+        let path = companion_report_path(&report_path, "address-reuse");
+        write_reuse_report(&path, &rows)?;
+
+        eprintln!(
+            "Wrote {} reused addresses to {}",
+            rows.len(),
+            path.display()
+        );
+    }
+
+    if args.vat {
+        let rows = vat_rows(
+            args.wallet.year,
+            grouper.transactions(timestamps),
+            &daily_prices,
+            prices.currency(),
+            config,
+        );
+        let path = companion_report_path(&report_path, "vat");
+        write_vat_report(&path, &rows)?;
+
+        let (sales, gross, vat, unpriced) = rows.iter().fold((0, 0.0, 0.0, 0), |totals, row| {
+            (
+                totals.0 + row.sales,
+                totals.1 + row.gross,
+                totals.2 + row.vat,
+                totals.3 + row.unpriced,
+            )
+        });
+        let currency = prices.currency().to_uppercase();
+        eprintln!(
+            "Wrote {} VAT periods to {}: {sales} sales, {gross:.2} {currency} gross, \
+             {vat:.2} {currency} VAT, {unpriced} unpriced",
+            rows.len(),
+            path.display()
+        );
+    }
+
+    if args.carry_losses {
+        let method = config.lot_method();
+        let gain = realized_gains(
+            grouper.transactions(timestamps),
+            year_range.clone(),
+            method,
+            &daily_prices,
+            config,
+        )
+        .values()
+        .map(|gains| gains.gain)
+        .sum::<f64>();
+
+        let path = carryforward_path(fingerprint, prices.currency());
+        let mut carryforward = Carryforward::load(&path)?;
+        let entity_name = entity.unwrap_or_default();
+        carryforward.record(entity_name, args.wallet.year, gain);
+        carryforward.save(&path)?;
+
+        let (carried_in, carried_out) = carryforward.carried(
+            entity_name,
+            args.wallet.year,
+            config.loss_carryforward.deduction_limit,
+        );
+        let currency = prices.currency().to_uppercase();
+        eprintln!(
+            "Realized {gain:.2} {currency} with {} lots, {:.2} {currency} after {carried_in:.2} \
+             {currency} of losses carried forward, carrying {carried_out:.2} {currency} into {}",
+            method.name(),
+            gain - carried_in,
+            args.wallet.year + 1
+        );
+    }
+
+    if args.disposals {
+        let disposals = disposals(
+            grouper.transactions(timestamps),
+            year_range,
+            config.lot_method(),
+            &daily_prices,
+            config,
+        );
+        let rows = disposal_rows(&disposals, prices.currency(), config);
+        let path = companion_report_path(&report_path, "disposals");
+        write_disposal_report(&path, &rows)?;
+
+        let gain = disposals.iter().map(|disposal| disposal.gain).sum::<f64>();
+        eprintln!(
+            "Wrote {} disposals to {}, realizing {gain:.2} {}",
+            rows.len(),
+            path.display(),
+            prices.currency().to_uppercase()
+        );
+    }
+
+    // Only once everything is written, so a report that failed partway is written again.
+    if !is_stdout {
+        fs::write(fingerprint_path(&report_path), content_hash)?;
+    }
+
+    Ok(summary)
+}
+
+/// Writes the part of the year lived in each residency as a report of its own, with the
+/// currency and lot method the residency overrides. Every receive before the part ends is
+/// priced in its currency, for the basis of the lots its gains are taken out of.
+async fn write_residency_reports(
+    args: &ReportArgs,
+    report_path: &Path,
+    grouper: &mut TransactionGrouper,
+    timestamps: &IndexMap<u32, u64>,
+    year_range: Range<i64>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if config.residencies.is_empty() {
+        bail!("--split-residency needs `residencies` in the config");
+    }
+
+    let mut residencies = config
+        .residencies
+        .iter()
+        .filter_map(|(name, residency)| Some((residency.start_date()?, name, residency)))
+        .collect::<Vec<_>>();
+    residencies.sort_by_key(|(from, _, _)| *from);
+
+    let day_start = |date: NaiveDate| day_end(date.pred_opt().unwrap_or(date));
+    let price_cache_path = PathBuf::from("cache").join(PRICE_CACHE_FILE);
+
+    for (index, (from, name, residency)) in residencies.iter().enumerate() {
+        // The earliest residency also covers the time before it, since there's nothing else.
+        let start = if index == 0 {
+            year_range.start
+        } else {
+            day_start(*from)?.max(year_range.start)
+        };
+        let end = match residencies.get(index + 1) {
+            Some((next, _, _)) => day_start(*next)?,
+            None => year_range.end,
+        }
+        .min(year_range.end);
+        if start >= end {
+            continue;
+        }
+        let bounds = start..end;
+
+        let mut config = config.clone();
+        if let Some(currency) = &residency.currency {
+            config.currency = currency.to_lowercase();
+        }
+        let method = config
+            .cost_basis
+            .or(residency.lot_method)
+            .unwrap_or(config.lot_method);
+
+        let keys = grouper
+            .transactions(timestamps)
+            .filter(|tx| {
+                let timestamp = tx.timestamp as i64;
+                bounds.contains(&timestamp)
+                    || (tx.kind == TransactionKind::Receive && timestamp < bounds.end)
+            })
+            .filter_map(|tx| tx.price_key(&config))
+            .collect();
+        let prices = PriceProvider::new(&config, PriceCache::load(&price_cache_path)?);
+        let daily_prices = fetch_prices(keys, &prices, config.concurrency).await;
+        prices.save_cache(&price_cache_path)?;
+        let daily_prices = daily_prices?;
+
+        let path = suffixed_report_path(report_path, name);
+        let mut writer = open_report(&path, args.format, args.compress, &config)?;
+        let count = write_report(
+            collapse_self_spends(
+                grouper
+                    .transactions(timestamps)
+                    .filter(|tx| bounds.contains(&(tx.timestamp as i64))),
+                args.collapse_self_spends,
+            ),
+            &daily_prices,
+            prices.currency(),
+            &config,
+            writer.as_mut(),
+        )?;
+
+        let summary = summarize_year(
+            args.wallet.year,
+            bounds.clone(),
+            grouper.transactions(timestamps),
+            IndexMap::new(),
+            &daily_prices,
+            &config,
+        );
+        let gain = realized_gains(
+            grouper.transactions(timestamps),
+            bounds.clone(),
+            method,
+            &daily_prices,
+            &config,
+        )
+        .values()
+        .map(|gains| gains.gain)
+        .sum::<f64>();
+
+        let currency = prices.currency().to_uppercase();
+        let last_day = Local
+            .timestamp_opt(end - 1, 0)
+            .single()
+            .map(|date| date.date_naive().to_string())
+            .unwrap_or_default();
+        let first_day = Local
+            .timestamp_opt(start, 0)
+            .single()
+            .map(|date| date.date_naive().to_string())
+            .unwrap_or_default();
+        eprintln!(
+            "Wrote {count} transactions in {name} from {first_day} to {last_day} to {}: \
+             {:.2} {currency} income, {gain:.2} {currency} realized with {} lots",
+            path.display(),
+            summary.income,
+            method.name()
+        );
+    }
+
+    Ok(())
+}
+
+/// Where the content hash a report was written from is recorded, next to the report.
+fn fingerprint_path(report_path: &Path) -> PathBuf {
+    let mut file_name = report_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".fingerprint");
+    report_path.with_file_name(file_name)
+}
+
+/// A hash of everything a report is generated from: the cache as it's stored, the config,
+/// the address book, the arguments other than --check with the cost basis, and the version of
+/// thyme.
+fn report_content_hash(
+    args: &ReportArgs,
+    cache_path: &Path,
+    master_pk: &PublicKey,
+    config: &Config,
+) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    CacheWindows::open(cache_path, None)?.content_hash(&mut hasher)?;
+    hasher.update(fs::read(CONFIG_PATH).unwrap_or_default());
+    hasher.update(fs::read(address_book_path(master_pk)).unwrap_or_default());
+
+    let args = ReportArgs {
+        check: false,
+        ..args.clone()
+    };
+    hasher.update(format!("{args:?}{:?}", config.cost_basis));
+    hasher.update(env!("CARGO_PKG_VERSION"));
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Opens a report writer for the path, where `-` is stdout.
+fn open_report(
+    path: &Path,
+    format: ReportFormat,
+    compress: Option<ReportCompression>,
+    config: &Config,
+) -> anyhow::Result<Box<dyn ReportWriter>> {
+    let output: Box<dyn Write> = if path.as_os_str() == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(path)?)
+    };
+    let output = match compress {
+        Some(compression) => compression.wrap(output)?,
+        None => ReportOutput::plain(output),
+    };
+    format.create_writer(output, config)
+}
+
+/// The report's path with a suffix, such as an asset or entity name, inserted before the extensions.
+fn suffixed_report_path(report_path: &Path, suffix: &str) -> PathBuf {
+    let file_name = report_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (stem, extensions) = file_name.split_at(file_name.find('.').unwrap_or(file_name.len()));
+    let suffix = suffix
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    report_path.with_file_name(format!("{stem}-{suffix}{extensions}"))
+}
+
+/// The path of a report written next to the report, like the NFT collections, which is always
+/// CSV regardless of the report's format.
+fn companion_report_path(report_path: &Path, name: &str) -> PathBuf {
+    let file_name = report_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem = &file_name[..file_name.find('.').unwrap_or(file_name.len())];
+    report_path.with_file_name(format!("{stem}-{name}.csv"))
+}
+
+/// The timestamps from January 1st of the year up to, but excluding, January 1st of the next year.
+fn year_bounds(year: i32) -> Range<i64> {
+    let local_timezone = Local::now().timezone();
+    let start = local_timezone
+        .with_ymd_and_hms(year, 1, 1, 0, 0, 0)
+        .unwrap();
+    let end = local_timezone
+        .with_ymd_and_hms(year + 1, 1, 1, 0, 0, 0)
+        .unwrap();
+    start.timestamp()..end.timestamp()
+}
+
+/// Prints the holdings of the year valued the way the wealth tax config says. Averages are
+/// taken over every day of the year so far, and a day without a price uses the one before it.
+async fn print_wealth_tax(
+    year: i32,
+    grouper: &mut TransactionGrouper,
+    timestamps: &IndexMap<u32, u64>,
+    config: &Config,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let valuation = config
+        .wealth_tax
+        .valuation()
+        .map_err(|error| anyhow!(error))?;
+
+    eprintln!("Pricing holdings");
+
+    let started = Instant::now();
+    let price_cache_path = PathBuf::from("cache").join(PRICE_CACHE_FILE);
+    let prices = PriceProvider::new(config, PriceCache::load(&price_cache_path)?);
+
+    let rows = match valuation {
+        Valuation::Day { .. } => {
+            let Some(date) = valuation.date(year) else {
+                bail!(
+                    "`wealth_tax.valuation` {} isn't a day in {year}",
+                    config.wealth_tax.valuation
+                );
+            };
+            println!("Holdings on {date}");
+
+            let holdings = holdings_at(grouper.transactions(timestamps), day_end(date)?);
+            let keys = holdings
+                .keys()
+                .filter_map(|asset| Some((asset.coingecko_id(config)?, date)))
+                .collect();
+            let daily_prices = fetch_prices(keys, &prices, config.concurrency).await;
+            prices.save_cache(&price_cache_path)?;
+            let daily_prices = daily_prices?;
+
+            holdings
+                .into_iter()
+                .map(|(asset, amount)| {
+                    let value = asset
+                        .coingecko_id(config)
+                        .and_then(|id| daily_prices.get(&(id, date)))
+                        .map(|price| price * asset.display_amount(amount));
+                    (asset, asset.display_amount(amount), value)
+                })
+                .collect::<Vec<_>>()
+        }
+        Valuation::Average => {
+            let today = Local::now().date_naive();
+            let dates = NaiveDate::from_ymd_opt(year, 1, 1)
+                .unwrap()
+                .iter_days()
+                .take_while(|date| date.year() == year && *date <= today)
+                .collect::<Vec<_>>();
+            let (Some(first), Some(last)) = (dates.first().copied(), dates.last().copied()) else {
+                bail!("{year} hasn't started yet");
+            };
+            println!(
+                "Average holdings over the {} days from {first} to {last}",
+                dates.len()
+            );
+
+            let mut rows = Vec::new();
+            for (asset, series) in daily_balances(grouper.transactions(timestamps), &dates)? {
+                let days = dates.len() as f64;
+                let average = series
+                    .iter()
+                    .map(|balance| asset.display_amount(*balance))
+                    .sum::<f64>()
+                    / days;
+
+                let value = match asset.coingecko_id(config) {
+                    Some(id) => {
+                        let range = prices.price_range(&id, first, last).await;
+                        prices.save_cache(&price_cache_path)?;
+                        let range = range?;
+
+                        let mut price = None;
+                        let mut total = 0.0;
+                        for (date, balance) in dates.iter().zip(&series) {
+                            price = range.get(date).copied().or(price);
+                            total += price.unwrap_or_default() * asset.display_amount(*balance);
+                        }
+                        price.map(|_| total / days)
+                    }
+                    None => None,
+                };
+
+                rows.push((asset, average, value));
+            }
+            rows
+        }
+    };
+    timings.record(Phase::Pricing, started, prices.requests());
+
+    let currency = config.currency.to_uppercase();
+    println!(
+        "{:<16} {:>24} {:>16}",
+        "Asset",
+        "Amount",
+        format!("Value ({currency})")
+    );
+    let mut total = 0.0;
+    for (asset, amount, value) in rows {
+        total += value.unwrap_or_default();
+        println!(
+            "{:<16} {:>24.precision$} {:>16}",
+            asset.name(config),
+            amount,
+            value.map_or("unpriced".to_string(), |value| format!("{value:.2}")),
+            precision = asset.precision() as usize
+        );
+    }
+    println!("{:<16} {:>24} {total:>16.2}", "Total", "");
+
+    Ok(())
+}
+
+/// Prints the holdings at the end of the date, valued at the day's prices, along with the
+/// open lots they're made up of and what each was worth when it was received.
+async fn print_snapshot(
+    as_of: NaiveDate,
+    grouper: &mut TransactionGrouper,
+    timestamps: &IndexMap<u32, u64>,
+    config: &Config,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let end = day_end(as_of)?;
+    let holdings = holdings_at(grouper.transactions(timestamps), end);
+
+    eprintln!("Pricing transactions");
+
+    // Price the receives the lots could come from, and every holding on the day.
+    let mut keys = grouper
+        .transactions(timestamps)
+        .filter(|tx| tx.kind == TransactionKind::Receive && (tx.timestamp as i64) < end)
+        .filter_map(|tx| tx.price_key(config))
+        .collect::<IndexSet<_>>();
+    keys.extend(
+        holdings
+            .keys()
+            .filter_map(|asset| Some((asset.coingecko_id(config)?, as_of))),
+    );
+
+    let started = Instant::now();
+    let price_cache_path = PathBuf::from("cache").join(PRICE_CACHE_FILE);
+    let prices = PriceProvider::new(config, PriceCache::load(&price_cache_path)?);
+    let daily_prices = fetch_prices(keys, &prices, config.concurrency).await;
+    prices.save_cache(price_cache_path)?;
+    let daily_prices = daily_prices?;
+    timings.record(Phase::Pricing, started, prices.requests());
+
+    let method = config.lot_method();
+    let mut tracker = LotTracker::new(method);
+    for tx in grouper
+        .transactions(timestamps)
+        .filter(|tx| (tx.timestamp as i64) < end)
+    {
+        let value = tx
+            .price(&daily_prices, config)
+            .map(|price| price * tx.asset.display_amount(tx.amount));
+        tracker.apply(&tx, value);
+    }
+    let currency = config.currency.to_uppercase();
+    let format_value =
+        |value: Option<f64>| value.map_or("unpriced".to_string(), |v| format!("{v:.2}"));
+
+    println!("Holdings at the end of {as_of}");
+    println!(
+        "{:<16} {:>24} {:>16}",
+        "Asset",
+        "Amount",
+        format!("Value ({currency})")
+    );
+    let mut total = 0.0;
+    for (asset, amount) in &holdings {
+        let value = asset
+            .coingecko_id(config)
+            .and_then(|id| daily_prices.get(&(id, as_of)))
+            .map(|price| price * asset.display_amount(*amount));
+        total += value.unwrap_or_default();
+        println!(
+            "{:<16} {:>24} {:>16}",
+            asset.name(config),
+            asset.format_amount(*amount),
+            format_value(value)
+        );
+    }
+    println!("{:<16} {:>24} {total:>16.2}", "Total", "");
+
+    let committed = grouper.committed_at(timestamps, end).collect::<Vec<_>>();
+    if !committed.is_empty() {
+        println!();
+        println!("Committed");
+        println!("{:<16} {:>24} {:<30} Coin", "Asset", "Amount", "Reason");
+        let mut spendable = holdings.clone();
+        for coin in &committed {
+            let reason = match coin.commitment {
+                Commitment::Offer => "open offer",
+                Commitment::Unclaimed => "unclaimed, like a clawback",
+            };
+            println!(
+                "{:<16} {:>24} {reason:<30} {}",
+                coin.asset.name(config),
+                coin.asset.format_amount(coin.amount),
+                coin.coin_id
+            );
+            if let Some(balance) = spendable.get_mut(&coin.asset) {
+                *balance = balance.saturating_sub(coin.amount);
+            }
+        }
+
+        println!();
+        println!("Spendable");
+        println!("{:<16} {:>24}", "Asset", "Amount");
+        for (asset, amount) in &spendable {
+            println!(
+                "{:<16} {:>24}",
+                asset.name(config),
+                asset.format_amount(*amount)
+            );
+        }
+    }
+
+    println!();
+    println!("Open lots, taking sends out of them {}", method.name());
+    println!(
+        "{:<19} {:>10} {:<16} {:>24} {:>16}",
+        "Received",
+        "Height",
+        "Asset",
+        "Amount",
+        format!("Basis ({currency})")
+    );
+    for (asset, lot) in tracker.open_lots() {
+        let received = Local
+            .timestamp_opt(lot.acquired as i64, 0)
+            .single()
+            .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        println!(
+            "{received:<19} {:>10} {:<16} {:>24} {:>16}",
+            lot.height,
+            asset.name(config),
+            asset.format_amount(lot.amount),
+            format_value(lot.priced.then_some(lot.basis))
+        );
+    }
+
+    Ok(())
+}
+
+async fn compare_years(
+    years: &[i32],
+    grouper: &mut TransactionGrouper,
+    timestamps: &IndexMap<u32, u64>,
+    config: &Config,
+    timings: &Timings,
+) -> anyhow::Result<Vec<YearSummary>> {
+    eprintln!("Pricing transactions");
+
+    let holdings = years
+        .iter()
+        .map(|year| holdings_at(grouper.transactions(timestamps), year_bounds(*year).end))
+        .collect::<Vec<_>>();
+
+    // Price every transaction in the compared years, and every holding at the end of each year.
+    let mut keys = IndexSet::new();
+    for (year, holdings) in years.iter().zip(&holdings) {
+        let bounds = year_bounds(*year);
+        for tx in grouper
+            .transactions(timestamps)
+            .filter(|tx| bounds.contains(&(tx.timestamp as i64)))
+        {
+            if let Some(key) = tx.price_key(config) {
+                keys.insert(key);
+            }
+        }
+        for asset in holdings.keys() {
+            if let Some(id) = asset.coingecko_id(config) {
+                keys.insert((id, year_end(*year)));
+            }
+        }
+    }
+
+    let started = Instant::now();
+    let price_cache_path = PathBuf::from("cache").join(PRICE_CACHE_FILE);
+    let prices = PriceProvider::new(config, PriceCache::load(&price_cache_path)?);
+    let daily_prices = fetch_prices(keys, &prices, config.concurrency).await;
+    prices.save_cache(price_cache_path)?;
+    let daily_prices = daily_prices?;
+    timings.record(Phase::Pricing, started, prices.requests());
+
+    Ok(years
+        .iter()
+        .zip(holdings)
+        .map(|(year, holdings)| {
+            summarize_year(
+                *year,
+                year_bounds(*year),
+                grouper.transactions(timestamps),
+                holdings,
+                &daily_prices,
+                config,
+            )
+        })
+        .collect())
+}
+
+fn print_comparison(summaries: &[YearSummary], config: &Config) {
+    let currency = config.currency.to_uppercase();
+
+    let mut header = format!("{:<24}", "");
+    for (i, summary) in summaries.iter().enumerate() {
+        if i > 0 {
+            header.push_str(&format!(" {:>14}", "change"));
+        }
+        header.push_str(&format!(" {:>14}", summary.year));
+    }
+    println!("{header}");
+
+    // Each year is followed by its change from the year before, so anomalies stand out.
+    let row = |label: &str, decimals: usize, value: &dyn Fn(&YearSummary) -> f64| {
+        let values = summaries.iter().map(value).collect::<Vec<_>>();
+        let mut line = format!("{label:<24}");
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                let change = value - values[i - 1];
+                line.push_str(&format!(" {change:>+14.decimals$}"));
+            }
+            line.push_str(&format!(" {value:>14.decimals$}"));
+        }
+        println!("{line}");
+    };
+
+    row("Receives", 0, &|s| s.receives as f64);
+    row("Sends", 0, &|s| s.sends as f64);
+    row(&format!("Income ({currency})"), 2, &|s| s.income);
+    row(&format!("Proceeds ({currency})"), 2, &|s| s.proceeds);
+    row(&format!("Fees ({currency})"), 2, &|s| s.fees);
+    row("Unpriced", 0, &|s| s.unpriced as f64);
+    row(&format!("Holdings ({currency})"), 2, &|s| s.holdings_value);
+
+    let assets = summaries
+        .iter()
+        .flat_map(|s| s.holdings.keys().copied())
+        .collect::<IndexSet<_>>();
+
+    for asset in assets {
+        row(
+            &format!("Holdings ({})", asset.name(config)),
+            asset.precision() as usize,
+            &|s| asset.display_amount(s.holdings.get(&asset).copied().unwrap_or_default()),
+        );
+    }
+}
+
+fn print_groups(group_by: GroupBy, summaries: &IndexMap<String, YearSummary>, config: &Config) {
+    let currency = config.currency.to_uppercase();
+    let name = match group_by {
+        GroupBy::Tag => "Tag",
+        GroupBy::Label => "Label",
+    };
+
+    println!(
+        "{name:<24} {:>10} {:>10} {:>16} {:>16} {:>16} {:>10}",
+        "Receives",
+        "Sends",
+        format!("Income ({currency})"),
+        format!("Proceeds ({currency})"),
+        format!("Fees ({currency})"),
+        "Unpriced"
+    );
+    for (group, summary) in summaries {
+        println!(
+            "{group:<24} {:>10} {:>10} {:>16.2} {:>16.2} {:>16.2} {:>10}",
+            summary.receives,
+            summary.sends,
+            summary.income,
+            summary.proceeds,
+            summary.fees,
+            summary.unpriced
+        );
+    }
+}
+
+/// Each entity's figures, what was eliminated as transfers between them, and the consolidated
+/// figures, which are the sum of the entities less the eliminations.
+fn print_consolidation(
+    entities: &IndexMap<String, YearSummary>,
+    consolidated: &YearSummary,
+    config: &Config,
+) {
+    let currency = config.currency.to_uppercase();
+    let row = |name: &str, counts: [i64; 3], values: [f64; 3]| {
+        println!(
+            "{name:<24} {:>10} {:>10} {:>16.2} {:>16.2} {:>16.2} {:>10}",
+            counts[0], counts[1], values[0], values[1], values[2], counts[2]
+        );
+    };
+    let counts = |s: &YearSummary| [s.receives as i64, s.sends as i64, s.unpriced as i64];
+    let values = |s: &YearSummary| [s.income, s.proceeds, s.fees];
+
+    println!(
+        "{:<24} {:>10} {:>10} {:>16} {:>16} {:>16} {:>10}",
+        "Entity",
+        "Receives",
+        "Sends",
+        format!("Income ({currency})"),
+        format!("Proceeds ({currency})"),
+        format!("Fees ({currency})"),
+        "Unpriced"
+    );
+
+    let mut total_counts = [0; 3];
+    let mut total_values = [0.0; 3];
+    for (entity, summary) in entities {
+        row(entity, counts(summary), values(summary));
+        for (total, count) in total_counts.iter_mut().zip(counts(summary)) {
+            *total += count;
+        }
+        for (total, value) in total_values.iter_mut().zip(values(summary)) {
+            *total += value;
+        }
+    }
+
+    let eliminated_counts = counts(consolidated);
+    let eliminated_values = values(consolidated);
+    row(
+        "eliminated",
+        std::array::from_fn(|i| eliminated_counts[i] - total_counts[i]),
+        std::array::from_fn(|i| eliminated_values[i] - total_values[i]),
+    );
+    row(CONSOLIDATED, counts(consolidated), values(consolidated));
+}
+
+fn print_method_comparison(
+    methods: &[(LotMethod, IndexMap<Asset, RealizedGains>)],
+    config: &Config,
+) {
+    let currency = config.currency.to_uppercase();
+    println!(
+        "{:<12} {:<16} {:>10} {:>16} {:>16} {:>16} {:>10}",
+        "Method",
+        "Asset",
+        "Disposals",
+        format!("Proceeds ({currency})"),
+        format!("Basis ({currency})"),
+        format!("Gain ({currency})"),
+        "Unpriced"
+    );
+    for (method, gains) in methods {
+        let mut total = RealizedGains::default();
+        for (asset, gains) in gains {
+            println!(
+                "{:<12} {:<16} {:>10} {:>16.2} {:>16.2} {:>16.2} {:>10}",
+                method.name(),
+                asset.name(config),
+                gains.disposals,
+                gains.proceeds,
+                gains.basis,
+                gains.gain,
+                gains.unpriced
+            );
+            total.disposals += gains.disposals;
+            total.proceeds += gains.proceeds;
+            total.basis += gains.basis;
+            total.gain += gains.gain;
+            total.unpriced += gains.unpriced;
+        }
+        println!(
+            "{:<12} {:<16} {:>10} {:>16.2} {:>16.2} {:>16.2} {:>10}",
+            method.name(),
+            "Total",
+            total.disposals,
+            total.proceeds,
+            total.basis,
+            total.gain,
+            total.unpriced
+        );
+    }
+}
+
+fn print_pool_history(
+    histories: &IndexMap<String, Vec<PoolPeriod>>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    for (name, periods) in histories {
+        println!("Plot NFT {name}");
+        println!("{:<10} {:<10} Pool rewards paid to", "From", "To");
+        for period in periods {
+            let target = match period.target {
+                Some(target) => address(config, target)?,
+                None => "not revealed until its next spend".to_string(),
+            };
+            println!(
+                "{:<10} {:<10} {target}{}",
+                period.start_height,
+                period
+                    .end_height
+                    .map_or("now".to_string(), |height| height.to_string()),
+                if period.leaving { " (leaving)" } else { "" }
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_installments(installments: &[Installment], config: &Config) {
+    let currency = config.currency.to_uppercase();
+
+    println!(
+        "Estimated tax at {}% of income received and gains realized with {} lots",
+        config.estimated_tax.rate,
+        config.lot_method().name()
+    );
+    println!(
+        "{:<12} {:<12} {:>16} {:>16} {:>16}",
+        "Due",
+        "Covers to",
+        format!("Income ({currency})"),
+        format!("Gains ({currency})"),
+        format!("Payment ({currency})")
+    );
+    for installment in installments {
+        println!(
+            "{:<12} {:<12} {:>16.2} {:>16.2} {:>16.2}{}",
+            installment.due.to_string(),
+            installment.covers_to.to_string(),
+            installment.income,
+            installment.gains,
+            installment.payment,
+            if installment.projected {
+                " (projected)"
+            } else {
+                ""
+            }
+        );
+    }
+    let total = installments
+        .iter()
+        .map(|installment| installment.payment)
+        .sum::<f64>();
+    println!(
+        "{:<12} {:<12} {:>16} {:>16} {total:>16.2}",
+        "Total", "", "", ""
+    );
+}
+
+async fn simulate(args: SimulateArgs, cost_basis: Option<LotMethod>) -> anyhow::Result<()> {
+    let master_pk = parse_pk(args.wallet.key.as_deref())?;
+    let mut config = Config::load(CONFIG_PATH)?;
+    config.cost_basis = cost_basis;
+    let today = Local::now().date_naive();
+    let date = args.date.unwrap_or(today);
+
+    let mut sales = Vec::new();
+    for sale in args.sell.chunks(2) {
+        let [amount, name] = sale else {
+            bail!("--sell needs an amount and an asset, like `--sell 100 XCH`");
+        };
+        let Some(asset) = Asset::parse(name, &config) else {
+            bail!("Unknown asset {name:?}, expected XCH or a CAT's name or asset id");
+        };
+        let Some(amount) = asset.parse_amount(amount) else {
+            bail!("Invalid amount {amount:?} of {}", asset.name(&config));
+        };
+        sales.push((asset, amount));
+    }
+
+    let cache_path = cache_path(&master_pk, args.wallet.year)?;
+    if !cache_path.try_exists()? {
+        bail!(
+            "No cache at {}, run a report for {} first",
+            cache_path.display(),
+            args.wallet.year
+        );
+    }
+    let _lock = CacheLock::acquire_shared(&cache_path, true)?;
+
+    let mut grouper = TransactionGrouper::default();
+    let mut timestamps = IndexMap::new();
+    for derivations in CacheWindows::open(
+        &cache_path,
+        Some(&master_to_wallet_unhardened_intermediate(&master_pk)),
+    )?
+    .iter()
+    {
+        let derivations = derivations?;
+        grouper.add(&derivations);
+        timestamps.extend(derivations.timestamps);
+    }
+    grouper.set_max_fee(config.self_spends.max_fee);
+
+    // Only heights synced before timestamps were cached need the peer.
+    let missing = grouper
+        .heights()
+        .into_iter()
+        .filter(|height| !timestamps.contains_key(height))
+        .collect::<IndexSet<_>>();
+    if !missing.is_empty() {
+        let peer = connect(&config).await?.peer;
+        timestamps
+            .extend(resolve_timestamps(&peer, missing, config.concurrency, &config.retry).await?);
+    }
+
+    let end = day_end(date)?;
+    let mut keys = grouper
+        .transactions(&timestamps)
+        .filter(|tx| tx.kind == TransactionKind::Receive && (tx.timestamp as i64) < end)
+        .filter_map(|tx| tx.price_key(&config))
+        .collect::<IndexSet<_>>();
+    if args.price.is_none() && date < today {
+        keys.extend(
+            sales
+                .iter()
+                .filter_map(|(asset, _)| Some((asset.coingecko_id(&config)?, date))),
+        );
+    }
+
+    // The price cache is left as it was, like everything else.
+    let price_cache_path = PathBuf::from("cache").join(PRICE_CACHE_FILE);
+    let prices = PriceProvider::new(&config, PriceCache::load(&price_cache_path)?);
+    let daily_prices = fetch_prices(keys, &prices, config.concurrency).await?;
+
+    let mut lots = LotTracker::new(config.lot_method());
+    for tx in grouper
+        .transactions(&timestamps)
+        .filter(|tx| (tx.timestamp as i64) < end)
+    {
+        let value = tx
+            .price(&daily_prices, &config)
+            .map(|price| price * tx.asset.display_amount(tx.amount));
+        lots.apply(&tx, value);
+    }
+
+    let currency = config.currency.to_uppercase();
+    println!(
+        "Selling on {date}, taking {} lots",
+        config.lot_method().name()
+    );
+    println!(
+        "{:<16} {:>24} {:>16} {:>16} {:>16} {:>16}",
+        "Asset",
+        "Amount",
+        format!("Price ({currency})"),
+        format!("Proceeds ({currency})"),
+        format!("Basis ({currency})"),
+        format!("Gain ({currency})")
+    );
+
+    let mut total = 0.0;
+    for (asset, amount) in sales {
+        let price = match (args.price, asset.coingecko_id(&config)) {
+            (Some(price), _) => Some(price),
+            (None, Some(id)) if date >= today => Some(prices.current_price(&id).await?),
+            (None, Some(id)) => daily_prices.get(&(id, date)).copied(),
+            (None, None) => None,
+        };
+        let taken = lots.take(asset, amount);
+        if taken.amount < amount {
+            eprintln!(
+                "Warning: only {} {} was held on {date}, so the rest has no basis",
+                asset.format_amount(taken.amount),
+                asset.name(&config)
+            );
+        }
+        if !taken.priced {
+            eprintln!(
+                "Warning: some {} lots couldn't be priced, so their basis is zero",
+                asset.name(&config)
+            );
+        }
+
+        let Some(price) = price else {
+            println!(
+                "{:<16} {:>24} {:>16} {:>16} {:>16.2} {:>16}",
+                asset.name(&config),
+                asset.format_amount(amount),
+                "unpriced",
+                "",
+                taken.basis,
+                ""
+            );
+            continue;
+        };
+        let proceeds = price * asset.display_amount(amount);
+        let gain = proceeds - taken.basis;
+        total += gain;
+        println!(
+            "{:<16} {:>24} {price:>16.4} {proceeds:>16.2} {:>16.2} {gain:>16.2}",
+            asset.name(&config),
+            asset.format_amount(amount),
+            taken.basis
+        );
+    }
+    println!(
+        "{:<16} {:>24} {:>16} {:>16} {:>16} {total:>16.2}",
+        "Total", "", "", "", ""
+    );
+
+    Ok(())
+}
+
+async fn offer_status(wallet: WalletArgs, offers: Vec<String>) -> anyhow::Result<()> {
+    let master_pk = parse_pk(wallet.key.as_deref())?;
+    let cache = Cache::load(
+        cache_path(&master_pk, wallet.year)?,
+        Some(&master_to_wallet_unhardened_intermediate(&master_pk)),
+    )?;
+    let mut dexie = None;
+
+    for name in offers {
+        // Anything that isn't a file is taken to be a Dexie id, which are plain base58.
+        let path = Path::new(&name);
+        if path.try_exists()? || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            let offer = load_offer(path)?;
+            println!("{name}: {}", offer::offer_status(&cache, &offer));
+            continue;
+        }
+
+        let dexie = match &mut dexie {
+            Some(dexie) => dexie,
+            None => dexie.insert(Dexie::new(&Config::load(CONFIG_PATH)?.dexie)),
+        };
+        let posted = dexie.offer(&name).await?;
+        let offer = parse_offer(&posted.offer)?;
+        println!(
+            "{name}: {} (Dexie: {})",
+            offer::offer_status(&cache, &offer),
+            posted.status
+        );
+    }
+
+    Ok(())
+}
+
+async fn offer_inspect(wallet: WalletArgs, path: PathBuf) -> anyhow::Result<()> {
+    let master_pk = parse_pk(wallet.key.as_deref())?;
+    let config = Config::load(CONFIG_PATH)?;
+    let cache = Cache::load(
+        cache_path(&master_pk, wallet.year)?,
+        Some(&master_to_wallet_unhardened_intermediate(&master_pk)),
+    )?;
+
+    let offer = load_offer(&path)?;
+    let summary = summarize_offer(&offer)?;
+    let status = offer::offer_status(&cache, &offer);
+
+    // The offered side is ours if we made the offer, otherwise it's what we would receive.
+    let (give, receive) = if is_maker(&cache, &offer) {
+        (&summary.offered, &summary.requested)
+    } else {
+        (&summary.requested, &summary.offered)
+    };
+
+    let execution_date = match status {
+        OfferStatus::Executed { height } => {
+            let peer = connect(&config).await?.peer;
+            let timestamp = block_timestamp(&peer, height, &config.retry).await?;
+            Local.timestamp_opt(timestamp as i64, 0).single()
+        }
+        _ => None,
+    };
+
+    let price_cache_path = PathBuf::from("cache").join(PRICE_CACHE_FILE);
+    let prices = PriceProvider::new(&config, PriceCache::load(&price_cache_path)?);
+
+    println!("{}: {status}", path.display());
+
+    for (label, assets) in [("You give", give), ("You receive", receive)] {
+        for (asset, amount) in assets {
+            let mut line = format!(
+                "  {label}: {} {}",
+                asset.format_amount(*amount),
+                asset.name(&config)
+            );
+
+            if let Some(id) = asset.coingecko_id(&config) {
+                let value = asset.display_amount(*amount);
+                let currency = prices.currency().to_uppercase();
+
+                let current = prices.current_price(&id).await?;
+                line.push_str(&format!(", {:.2} {currency} now", value * current));
+
+                if let Some(date) = execution_date {
+                    let historical = prices.historical_price(&id, date.date_naive()).await?;
+                    line.push_str(&format!(
+                        ", {:.2} {currency} at execution on {}",
+                        value * historical,
+                        date.date_naive()
+                    ));
+                }
+            }
+
+            println!("{line}");
+        }
+    }
+
+    println!("  Fee: {} XCH", Asset::Xch.format_amount(summary.fee));
+
+    prices.save_cache(price_cache_path)?;
+
+    Ok(())
+}
+
+fn cache_diff(old: PathBuf, new: PathBuf) -> anyhow::Result<()> {
+    for path in [&old, &new] {
+        if !path.try_exists()? {
+            bail!("Cache {} does not exist", path.display());
+        }
+    }
+
+    let config = Config::load(CONFIG_PATH)?;
+    let old = Cache::load(old, None)?;
+    let new = Cache::load(new, None)?;
+    let diff = diff_caches(&old, &new);
+
+    if diff.is_empty() {
+        println!("No changes");
+        return Ok(());
+    }
+
+    println!("Added ({}):", diff.added.len());
+    for (coin_id, coin_state) in &diff.added {
+        println!("  {}", describe_coin(&config, coin_id, coin_state));
+    }
+
+    println!("Newly spent ({}):", diff.spent.len());
+    for (coin_id, coin_state) in &diff.spent {
+        println!("  {}", describe_coin(&config, coin_id, coin_state));
+    }
+
+    println!("Reclassified ({}):", diff.reclassified.len());
+    for (coin_id, old, new) in &diff.reclassified {
+        println!(
+            "  {} {} -> {}",
+            hex::encode(coin_id),
+            asset_name(&config, old),
+            asset_name(&config, new)
+        );
+    }
+
+    Ok(())
+}
+
+async fn cache_reconcile(wallet: WalletArgs) -> anyhow::Result<()> {
+    let master_pk = parse_pk(wallet.key.as_deref())?;
+    let config = Config::load(CONFIG_PATH)?;
+    let windows = CacheWindows::open(
+        cache_path(&master_pk, wallet.year)?,
+        Some(&master_to_wallet_unhardened_intermediate(&master_pk)),
+    )?;
+
+    let peer = connect(&config).await?;
+    let reconciliation = reconcile(&peer, &config, &windows).await?;
+
+    println!("Unspent balances at height {}:", reconciliation.height);
+
+    let mut assets = reconciliation.cached.keys().collect::<Vec<_>>();
+    for asset in reconciliation.on_chain.keys() {
+        if !assets.contains(&asset) {
+            assets.push(asset);
+        }
+    }
+
+    for asset in assets {
+        let cached = reconciliation.cached.get(asset).copied().unwrap_or(0);
+        let on_chain = reconciliation.on_chain.get(asset).copied().unwrap_or(0);
+        let format = |amount: u64| asset.map_or(amount.to_string(), |a| a.format_amount(amount));
+        let name = asset.map_or("unknown".to_string(), |asset| asset.name(&config));
+        let flag = if cached == on_chain { "ok" } else { "MISMATCH" };
+        println!(
+            "  {name}: cache {}, peer {}, {flag}",
+            format(cached),
+            format(on_chain)
+        );
+    }
+
+    for coin_id in &reconciliation.missing {
+        println!("Unspent on chain but not in cache: {coin_id}");
+    }
+
+    for coin_id in &reconciliation.stale {
+        println!("Unspent in cache but not on chain: {coin_id}");
+    }
+
+    if reconciliation.is_consistent() {
+        println!("Cache is consistent with the peer");
+    } else {
+        println!(
+            "Found {} differences between the cache and the peer",
+            reconciliation.missing.len() + reconciliation.stale.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn cert_command(command: CertCommand) -> anyhow::Result<()> {
+    let paths = CertPaths::new()?;
+
+    match command {
+        CertCommand::Generate => {
+            let cert = cert::generate(&paths, false)?;
+            println!("Generated {}", paths.cert.display());
+            println!("Fingerprint: {}", cert::fingerprint(&cert)?);
+        }
+        CertCommand::Show => {
+            if !paths.exists() {
+                bail!(
+                    "No certificate at {}, run `thyme cert generate` first",
+                    paths.cert.display()
+                );
+            }
+            println!("Certificate: {}", paths.cert.display());
+            println!("Key: {}", paths.key.display());
+            println!("Fingerprint: {}", cert::fingerprint(&paths.load()?)?);
+        }
+        CertCommand::Rotate => {
+            let previous = paths.exists().then(|| paths.load()).transpose()?;
+            let cert = cert::generate(&paths, true)?;
+            if let Some(previous) = previous {
+                println!("Previous fingerprint: {}", cert::fingerprint(&previous)?);
+            }
+            println!("Rotated {}", paths.cert.display());
+            println!("Fingerprint: {}", cert::fingerprint(&cert)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn config_command(command: ConfigCommand) -> anyhow::Result<()> {
+    match command {
+        ConfigCommand::SetSecret { name, value } => {
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    eprintln!("Enter the value for {}:", name.name());
+                    let mut value = String::new();
+                    io::stdin().read_line(&mut value)?;
+                    value
+                }
+            };
+            let mut value = value.trim().to_string();
+            if value.is_empty() {
+                bail!("The secret is empty");
+            }
+            // Store the key itself, whichever format it was given in.
+            if name == Secret::MasterPublicKey {
+                value = hex::encode(parse_pk(Some(&value))?.to_bytes());
+            }
+            set_secret(name, &value)?;
+            eprintln!("Stored the secret in the keyring");
+        }
+        ConfigCommand::DeleteSecret { name } => {
+            if delete_secret(name)? {
+                eprintln!("Removed the secret from the keyring");
+            } else {
+                eprintln!("The secret isn't in the keyring");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn issue_address(args: AddressArgs) -> anyhow::Result<()> {
+    let master_pk = parse_pk(args.key.as_deref())?;
+    let intermediate_pk = master_to_wallet_unhardened_intermediate(&master_pk);
+    let config = Config::load(CONFIG_PATH)?;
+    let book_path = address_book_path(&master_pk);
+    let mut book = AddressBook::load(&book_path, &config, &intermediate_pk)?;
+
+    if let Some(amount) = &args.amount {
+        if amount.parse::<f64>().is_err() {
+            bail!("Expected a number for --amount, got `{amount}`");
+        }
+    }
+
+    let index = match args.index {
+        Some(index) => index,
+        None => {
+            let year = args.year.unwrap_or_else(|| Local::now().year());
+            let cache_path = cache_path(&master_pk, year)?;
+            if !cache_path.try_exists()? {
+                bail!(
+                    "No cache at {}, run a report for {year} first so used addresses are known",
+                    cache_path.display()
+                );
+            }
+
+            let mut used = IndexSet::new();
+            for derivations in CacheWindows::open(&cache_path, Some(&intermediate_pk))?.iter() {
+                used.extend(
+                    derivations?
+                        .coin_states
+                        .values()
+                        .map(CoinStateJson::p2_puzzle_hash),
+                );
+            }
+
+            (0..)
+                .find(|index| {
+                    !book.addresses.contains_key(index)
+                        && !used.contains(&derive_puzzle_hash(&intermediate_pk, *index))
+                })
+                .expect("some derivation is unused")
+        }
+    };
+
+    let puzzle_hash = derive_puzzle_hash(&intermediate_pk, index);
+    let issued = IssuedAddress {
+        address: address(&config, puzzle_hash)?,
+        puzzle_hash: puzzle_hash.to_bytes(),
+        purpose: args.purpose,
+        amount: args.amount,
+        memo: args.memo,
+        issued: Local::now().format("%Y-%m-%d").to_string(),
+    };
+
+    println!("Address: {}", issued.address);
+    println!("Derivation: {index}");
+    if let Some(amount) = &issued.amount {
+        println!("Amount: {amount} XCH");
+    }
+    if let Some(memo) = &issued.memo {
+        println!("Memo: {memo}");
+    }
+    if let Some(purpose) = &issued.purpose {
+        println!("Purpose: {purpose}");
+    }
+
+    book.addresses.insert(index, issued);
+    book.addresses.sort_keys();
+    book.save(book_path, &config)?;
+
+    Ok(())
+}
+
+async fn invoices_match(
+    wallet: WalletArgs,
+    path: PathBuf,
+    tag: Option<String>,
+) -> anyhow::Result<()> {
+    let master_pk = parse_pk(wallet.key.as_deref())?;
+    let mut config = Config::load(CONFIG_PATH)?;
+    let invoices = load_invoices(&path)?;
+    let windows = CacheWindows::open(
+        cache_path(&master_pk, wallet.year)?,
+        Some(&master_to_wallet_unhardened_intermediate(&master_pk)),
+    )?;
+
+    let peer = connect(&config).await?.peer;
+
+    let coins = incoming_coins(&windows)?;
+    let heights = coins
+        .iter()
+        .filter_map(|(_, coin_state)| coin_state.created_height)
+        .collect::<IndexSet<_>>();
+    let timestamps = resolve_timestamps(&peer, heights, config.concurrency, &config.retry).await?;
+    let year_range = year_bounds(wallet.year);
+    let coins = coins
+        .into_iter()
+        .filter(|(_, coin_state)| {
+            coin_state
+                .created_height
+                .and_then(|height| timestamps.get(&height))
+                .is_some_and(|timestamp| year_range.contains(&(*timestamp as i64)))
+        })
+        .collect::<Vec<_>>();
+
+    eprintln!("Looking up memos for {} received coins", coins.len());
+
+    let payments = payment_memos(
+        &peer,
+        config.genesis_challenge.into(),
+        coins,
+        config.concurrency,
+        &config.retry,
+    )
+    .await?;
+    let matches = match_invoices(invoices, payments);
+
+    let describe = |payment: &invoice::Payment| {
+        format!(
+            "{} {} at height {}, coin {}",
+            payment.asset.format_amount(payment.amount),
+            payment.asset.name(&config),
+            payment.height,
+            payment.coin_id
+        )
+    };
+    let label = |invoice: &invoice::Invoice| match &invoice.client {
+        Some(client) => format!("{} ({client})", invoice.reference),
+        None => invoice.reference.clone(),
+    };
+
+    println!("Matched invoices ({}):", matches.matched.len());
+    for (invoice, payments) in &matches.matched {
+        println!("  {}", label(invoice));
+        for payment in payments {
+            println!("    {}", describe(payment));
+        }
+        if let Some(expected) = invoice.amount {
+            let paid = payments
+                .iter()
+                .map(|payment| payment.asset.display_amount(payment.amount))
+                .sum::<f64>();
+            if (paid - expected).abs() > 1e-9 {
+                println!("    Paid {paid}, expected {expected}");
+            }
+        }
+    }
+
+    println!("Unmatched invoices ({}):", matches.unmatched.len());
+    for invoice in &matches.unmatched {
+        match invoice.amount {
+            Some(amount) => println!("  {}, expected {amount}", label(invoice)),
+            None => println!("  {}", label(invoice)),
+        }
+    }
+
+    println!(
+        "Payments with memos that match no invoice ({}):",
+        matches.unreferenced.len()
+    );
+    for payment in &matches.unreferenced {
+        println!(
+            "  {}, memo {:?}",
+            describe(payment),
+            payment.memos.join(" ")
+        );
+    }
+
+    if let Some(entity) = tag {
+        let coins = &mut config.entities.entry(entity.clone()).or_default().coins;
+        let mut untagged = Vec::new();
+        for (_, payments) in &matches.matched {
+            for payment in payments {
+                let coin_id = payment.coin_id.to_string();
+                if !coins.contains(&coin_id) {
+                    coins.push(coin_id.clone());
+                    untagged.push(coin_id);
+                }
+            }
+        }
+        // Check the entity would be valid, since the name could be reserved.
+        Entities::from_config(&config)?;
+
+        if untagged.is_empty() {
+            eprintln!("Every matched coin is already in entities.{entity}.coins");
+        } else {
+            eprintln!(
+                "Add these {} coins to entities.{entity}.coins in {CONFIG_PATH}:",
+                untagged.len()
+            );
+            for coin_id in untagged {
+                println!("    \"{coin_id}\",");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn cache_snapshot(wallet: WalletArgs, name: Option<String>) -> anyhow::Result<()> {
+    let master_pk = parse_pk(wallet.key.as_deref())?;
+    let cache_path = cache_path(&master_pk, wallet.year)?;
+    if !cache_path.try_exists()? {
+        bail!(
+            "No cache at {}, run a report for {} first",
+            cache_path.display(),
+            wallet.year
+        );
+    }
+    let _lock = CacheLock::acquire_shared(&cache_path, true)?;
+    let windows = CacheWindows::open(&cache_path, None)?;
+
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let mut height = 0;
+            for derivations in windows.iter() {
+                height = height.max(derivations?.previous_height.unwrap_or_default());
+            }
+            format!("height-{height}")
+        }
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Snapshot names can only have letters, digits, `-` and `_`, not {name:?}");
+    }
+
+    let path = snapshot_path(&master_pk, wallet.year, &name);
+    if path.try_exists()? {
+        bail!("There's already a snapshot {name} at {}", path.display());
+    }
+    windows.snapshot(&path)?;
+
+    eprintln!(
+        "Saved snapshot {name} of {} windows to {}",
+        windows.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn cache_puzzle_hashes(wallet: WalletArgs, output: Option<PathBuf>) -> anyhow::Result<()> {
+    let config = Config::load(CONFIG_PATH)?;
+    let master_pk = parse_pk(wallet.key.as_deref())?;
+    let cache_path = cache_path(&master_pk, wallet.year)?;
+
+    if !cache_path.try_exists()? {
+        bail!("No cache at {}, run a report first", cache_path.display());
+    }
+
+    let out: Box<dyn Write> = match &output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["derivation", "window", "puzzle_hash", "address", "coins"])?;
+    let mut count = 0;
+
+    for (window, derivations) in CacheWindows::open(
+        &cache_path,
+        Some(&master_to_wallet_unhardened_intermediate(&master_pk)),
+    )?
+    .iter()
+    .enumerate()
+    {
+        let derivations = derivations?;
+
+        let mut coins = IndexMap::<Bytes32, usize>::new();
+        for coin_state in derivations.coin_states.values() {
+            *coins.entry(coin_state.p2_puzzle_hash()).or_default() += 1;
+        }
+
+        for (offset, puzzle_hash) in derivations.puzzle_hashes.iter().enumerate() {
+            let puzzle_hash = Bytes32::from(*puzzle_hash);
+            writer.write_record([
+                (window as u32 * WINDOW_SIZE + offset as u32).to_string(),
+                window.to_string(),
+                puzzle_hash.to_string(),
+                address(&config, puzzle_hash)?,
+                coins
+                    .get(&puzzle_hash)
+                    .copied()
+                    .unwrap_or_default()
+                    .to_string(),
+            ])?;
+            count += 1;
+        }
+    }
+    writer.flush()?;
+
+    if let Some(path) = output {
+        eprintln!("Wrote {count} puzzle hashes to {}", path.display());
+    }
+
+    Ok(())
+}
+
+fn cache_query(wallet: WalletArgs, query: &str, json: bool) -> anyhow::Result<()> {
+    let query: Query = query.parse()?;
+    query.validate()?;
+
+    let config = Config::load(CONFIG_PATH)?;
+    let master_pk = parse_pk(wallet.key.as_deref())?;
+    let cache_path = cache_path(&master_pk, wallet.year)?;
+
+    if !cache_path.try_exists()? {
+        bail!("No cache at {}, run a report first", cache_path.display());
+    }
+
+    let mut matches = Vec::new();
+
+    for derivations in CacheWindows::open(
+        &cache_path,
+        Some(&master_to_wallet_unhardened_intermediate(&master_pk)),
+    )?
+    .iter()
+    {
+        for (coin_id, coin_state) in derivations?.coin_states {
+            if query.matches(&config, &coin_id, &coin_state) {
+                matches.push((coin_id, coin_state));
+            }
+        }
+    }
+
+    if json {
+        let coins = matches
+            .iter()
+            .map(|(coin_id, coin_state)| {
+                serde_json::json!({
+                    "coin_id": hex::encode(coin_id),
+                    "parent_coin_id": hex::encode(coin_state.coin.parent_coin_info),
+                    "puzzle_hash": hex::encode(coin_state.coin.puzzle_hash),
+                    "asset": asset_name(&config, coin_state),
+                    "amount": coin_state.coin.amount,
+                    "parent_spend": coin_state.parent_spend,
+                    "created_height": coin_state.created_height,
+                    "spent_height": coin_state.spent_height,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&coins)?);
+        return Ok(());
+    }
+
+    for (coin_id, coin_state) in &matches {
+        println!("{}", describe_coin(&config, coin_id, coin_state));
+    }
+    println!("{} matching coins", matches.len());
+
+    Ok(())
+}
+
+fn describe_coin(config: &Config, coin_id: &[u8; 32], coin_state: &CoinStateJson) -> String {
+    let amount = coin_state.asset().map_or_else(
+        || coin_state.coin.amount.to_string(),
+        |asset| asset.format_amount(coin_state.coin.amount),
+    );
+    let height = |height: Option<u32>| height.map_or("-".to_string(), |h| h.to_string());
+
+    // Unrecognized parents are plain coins, so their amounts are in mojos of XCH.
+    let parent = coin_state
+        .parent_spend
+        .as_ref()
+        .map_or(String::new(), |spend| {
+            format!(
+                " from a {} XCH spend creating {} coins",
+                Asset::Xch.format_amount(spend.parent_amount),
+                spend.created_coins
+            )
+        });
+
+    format!(
+        "{} {amount} {} created {} spent {}{parent}",
+        hex::encode(coin_id),
+        asset_name(config, coin_state),
+        height(coin_state.created_height),
+        height(coin_state.spent_height)
+    )
+}
+
+fn asset_name(config: &Config, coin_state: &CoinStateJson) -> String {
+    coin_state
+        .asset()
+        .map_or("unknown".to_string(), |asset| asset.name(config))
+}
+
+fn label_counterparty(key: Option<&str>, counterparty: &str, label: String) -> anyhow::Result<()> {
+    let master_pk = parse_pk(key)?;
+    let intermediate_pk = master_to_wallet_unhardened_intermediate(&master_pk);
+    let config = Config::load(CONFIG_PATH)?;
+    let book_path = address_book_path(&master_pk);
+    let mut book = AddressBook::load(&book_path, &config, &intermediate_pk)?;
+    let clusters = Clusters::load(clusters_path(&master_pk))?;
+
+    // A cluster is labeled through its first address, which is where its id comes from.
+    let address = if counterparty.starts_with("cluster-") {
+        let Some(addresses) = clusters.clusters.get(counterparty) else {
+            bail!("Unknown cluster `{counterparty}`, run a report with --counterparties first");
+        };
+        addresses[0].clone()
+    } else {
+        decode_address(counterparty)
+            .map_err(|error| anyhow!("Invalid address `{counterparty}`: {error}"))?;
+        counterparty.to_string()
+    };
+
+    match clusters.cluster_of(&address) {
+        Some(id) => {
+            // Replace any earlier label of the cluster, which would otherwise take precedence.
+            let members = &clusters.clusters[id];
+            book.counterparties
+                .retain(|labeled, _| !members.contains(labeled));
+            println!("Labeled {} addresses in {id} as {label}", members.len());
+        }
+        None => println!("Labeled {address} as {label}"),
+    }
+
+    book.counterparties.insert(address, label);
+    book.save(book_path, &config)?;
+
+    Ok(())
+}
+
+fn address_book_path(master_pk: &PublicKey) -> PathBuf {
+    let fingerprint = master_pk.get_fingerprint();
+    PathBuf::from("cache").join(format!("addresses-{fingerprint}.json"))
+}
+
+fn clusters_path(master_pk: &PublicKey) -> PathBuf {
+    let fingerprint = master_pk.get_fingerprint();
+    PathBuf::from("cache").join(format!("clusters-{fingerprint}.json"))
+}
+
+fn subscriptions_path(master_pk: &PublicKey, year: i32) -> PathBuf {
+    let fingerprint = master_pk.get_fingerprint();
+    PathBuf::from("cache").join(format!("subscriptions-{fingerprint}-{year}.json"))
+}
+
+fn cache_path(master_pk: &PublicKey, year: i32) -> anyhow::Result<PathBuf> {
+    let cache_dir = PathBuf::from("cache");
+    if !cache_dir.try_exists()? {
+        read_only::check_writable(&cache_dir)?;
+        fs::create_dir_all(cache_dir.as_path())?;
+    }
+    let fingerprint = master_pk.get_fingerprint();
+    Ok(cache_dir.join(format!("cache-{fingerprint}-{year}")))
+}
+
+/// Where the net realized gains recorded with `--carry-losses` are kept, by currency.
+fn carryforward_path(fingerprint: u32, currency: &str) -> PathBuf {
+    PathBuf::from("cache").join(format!("carryforward-{fingerprint}-{currency}.json"))
+}
+
+fn snapshot_path(master_pk: &PublicKey, year: i32, name: &str) -> PathBuf {
+    let fingerprint = master_pk.get_fingerprint();
+    PathBuf::from("cache")
+        .join("snapshots")
+        .join(format!("cache-{fingerprint}-{year}"))
+        .join(name)
+}
+
+/// The path of a snapshot that was taken, listing the ones there are if it wasn't.
+fn existing_snapshot_path(master_pk: &PublicKey, year: i32, name: &str) -> anyhow::Result<PathBuf> {
+    let path = snapshot_path(master_pk, year, name);
+    if path.is_dir() {
+        return Ok(path);
+    }
+
+    let mut names = Vec::new();
+    if let Some(dir) = path.parent().filter(|dir| dir.is_dir()) {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+    names.sort();
+
+    if names.is_empty() {
+        bail!("No snapshot {name} for {year}, take one with `thyme cache snapshot`");
+    }
+    bail!(
+        "No snapshot {name} for {year}, expected one of: {}",
+        names.join(", ")
+    )
+}
+
+/// What a watch works with between events, which stays the same across reconnects.
+struct WatchContext<'a> {
+    config: &'a Config,
+    intermediate_pk: &'a PublicKey,
+    cache_path: &'a Path,
+    subscriptions_path: &'a Path,
+    timings: &'a Timings,
+    publisher: Option<&'a Publisher>,
+    /// Where pending spend bundles are looked for, with --mempool.
+    mempool: Option<&'a Indexer>,
+}
+
+/// Where the transactions a watch detects are sent, which is WebSocket clients when serving
+/// and the chats in the config.
+struct Publisher {
+    transactions: Option<broadcast::Sender<String>>,
+    notifier: Option<Notifier>,
+    labels: IndexMap<Bytes32, String>,
+    prices: PriceProvider,
+    /// The gains realized during the year being watched, once they've been worked out from
+    /// the cache, with `notifications.gain_thresholds`.
+    gain_alerts: Mutex<Option<GainAlerts>>,
+    year: i32,
+}
+
+/// How many transactions a WebSocket client can fall behind by before it misses some.
+const PUBLISH_BUFFER: usize = 256;
+
+/// Watches the wallet, also serving the transactions over a WebSocket if `listen` is set
+/// and posting them to the configured chats.
+async fn watch(
+    wallet: WalletArgs,
+    listen: Option<SocketAddr>,
+    mempool: bool,
+    cost_basis: Option<LotMethod>,
+) -> anyhow::Result<()> {
+    let master_pk = parse_pk(wallet.key.as_deref())?;
+    let intermediate_pk = master_to_wallet_unhardened_intermediate(&master_pk);
+    let mut config = Config::load(CONFIG_PATH)?;
+    config.cost_basis = cost_basis;
+    let cache_path = cache_path(&master_pk, wallet.year)?;
+    let subscriptions_path = subscriptions_path(&master_pk, wallet.year);
+    let timings = Timings::default();
+
+    let indexer = if mempool {
+        let Some(indexer) = Indexer::new(&config.indexer) else {
+            bail!("--mempool needs `indexer.url` in the config to look at the mempool");
+        };
+        Some(indexer)
+    } else {
+        None
+    };
+
+    let notifier = Notifier::new(&config.notifications)?;
+
+    // Only serving and notifications need the transactions the coins make up.
+    if listen.is_none() && notifier.is_none() {
+        let context = WatchContext {
+            config: &config,
+            intermediate_pk: &intermediate_pk,
+            cache_path: &cache_path,
+            subscriptions_path: &subscriptions_path,
+            timings: &timings,
+            publisher: None,
+            mempool: indexer.as_ref(),
+        };
+        return watch_loop(&context).await;
+    }
+
+    let listener = match listen {
+        Some(listen) => {
+            let listener = TcpListener::bind(listen).await?;
+            eprintln!(
+                "Serving transactions at ws://{}{}",
+                listener.local_addr()?,
+                serve::TRANSACTIONS_PATH
+            );
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let publisher = Publisher {
+        transactions: listener
+            .is_some()
+            .then(|| broadcast::channel(PUBLISH_BUFFER).0),
+        notifier,
+        labels: AddressBook::load(address_book_path(&master_pk), &config, &intermediate_pk)?
+            .labels(),
+        prices: PriceProvider::new(
+            &config,
+            PriceCache::load(PathBuf::from("cache").join(PRICE_CACHE_FILE))?,
+        ),
+        gain_alerts: Mutex::new(None),
+        year: wallet.year,
+    };
+    let context = WatchContext {
+        config: &config,
+        intermediate_pk: &intermediate_pk,
+        cache_path: &cache_path,
+        subscriptions_path: &subscriptions_path,
+        timings: &timings,
+        publisher: Some(&publisher),
+        mempool: indexer.as_ref(),
+    };
+
+    match (listener, &publisher.transactions) {
+        (Some(listener), Some(transactions)) => tokio::select! {
+            result = watch_loop(&context) => result,
+            result = serve_transactions(listener, transactions.clone()) => result,
+        },
+        _ => watch_loop(&context).await,
+    }
+}
+
+/// Watches the confirmed coins, and the mempool alongside them with --mempool.
+async fn watch_loop(context: &WatchContext<'_>) -> anyhow::Result<()> {
+    match context.mempool {
+        Some(indexer) => tokio::select! {
+            result = watch_confirmed(context) => result,
+            result = watch_mempool(context, indexer) => result,
+        },
+        None => watch_confirmed(context).await,
+    }
+}
+
+/// Polls the mempool for spend bundles that create coins for the wallet's puzzle hashes or
+/// spend its unspent coins, and prints each one once as pending. Nothing is written to the
+/// cache, so pending coins never reach a report.
+async fn watch_mempool(context: &WatchContext<'_>, indexer: &Indexer) -> anyhow::Result<()> {
+    let mut shown = IndexSet::new();
+
+    loop {
+        let items = match indexer.mempool_items().await {
+            Ok(items) => items,
+            Err(error) => {
+                eprintln!("Warning: couldn't check the mempool: {error}");
+                sleep(MEMPOOL_POLL_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let mut puzzle_hashes = IndexSet::new();
+        let mut unspent = IndexMap::new();
+        {
+            // The watch holds the lock while it syncs, and this runs on the same task, so
+            // waiting for it would never end. The mempool is checked again next time.
+            let Ok(_lock) = CacheLock::acquire(context.cache_path, false) else {
+                sleep(MEMPOOL_POLL_INTERVAL).await;
+                continue;
+            };
+            for derivations in
+                CacheWindows::open(context.cache_path, Some(context.intermediate_pk))?.iter()
+            {
+                let derivations = derivations?;
+                puzzle_hashes.extend(derivations.puzzle_hashes);
+                unspent.extend(
+                    derivations
+                        .coin_states
+                        .into_iter()
+                        .filter(|(_, coin_state)| coin_state.spent_height.is_none()),
+                );
+            }
+        }
+
+        for item in &items {
+            if shown.contains(&item.id) {
+                continue;
+            }
+
+            for coin in &item.removals {
+                if let Some(coin_state) = unspent.get(&coin.coin_id().to_bytes()) {
+                    let amount = coin_state.asset().map_or_else(
+                        || coin.amount.to_string(),
+                        |asset| asset.format_amount(coin.amount),
+                    );
+                    println!(
+                        "Pending: spending {amount} {} in coin {} (unconfirmed)",
+                        asset_name(context.config, coin_state),
+                        coin.coin_id()
+                    );
+                }
+            }
+            for coin in &item.additions {
+                if puzzle_hashes.contains(&coin.puzzle_hash.to_bytes()) {
+                    println!(
+                        "Pending: receiving {} XCH in coin {} (unconfirmed)",
+                        Asset::Xch.format_amount(coin.amount),
+                        coin.coin_id()
+                    );
+                }
+            }
+        }
+
+        // Bundles that left the mempool are forgotten, and confirmed ones show up as usual.
+        shown = items.into_iter().map(|item| item.id).collect();
+        sleep(MEMPOOL_POLL_INTERVAL).await;
+    }
+}
+
+/// Keeps watching through reconnects, until something other than losing the peer goes wrong.
+async fn watch_confirmed(context: &WatchContext<'_>) -> anyhow::Result<()> {
+    let config = context.config;
+    let mut backoff = Duration::from_secs(1);
+    let mut first = true;
+
+    loop {
+        let mut peer = match connect(config).await {
+            Ok(peer) => peer,
+            Err(error) => {
+                eprintln!("{error}, retrying in {}", format_duration(backoff));
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_WATCH_BACKOFF);
+                continue;
+            }
+        };
+        backoff = Duration::from_secs(1);
+
+        // The coins watched last time can be subscribed to before anything is paged, so a
+        // spend since then shows up right away.
+        if first {
+            let subscriptions = Subscriptions::load(context.subscriptions_path)?;
+            if !subscriptions.coin_ids.is_empty() {
+                let coin_ids = subscriptions.coin_ids.into_iter().collect::<Vec<_>>();
+                let coin_states = subscribe_coins(&peer, config, &coin_ids).await?;
+                apply_pushed(context, &peer, &coin_states, None).await?;
+            }
+            first = false;
+        }
+
+        match watch_peer(context, &mut peer).await {
+            Err(error) if error.is::<PeerDisconnected>() => eprintln!("{error}, reconnecting"),
+            result => return result,
+        }
+    }
+}
+
+/// Catches up with the peer, then applies the coin states it pushes until it goes away.
+async fn watch_peer(context: &WatchContext<'_>, peer: &mut ProbedPeer) -> anyhow::Result<()> {
+    let mut events = catch_up(context, peer).await?;
+
+    loop {
+        let event = with_heartbeat(peer, context.config, async { Ok(events.recv().await) }).await?;
+
+        match event {
+            Ok(PeerEvent::CoinStateUpdate(update)) => {
+                // Pushes don't move the checkpoints, so a reorg past one means paging again.
+                let reorg = {
+                    let _lock = CacheLock::acquire(context.cache_path, true)?;
+                    CacheWindows::open(context.cache_path, Some(context.intermediate_pk))?
+                        .iter()
+                        .any(|derivations| {
+                            derivations.is_ok_and(|derivations| {
+                                derivations
+                                    .previous_height
+                                    .is_some_and(|height| height > update.fork_height)
+                            })
+                        })
+                };
+
+                if reorg || !apply_pushed(context, peer, &update.items, Some(update.height)).await?
+                {
+                    events = catch_up(context, peer).await?;
+                }
+            }
+            Ok(_) => {}
+            // Some pushes were missed, which only paging again can make up for.
+            Err(RecvError::Lagged(_)) => events = catch_up(context, peer).await?,
+            Err(RecvError::Closed) => {
+                return Err(PeerDisconnected {
+                    uri: peer.uri.clone(),
+                    reason: "the connection was closed".to_string(),
+                }
+                .into())
+            }
+        }
+    }
+}
+
+/// Syncs the cache and subscribes to every puzzle hash and coin it has, returning the events
+/// of the peer from then on. Subscribing pages each window from its checkpoint again, which
+/// is nearly free right after a sync and leaves no gap for a push to fall into.
+async fn catch_up(
+    context: &WatchContext<'_>,
+    peer: &mut ProbedPeer,
+) -> anyhow::Result<Receiver<PeerEvent>> {
+    let config = context.config;
+    let _lock = CacheLock::acquire(context.cache_path, true)?;
+    let mut cache = Cache::load(context.cache_path, Some(context.intermediate_pk))?;
+    let before = snapshot(&cache);
+
+    update_cache(
+        &mut cache,
+        context.cache_path,
+        config,
+        peer,
+        context.intermediate_pk,
+        None,
+        context.timings,
+    )
+    .await?;
+
+    // The sync can switch peers, so only the one that's subscribed to is listened to.
+    let events = peer.peer.receiver().resubscribe();
+
+    let subscribe = async {
+        for derivations in &mut cache.derivations {
+            discover_window(derivations, config, peer, true, context.timings).await?;
+            if !derivations.pending.is_empty() {
+                let puzzle_cache = PuzzleCache::default();
+                resolve_window(derivations, config, peer, &puzzle_cache, context.timings).await?;
+            }
+        }
+
+        let subscriptions = Subscriptions::from_cache(&cache);
+        let coin_ids = subscriptions.coin_ids.iter().copied().collect::<Vec<_>>();
+        let coin_states = subscribe_coins(peer, config, &coin_ids).await?;
+        anyhow::Ok((subscriptions, coin_states))
+    };
+    let (subscriptions, coin_states) = with_heartbeat(peer, config, subscribe).await?;
+
+    check_coin_states(&peer.uri, &coin_states, None, &cache.derivations)?;
+    apply_coin_states(&mut cache, &dust_filtered(config, &coin_states));
+    for index in 0..cache.derivations.len() {
+        save_window(&cache, context.cache_path, index, context.timings)?;
+    }
+    subscriptions.save(context.subscriptions_path)?;
+
+    // The first sync of a wallet would print its whole history.
+    if !before.is_empty() {
+        announce_coin_events(context, peer, &before, &cache).await?;
+    }
+
+    eprintln!(
+        "Watching {} puzzle hashes and {} coins for changes",
+        subscriptions.puzzle_hashes.len(),
+        subscriptions.coin_ids.len()
+    );
+
+    Ok(events)
+}
+
+/// Applies pushed coin states to the cache and prints what changed. Returns false if any of
+/// them belong to coins the cache doesn't know of yet, which takes a sync to place.
+async fn apply_pushed(
+    context: &WatchContext<'_>,
+    peer: &ProbedPeer,
+    coin_states: &[CoinState],
+    peak_height: Option<u32>,
+) -> anyhow::Result<bool> {
+    let _lock = CacheLock::acquire(context.cache_path, true)?;
+    let mut cache = Cache::load(context.cache_path, Some(context.intermediate_pk))?;
+    let before = snapshot(&cache);
+    check_coin_states(&peer.uri, coin_states, peak_height, &cache.derivations)?;
+
+    let Some(changed) = apply_coin_states(&mut cache, &dust_filtered(context.config, coin_states))
+    else {
+        return Ok(false);
+    };
+
+    for index in changed {
+        save_window(&cache, context.cache_path, index, context.timings)?;
+    }
+    announce_coin_events(context, peer, &before, &cache).await?;
+
+    Ok(true)
+}
+
+/// Leaves out the coins a sync would have filtered, so pushes don't add them.
+fn dust_filtered(config: &Config, coin_states: &[CoinState]) -> Vec<CoinState> {
+    let min_amount = config
+        .dust_threshold
+        .max(config.coin_state_filters().min_amount);
+    coin_states
+        .iter()
+        .filter(|coin_state| coin_state.coin.amount >= min_amount)
+        .cloned()
+        .collect()
+}
+
+/// Prints the coins received and spent since the snapshot. When serving or notifying, the
+/// transactions they make up are classified like they would be in a report and published too.
+async fn announce_coin_events(
+    context: &WatchContext<'_>,
+    peer: &ProbedPeer,
+    before: &IndexMap<[u8; 32], Option<u32>>,
+    cache: &Cache,
+) -> anyhow::Result<()> {
+    let config = context.config;
+    let events = coin_events(before, cache);
+    print_coin_events(config, &events);
+
+    let Some(publisher) = context.publisher.filter(|_| !events.is_empty()) else {
+        return Ok(());
+    };
+
+    // Every coin at the heights is needed to net change out of the new transactions.
+    let heights = events
+        .iter()
+        .map(|event| event.height)
+        .collect::<IndexSet<_>>();
+    let mut grouper = TransactionGrouper::with_labels(publisher.labels.clone());
+    grouper.set_max_fee(context.config.self_spends.max_fee);
+    for derivations in &cache.derivations {
+        grouper.add_matching(derivations, |_, coin_state| {
+            [coin_state.created_height, coin_state.spent_height]
+                .into_iter()
+                .flatten()
+                .any(|height| heights.contains(&height))
+        });
+    }
+
+    let timestamps = resolve_timestamps(
+        &peer.peer,
+        heights.iter().copied(),
+        config.concurrency,
+        &config.retry,
+    )
+    .await?;
+    let transactions = grouper
+        .transactions(&timestamps)
+        .filter(|tx| heights.contains(&tx.height))
+        .collect::<Vec<_>>();
+
+    let daily_prices = daily_prices(transactions.iter().cloned(), &publisher.prices, config)
+        .await
+        .unwrap_or_else(|error| {
+            eprintln!("Warning: couldn't price the new transactions: {error}");
+            DailyPrices::default()
+        });
+    publisher
+        .prices
+        .save_cache(PathBuf::from("cache").join(PRICE_CACHE_FILE))?;
+
+    for tx in &transactions {
+        let row = report_row(tx, &daily_prices, publisher.prices.currency(), config);
+
+        if let Some(transactions) = &publisher.transactions {
+            // Sending only fails when no client is connected, and then there is nobody to tell.
+            let _ = transactions.send(serde_json::to_string(&row)?);
+        }
+
+        if let Some(notifier) = publisher.notifier.as_ref().filter(|n| n.wants(&row)) {
+            // A chat being down shouldn't stop the watch.
+            if let Err(error) = notifier.notify(&notify::message(&row)).await {
+                eprintln!("Warning: {error}");
+            }
+        }
+    }
+
+    if !config.notifications.gain_thresholds.is_empty() {
+        let alerted = alert_gains(
+            context,
+            publisher,
+            peer,
+            cache,
+            &transactions,
+            &daily_prices,
+        );
+        // They're worked out again with the next transactions.
+        if let Err(error) = alerted.await {
+            eprintln!("Warning: couldn't work out the gains realized this year: {error}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the new transactions to the gains realized during the year, posting about every
+/// threshold they pass. The gains up to the new transactions are worked out from the cache
+/// the first time, and any thresholds they already passed are skipped. If that fails, it's
+/// tried again with the next transactions.
+async fn alert_gains(
+    context: &WatchContext<'_>,
+    publisher: &Publisher,
+    peer: &ProbedPeer,
+    cache: &Cache,
+    transactions: &[Transaction],
+    daily_prices: &DailyPrices,
+) -> anyhow::Result<()> {
+    let config = context.config;
+    let value = |tx: &Transaction, daily_prices: &DailyPrices| {
+        tx.price(daily_prices, config)
+            .map(|price| price * tx.asset.display_amount(tx.amount))
+    };
+
+    let alerts = publisher.gain_alerts.lock().unwrap().take();
+    let mut alerts = match alerts {
+        Some(alerts) => alerts,
+        None => {
+            let heights = transactions
+                .iter()
+                .map(|tx| tx.height)
+                .collect::<IndexSet<_>>();
+            let mut grouper = TransactionGrouper::with_labels(publisher.labels.clone());
+            grouper.set_max_fee(config.self_spends.max_fee);
+            for derivations in &cache.derivations {
+                grouper.add(derivations);
+            }
+
+            let timestamps = resolve_timestamps(
+                &peer.peer,
+                grouper.heights(),
+                config.concurrency,
+                &config.retry,
+            )
+            .await?;
+            let history = grouper
+                .transactions(&timestamps)
+                .filter(|tx| !heights.contains(&tx.height))
+                .collect::<Vec<_>>();
+
+            // Receives are priced for the basis of their lots, and sends for what they realized.
+            let bounds = year_bounds(publisher.year);
+            let keys = history
+                .iter()
+                .filter(|tx| {
+                    tx.kind == TransactionKind::Receive || bounds.contains(&(tx.timestamp as i64))
+                })
+                .filter_map(|tx| tx.price_key(config))
+                .collect();
+            let history_prices = fetch_prices(keys, &publisher.prices, config.concurrency).await;
+            publisher
+                .prices
+                .save_cache(PathBuf::from("cache").join(PRICE_CACHE_FILE))?;
+            let history_prices = history_prices?;
+
+            let mut alerts = GainAlerts::new(&config.notifications, config.lot_method(), bounds);
+            for tx in &history {
+                alerts.apply(tx, value(tx, &history_prices));
+            }
+            alerts
+        }
+    };
+
+    let currency = publisher.prices.currency().to_uppercase();
+    for tx in transactions {
+        for threshold in alerts.apply(tx, value(tx, daily_prices)) {
+            let message = notify::gain_message(threshold, alerts.gain(), &currency);
+            println!("{message}");
+
+            if let Some(notifier) = &publisher.notifier {
+                if let Err(error) = notifier.notify(&message).await {
+                    eprintln!("Warning: {error}");
+                }
+            }
+        }
+    }
+
+    *publisher.gain_alerts.lock().unwrap() = Some(alerts);
+    Ok(())
+}
+
+fn print_coin_events(config: &Config, events: &[CoinEvent]) {
+    for event in events {
+        let amount = event.coin_state.asset().map_or_else(
+            || event.coin_state.coin.amount.to_string(),
+            |asset| asset.format_amount(event.coin_state.coin.amount),
+        );
+        let kind = match event.kind {
+            CoinEventKind::Received => "Received",
+            CoinEventKind::Spent => "Spent",
+        };
+
+        println!(
+            "{kind} {amount} {} at height {} in coin {}",
+            asset_name(config, &event.coin_state),
+            event.height,
+            hex::encode(event.coin_id)
+        );
+    }
+}
+
+/// Syncs the cache in two phases. Discovery pages the new coin states of every window and
+/// saves them right away, queueing the coins whose parent puzzles are still unknown in their
+/// window. Resolution then works through those queues. Either phase can fail without losing
+/// the other's progress, and the next run picks up the queues where this one stopped.
+async fn update_cache(
+    cache: &mut Cache,
+    cache_path: impl AsRef<Path>,
+    config: &Config,
+    peer: &mut ProbedPeer,
+    intermediate_pk: &PublicKey,
+    start_height: Option<u32>,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let cache_path = cache_path.as_ref();
+    let mut start_height = start_height.or_else(|| recorded_start_height(cache));
+    let mut index = 0;
+
+    // Windows are saved as the config says from now on, which takes a resave of each.
+    let fingerprint = intermediate_pk.get_fingerprint();
+    for index in 0..cache.derivations.len() {
+        let derivations = &mut cache.derivations[index];
+        if derivations.puzzle_hashes.is_empty() {
+            bail!(
+                "Window {index} of {} was saved without its puzzle hashes by another key",
+                cache_path.display()
+            );
+        }
+        let key_fingerprint = config.private_cache.then_some(fingerprint);
+        if derivations.key_fingerprint != key_fingerprint {
+            derivations.key_fingerprint = key_fingerprint;
+            cache.save_window(cache_path, index)?;
+        }
+    }
+    let mut last_probe = Instant::now();
+    let puzzle_cache = PuzzleCache::default();
+    let indexer = Indexer::new(&config.indexer);
+
+    // Each round syncs one window on every peer at once.
+    let mut helpers = if config.sync_peers > 1 {
+        connect_many(config, config.sync_peers)
+            .await?
+            .into_iter()
+            .filter(|helper| helper.uri != peer.uri)
+            .take(config.sync_peers - 1)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    if !helpers.is_empty() {
+        eprintln!("Splitting the sync between {} peers", helpers.len() + 1);
+    }
+
+    loop {
+        reprobe(config, peer, &mut last_probe).await?;
+
+        let round = index..index + 1 + helpers.len();
+
+        for index in round.clone() {
+            eprintln!(
+                "Fetching coin states starting from derivation {}",
+                index * WINDOW_SIZE as usize
+            );
+
+            if cache.derivations.len() <= index {
+                if start_height.is_none() {
+                    start_height = detected_start_height(cache);
+                    if let Some(height) = start_height {
+                        eprintln!(
+                            "The wallet's first coin came after height {height}, so new \
+                             windows start from there"
+                        );
+                    }
+                }
+                let started = Instant::now();
+                let derivations =
+                    new_window(index, config, peer, intermediate_pk, start_height).await?;
+                cache.derivations.push(derivations);
+                timings.record(Phase::Derivation, started, 0);
+
+                save_window(cache, cache_path, index, timings)?;
+            }
+
+            if let Some(indexer) = &indexer {
+                let derivations = &mut cache.derivations[index];
+                if derivations.previous_height.is_none() {
+                    bootstrap_window(derivations, config, peer, indexer).await?;
+                    save_window(cache, cache_path, index, timings)?;
+                }
+            }
+        }
+
+        let peers = iter::once(&mut *peer).chain(helpers.iter_mut());
+        let results = join_all(cache.derivations[round.clone()].iter_mut().zip(peers).map(
+            |(derivations, peer)| {
+                with_reconnects(
+                    SyncStep::Discover,
+                    derivations,
+                    config,
+                    peer,
+                    &puzzle_cache,
+                    timings,
+                )
+            },
+        ))
+        .await;
+
+        for result in results {
+            result?;
+        }
+
+        if !helpers.is_empty() {
+            check_consistency(peer, config, &cache.derivations[round.clone()]).await?;
+        }
+        // Each window was only checked on its own, and the wallet's coins across them can't
+        // add up to more than there is either.
+        check_coin_states(&peer.uri, &[], None, &cache.derivations)?;
+
+        for index in round.clone() {
+            save_window(cache, cache_path, index, timings)?;
+        }
+
+        if cache.derivations[round.clone()]
+            .iter()
+            .any(|derivations| derivations.coin_states.is_empty())
+        {
+            break;
+        }
+
+        index = round.end;
+    }
+
+    let queued = cache
+        .derivations
+        .iter()
+        .enumerate()
+        .filter(|(_, derivations)| !derivations.pending.is_empty())
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    for round in queued.chunks(1 + helpers.len()) {
+        reprobe(config, peer, &mut last_probe).await?;
+
+        let windows = cache
+            .derivations
+            .iter_mut()
+            .enumerate()
+            .filter(|(index, _)| round.contains(index))
+            .map(|(_, derivations)| derivations);
+        let peers = iter::once(&mut *peer).chain(helpers.iter_mut());
+        let results = join_all(windows.zip(peers).map(|(derivations, peer)| {
+            with_reconnects(
+                SyncStep::Resolve,
+                derivations,
+                config,
+                peer,
+                &puzzle_cache,
+                timings,
+            )
+        }))
+        .await;
+
+        for result in results {
+            result?;
+        }
+
+        for &index in round {
+            save_window(cache, cache_path, index, timings)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The start height windows were first synced from, which new windows carry on from, since
+/// the wallet had no activity before it.
+fn recorded_start_height(cache: &Cache) -> Option<u32> {
+    cache
+        .derivations
+        .iter()
+        .find_map(|derivations| derivations.start_height)
+}
+
+/// The block before the first coin of the first window, once it's been synced from genesis.
+/// Addresses are handed out in order, so the later windows had no activity before it either.
+fn detected_start_height(cache: &Cache) -> Option<u32> {
+    let first = cache.derivations.first()?;
+    if first.start_height.is_some() || first.previous_height.is_none() {
+        return None;
+    }
+    first_activity(first.coin_states.values().map(|cs| cs.created_height))
+}
+
+fn first_activity(created_heights: impl Iterator<Item = Option<u32>>) -> Option<u32> {
+    created_heights
+        .flatten()
+        .min()
+        .map(|height| height.saturating_sub(1))
+}
+
+/// Long syncs can outlive the peer that was fastest when they started.
+async fn reprobe(
+    config: &Config,
+    peer: &mut ProbedPeer,
+    last_probe: &mut Instant,
+) -> anyhow::Result<()> {
+    if config.full_node_uris().len() > 1
+        && last_probe.elapsed().as_secs() >= config.peer_reprobe_interval
+    {
+        *peer = connect(config).await?;
+        *last_probe = Instant::now();
+    }
+    Ok(())
+}
+
+/// Derives the puzzle hashes of a window that isn't in the cache yet. With a start height,
+/// the window is paged from that block instead of from genesis.
+async fn new_window(
+    index: usize,
+    config: &Config,
+    peer: &ProbedPeer,
+    intermediate_pk: &PublicKey,
+    start_height: Option<u32>,
+) -> anyhow::Result<Derivations> {
+    let (previous_height, header_hash) = sync_start(config, peer, start_height).await?;
+
+    Ok(Derivations {
+        previous_height,
+        header_hash,
+        start_height,
+        puzzle_hashes: window_puzzle_hashes(intermediate_pk, index),
+        key_fingerprint: config
+            .private_cache
+            .then(|| intermediate_pk.get_fingerprint()),
+        coin_states: IndexMap::new(),
+        pending: IndexSet::new(),
+        timestamps: IndexMap::new(),
+        filters: Some(config.sync_filters()).filter(|filters| *filters != SyncFilters::default()),
+        resync: false,
+    })
+}
+
+/// The height and header hash a window is first paged from, which is genesis without a start
+/// height.
+async fn sync_start(
+    config: &Config,
+    peer: &ProbedPeer,
+    start_height: Option<u32>,
+) -> anyhow::Result<(Option<u32>, [u8; 32])> {
+    match start_height {
+        Some(height) => match header_hash_at(&peer.peer, height, &config.retry).await? {
+            Some(header_hash) => Ok((Some(height), header_hash.to_bytes())),
+            None => bail!("{} hasn't reached the start height {height}", peer.uri),
+        },
+        None => Ok((None, config.genesis_challenge)),
+    }
+}
+
+/// Pages the new coin states of each window like a sync would, but only counts them, then
+/// estimates the parent lookups still to do from how long the paging took and the peer latency.
+async fn estimate_sync(
+    cache: &Cache,
+    config: &Config,
+    peer: &ProbedPeer,
+    intermediate_pk: &PublicKey,
+    start_height: Option<u32>,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let mut start_height = start_height.or_else(|| recorded_start_height(cache));
+    let started = Instant::now();
+    let mut total_coins = 0;
+    let mut total_parents = 0;
+    let mut index = 0;
+
+    loop {
+        let start = index as u32 * WINDOW_SIZE;
+        let derivations = match cache.derivations.get(index) {
+            Some(derivations) => derivations.clone(),
+            None => new_window(index, config, peer, intermediate_pk, start_height).await?,
+        };
+        derivations.check_filters(config.sync_filters())?;
+
+        let (coin_states, _, _) = fetch_coin_states(
+            peer,
+            config.genesis_challenge.into(),
+            derivations.previous_height,
+            derivations.header_hash.into(),
+            derivations.puzzle_hashes.clone(),
+            config.coin_state_filters(),
+            config.coin_states.puzzle_hashes_per_request,
+            false,
+            config.dust_threshold,
+            &config.retry,
+            timings,
+        )
+        .await?;
+
+        // Later windows start from the first coin of the first, like the sync would have them.
+        if index == 0 && start_height.is_none() {
+            start_height = first_activity(
+                derivations
+                    .coin_states
+                    .values()
+                    .map(|cs| cs.created_height)
+                    .chain(coin_states.iter().map(|cs| cs.created_height)),
+            );
+        }
+
+        // Count the same coins a sync would look up parents for.
+        let mut coins = 0;
+        let mut parents = IndexSet::new();
+        for coin_state in &coin_states {
+            let is_own = derivations
+                .puzzle_hashes
+                .contains(&coin_state.coin.puzzle_hash.to_bytes());
+            let existing = derivations
+                .coin_states
+                .get(&coin_state.coin.coin_id().to_bytes());
+            let unchanged =
+                existing.is_some_and(|existing| existing.spent_height == coin_state.spent_height);
+            let summarized = existing.is_some_and(|existing| existing.parent_spend.is_some());
+
+            if is_own || !unchanged {
+                coins += 1;
+            }
+            if (is_own && !summarized) || (!is_own && !unchanged) {
+                parents.insert(coin_state.coin.parent_coin_info);
+            }
+        }
+
+        // Coins left queued by an earlier sync are resolved along with the new ones.
+        for coin_id in &derivations.pending {
+            if let Some(coin_state) = derivations.coin_states.get(coin_id) {
+                parents.insert(coin_state.coin.parent_coin_info.into());
+            }
+        }
+
+        println!(
+            "Derivations {start}-{}: {} cached coins, {coins} new, {} parents to look up",
+            start + WINDOW_SIZE,
+            derivations.coin_states.len(),
+            parents.len()
+        );
+
+        total_coins += coins;
+        total_parents += parents.len();
+
+        if derivations.coin_states.is_empty() && coin_states.is_empty() {
+            break;
+        }
+        index += 1;
+    }
+
+    let paging = started.elapsed();
+    let requests = parent_fetch_requests(total_parents);
+    let mut parent_fetches = peer.latency * requests as u32 / config.concurrency.max(1) as u32;
+    if let Some(rate) = config.rate_limit.requests_per_second {
+        parent_fetches = parent_fetches.max(Duration::from_secs_f64(requests as f64 / rate));
+    }
+
+    println!();
+    println!(
+        "{total_coins} new coins in {} windows, {total_parents} parents to look up in about {requests} requests",
+        index + 1
+    );
+    println!(
+        "The sync would take about {} ({} paging coin states, {} fetching parents)",
+        format_duration(paging + parent_fetches),
+        format_duration(paging),
+        format_duration(parent_fetches)
+    );
+
+    Ok(())
+}
+
+/// A rough duration like `2h 05m` or `40s`, which is all an estimate is good for.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// The two phases of a sync, which each update a window as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyncStep {
+    Discover,
+    Resolve,
+}
+
+/// Runs a phase of the sync on a window, reconnecting and starting the window over if the
+/// peer goes away. Nothing is written to the window until the phase is done with it, so it
+/// can be retried as a whole.
+async fn with_reconnects(
+    step: SyncStep,
+    derivations: &mut Derivations,
+    config: &Config,
+    peer: &mut ProbedPeer,
+    puzzle_cache: &PuzzleCache,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let mut reconnects = 0;
+
+    loop {
+        let result = match step {
+            SyncStep::Discover => {
+                let discover = discover_window(derivations, config, peer, false, timings);
+                with_heartbeat(peer, config, discover).await
+            }
+            SyncStep::Resolve => {
+                let resolve = resolve_window(derivations, config, peer, puzzle_cache, timings);
+                with_heartbeat(peer, config, resolve).await
+            }
+        };
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(error) if error.is::<PeerDisconnected>() && reconnects < MAX_RECONNECTS => {
+                eprintln!("{error}, reconnecting");
+                *peer = connect(config).await?;
+                reconnects += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Fetches the new coin states of a window and records how far the window is synced.
+/// Coins received from others are queued for their parent puzzles to be resolved.
+/// With `subscribe`, the peer pushes later changes to the window's puzzle hashes.
+async fn discover_window(
+    derivations: &mut Derivations,
+    config: &Config,
+    peer: &ProbedPeer,
+    subscribe: bool,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    derivations.check_filters(config.sync_filters())?;
+    if derivations.resync {
+        eprintln!("Syncing a window again from its start, to fetch the coins set aside");
+        (derivations.previous_height, derivations.header_hash) =
+            sync_start(config, peer, derivations.start_height).await?;
+        derivations.resync = false;
+    }
+
+    // Coins locked by a custom puzzle are found by the puzzle hash curried with each owner,
+    // and are the wallet's own without looking up their parents.
+    let custom_puzzle_hashes =
+        CustomPuzzles::from_config(config)?.puzzle_hashes(&derivations.puzzle_hashes);
+
+    let (coin_states, previous_height, previous_header_hash) = fetch_coin_states(
+        peer,
+        config.genesis_challenge.into(),
+        derivations.previous_height,
+        derivations.header_hash.into(),
+        derivations
+            .puzzle_hashes
+            .iter()
+            .chain(custom_puzzle_hashes.keys())
+            .copied(),
+        config.coin_state_filters(),
+        config.coin_states.puzzle_hashes_per_request,
+        subscribe,
+        config.dust_threshold,
+        &config.retry,
+        timings,
+    )
+    .await?;
+    // Batches can finish at different heights, and the lowest is what's returned, so coins
+    // past it aren't necessarily wrong.
+    check_coin_states(
+        &peer.uri,
+        &coin_states,
+        None,
+        slice::from_ref(&*derivations),
+    )?;
+
+    for coin_state in coin_states {
+        let coin_id = coin_state.coin.coin_id().to_bytes();
+        let puzzle_hash = coin_state.coin.puzzle_hash.to_bytes();
+        let custom = custom_puzzle_hashes.get(&puzzle_hash);
+        let is_own = derivations.puzzle_hashes.contains(&puzzle_hash) || custom.is_some();
+        let existing = derivations.coin_states.get(&coin_id);
+
+        // Coins we already have with the same spent height don't need their parents looked up again.
+        if !is_own
+            && existing.is_some_and(|existing| existing.spent_height == coin_state.spent_height)
+        {
+            eprintln!("Skipping existing coin {}", coin_state.coin.coin_id());
+            continue;
+        }
+
+        // The puzzle found before stays until the lookup replaces it.
+        let parent_puzzle = match custom {
+            Some(custom) => Some(PuzzleInfo::Custom(custom.clone())),
+            None => existing
+                .filter(|_| !is_own)
+                .and_then(|existing| existing.parent_puzzle.clone()),
+        };
+        let parent_spend = existing.and_then(|existing| existing.parent_spend.clone());
+
+        // Our own XCH coins are still looked up for what their parent spend created, which
+        // tells a self-spend from a payment. Each is only looked up once.
+        if !is_own || (custom.is_none() && parent_spend.is_none()) {
+            derivations.pending.insert(coin_id);
+        }
+
+        derivations.coin_states.insert(
+            coin_id,
+            CoinStateJson {
+                coin: coin_state.coin.into(),
+                parent_puzzle,
+                parent_spend,
+                created_height: coin_state.created_height,
+                spent_height: coin_state.spent_height,
+            },
+        );
+    }
+
+    derivations.previous_height = Some(previous_height);
+    derivations.header_hash = previous_header_hash.into();
+
+    Ok(())
+}
+
+/// Looks up the parent puzzles of the coins queued in a window, which tell which asset
+/// each coin is, and empties the queue.
+async fn resolve_window(
+    derivations: &mut Derivations,
+    config: &Config,
+    peer: &ProbedPeer,
+    puzzle_cache: &PuzzleCache,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let coins = derivations
+        .pending
+        .iter()
+        .filter_map(|coin_id| Some((*coin_id, derivations.coin_states.get(coin_id)?)))
+        .collect::<Vec<_>>();
+
+    let parents = coins
+        .iter()
+        .filter_map(|(_, coin_state)| {
+            Some((
+                Bytes32::from(coin_state.coin.parent_coin_info),
+                coin_state.created_height?,
+            ))
+        })
+        .collect::<IndexMap<_, _>>();
+
+    eprintln!(
+        "Fetching puzzle data for {} parent coins of {} coins",
+        parents.len(),
+        coins.len()
+    );
+
+    let parent_spends = fetch_parent_spends(
+        &peer.peer,
+        config.genesis_challenge.into(),
+        parents,
+        config.concurrency,
+        &config.retry,
+        timings,
+    )
+    .await?;
+    check_parent_spends(&peer.uri, &parent_spends)?;
+
+    let mut parent_puzzles = Vec::new();
+    for (coin_id, coin_state) in coins {
+        let parent_coin_info = Bytes32::from(coin_state.coin.parent_coin_info);
+        let (parent_puzzle, parent_spend) = match parent_spends.get(&parent_coin_info) {
+            Some(Some(parent_spend)) => {
+                parent_spend.child_puzzle_info(coin_state.coin.clone().into(), puzzle_cache)?
+            }
+            _ => (None, None),
+        };
+        let is_own = derivations
+            .puzzle_hashes
+            .contains(&coin_state.coin.puzzle_hash);
+        parent_puzzles.push((coin_id, is_own, parent_puzzle, parent_spend));
+    }
+
+    for (coin_id, is_own, parent_puzzle, parent_spend) in parent_puzzles {
+        if let Some(coin_state) = derivations.coin_states.get_mut(&coin_id) {
+            // Our own coins are plain XCH whatever their parent was.
+            if !is_own {
+                coin_state.parent_puzzle = parent_puzzle;
+            }
+            coin_state.parent_spend = parent_spend;
+        }
+    }
+    derivations.pending.clear();
+
+    Ok(())
+}
+
+fn save_window(
+    cache: &Cache,
+    cache_path: &Path,
+    index: usize,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    cache.save_window(cache_path, index)?;
+    timings.record(Phase::CacheIo, started, 0);
+    Ok(())
 }
@@ -0,0 +1,60 @@
+use std::time::Instant;
+
+use chia::{client::Peer, protocol::Bytes32, puzzles::singleton::SINGLETON_LAUNCHER_PUZZLE_HASH};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use indexmap::IndexMap;
+
+use crate::{
+    config::RetryConfig,
+    retry::{with_retries, Rejections},
+    timings::{Phase, Timings},
+};
+
+/// Finds the NFTs (or other singletons) launched by spending our coins, keyed by launch height.
+///
+/// A mint spends one of our coins to create a launcher coin in the same block, so the children
+/// of each coin spent at a height are checked for the singleton launcher puzzle hash.
+pub async fn detect_mints(
+    peer: &Peer,
+    spent_coins: impl IntoIterator<Item = (u32, Bytes32)>,
+    concurrency: usize,
+    retry: &RetryConfig,
+    timings: &Timings,
+) -> anyhow::Result<IndexMap<u32, Vec<Bytes32>>> {
+    let launchers: Vec<(u32, Vec<Bytes32>)> = stream::iter(spent_coins)
+        .map(|(height, coin_id)| async move {
+            let started = Instant::now();
+            let children =
+                with_retries(retry, Rejections::Retry, || peer.request_children(coin_id))
+                    .await?
+                    .map_err(|()| {
+                        anyhow::anyhow!("Peer rejected the children request for {coin_id}")
+                    })?;
+            timings.record(Phase::MintDetection, started, 1);
+
+            let launcher_ids = children
+                .into_iter()
+                .filter(|child| {
+                    child.created_height == Some(height)
+                        && child.coin.puzzle_hash == SINGLETON_LAUNCHER_PUZZLE_HASH.into()
+                })
+                .map(|child| child.coin.coin_id())
+                .collect::<Vec<_>>();
+
+            anyhow::Ok((height, launcher_ids))
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    let mut mints = IndexMap::<u32, Vec<Bytes32>>::new();
+
+    for (height, launcher_ids) in launchers {
+        if !launcher_ids.is_empty() {
+            mints.entry(height).or_default().extend(launcher_ids);
+        }
+    }
+
+    mints.sort_keys();
+    Ok(mints)
+}
@@ -0,0 +1,696 @@
+use std::{
+    fs,
+    ops::Range,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use chia::protocol::Bytes32;
+use chia_wallet_sdk::encode_address;
+use chrono::{DateTime, Days, NaiveDate};
+use futures_util::{stream, StreamExt};
+use indexmap::{IndexMap, IndexSet};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    asset::Asset,
+    config::{Config, NftValuationConfig, NftValuationMethod},
+    dexie::{Dexie, DexieSale},
+    read_only::is_read_only,
+    report::{DailyPrices, Transaction, TransactionKind},
+    timings::{Phase, Timings},
+};
+
+/// How long to wait for each off-chain metadata file, since many are hosted on slow gateways.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SECONDS_PER_DAY: f64 = 86_400.0;
+
+/// How many days of a collection's sales up to the day an NFT was received its floor is the
+/// lowest of.
+const FLOOR_DAYS: u64 = 7;
+
+/// The collection an NFT belongs to, from the `collection` field of its CHIP-0007 metadata.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Collection {
+    pub id: Option<String>,
+    pub name: Option<String>,
+}
+
+impl Collection {
+    pub fn label(&self) -> &str {
+        self.name
+            .as_deref()
+            .or(self.id.as_deref())
+            .unwrap_or("unknown")
+    }
+}
+
+/// Off-chain metadata doesn't change once minted, so each NFT's collection is persisted between runs.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CollectionCache {
+    /// Collections keyed by launcher id.
+    pub collections: IndexMap<String, Collection>,
+}
+
+impl CollectionCache {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// A read-only run keeps what it looked up in memory, since this is only a cache.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        if is_read_only() {
+            return Ok(());
+        }
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    pub fn get(&self, launcher_id: Bytes32) -> Option<&Collection> {
+        self.collections.get(&launcher_id.to_string())
+    }
+
+    /// How the config values the NFT's collection when it's received without a payment.
+    pub fn valuation<'a>(
+        &self,
+        launcher_id: Bytes32,
+        config: &'a Config,
+    ) -> Option<&'a NftValuationConfig> {
+        let collection = self.get(launcher_id)?;
+        [&collection.id, &collection.name]
+            .into_iter()
+            .flatten()
+            .find_map(|key| config.nft_valuations.get(key))
+    }
+}
+
+/// Looks up the collection of every NFT that isn't cached yet from its off-chain metadata.
+///
+/// The URIs are tried in order. NFTs whose metadata can't be fetched are left out of the cache,
+/// so they are retried on the next run, and are reported under an unknown collection meanwhile.
+pub async fn resolve_collections(
+    metadata_uris: &IndexMap<Bytes32, Vec<String>>,
+    cache: &mut CollectionCache,
+    concurrency: usize,
+    timings: &Timings,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(METADATA_TIMEOUT)
+        .build()?;
+    let client = &client;
+
+    let resolved: Vec<(Bytes32, Option<Collection>)> = stream::iter(metadata_uris)
+        .filter(|(launcher_id, _)| std::future::ready(cache.get(**launcher_id).is_none()))
+        .map(|(launcher_id, uris)| async move {
+            let started = Instant::now();
+            let collection = fetch_collection(client, uris).await;
+            timings.record(Phase::NftMetadata, started, 1);
+            (*launcher_id, collection)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    for (launcher_id, collection) in resolved {
+        match collection {
+            Some(collection) => {
+                cache
+                    .collections
+                    .insert(launcher_id.to_string(), collection);
+            }
+            None => eprintln!("Couldn't fetch the metadata of NFT {launcher_id}"),
+        }
+    }
+
+    Ok(())
+}
+
+async fn fetch_collection(client: &reqwest::Client, uris: &[String]) -> Option<Collection> {
+    for uri in uris {
+        let Ok(response) = client
+            .get(uri)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+        else {
+            continue;
+        };
+
+        let Ok(metadata) = response.json::<Value>().await else {
+            continue;
+        };
+
+        let collection = &metadata["collection"];
+
+        return Some(Collection {
+            id: collection["id"].as_str().map(str::to_string),
+            name: collection["name"].as_str().map(str::to_string),
+        });
+    }
+    None
+}
+
+/// The sales on Dexie that NFTs received without a payment are appraised by, for the
+/// collections whose valuation has a `dexie_collection`.
+#[derive(Debug, Default, Clone)]
+pub struct MarketSales {
+    /// The sales of each collection by its Dexie id, with the latest first.
+    collections: IndexMap<String, Vec<DexieSale>>,
+    /// The sales of each NFT of the collections valued by their last sale.
+    nfts: IndexMap<Bytes32, Vec<DexieSale>>,
+}
+
+impl MarketSales {
+    /// The lowest price the collection sold for in the days up to the date, and the day of
+    /// that sale.
+    fn floor(&self, collection: &str, date: NaiveDate) -> Option<(NaiveDate, f64)> {
+        let since = date - Days::new(FLOOR_DAYS - 1);
+        self.collections
+            .get(collection)?
+            .iter()
+            .filter(|sale| (since..=date).contains(&sale.completed))
+            .min_by(|a, b| a.price.total_cmp(&b.price))
+            .map(|sale| (sale.completed, sale.price))
+    }
+
+    /// The latest sale of the NFT on or before the date.
+    fn last_sale(&self, launcher_id: Bytes32, date: NaiveDate) -> Option<(NaiveDate, f64)> {
+        self.nfts
+            .get(&launcher_id)?
+            .iter()
+            .find(|sale| sale.completed <= date)
+            .map(|sale| (sale.completed, sale.price))
+    }
+}
+
+/// Looks up the Dexie sales needed to appraise the NFTs received, which are each collection's
+/// sales since the week before the first of its NFTs was received, and for collections valued
+/// by their last sale, those of each NFT received.
+///
+/// Sales that can't be looked up are left out, so those NFTs are appraised without them.
+pub async fn market_sales(
+    transactions: impl Iterator<Item = Transaction>,
+    collections: &CollectionCache,
+    config: &Config,
+    timings: &Timings,
+) -> anyhow::Result<MarketSales> {
+    let mut since = IndexMap::<String, NaiveDate>::new();
+    let mut nfts = IndexMap::<Bytes32, String>::new();
+
+    for tx in transactions.filter(|tx| tx.kind == TransactionKind::Receive) {
+        let Asset::Nft(launcher_id) = tx.asset else {
+            continue;
+        };
+        let Some(valuation) = collections.valuation(launcher_id, config) else {
+            continue;
+        };
+        let Some(collection) = &valuation.dexie_collection else {
+            continue;
+        };
+        if valuation.method == NftValuationMethod::Manual {
+            continue;
+        }
+
+        let date = tx.price_date() - Days::new(FLOOR_DAYS);
+        let first = since.entry(collection.clone()).or_insert(date);
+        *first = (*first).min(date);
+        if valuation.method == NftValuationMethod::LastSale {
+            nfts.insert(launcher_id, encode_address(launcher_id.into(), "nft")?);
+        }
+    }
+
+    let dexie = &Dexie::new(&config.dexie);
+    let lookups = since
+        .into_iter()
+        .map(|(collection, since)| (None, collection, since))
+        .chain(
+            nfts.into_iter()
+                .map(|(launcher_id, nft_id)| (Some(launcher_id), nft_id, NaiveDate::MIN)),
+        );
+
+    let found: Vec<_> = stream::iter(lookups)
+        .map(|(launcher_id, offered, since)| async move {
+            let started = Instant::now();
+            let sales = dexie.sales(&offered, since).await;
+            timings.record(Phase::Pricing, started, 1);
+            (launcher_id, offered, sales)
+        })
+        .buffer_unordered(config.concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut market = MarketSales::default();
+    for (launcher_id, offered, sales) in found {
+        match (launcher_id, sales) {
+            (_, Err(error)) => eprintln!("Couldn't look up the Dexie sales of {offered}: {error}"),
+            (Some(launcher_id), Ok(sales)) => {
+                market.nfts.insert(launcher_id, sales);
+            }
+            (None, Ok(sales)) => {
+                market.collections.insert(offered, sales);
+            }
+        }
+    }
+
+    Ok(market)
+}
+
+/// A single holding of an NFT, from when it was received until it was sent.
+#[derive(Debug, Clone)]
+pub struct NftTrade {
+    pub launcher_id: Bytes32,
+    pub bought_at: Option<u64>,
+    /// The fiat value paid, or [`None`] if what was paid couldn't be priced.
+    pub basis: Option<f64>,
+    pub sold_at: Option<u64>,
+    /// The fiat value received, or [`None`] if what was received couldn't be priced.
+    pub proceeds: Option<f64>,
+    /// How the basis was estimated, if the NFT was received without a payment.
+    pub appraisal: Option<Appraisal>,
+}
+
+/// An estimate of what an NFT received without a payment was worth, kept to show where its
+/// basis came from.
+#[derive(Debug, Clone)]
+pub struct Appraisal {
+    pub method: NftValuationMethod,
+    pub detail: String,
+}
+
+/// The price keys needed to value every NFT trade, including those outside the report's year,
+/// since an NFT sold this year may have been bought in an earlier one.
+///
+/// With NFT valuations in the config, the XCH price of the day each NFT was received is
+/// included too, for valuing them at a floor price.
+pub fn price_keys(
+    transactions: impl Iterator<Item = Transaction>,
+    config: &Config,
+) -> IndexSet<(String, NaiveDate)> {
+    let transactions = transactions.collect::<Vec<_>>();
+
+    let nfts = transactions
+        .iter()
+        .filter(|tx| matches!(tx.asset, Asset::Nft(_)));
+    let heights = nfts.clone().map(|tx| tx.height).collect::<IndexSet<_>>();
+    let floor_keys = nfts
+        .filter(|tx| !config.nft_valuations.is_empty() && tx.kind == TransactionKind::Receive)
+        .filter_map(|tx| Some((Asset::Xch.coingecko_id(config)?, tx.price_date())));
+
+    transactions
+        .iter()
+        .filter(|tx| heights.contains(&tx.height))
+        .filter_map(|tx| tx.price_key(config))
+        .chain(floor_keys)
+        .collect()
+}
+
+/// Pairs each NFT received with when it was sent, valuing both sides by what else moved in the
+/// same block.
+///
+/// The XCH and CATs paid at a height are split evenly between the NFTs received there, and those
+/// received are split between the NFTs sent, which is how offers for NFTs settle. Minted NFTs
+/// with a capitalized cost use that instead. NFTs received without a payment are appraised as
+/// the config says for their collection, and have a zero basis otherwise.
+pub fn nft_trades(
+    transactions: impl Iterator<Item = Transaction>,
+    daily_prices: &DailyPrices,
+    collections: &CollectionCache,
+    market: &MarketSales,
+    config: &Config,
+) -> Vec<NftTrade> {
+    let transactions = transactions.collect::<Vec<_>>();
+    let value = |tx: &Transaction| {
+        tx.price(daily_prices, config)
+            .map(|price| price * tx.asset.display_amount(tx.amount))
+    };
+
+    let mut trades = Vec::<NftTrade>::new();
+    let mut open = IndexMap::<Bytes32, usize>::new();
+
+    for block in transactions.chunk_by(|a, b| a.height == b.height) {
+        let (nfts, payments): (Vec<_>, Vec<_>) = block
+            .iter()
+            .partition(|tx| matches!(tx.asset, Asset::Nft(_)));
+
+        if nfts.is_empty() {
+            continue;
+        }
+
+        let total = |kind: TransactionKind| {
+            payments
+                .iter()
+                .filter(|tx| tx.kind == kind)
+                .map(|tx| value(tx))
+                .sum::<Option<f64>>()
+        };
+        let paid = total(TransactionKind::Send);
+        let received = total(TransactionKind::Receive);
+        let unpaid = !payments.iter().any(|tx| tx.kind == TransactionKind::Send);
+
+        let count = |kind: TransactionKind| {
+            nfts.iter()
+                .filter(|tx| tx.kind == kind && tx.cost.is_none())
+                .count() as f64
+        };
+        let bought = count(TransactionKind::Receive);
+        let sold = count(TransactionKind::Send);
+
+        for tx in nfts {
+            let Asset::Nft(launcher_id) = tx.asset else {
+                continue;
+            };
+
+            match tx.kind {
+                TransactionKind::Receive => {
+                    let appraised = (tx.cost.is_none() && unpaid)
+                        .then(|| collections.valuation(launcher_id, config))
+                        .flatten()
+                        .map(|valuation| {
+                            appraise(
+                                launcher_id,
+                                tx,
+                                valuation,
+                                &trades,
+                                market,
+                                daily_prices,
+                                config,
+                            )
+                        });
+                    let (basis, appraisal) = match appraised {
+                        Some((basis, appraisal)) => (basis, Some(appraisal)),
+                        None if tx.cost.is_some() => (value(tx), None),
+                        None => (paid.map(|paid| paid / bought), None),
+                    };
+
+                    open.insert(launcher_id, trades.len());
+                    trades.push(NftTrade {
+                        launcher_id,
+                        bought_at: Some(tx.timestamp),
+                        basis,
+                        sold_at: None,
+                        proceeds: None,
+                        appraisal,
+                    });
+                }
+                // Fees are only paid in XCH.
+                TransactionKind::Fee => {}
+                TransactionKind::Send => {
+                    let proceeds = received.map(|received| received / sold);
+
+                    if let Some(index) = open.swap_remove(&launcher_id) {
+                        trades[index].sold_at = Some(tx.timestamp);
+                        trades[index].proceeds = proceeds;
+                    } else {
+                        trades.push(NftTrade {
+                            launcher_id,
+                            bought_at: None,
+                            basis: None,
+                            sold_at: Some(tx.timestamp),
+                            proceeds,
+                            appraisal: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    trades
+}
+
+/// Values an NFT received without a payment by the collection's method. An appraisal that
+/// can't find a value, like a floor on a day without an XCH price, leaves the basis unpriced.
+///
+/// Floors in the config take the place of those from Dexie, and a sale in the wallet's own
+/// history comes before one on Dexie.
+fn appraise(
+    launcher_id: Bytes32,
+    tx: &Transaction,
+    valuation: &NftValuationConfig,
+    trades: &[NftTrade],
+    market: &MarketSales,
+    daily_prices: &DailyPrices,
+    config: &Config,
+) -> (Option<f64>, Appraisal) {
+    let date = tx.price_date();
+    let xch_price = Asset::Xch
+        .coingecko_id(config)
+        .and_then(|id| daily_prices.get(&(id, date)).copied());
+    let dexie_floor = || {
+        let collection = valuation.dexie_collection.as_ref()?;
+        let (day, floor) = market.floor(collection, date)?;
+        Some((day, floor, " on Dexie"))
+    };
+    let floor = |method| {
+        let found = valuation
+            .floor_price(date)
+            .map(|(day, floor)| (day, floor, ""))
+            .or_else(dexie_floor);
+        let Some((day, floor, source)) = found else {
+            return (
+                None,
+                Appraisal {
+                    method,
+                    detail: format!("no floor price on or before {date}"),
+                },
+            );
+        };
+        (
+            xch_price.map(|price| price * floor),
+            Appraisal {
+                method,
+                detail: format!("floor of {floor} XCH{source} from {day}"),
+            },
+        )
+    };
+
+    match valuation.method {
+        NftValuationMethod::Manual => (
+            valuation.value,
+            Appraisal {
+                method: NftValuationMethod::Manual,
+                detail: "value from the config".to_string(),
+            },
+        ),
+        NftValuationMethod::Floor => floor(NftValuationMethod::Floor),
+        NftValuationMethod::LastSale => {
+            let last_sale = trades.iter().rev().find_map(|trade| {
+                (trade.launcher_id == launcher_id)
+                    .then_some(trade.sold_at.zip(trade.proceeds))
+                    .flatten()
+            });
+            match (last_sale, market.last_sale(launcher_id, date)) {
+                (Some((sold_at, proceeds)), _) => {
+                    let sold_on = DateTime::from_timestamp(sold_at as i64, 0)
+                        .unwrap_or_default()
+                        .date_naive();
+                    (
+                        Some(proceeds),
+                        Appraisal {
+                            method: NftValuationMethod::LastSale,
+                            detail: format!("sold on {sold_on}"),
+                        },
+                    )
+                }
+                (None, Some((sold_on, price))) => (
+                    xch_price.map(|xch_price| xch_price * price),
+                    Appraisal {
+                        method: NftValuationMethod::LastSale,
+                        detail: format!("sold for {price} XCH on Dexie on {sold_on}"),
+                    },
+                ),
+                (None, None) if valuation.has_floor() => floor(NftValuationMethod::Floor),
+                (None, None) => (
+                    None,
+                    Appraisal {
+                        method: NftValuationMethod::LastSale,
+                        detail: "never sold from the wallet".to_string(),
+                    },
+                ),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CollectionRow {
+    pub collection: String,
+    pub collection_id: Option<String>,
+    /// NFTs received during the year.
+    pub bought: usize,
+    /// NFTs sent during the year, which the proceeds, basis and net are totalled over.
+    pub sold: usize,
+    /// NFTs still held at the end of the year.
+    pub held: usize,
+    pub proceeds: f64,
+    pub basis: f64,
+    pub net: f64,
+    pub average_holding_days: Option<f64>,
+    /// Sales whose proceeds or basis couldn't be valued, which are counted as zero.
+    pub unpriced: usize,
+    pub currency: String,
+}
+
+/// Totals the trades by collection. Profit and loss is realized when an NFT is sold,
+/// so only trades sold within the bounds contribute to the proceeds, basis and net.
+pub fn collection_rows(
+    trades: &[NftTrade],
+    collections: &CollectionCache,
+    bounds: Range<i64>,
+    currency: &str,
+) -> Vec<CollectionRow> {
+    let in_bounds = |timestamp: Option<u64>| {
+        timestamp.is_some_and(|timestamp| bounds.contains(&(timestamp as i64)))
+    };
+
+    // Each row is kept with the total seconds held and the number of sales they're summed over.
+    let mut rows = IndexMap::<Collection, (CollectionRow, u64, usize)>::new();
+    let unknown = Collection::default();
+
+    for trade in trades {
+        let bought = in_bounds(trade.bought_at);
+        let sold = in_bounds(trade.sold_at);
+        let held = trade
+            .bought_at
+            .is_some_and(|timestamp| (timestamp as i64) < bounds.end)
+            && trade
+                .sold_at
+                .is_none_or(|timestamp| (timestamp as i64) >= bounds.end);
+
+        if !bought && !sold && !held {
+            continue;
+        }
+
+        let collection = collections.get(trade.launcher_id).unwrap_or(&unknown);
+        let (row, holding_seconds, holding_count) =
+            rows.entry(collection.clone()).or_insert_with(|| {
+                (
+                    CollectionRow {
+                        collection: collection.label().to_string(),
+                        collection_id: collection.id.clone(),
+                        bought: 0,
+                        sold: 0,
+                        held: 0,
+                        proceeds: 0.0,
+                        basis: 0.0,
+                        net: 0.0,
+                        average_holding_days: None,
+                        unpriced: 0,
+                        currency: currency.to_uppercase(),
+                    },
+                    0,
+                    0,
+                )
+            });
+
+        row.bought += usize::from(bought);
+        row.held += usize::from(held);
+
+        if sold {
+            row.sold += 1;
+            row.proceeds += trade.proceeds.unwrap_or_default();
+            row.basis += trade.basis.unwrap_or_default();
+
+            if trade.proceeds.is_none() || trade.basis.is_none() {
+                row.unpriced += 1;
+            }
+
+            if let (Some(bought_at), Some(sold_at)) = (trade.bought_at, trade.sold_at) {
+                *holding_seconds += sold_at.saturating_sub(bought_at);
+                *holding_count += 1;
+            }
+        }
+    }
+
+    let mut rows = rows
+        .into_values()
+        .map(|(mut row, holding_seconds, holding_count)| {
+            row.net = row.proceeds - row.basis;
+            row.average_holding_days = (holding_count > 0)
+                .then(|| holding_seconds as f64 / SECONDS_PER_DAY / holding_count as f64);
+            row
+        })
+        .collect::<Vec<_>>();
+
+    rows.sort_by(|a, b| a.collection.cmp(&b.collection));
+    rows
+}
+
+/// Writes one row per collection as CSV.
+pub fn write_collection_report(path: &Path, rows: &[CollectionRow]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// An NFT whose basis was appraised rather than paid, so the estimate can be checked.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValuationRow {
+    pub launcher_id: String,
+    pub collection: String,
+    pub received: String,
+    pub method: NftValuationMethod,
+    pub basis: Option<f64>,
+    pub currency: String,
+    pub detail: String,
+}
+
+/// The appraised NFTs received within the bounds, or held into them and sold within them,
+/// since their basis is part of that sale.
+pub fn valuation_rows(
+    trades: &[NftTrade],
+    collections: &CollectionCache,
+    bounds: Range<i64>,
+    currency: &str,
+) -> Vec<ValuationRow> {
+    let in_bounds = |timestamp: Option<u64>| {
+        timestamp.is_some_and(|timestamp| bounds.contains(&(timestamp as i64)))
+    };
+
+    trades
+        .iter()
+        .filter(|trade| in_bounds(trade.bought_at) || in_bounds(trade.sold_at))
+        .filter_map(|trade| {
+            let appraisal = trade.appraisal.as_ref()?;
+            let received = trade
+                .bought_at
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp as i64, 0))
+                .map(|date| date.date_naive().to_string())
+                .unwrap_or_default();
+
+            Some(ValuationRow {
+                launcher_id: trade.launcher_id.to_string(),
+                collection: collections
+                    .get(trade.launcher_id)
+                    .map_or("unknown", Collection::label)
+                    .to_string(),
+                received,
+                method: appraisal.method,
+                basis: trade.basis,
+                currency: currency.to_uppercase(),
+                detail: appraisal.detail.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Writes one row per appraised NFT as CSV.
+pub fn write_valuation_report(path: &Path, rows: &[ValuationRow]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,232 @@
+use std::ops::Range;
+
+use anyhow::bail;
+use serde_json::json;
+
+use crate::{
+    accounting::{LotMethod, LotTracker},
+    config::NotificationsConfig,
+    report::{ReportRow, Transaction, TransactionKind},
+    secrets::{env_or_secret, Secret},
+};
+
+const TELEGRAM_API: &str = "https://api.telegram.org";
+
+/// The category of transactions received to addresses without a label.
+const UNLABELED: &str = "unlabeled";
+
+/// Posts a message about each new transaction to the chats in the config.
+pub struct Notifier {
+    client: reqwest::Client,
+    config: NotificationsConfig,
+    discord_webhook_url: Option<String>,
+    /// The bot's token and the chat it posts to.
+    telegram: Option<(String, String)>,
+}
+
+impl Notifier {
+    /// A notifier for the chats whose secrets are stored, or `None` if there are none.
+    pub fn new(config: &NotificationsConfig) -> anyhow::Result<Option<Self>> {
+        let discord_webhook_url = env_or_secret(Secret::DiscordWebhookUrl)?;
+        let telegram = match (
+            env_or_secret(Secret::TelegramBotToken)?,
+            &config.telegram_chat_id,
+        ) {
+            (Some(token), Some(chat_id)) => Some((token, chat_id.clone())),
+            (None, None) => None,
+            (Some(_), None) => bail!(
+                "The Telegram bot's token is stored, but `notifications.telegram_chat_id` isn't \
+                 set in the config"
+            ),
+            (None, Some(_)) => bail!(
+                "`notifications.telegram_chat_id` is set in the config, but the bot's token isn't \
+                 stored with `thyme config set-secret telegram_bot_token` or in {}",
+                Secret::TelegramBotToken.env_var()
+            ),
+        };
+
+        if discord_webhook_url.is_none() && telegram.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            client: reqwest::Client::new(),
+            config: config.clone(),
+            discord_webhook_url,
+            telegram,
+        }))
+    }
+
+    /// Whether the kind and category of the transaction are turned on.
+    pub fn wants(&self, row: &ReportRow) -> bool {
+        let kind = match row.kind {
+            TransactionKind::Receive => self.config.receive,
+            TransactionKind::Send | TransactionKind::Fee => self.config.send,
+        };
+        let category = row.label.as_deref().unwrap_or(UNLABELED);
+
+        kind && self
+            .config
+            .categories
+            .get(category)
+            .copied()
+            .unwrap_or(true)
+    }
+
+    /// Posts the message to every configured chat. A chat that fails doesn't keep the others
+    /// from being posted to, but is reported once they're done.
+    pub async fn notify(&self, message: &str) -> anyhow::Result<()> {
+        let mut failed = Vec::new();
+
+        if let Some(url) = &self.discord_webhook_url {
+            let response = self
+                .client
+                .post(url)
+                .json(&json!({ "content": message }))
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            // The errors would include the URL, which has the webhook's or bot's token in it.
+            if let Err(error) = response {
+                failed.push(format!("Discord: {}", error.without_url()));
+            }
+        }
+
+        if let Some((token, chat_id)) = &self.telegram {
+            let response = self
+                .client
+                .post(format!("{TELEGRAM_API}/bot{token}/sendMessage"))
+                .json(&json!({ "chat_id": chat_id, "text": message }))
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(error) = response {
+                failed.push(format!("Telegram: {}", error.without_url()));
+            }
+        }
+
+        if !failed.is_empty() {
+            bail!("Couldn't post the notification to {}", failed.join(", "));
+        }
+
+        Ok(())
+    }
+}
+
+/// The gains realized so far in the year, kept up to date as a watch sees transactions, so it
+/// can post when they pass the thresholds in the config.
+pub struct GainAlerts {
+    lots: LotTracker,
+    bounds: Range<i64>,
+    gain: f64,
+    /// The thresholds that haven't been passed yet, lowest first.
+    thresholds: Vec<f64>,
+}
+
+impl GainAlerts {
+    pub fn new(config: &NotificationsConfig, method: LotMethod, bounds: Range<i64>) -> Self {
+        let mut thresholds = config.gain_thresholds.clone();
+        thresholds.sort_by(f64::total_cmp);
+
+        Self {
+            lots: LotTracker::new(method),
+            bounds,
+            gain: 0.0,
+            thresholds,
+        }
+    }
+
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+
+    /// Applies the transaction to the lots, returning the thresholds the gain realized by it
+    /// passed. Transactions must be applied in height order.
+    pub fn apply(&mut self, tx: &Transaction, value: Option<f64>) -> Vec<f64> {
+        let Some(disposal) = self.lots.apply(tx, value) else {
+            return Vec::new();
+        };
+        if !self.bounds.contains(&(tx.timestamp as i64)) {
+            return Vec::new();
+        }
+
+        self.gain += disposal.gain;
+        let passed = self
+            .thresholds
+            .iter()
+            .take_while(|threshold| **threshold <= self.gain)
+            .count();
+        self.thresholds.drain(..passed).collect()
+    }
+}
+
+/// A message like `Realized gains this year passed $10000, and are now $10250`.
+pub fn gain_message(threshold: f64, gain: f64, currency: &str) -> String {
+    format!(
+        "Realized gains this year passed {}, and are now {}",
+        format_value(threshold, currency),
+        format_value(gain, currency)
+    )
+}
+
+/// A message like `Received 2.5 XCH (pool payout, ~$55) at block 6,123,456`.
+pub fn message(row: &ReportRow) -> String {
+    let kind = match row.kind {
+        TransactionKind::Receive => "Received",
+        TransactionKind::Send => "Sent",
+        TransactionKind::Fee => "Paid a fee of",
+    };
+
+    let context = row
+        .label
+        .iter()
+        .cloned()
+        .chain(
+            row.value
+                .map(|value| format!("~{}", format_value(value, &row.currency))),
+        )
+        .collect::<Vec<_>>();
+    let context = if context.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", context.join(", "))
+    };
+
+    format!(
+        "{kind} {} {}{context} at block {}",
+        row.amount,
+        row.asset,
+        group_thousands(row.height)
+    )
+}
+
+/// Rounds to whole units unless the value is small, since the price is only the day's,
+/// with the currency's symbol if it's a common one.
+fn format_value(value: f64, currency: &str) -> String {
+    let amount = if value.abs() < 10.0 {
+        format!("{value:.2}")
+    } else {
+        format!("{value:.0}")
+    };
+
+    match currency {
+        "USD" => format!("${amount}"),
+        "EUR" => format!("€{amount}"),
+        "GBP" => format!("£{amount}"),
+        _ => format!("{amount} {currency}"),
+    }
+}
+
+fn group_thousands(number: u32) -> String {
+    let digits = number.to_string();
+    let mut grouped = String::new();
+
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+
+    grouped
+}
@@ -0,0 +1,153 @@
+use std::{fmt, fs, path::Path};
+
+use chia::{
+    clvm_traits::ToClvm,
+    protocol::Bytes32,
+    puzzles::{cat::CatArgs, offer::SETTLEMENT_PAYMENTS_PUZZLE_HASH},
+};
+use chia_wallet_sdk::{
+    decode_offer, decompress_offer, parse_conditions, run_puzzle, Condition, Offer, SpendContext,
+};
+use clvmr::Allocator;
+use indexmap::IndexMap;
+
+use crate::{asset::Asset, cache::Cache};
+
+/// The assets exchanged by an offer, from the point of view of its maker.
+#[derive(Debug, Default, Clone)]
+pub struct OfferSummary {
+    pub offered: IndexMap<Asset, u64>,
+    pub requested: IndexMap<Asset, u64>,
+    pub fee: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OfferStatus {
+    /// None of the offered coins are in the cache, so it isn't one of our offers.
+    Unknown,
+    /// At least one of our offered coins is still unspent.
+    Open,
+    /// Our offered coins were spent and we received a requested payment at that height.
+    Executed { height: u32 },
+    /// Our offered coins were spent without any requested payment coming back to us.
+    Cancelled { height: u32 },
+}
+
+impl fmt::Display for OfferStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown (no offered coins in cache)"),
+            Self::Open => write!(f, "open"),
+            Self::Executed { height } => write!(f, "executed at height {height}"),
+            Self::Cancelled { height } => write!(f, "cancelled at height {height}"),
+        }
+    }
+}
+
+pub fn load_offer(path: impl AsRef<Path>) -> anyhow::Result<Offer> {
+    parse_offer(&fs::read_to_string(path)?)
+}
+
+/// Decodes an `offer1...` string.
+pub fn parse_offer(text: &str) -> anyhow::Result<Offer> {
+    let bytes = decode_offer(text.trim())?;
+    let spend_bundle = decompress_offer(&bytes)?;
+    let mut ctx = SpendContext::new();
+    Ok(Offer::from_spend_bundle(&mut ctx, spend_bundle)?)
+}
+
+pub fn offer_status(cache: &Cache, offer: &Offer) -> OfferStatus {
+    let offered = offer
+        .offered_coin_spends()
+        .iter()
+        .filter_map(|cs| cache.coin_state(&cs.coin.coin_id().to_bytes()))
+        .collect::<Vec<_>>();
+
+    if offered.is_empty() {
+        return OfferStatus::Unknown;
+    }
+
+    let Some(height) = offered
+        .iter()
+        .map(|cs| cs.spent_height)
+        .collect::<Option<Vec<_>>>()
+        .and_then(|heights| heights.into_iter().max())
+    else {
+        return OfferStatus::Open;
+    };
+
+    let received = offer
+        .requested_payments()
+        .values()
+        .flatten()
+        .flat_map(|np| &np.payments)
+        .any(|payment| {
+            cache.coin_states().any(|cs| {
+                cs.created_height == Some(height)
+                    && cs.coin.amount == payment.amount
+                    && cs.p2_puzzle_hash() == payment.puzzle_hash
+            })
+        });
+
+    if received {
+        OfferStatus::Executed { height }
+    } else {
+        OfferStatus::Cancelled { height }
+    }
+}
+
+/// Whether any of the offered coins are ours, meaning we created the offer.
+pub fn is_maker(cache: &Cache, offer: &Offer) -> bool {
+    offer
+        .offered_coin_spends()
+        .iter()
+        .any(|cs| cache.coin_state(&cs.coin.coin_id().to_bytes()).is_some())
+}
+
+pub fn summarize_offer(offer: &Offer) -> anyhow::Result<OfferSummary> {
+    let mut allocator = Allocator::new();
+    let mut summary = OfferSummary::default();
+
+    for coin_spend in offer.offered_coin_spends() {
+        let puzzle = coin_spend.puzzle_reveal.to_clvm(&mut allocator)?;
+        let solution = coin_spend.solution.to_clvm(&mut allocator)?;
+
+        // Offered amounts are whatever the coin locks up in the settlement puzzle for its asset.
+        let asset = Asset::from_puzzle(&allocator, puzzle);
+        let settlement_puzzle_hash: Bytes32 = match asset {
+            Asset::Xch => SETTLEMENT_PAYMENTS_PUZZLE_HASH.into(),
+            Asset::Cat(asset_id) => {
+                CatArgs::curry_tree_hash(asset_id, SETTLEMENT_PAYMENTS_PUZZLE_HASH).into()
+            }
+            Asset::Nft(..) => {
+                *summary.offered.entry(asset).or_default() += 1;
+                continue;
+            }
+        };
+
+        let output = run_puzzle(&mut allocator, puzzle, solution)?;
+
+        for condition in parse_conditions(&mut allocator, output)? {
+            match condition {
+                Condition::CreateCoin(create_coin)
+                    if create_coin.puzzle_hash == settlement_puzzle_hash =>
+                {
+                    *summary.offered.entry(asset).or_default() += create_coin.amount;
+                }
+                Condition::ReserveFee(reserve_fee) => summary.fee += reserve_fee.amount,
+                _ => {}
+            }
+        }
+    }
+
+    for (puzzle, notarized_payments) in offer.requested_payments() {
+        let puzzle = puzzle.to_clvm(&mut allocator)?;
+        let asset = Asset::from_puzzle(&allocator, puzzle);
+
+        for payment in notarized_payments.iter().flat_map(|np| &np.payments) {
+            *summary.requested.entry(asset).or_default() += payment.amount;
+        }
+    }
+
+    Ok(summary)
+}
@@ -0,0 +1,298 @@
+use std::{num::NonZeroUsize, sync::Mutex, time::Instant};
+
+use anyhow::bail;
+use chia::{
+    client::Peer,
+    clvm_traits::{FromClvm, ToClvm},
+    protocol::{Bytes32, Coin, Program, RejectCoinState, RequestCoinState, RespondCoinState},
+};
+use chia_wallet_sdk::{run_puzzle, Condition, Puzzle};
+use clvmr::{sha2::Sha256, Allocator, NodePtr};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use indexmap::IndexMap;
+use lru::LruCache;
+
+use crate::{
+    cache::{PuzzleInfo, SpendSummaryJson},
+    config::RetryConfig,
+    drivers::DriverRegistry,
+    retry::{with_retries, Rejections},
+    timings::{Phase, Timings},
+};
+
+/// The maximum number of coin ids sent in a single coin state request.
+const COIN_STATE_BATCH_SIZE: usize = 100;
+
+/// The number of puzzle hashes whose classification is remembered.
+const PUZZLE_CACHE_SIZE: NonZeroUsize = match NonZeroUsize::new(4096) {
+    Some(size) => size,
+    None => unreachable!(),
+};
+
+/// Classifies parent puzzles with the drivers, remembering which driver matched each puzzle hash.
+/// Most parents don't match any driver, and those can skip parsing the puzzle entirely.
+pub struct PuzzleCache {
+    drivers: DriverRegistry,
+    /// The index of the driver that matched each puzzle hash, or `None` if none did.
+    classes: Mutex<LruCache<Bytes32, Option<usize>>>,
+}
+
+impl Default for PuzzleCache {
+    fn default() -> Self {
+        Self {
+            drivers: DriverRegistry::default(),
+            classes: Mutex::new(LruCache::new(PUZZLE_CACHE_SIZE)),
+        }
+    }
+}
+
+/// The spend of a parent coin, which determines what kind of asset its children are.
+#[derive(Debug, Clone)]
+pub struct ParentSpend {
+    pub coin: Coin,
+    pub puzzle: Program,
+    pub solution: Program,
+}
+
+impl ParentSpend {
+    /// What kind of coin the child is, and a summary of the spend's conditions if no driver
+    /// recognized the parent.
+    pub fn child_puzzle_info(
+        &self,
+        child: Coin,
+        puzzle_cache: &PuzzleCache,
+    ) -> anyhow::Result<(Option<PuzzleInfo>, Option<SpendSummaryJson>)> {
+        let puzzle_hash = self.coin.puzzle_hash;
+        let class = puzzle_cache
+            .classes
+            .lock()
+            .unwrap()
+            .get(&puzzle_hash)
+            .copied();
+
+        let mut allocator = Allocator::new();
+        let puzzle_ptr = self.puzzle.to_clvm(&mut allocator)?;
+        let parent_solution = self.solution.to_clvm(&mut allocator)?;
+
+        if class == Some(None) {
+            let summary = self.summarize(&mut allocator, puzzle_ptr, parent_solution);
+            return Ok((None, summary));
+        }
+
+        let parent_puzzle = Puzzle::parse(&allocator, puzzle_ptr);
+
+        let class = class.unwrap_or_else(|| {
+            let class = puzzle_cache.drivers.find(parent_puzzle);
+            puzzle_cache.classes.lock().unwrap().put(puzzle_hash, class);
+            class
+        });
+
+        let Some(index) = class else {
+            let summary = self.summarize(&mut allocator, puzzle_ptr, parent_solution);
+            return Ok((None, summary));
+        };
+
+        let info = puzzle_cache.drivers.get(index).child_info(
+            &mut allocator,
+            self.coin,
+            parent_puzzle,
+            parent_solution,
+            child,
+        );
+        Ok((info, None))
+    }
+
+    /// The coins created and signatures required by the spend. A puzzle that fails to run
+    /// has no summary rather than failing the sync, since it was spent on chain regardless.
+    fn summarize(
+        &self,
+        allocator: &mut Allocator,
+        puzzle: NodePtr,
+        solution: NodePtr,
+    ) -> Option<SpendSummaryJson> {
+        let output = run_puzzle(allocator, puzzle, solution).ok()?;
+        let conditions = Vec::<Condition>::from_clvm(allocator, output).ok()?;
+
+        let mut summary = SpendSummaryJson {
+            parent_amount: self.coin.amount,
+            created_coins: 0,
+            created_amount: 0,
+            change_amount: 0,
+            signers: Vec::new(),
+        };
+
+        for condition in conditions {
+            match condition {
+                Condition::CreateCoin(create_coin) => {
+                    summary.created_coins += 1;
+                    summary.created_amount += create_coin.amount;
+                    if create_coin.puzzle_hash == self.coin.puzzle_hash {
+                        summary.change_amount += create_coin.amount;
+                    }
+                }
+                Condition::AggSig(agg_sig) => {
+                    let signer = agg_sig.public_key.to_bytes();
+                    if !summary.signers.contains(&signer) {
+                        summary.signers.push(signer);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Some(summary)
+    }
+
+    /// The text memos the parent attached when creating the child, such as a payment reference.
+    /// Memos that aren't printable text, like the hint, are skipped.
+    pub fn child_memos(&self, child: Coin) -> anyhow::Result<Vec<String>> {
+        let mut allocator = Allocator::new();
+        let puzzle = self.puzzle.to_clvm(&mut allocator)?;
+        let solution = self.solution.to_clvm(&mut allocator)?;
+        let output = run_puzzle(&mut allocator, puzzle, solution)?;
+        let conditions = Vec::<Condition>::from_clvm(&allocator, output)?;
+
+        let memos = conditions
+            .into_iter()
+            .filter_map(|condition| match condition {
+                Condition::CreateCoin(create_coin)
+                    if create_coin.puzzle_hash == child.puzzle_hash
+                        && create_coin.amount == child.amount =>
+                {
+                    Some(create_coin.memos)
+                }
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|memo| String::from_utf8(memo.to_vec()).ok())
+            .filter(|memo| !memo.is_empty() && !memo.chars().any(char::is_control))
+            .collect();
+
+        Ok(memos)
+    }
+
+    /// The ids of the announcements the spend created and of those it asserted. A spend that
+    /// asserts another's announcement had to be in the same spend bundle as it.
+    pub fn announcements(&self) -> anyhow::Result<(Vec<Bytes32>, Vec<Bytes32>)> {
+        let mut allocator = Allocator::new();
+        let puzzle = self.puzzle.to_clvm(&mut allocator)?;
+        let solution = self.solution.to_clvm(&mut allocator)?;
+        let output = run_puzzle(&mut allocator, puzzle, solution)?;
+        let conditions = Vec::<Condition>::from_clvm(&allocator, output)?;
+
+        let announcement_id = |prefix: Bytes32, message: &[u8]| {
+            let mut hasher = Sha256::new();
+            hasher.update(prefix);
+            hasher.update(message);
+            Bytes32::from(hasher.finalize())
+        };
+
+        let mut created = Vec::new();
+        let mut asserted = Vec::new();
+        for condition in conditions {
+            match condition {
+                Condition::CreateCoinAnnouncement(announcement) => {
+                    created.push(announcement_id(self.coin.coin_id(), &announcement.message));
+                }
+                Condition::CreatePuzzleAnnouncement(announcement) => {
+                    created.push(announcement_id(
+                        self.coin.puzzle_hash,
+                        &announcement.message,
+                    ));
+                }
+                Condition::AssertCoinAnnouncement(announcement) => {
+                    asserted.push(announcement.announcement_id);
+                }
+                Condition::AssertPuzzleAnnouncement(announcement) => {
+                    asserted.push(announcement.announcement_id);
+                }
+                _ => {}
+            }
+        }
+
+        Ok((created, asserted))
+    }
+}
+
+/// Fetches the spends of parent coins, keyed by parent coin id with the height they were spent at.
+///
+/// How many requests [`fetch_parent_spends`] sends for this many distinct parents.
+pub fn parent_fetch_requests(parents: usize) -> u64 {
+    (parents + parents.div_ceil(COIN_STATE_BATCH_SIZE)) as u64
+}
+
+/// Coins often share a handful of parents, so each distinct parent is only requested once
+/// and the result is fanned out to all of its children. The parent coin states are requested
+/// in batches rather than one call per coin. Parents the peer rejects map to `None`.
+pub async fn fetch_parent_spends(
+    peer: &Peer,
+    genesis_challenge: Bytes32,
+    parents: IndexMap<Bytes32, u32>,
+    concurrency: usize,
+    retry: &RetryConfig,
+    timings: &Timings,
+) -> anyhow::Result<IndexMap<Bytes32, Option<ParentSpend>>> {
+    let responses: IndexMap<Bytes32, _> = stream::iter(parents)
+        .map(|(coin_id, height)| async move {
+            let started = Instant::now();
+            let response = with_retries(retry, Rejections::Return, || {
+                peer.request_puzzle_and_solution(coin_id, height)
+            })
+            .await?;
+            timings.record(Phase::ParentFetches, started, 1);
+
+            anyhow::Ok((coin_id, response.ok()))
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    let found = responses
+        .iter()
+        .filter(|(_, response)| response.is_some())
+        .map(|(coin_id, _)| *coin_id)
+        .collect::<Vec<_>>();
+
+    let mut coins = IndexMap::new();
+
+    for coin_ids in found.chunks(COIN_STATE_BATCH_SIZE) {
+        let started = Instant::now();
+        let csr = with_retries(retry, Rejections::Retry, || {
+            peer.request_or_reject::<RespondCoinState, RejectCoinState, _>(RequestCoinState {
+                coin_ids: coin_ids.to_vec(),
+                previous_height: None,
+                header_hash: genesis_challenge,
+                subscribe: false,
+            })
+        })
+        .await?
+        .map_err(|_| anyhow::anyhow!("Peer rejected the coin state request for parent coins"))?;
+        timings.record(Phase::ParentFetches, started, 1);
+
+        for coin_state in csr.coin_states {
+            coins.insert(coin_state.coin.coin_id(), coin_state.coin);
+        }
+    }
+
+    responses
+        .into_iter()
+        .map(|(coin_id, response)| {
+            let Some(response) = response else {
+                return Ok((coin_id, None));
+            };
+
+            let Some(coin) = coins.get(&coin_id).copied() else {
+                bail!("Parent coin state not found with id {coin_id}");
+            };
+
+            Ok((
+                coin_id,
+                Some(ParentSpend {
+                    coin,
+                    puzzle: response.puzzle,
+                    solution: response.solution,
+                }),
+            ))
+        })
+        .collect()
+}
@@ -0,0 +1,306 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::atomic::AtomicUsize,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use chia::{
+    client::{Peer, PeerEvent},
+    protocol::{
+        CoinStateFilters, NodeType, RejectPuzzleState, RequestPuzzleState, RespondPuzzleState,
+    },
+};
+use chia_wallet_sdk::{connect_peer, create_tls_connector};
+use futures_util::future::join_all;
+use native_tls::TlsConnector;
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+use crate::{cert::CertPaths, config::Config, retry::set_rate_limit, timestamps::block_timestamp};
+
+/// How long to wait for a peer to announce its peak after the handshake.
+const PEAK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peers whose peak is within this many blocks of the highest one are considered synced.
+const SYNC_TOLERANCE: u32 = 32;
+
+/// A peer whose latest transaction block is older than this is most likely still syncing.
+const MAX_PEAK_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// How long to wait for an answer to a message the peer might not support.
+const CAPABILITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a heartbeat can go unanswered before the peer is considered gone.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A connected peer along with how quickly it responded and how far it is synced.
+pub struct ProbedPeer {
+    pub uri: String,
+    pub peer: Peer,
+    pub latency: Duration,
+    pub peak_height: u32,
+    /// Whether the peer answers `RequestPuzzleState`, which older and lite nodes don't.
+    /// Coin states are fetched with `RegisterForPhUpdates` instead when it doesn't.
+    pub supports_puzzle_state: bool,
+    /// How many puzzle hashes the peer was last asked for at once, which the next window
+    /// starts from, or 0 before the first request.
+    pub puzzle_hashes_per_request: AtomicUsize,
+}
+
+/// The peer closed the connection or stopped responding partway through a run.
+#[derive(Debug)]
+pub struct PeerDisconnected {
+    pub uri: String,
+    pub reason: String,
+}
+
+impl fmt::Display for PeerDisconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Lost the connection to peer {}: {}",
+            self.uri, self.reason
+        )
+    }
+}
+
+impl std::error::Error for PeerDisconnected {}
+
+/// Runs the work while sending the peer a small request at the heartbeat interval, so peers that
+/// drop idle sockets keep the connection open through long phases like fetching parents.
+///
+/// Fails with [`PeerDisconnected`] as soon as the peer goes away, since requests that were in
+/// flight would otherwise wait for an answer forever.
+pub async fn with_heartbeat<T>(
+    peer: &ProbedPeer,
+    config: &Config,
+    work: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    if config.heartbeat_interval == 0 {
+        return work.await;
+    }
+
+    tokio::select! {
+        result = work => result,
+        error = heartbeat(peer, Duration::from_secs(config.heartbeat_interval)) => Err(error.into()),
+    }
+}
+
+async fn heartbeat(probed: &ProbedPeer, period: Duration) -> PeerDisconnected {
+    let disconnected = |reason: &str| PeerDisconnected {
+        uri: probed.uri.clone(),
+        reason: reason.to_string(),
+    };
+
+    let mut events = probed.peer.receiver().resubscribe();
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                if let Err(RecvError::Closed) = event {
+                    return disconnected("the connection was closed");
+                }
+            }
+            _ = ticks.tick() => {
+                let response = timeout(
+                    HEARTBEAT_TIMEOUT,
+                    probed.peer.request_block_header(probed.peak_height),
+                )
+                .await;
+
+                match response {
+                    Ok(Ok(_) | Err(chia::client::Error::Rejection(_))) => {}
+                    Ok(Err(error)) => return disconnected(&error.to_string()),
+                    Err(_) => return disconnected("it stopped responding"),
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the fastest synced peer out of the configured full nodes.
+pub async fn connect(config: &Config) -> anyhow::Result<ProbedPeer> {
+    let best = connect_many(config, 1)
+        .await?
+        .pop()
+        .expect("the highest peer is always synced");
+
+    if config.full_node_uris().len() > 1 {
+        eprintln!(
+            "Using peer {} at height {} ({} ms)",
+            best.uri,
+            best.peak_height,
+            best.latency.as_millis()
+        );
+    }
+
+    Ok(best)
+}
+
+/// Connects to up to `count` of the synced peers out of the configured full nodes, fastest first.
+pub async fn connect_many(config: &Config, count: usize) -> anyhow::Result<Vec<ProbedPeer>> {
+    set_rate_limit(&config.rate_limit);
+
+    // Create and load an SSL certificate and connect to the peer.
+    let cert = CertPaths::new()?.load_or_generate()?;
+    let tls_connector = create_tls_connector(&cert)?;
+
+    let uris = config.full_node_uris();
+
+    let results = join_all(
+        uris.iter()
+            .map(|uri| probe(uri, config, tls_connector.clone())),
+    )
+    .await;
+
+    let mut probed = Vec::new();
+
+    for (uri, result) in uris.iter().zip(results) {
+        match result {
+            Ok(peer) => probed.push(peer),
+            Err(error) if uris.len() == 1 => return Err(error),
+            Err(error) => eprintln!("Skipping peer {uri}: {error}"),
+        }
+    }
+
+    let Some(highest) = probed.iter().map(|peer| peer.peak_height).max() else {
+        bail!("Could not connect to any of the configured peers");
+    };
+
+    probed.retain(|peer| peer.peak_height + SYNC_TOLERANCE >= highest);
+    probed.sort_by_key(|peer| peer.latency);
+    probed.truncate(count.max(1));
+
+    for peer in &probed {
+        check_synced(peer, config).await?;
+    }
+
+    Ok(probed)
+}
+
+/// Makes sure the peer isn't far behind the chain, which would silently give incomplete results.
+/// The peak is checked against the configured minimum height and against the local clock.
+pub async fn check_synced(probed: &ProbedPeer, config: &Config) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+
+    if let Some(min_peak_height) = config.min_peak_height {
+        if probed.peak_height < min_peak_height {
+            problems.push(format!(
+                "its peak height {} is below the configured minimum of {min_peak_height}",
+                probed.peak_height
+            ));
+        }
+    }
+
+    let timestamp = block_timestamp(&probed.peer, probed.peak_height, &config.retry).await?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let age = Duration::from_secs(now.saturating_sub(timestamp));
+
+    if age > MAX_PEAK_AGE {
+        problems.push(format!(
+            "its latest transaction block is {} minutes old",
+            age.as_secs() / 60
+        ));
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "Peer {} looks unsynced: {}",
+        probed.uri,
+        problems.join(", ")
+    );
+
+    if config.allow_unsynced_peer {
+        eprintln!("Warning: {message}");
+        Ok(())
+    } else {
+        bail!("{message}. Set allow_unsynced_peer to continue anyway.");
+    }
+}
+
+/// Connects and handshakes with a peer, then measures a round trip request.
+pub async fn probe(
+    uri: &str,
+    config: &Config,
+    tls_connector: TlsConnector,
+) -> anyhow::Result<ProbedPeer> {
+    let started = Instant::now();
+
+    let (mut peer, peak_height) = open(uri, config, tls_connector.clone()).await?;
+    peer.request_block_header(peak_height).await?;
+    let latency = started.elapsed();
+
+    let supports_puzzle_state = supports_puzzle_state(&peer, config).await;
+
+    // Nodes usually drop the connection when sent a message they don't know.
+    if !supports_puzzle_state {
+        eprintln!(
+            "Peer {uri} doesn't support RequestPuzzleState, falling back to RegisterForPhUpdates"
+        );
+        peer = open(uri, config, tls_connector).await?.0;
+    }
+
+    Ok(ProbedPeer {
+        uri: uri.to_string(),
+        peer,
+        latency,
+        peak_height,
+        supports_puzzle_state,
+        puzzle_hashes_per_request: AtomicUsize::new(0),
+    })
+}
+
+/// Connects and handshakes with a peer, returning it with the peak it announced.
+async fn open(
+    uri: &str,
+    config: &Config,
+    tls_connector: TlsConnector,
+) -> anyhow::Result<(Peer, u32)> {
+    let mut peer = connect_peer(uri, tls_connector).await?;
+    peer.send_handshake(config.network_id.clone(), NodeType::Wallet)
+        .await?;
+
+    // Full nodes announce their peak to wallets right after the handshake.
+    let peak_height = timeout(PEAK_TIMEOUT, async {
+        loop {
+            match peer.receiver_mut().recv().await {
+                Ok(PeerEvent::NewPeakWallet(peak)) => return Ok(peak.height),
+                Ok(_) => continue,
+                Err(error) => bail!("Peer closed before announcing its peak: {error}"),
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Peer did not announce its peak in time"))??;
+
+    Ok((peer, peak_height))
+}
+
+/// Sends an empty puzzle state request, which any answer at all shows the peer understands.
+async fn supports_puzzle_state(peer: &Peer, config: &Config) -> bool {
+    let request = RequestPuzzleState {
+        puzzle_hashes: Vec::new(),
+        previous_height: None,
+        header_hash: config.genesis_challenge.into(),
+        filters: CoinStateFilters::new(true, true, true, 0),
+        subscribe_when_finished: false,
+    };
+
+    let response = timeout(
+        CAPABILITY_TIMEOUT,
+        peer.request_or_reject::<RespondPuzzleState, RejectPuzzleState, _>(request),
+    )
+    .await;
+
+    matches!(response, Ok(Ok(_) | Err(chia::client::Error::Rejection(_))))
+}
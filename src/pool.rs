@@ -0,0 +1,285 @@
+use anyhow::{anyhow, bail};
+use chia::{
+    client::Peer,
+    clvm_traits::{FromClvm, ToClvm},
+    protocol::{
+        Bytes, Bytes32, CoinState, Program, RejectCoinState, RequestCoinState, RespondCoinState,
+    },
+    puzzles::singleton::{SingletonArgs, SINGLETON_TOP_LAYER_PUZZLE_HASH},
+};
+use chia_wallet_sdk::Puzzle;
+use clvmr::{Allocator, NodePtr};
+use indexmap::IndexMap;
+
+use crate::{
+    addresses::address,
+    asset::Asset,
+    config::Config,
+    report::{Transaction, TransactionKind},
+    retry::{with_retries, Rejections},
+};
+
+/// The pool state in a plot NFT's launcher solution is stored under this key.
+const POOL_STATE_KEY: &[u8] = b"p";
+
+/// The pool state's value for a plot NFT in the waiting room, which still pays the old target.
+const LEAVING_POOL: u8 = 2;
+
+/// Where a plot NFT's pool rewards were paid, from the spend that set it until the next one
+/// that changed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolPeriod {
+    pub start_height: u32,
+    /// `None` while the plot NFT still pays there.
+    pub end_height: Option<u32>,
+    /// The puzzle hash rewards are paid to, or `None` if the plot NFT was moved by a spend
+    /// and the new target isn't revealed until it's spent again.
+    pub target: Option<Bytes32>,
+    /// Whether the plot NFT was in the waiting room, leaving the pool it still pays.
+    pub leaving: bool,
+}
+
+impl PoolPeriod {
+    pub fn contains(&self, height: u32) -> bool {
+        height >= self.start_height && self.end_height.is_none_or(|end| height < end)
+    }
+}
+
+/// The target and waiting room state a plot NFT's coin was locked to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PoolTarget {
+    target: Option<Bytes32>,
+    leaving: bool,
+}
+
+/// Follows the plot NFT's singleton from its launcher, returning where its pool rewards were
+/// paid over time, oldest first.
+///
+/// Only spends that changed the singleton's puzzle hash are looked up, since absorbing
+/// rewards leaves it as it was. The new target is read from the puzzle revealed by the
+/// spend after, and from the launcher's pool state for the first one.
+pub async fn pool_history(
+    peer: &Peer,
+    launcher_id: Bytes32,
+    config: &Config,
+) -> anyhow::Result<Vec<PoolPeriod>> {
+    let Some(launcher) = coin_state(peer, launcher_id, config).await? else {
+        bail!("The peer doesn't know the plot NFT launcher {launcher_id}");
+    };
+    let Some(launched) = launcher.spent_height else {
+        bail!("The plot NFT launcher {launcher_id} was never spent");
+    };
+
+    let (_, solution) = puzzle_and_solution(peer, launcher_id, launched, config).await?;
+    let mut current = launcher_target(&solution).unwrap_or(PoolTarget {
+        target: None,
+        leaving: false,
+    });
+    let mut periods = vec![PoolPeriod {
+        start_height: launched,
+        end_height: None,
+        target: current.target,
+        leaving: current.leaving,
+    }];
+
+    let mut coin = launcher;
+    let mut previous_puzzle_hash = None;
+    while let Some(spent_height) = coin.spent_height {
+        let coin_id = coin.coin.coin_id();
+        let children = with_retries(&config.retry, Rejections::Retry, || {
+            peer.request_children(coin_id)
+        })
+        .await?
+        .map_err(|()| anyhow!("Peer rejected the children request for {coin_id}"))?;
+
+        // The singleton is the only odd child, and the rest are the rewards it paid out.
+        let Some(child) = children
+            .into_iter()
+            .find(|child| child.coin.amount % 2 == 1 && child.coin.parent_coin_info == coin_id)
+        else {
+            break;
+        };
+
+        if previous_puzzle_hash.is_some_and(|hash| hash != child.coin.puzzle_hash) {
+            current = match child.spent_height {
+                Some(height) => {
+                    let (puzzle, _) =
+                        puzzle_and_solution(peer, child.coin.coin_id(), height, config).await?;
+                    puzzle_target(&puzzle, config).unwrap_or(PoolTarget {
+                        target: None,
+                        leaving: false,
+                    })
+                }
+                None => PoolTarget {
+                    target: None,
+                    leaving: false,
+                },
+            };
+
+            if let Some(last) = periods.last_mut() {
+                last.end_height = Some(spent_height);
+            }
+            periods.push(PoolPeriod {
+                start_height: spent_height,
+                end_height: None,
+                target: current.target,
+                leaving: current.leaving,
+            });
+        }
+
+        previous_puzzle_hash = Some(child.coin.puzzle_hash);
+        coin = child;
+    }
+
+    // A spend back to the same target, like leaving the waiting room, isn't a change.
+    periods.dedup_by(|next, period| {
+        let same = next.target == period.target && next.leaving == period.leaving;
+        if same {
+            period.end_height = next.end_height;
+        }
+        same
+    });
+
+    Ok(periods)
+}
+
+async fn coin_state(
+    peer: &Peer,
+    coin_id: Bytes32,
+    config: &Config,
+) -> anyhow::Result<Option<CoinState>> {
+    let response = with_retries(&config.retry, Rejections::Retry, || {
+        peer.request_or_reject::<RespondCoinState, RejectCoinState, _>(RequestCoinState {
+            coin_ids: vec![coin_id],
+            previous_height: None,
+            header_hash: config.genesis_challenge.into(),
+            subscribe: false,
+        })
+    })
+    .await?
+    .map_err(|_| anyhow!("Peer rejected the coin state request for {coin_id}"))?;
+
+    Ok(response.coin_states.into_iter().next())
+}
+
+async fn puzzle_and_solution(
+    peer: &Peer,
+    coin_id: Bytes32,
+    height: u32,
+    config: &Config,
+) -> anyhow::Result<(Program, Program)> {
+    let response = with_retries(&config.retry, Rejections::Return, || {
+        peer.request_puzzle_and_solution(coin_id, height)
+    })
+    .await?
+    .map_err(|_| anyhow!("Peer rejected the puzzle request for plot NFT coin {coin_id}"))?;
+
+    Ok((response.puzzle, response.solution))
+}
+
+/// Reads the pool state the launcher was spent with, which is a list of key value pairs
+/// after the singleton's puzzle hash and amount.
+fn launcher_target(solution: &Program) -> Option<PoolTarget> {
+    let mut allocator = Allocator::new();
+    let solution = solution.to_clvm(&mut allocator).ok()?;
+    let (_, (_, (key_values, ()))) =
+        <(NodePtr, (NodePtr, (Vec<(Bytes, Bytes)>, ())))>::from_clvm(&allocator, solution).ok()?;
+
+    // The state is serialized as its version, state, then target puzzle hash.
+    let (_, state) = key_values
+        .into_iter()
+        .find(|(key, _)| key.as_ref() == POOL_STATE_KEY)?;
+    let target = Bytes32::try_from(state.get(2..34)?).ok()?;
+
+    Some(PoolTarget {
+        target: Some(target),
+        leaving: state.get(1) == Some(&LEAVING_POOL),
+    })
+}
+
+/// Reads the target from the pool member or waiting room puzzle inside the singleton, which
+/// are both curried with the target, the singleton's reward puzzle hash, the owner's key and
+/// the network's pool reward prefix. The member puzzle is curried with the waiting room's
+/// puzzle hash last, and the waiting room with its relative lock height.
+fn puzzle_target(puzzle: &Program, config: &Config) -> Option<PoolTarget> {
+    let mut allocator = Allocator::new();
+    let puzzle = puzzle.to_clvm(&mut allocator).ok()?;
+
+    let singleton = Puzzle::parse(&allocator, puzzle).as_curried()?;
+    if singleton.mod_hash != SINGLETON_TOP_LAYER_PUZZLE_HASH {
+        return None;
+    }
+    let args = SingletonArgs::<NodePtr>::from_clvm(&allocator, singleton.args).ok()?;
+    let inner = Puzzle::parse(&allocator, args.inner_puzzle).as_curried()?;
+
+    let (target, (_, (_, (prefix, (last, ()))))) = <(
+        Bytes32,
+        (Bytes32, (NodePtr, (Bytes32, (NodePtr, ())))),
+    )>::from_clvm(&allocator, inner.args)
+    .ok()?;
+
+    let mut reward_prefix = [0; 32];
+    reward_prefix[..16].copy_from_slice(&config.genesis_challenge[..16]);
+    if prefix.to_bytes() != reward_prefix {
+        return None;
+    }
+
+    Some(PoolTarget {
+        target: Some(target),
+        leaving: Bytes32::from_clvm(&allocator, last).is_err(),
+    })
+}
+
+/// The tag of the payouts received during the period, like
+/// `plot nft main: xch1... from height 5123456`, or `... (leaving)` in the waiting room.
+pub fn period_tag(name: &str, period: &PoolPeriod, config: &Config) -> anyhow::Result<String> {
+    let target = match period.target {
+        Some(target) => address(config, target)?,
+        None => "an unrevealed target".to_string(),
+    };
+    let leaving = if period.leaving { " (leaving)" } else { "" };
+    Ok(format!(
+        "plot nft {name}: {target} from height {}{leaving}",
+        period.start_height
+    ))
+}
+
+/// Tags the XCH received to addresses with one of the payout labels with the period of each
+/// plot NFT it was received in, since pools pay out for the plot NFTs pointed at them. A
+/// payout received while several plot NFTs were in pools is tagged with each.
+pub fn payout_tags(
+    transactions: impl Iterator<Item = Transaction>,
+    histories: &IndexMap<String, Vec<PoolPeriod>>,
+    config: &Config,
+) -> anyhow::Result<IndexMap<Bytes32, Vec<String>>> {
+    let mut tags = IndexMap::<Bytes32, Vec<String>>::new();
+
+    for tx in transactions {
+        let is_payout = tx.kind == TransactionKind::Receive
+            && tx.asset == Asset::Xch
+            && tx.label.as_deref().is_some_and(|label| {
+                label.split("; ").any(|label| {
+                    config
+                        .pool
+                        .payout_labels
+                        .iter()
+                        .any(|payout| payout == label)
+                })
+            });
+        if !is_payout {
+            continue;
+        }
+
+        for (name, periods) in histories {
+            let Some(period) = periods.iter().find(|period| period.contains(tx.height)) else {
+                continue;
+            };
+            let tag = period_tag(name, period, config)?;
+            for coin_id in &tx.coin_ids {
+                tags.entry(*coin_id).or_default().push(tag.clone());
+            }
+        }
+    }
+
+    Ok(tags)
+}
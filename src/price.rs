@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, NaiveDate, Utc};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::{Config, PricesConfig},
+    read_only::is_read_only,
+    retry::RateLimiter,
+    secrets::{get_secret, Secret},
+};
+
+const COINGECKO_API: &str = "https://api.coingecko.com/api/v3";
+
+/// Historical prices are fixed once the day is over, so they are persisted between runs.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PriceCache {
+    /// Daily prices keyed by `{coingecko_id}:{currency}:{date}`.
+    pub daily: IndexMap<String, f64>,
+}
+
+impl PriceCache {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+pub struct PriceProvider {
+    client: reqwest::Client,
+    api_key: Option<String>,
+    currency: String,
+    cache: Mutex<PriceCache>,
+    requests: AtomicU64,
+    limiter: Option<RateLimiter>,
+    limits: PricesConfig,
+}
+
+impl PriceProvider {
+    pub fn new(config: &Config, cache: PriceCache) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_key: config.coingecko_api_key.clone().or_else(|| {
+                get_secret(Secret::CoingeckoApiKey).unwrap_or_else(|error| {
+                    eprintln!("Warning: couldn't read coingecko_api_key from the keyring: {error}");
+                    None
+                })
+            }),
+            currency: config.currency.to_lowercase(),
+            cache: Mutex::new(cache),
+            requests: AtomicU64::new(0),
+            limiter: config
+                .prices
+                .requests_per_minute
+                .map(|rate| RateLimiter::new(rate / 60.0, 1)),
+            limits: config.prices.clone(),
+        }
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    /// The number of requests sent to the price API so far, not counting cache hits.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// A read-only run keeps the prices it fetched in memory, since this is only a cache.
+    pub fn save_cache(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        if is_read_only() {
+            return Ok(());
+        }
+        self.cache.lock().unwrap().save(path)
+    }
+
+    pub fn is_cached(&self, id: &str, date: NaiveDate) -> bool {
+        let key = format!("{id}:{}:{date}", self.currency);
+        self.cache.lock().unwrap().daily.contains_key(&key)
+    }
+
+    /// Asks whether to go ahead when the requests are over the budget from the config, with
+    /// how long the rate limit makes them take. Without a terminal to ask on, such as when
+    /// run on a schedule, it only warns.
+    pub fn confirm_requests(&self, requests: u64) -> anyhow::Result<()> {
+        if requests <= self.limits.budget {
+            return Ok(());
+        }
+
+        let duration = match self.limits.requests_per_minute {
+            Some(rate) => format!(", which takes about {:.0} minutes", requests as f64 / rate),
+            None => String::new(),
+        };
+        let message = format!(
+            "Pricing needs about {requests} requests to CoinGecko, over the budget of {}{duration}",
+            self.limits.budget
+        );
+
+        if !io::stdin().is_terminal() {
+            eprintln!("Warning: {message}");
+            return Ok(());
+        }
+
+        eprintln!("{message}. Go ahead? [y/N]");
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            bail!("Stopped before pricing, raise `prices.budget` to skip asking");
+        }
+
+        Ok(())
+    }
+
+    pub async fn current_price(&self, id: &str) -> anyhow::Result<f64> {
+        let url = format!(
+            "{COINGECKO_API}/simple/price?ids={id}&vs_currencies={}",
+            self.currency
+        );
+        let response = self.get(&url).await?;
+        response[id][&self.currency]
+            .as_f64()
+            .ok_or_else(|| anyhow!("No current {} price for {id}", self.currency))
+    }
+
+    pub async fn historical_price(&self, id: &str, date: NaiveDate) -> anyhow::Result<f64> {
+        let key = format!("{id}:{}:{date}", self.currency);
+        if let Some(price) = self.cache.lock().unwrap().daily.get(&key) {
+            return Ok(*price);
+        }
+
+        let url = format!(
+            "{COINGECKO_API}/coins/{id}/history?date={}&localization=false",
+            date.format("%d-%m-%Y")
+        );
+        let response = self.get(&url).await?;
+        let price = response["market_data"]["current_price"][&self.currency]
+            .as_f64()
+            .ok_or_else(|| anyhow!("No {} price for {id} on {date}", self.currency))?;
+
+        self.cache.lock().unwrap().daily.insert(key, price);
+        Ok(price)
+    }
+
+    /// The price on each day from `from` to `to` inclusive, with the days that aren't cached
+    /// fetched in a single request. Days the API has no price for are left out.
+    pub async fn price_range(
+        &self,
+        id: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> anyhow::Result<IndexMap<NaiveDate, f64>> {
+        let key = |date: NaiveDate| format!("{id}:{}:{date}", self.currency);
+        let dates = from
+            .iter_days()
+            .take_while(|date| *date <= to)
+            .collect::<Vec<_>>();
+
+        let mut found = IndexMap::new();
+        let missing = {
+            let cache = self.cache.lock().unwrap();
+            dates
+                .iter()
+                .any(|date| !cache.daily.contains_key(&key(*date)))
+        };
+
+        if missing {
+            let timestamp =
+                |date: NaiveDate| date.and_time(Default::default()).and_utc().timestamp();
+            let url = format!(
+                "{COINGECKO_API}/coins/{id}/market_chart/range?vs_currency={}&from={}&to={}",
+                self.currency,
+                timestamp(from),
+                timestamp(to.succ_opt().unwrap_or(to))
+            );
+            let response = self.get(&url).await?;
+            let points = response["prices"].as_array().ok_or_else(|| {
+                anyhow!("No {} prices for {id} from {from} to {to}", self.currency)
+            })?;
+
+            // Ranges this long come back as one price a day, taken at midnight UTC.
+            for point in points {
+                let (Some(millis), Some(price)) = (point[0].as_i64(), point[1].as_f64()) else {
+                    continue;
+                };
+                if let Some(time) = DateTime::from_timestamp_millis(millis) {
+                    found.entry(time.date_naive()).or_insert(price);
+                }
+            }
+
+            // Today's price isn't final yet, so it isn't cached.
+            let today = Utc::now().date_naive();
+            let mut cache = self.cache.lock().unwrap();
+            for (date, price) in found.iter().filter(|(date, _)| **date < today) {
+                cache.daily.entry(key(*date)).or_insert(*price);
+            }
+        }
+
+        let cache = self.cache.lock().unwrap();
+        Ok(dates
+            .into_iter()
+            .filter_map(|date| {
+                let price = cache.daily.get(&key(date)).or_else(|| found.get(&date))?;
+                Some((date, *price))
+            })
+            .collect())
+    }
+
+    /// Checks that the price API can be reached with the configured key.
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        self.get(&format!("{COINGECKO_API}/ping")).await?;
+        Ok(())
+    }
+
+    async fn get(&self, url: &str) -> anyhow::Result<Value> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut request = self.client.get(url);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("x-cg-demo-api-key", api_key);
+        }
+        Ok(request.send().await?.error_for_status()?.json().await?)
+    }
+}
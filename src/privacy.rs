@@ -0,0 +1,45 @@
+use anyhow::bail;
+use chia::bls::PublicKey;
+use indexmap::IndexSet;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{addresses::derive_puzzle_hash, cache::Derivations, entity::WINDOW_SIZE};
+
+/// The puzzle hashes of the derivations in the window, which overlaps the next one by one.
+pub fn window_puzzle_hashes(intermediate_pk: &PublicKey, index: usize) -> IndexSet<[u8; 32]> {
+    let start = index as u32 * WINDOW_SIZE;
+    (start..=start + WINDOW_SIZE)
+        .into_par_iter()
+        .map(|i| derive_puzzle_hash(intermediate_pk, i).to_bytes())
+        .collect::<Vec<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Derives the puzzle hashes of a window that was saved without them, from the wallet's
+/// intermediate key. Without a key they're left out, for readers that only look at the coins
+/// like `cache diff`, but a window saved by another key can't be read with this one.
+pub fn restore_puzzle_hashes(
+    derivations: &mut Derivations,
+    index: usize,
+    intermediate_pk: Option<&PublicKey>,
+) -> anyhow::Result<()> {
+    let Some(fingerprint) = derivations.key_fingerprint else {
+        return Ok(());
+    };
+    if !derivations.puzzle_hashes.is_empty() {
+        return Ok(());
+    }
+    let Some(intermediate_pk) = intermediate_pk else {
+        return Ok(());
+    };
+    if intermediate_pk.get_fingerprint() != fingerprint {
+        bail!(
+            "Window {index} was saved without its puzzle hashes by the key with fingerprint \
+             {fingerprint}, so they can't be derived again from this one"
+        );
+    }
+
+    derivations.puzzle_hashes = window_puzzle_hashes(intermediate_pk, index);
+    Ok(())
+}
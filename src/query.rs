@@ -0,0 +1,264 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+
+use crate::{asset::Asset, cache::CoinStateJson, config::Config};
+
+/// A filter over cached coins, such as `asset=SBX and spent_height>4500000 and amount>1000`.
+///
+/// Comparisons are joined with `and` and `or`, where `and` binds tighter. There are no parentheses.
+/// Amounts are in the asset's display unit rather than mojos, and heights can be compared to `none`.
+#[derive(Debug, Clone)]
+pub struct Query {
+    /// Alternatives joined by `or`, each of which is a list of comparisons joined by `and`.
+    any: Vec<Vec<Comparison>>,
+}
+
+#[derive(Debug, Clone)]
+struct Comparison {
+    field: Field,
+    op: Op,
+    value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Asset,
+    CoinId,
+    ParentCoinId,
+    PuzzleHash,
+    Amount,
+    CreatedHeight,
+    SpentHeight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl FromStr for Query {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut any = Vec::new();
+        let mut all = Vec::new();
+        let mut expect_comparison = true;
+
+        for token in s.split_whitespace() {
+            match token.to_lowercase().as_str() {
+                "and" | "or" if expect_comparison => {
+                    bail!("Expected a comparison before `{token}`")
+                }
+                "and" => expect_comparison = true,
+                "or" => {
+                    any.push(std::mem::take(&mut all));
+                    expect_comparison = true;
+                }
+                _ if !expect_comparison => bail!("Expected `and` or `or` before `{token}`"),
+                _ => {
+                    all.push(token.parse()?);
+                    expect_comparison = false;
+                }
+            }
+        }
+
+        if expect_comparison {
+            bail!("Query must end with a comparison");
+        }
+
+        any.push(all);
+
+        Ok(Self { any })
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The earliest operator wins, preferring the longer one so `>=` isn't read as `>`.
+        const OPS: [(&str, Op); 6] = [
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("=", Op::Eq),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+
+        let (index, token, op) = OPS
+            .iter()
+            .filter_map(|(token, op)| Some((s.find(token)?, *token, *op)))
+            .min_by_key(|(index, token, _)| (*index, usize::MAX - token.len()))
+            .ok_or_else(|| anyhow!("Expected a comparison such as `amount>1000`, got `{s}`"))?;
+
+        let field = s[..index].parse()?;
+        let value = s[index + token.len()..].to_string();
+
+        if value.is_empty() {
+            bail!("Missing value in `{s}`");
+        }
+
+        Ok(Self { field, op, value })
+    }
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "asset" => Self::Asset,
+            "coin_id" => Self::CoinId,
+            "parent_coin_id" => Self::ParentCoinId,
+            "puzzle_hash" => Self::PuzzleHash,
+            "amount" => Self::Amount,
+            "created_height" => Self::CreatedHeight,
+            "spent_height" => Self::SpentHeight,
+            _ => bail!(
+                "Unknown field `{s}`, expected one of asset, coin_id, parent_coin_id, \
+                 puzzle_hash, amount, created_height, spent_height"
+            ),
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let token = match self {
+            Self::Eq => "=",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        };
+        f.write_str(token)
+    }
+}
+
+impl Query {
+    /// Checks that every value can be compared against its field, so mistakes are reported
+    /// up front rather than silently matching nothing.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for comparison in self.any.iter().flatten() {
+            comparison.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, config: &Config, coin_id: &[u8; 32], coin_state: &CoinStateJson) -> bool {
+        self.any.iter().any(|all| {
+            all.iter()
+                .all(|comparison| comparison.matches(config, coin_id, coin_state))
+        })
+    }
+}
+
+impl Comparison {
+    fn validate(&self) -> anyhow::Result<()> {
+        match self.field {
+            Field::Asset | Field::CoinId | Field::ParentCoinId | Field::PuzzleHash => {
+                if !matches!(self.op, Op::Eq | Op::Ne) {
+                    bail!(
+                        "Only = and != can be used with text fields, got {}",
+                        self.op
+                    );
+                }
+            }
+            Field::Amount => {
+                self.value
+                    .parse::<f64>()
+                    .map_err(|_| anyhow!("Expected a number for amount, got `{}`", self.value))?;
+            }
+            Field::CreatedHeight | Field::SpentHeight => {
+                if self.value.eq_ignore_ascii_case("none") {
+                    if !matches!(self.op, Op::Eq | Op::Ne) {
+                        bail!("Only = and != can be used with none, got {}", self.op);
+                    }
+                } else {
+                    self.value
+                        .parse::<u32>()
+                        .map_err(|_| anyhow!("Expected a height or none, got `{}`", self.value))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, config: &Config, coin_id: &[u8; 32], coin_state: &CoinStateJson) -> bool {
+        match self.field {
+            Field::Asset => {
+                let value = self.value.to_lowercase();
+                let is_match = match coin_state.asset() {
+                    Some(asset @ (Asset::Cat(id) | Asset::Nft(id))) => {
+                        asset.name(config).to_lowercase() == value || hex::encode(id) == value
+                    }
+                    Some(asset) => asset.name(config).to_lowercase() == value,
+                    None => value == "unknown",
+                };
+                self.eq(is_match)
+            }
+            Field::CoinId => self.eq_hex(coin_id),
+            Field::ParentCoinId => self.eq_hex(&coin_state.coin.parent_coin_info),
+            Field::PuzzleHash => self.eq_hex(&coin_state.coin.puzzle_hash),
+            Field::Amount => {
+                let amount = coin_state
+                    .asset()
+                    .map_or(coin_state.coin.amount as f64, |asset| {
+                        asset.display_amount(coin_state.coin.amount)
+                    });
+                self.value
+                    .parse::<f64>()
+                    .is_ok_and(|value| self.compare(amount.partial_cmp(&value)))
+            }
+            Field::CreatedHeight => self.compare_height(coin_state.created_height),
+            Field::SpentHeight => self.compare_height(coin_state.spent_height),
+        }
+    }
+
+    fn eq(&self, is_match: bool) -> bool {
+        match self.op {
+            Op::Eq => is_match,
+            Op::Ne => !is_match,
+            _ => false,
+        }
+    }
+
+    fn eq_hex(&self, bytes: &[u8; 32]) -> bool {
+        let value = self.value.to_lowercase();
+        self.eq(hex::encode(bytes) == value.strip_prefix("0x").unwrap_or(&value))
+    }
+
+    fn compare_height(&self, height: Option<u32>) -> bool {
+        if self.value.eq_ignore_ascii_case("none") {
+            return self.eq(height.is_none());
+        }
+
+        match (height, self.value.parse::<u32>()) {
+            (Some(height), Ok(value)) => self.compare(Some(height.cmp(&value))),
+            // Coins without the height, such as unspent coins for `spent_height`, only match `none`.
+            _ => false,
+        }
+    }
+
+    fn compare(&self, ordering: Option<std::cmp::Ordering>) -> bool {
+        let Some(ordering) = ordering else {
+            return false;
+        };
+        match self.op {
+            Op::Eq => ordering.is_eq(),
+            Op::Ne => ordering.is_ne(),
+            Op::Lt => ordering.is_lt(),
+            Op::Le => ordering.is_le(),
+            Op::Gt => ordering.is_gt(),
+            Op::Ge => ordering.is_ge(),
+        }
+    }
+}
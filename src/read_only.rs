@@ -0,0 +1,46 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Whether the run was started with `--read-only`, which holds for every command.
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// A file that would have been written by a run with `--read-only`.
+#[derive(Debug)]
+pub struct ReadOnly {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Refusing to write {} with --read-only",
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for ReadOnly {}
+
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Relaxed);
+}
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Fails with [`ReadOnly`] if the run is read-only, which every write of the cache, config,
+/// address book and certificates checks first.
+pub fn check_writable(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    if is_read_only() {
+        return Err(ReadOnly {
+            path: path.as_ref().to_path_buf(),
+        }
+        .into());
+    }
+    Ok(())
+}
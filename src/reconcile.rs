@@ -0,0 +1,104 @@
+use chia::protocol::{Bytes32, CoinStateFilters};
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    asset::Asset, cache::CacheWindows, config::Config, fetch::fetch_coin_states, peers::ProbedPeer,
+    timings::Timings,
+};
+
+/// Unspent balances according to the cache compared with a fresh query of the peer.
+#[derive(Debug, Default, Clone)]
+pub struct Reconciliation {
+    pub height: u32,
+    pub cached: IndexMap<Option<Asset>, u64>,
+    pub on_chain: IndexMap<Option<Asset>, u64>,
+    /// Coins the peer reports as unspent that the cache doesn't have as unspent.
+    pub missing: Vec<Bytes32>,
+    /// Coins the cache has as unspent that the peer doesn't report as unspent.
+    pub stale: Vec<Bytes32>,
+}
+
+impl Reconciliation {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty()
+    }
+}
+
+pub async fn reconcile(
+    peer: &ProbedPeer,
+    config: &Config,
+    windows: &CacheWindows,
+) -> anyhow::Result<Reconciliation> {
+    let mut reconciliation = Reconciliation::default();
+
+    for derivations in windows.iter() {
+        let derivations = derivations?;
+
+        let (coin_states, height, _header_hash) = fetch_coin_states(
+            peer,
+            config.genesis_challenge.into(),
+            None,
+            config.genesis_challenge.into(),
+            derivations.puzzle_hashes.clone(),
+            CoinStateFilters::new(
+                false,
+                true,
+                config.coin_states.include_hinted,
+                config.coin_state_filters().min_amount,
+            ),
+            config.coin_states.puzzle_hashes_per_request,
+            false,
+            config.dust_threshold,
+            &config.retry,
+            &Timings::default(),
+        )
+        .await?;
+
+        reconciliation.height = reconciliation.height.max(height);
+
+        // Only compare against cached coins that existed and were unspent at the queried height.
+        let cached = derivations
+            .coin_states
+            .iter()
+            .filter(|(_, cs)| {
+                cs.created_height.is_some_and(|created| created <= height)
+                    && cs.spent_height.is_none_or(|spent| spent > height)
+            })
+            .collect::<IndexMap<_, _>>();
+
+        for coin_state in cached.values() {
+            *reconciliation.cached.entry(coin_state.asset()).or_default() += coin_state.coin.amount;
+        }
+
+        let mut on_chain = IndexSet::new();
+
+        for coin_state in coin_states {
+            let coin_id = coin_state.coin.coin_id();
+            on_chain.insert(coin_id.to_bytes());
+
+            let asset = match derivations.coin_states.get(&coin_id.to_bytes()) {
+                Some(cached) => cached.asset(),
+                None if derivations
+                    .puzzle_hashes
+                    .contains(&coin_state.coin.puzzle_hash.to_bytes()) =>
+                {
+                    Some(Asset::Xch)
+                }
+                None => None,
+            };
+            *reconciliation.on_chain.entry(asset).or_default() += coin_state.coin.amount;
+
+            if !cached.contains_key(&coin_id.to_bytes()) {
+                reconciliation.missing.push(coin_id);
+            }
+        }
+
+        for coin_id in cached.keys() {
+            if !on_chain.contains(*coin_id) {
+                reconciliation.stale.push((**coin_id).into());
+            }
+        }
+    }
+
+    Ok(reconciliation)
+}
@@ -0,0 +1,1199 @@
+use std::{
+    cell::RefCell,
+    io::{self, BufWriter, Write},
+    rc::Rc,
+};
+
+use anyhow::bail;
+use chia::protocol::Bytes32;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use clvmr::sha2::Sha256;
+use flate2::{write::GzEncoder, Compression};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use indexmap::{IndexMap, IndexSet};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    asset::Asset,
+    bookkeeping::{BankFeedStyle, BankFeedWriter},
+    cache::{CoinStateJson, Derivations, PuzzleInfo},
+    config::Config,
+    journal::{JournalStyle, JournalWriter},
+    price::PriceProvider,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionKind {
+    Receive,
+    Send,
+    /// XCH that only moved between the wallet's own coins, like when splitting or merging
+    /// coins or bumping a fee, where what was lost is the network fee.
+    Fee,
+}
+
+/// The net movement of a single asset in or out of the wallet at a given height.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub height: u32,
+    pub timestamp: u64,
+    pub kind: TransactionKind,
+    pub asset: Asset,
+    pub amount: u64,
+    pub coin_ids: Vec<Bytes32>,
+    /// For sends, the coins created back to the wallet in the same block, which are left out of
+    /// `coin_ids` and the amount.
+    pub change_coin_ids: Vec<Bytes32>,
+    /// The total amount of the change coins.
+    pub change: u64,
+    /// What was paid for the asset, which values it instead of its market price.
+    /// This is how mint costs are capitalized into the minted NFT.
+    pub cost: Option<(Asset, u64)>,
+    /// Who sent the coins received, by address book label or cluster id, if they were looked up.
+    pub counterparty: Option<String>,
+    /// The purposes recorded for the addresses the coins were received to, if any.
+    pub label: Option<String>,
+    /// The tags from the config that the coins were tagged with.
+    pub tags: Vec<String>,
+    /// The evidence files from the config for the coins.
+    pub evidence: Vec<String>,
+}
+
+impl Transaction {
+    /// The id of the transaction, which stays the same between runs so it can be referred to.
+    pub fn id(&self) -> Bytes32 {
+        transaction_id(self.coin_ids.iter().chain(&self.change_coin_ids))
+    }
+
+    /// The UTC date, which is what daily price snapshots are keyed by.
+    pub fn price_date(&self) -> NaiveDate {
+        DateTime::from_timestamp(self.timestamp as i64, 0)
+            .unwrap_or_default()
+            .date_naive()
+    }
+
+    /// The daily price needed to value the transaction.
+    pub fn price_key(&self, config: &Config) -> Option<(String, NaiveDate)> {
+        let asset = self.cost.map_or(self.asset, |(asset, _)| asset);
+        Some((asset.coingecko_id(config)?, self.price_date()))
+    }
+
+    /// The fiat price per display unit of the asset, if it's known.
+    pub fn price(&self, daily_prices: &DailyPrices, config: &Config) -> Option<f64> {
+        let price = daily_prices.get(&self.price_key(config)?).copied()?;
+        match self.cost {
+            Some((asset, amount)) => {
+                Some(price * asset.display_amount(amount) / self.asset.display_amount(self.amount))
+            }
+            None => Some(price),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportRow {
+    pub id: String,
+    pub date: String,
+    pub height: u32,
+    #[serde(rename = "type")]
+    pub kind: TransactionKind,
+    pub asset: String,
+    pub amount: String,
+    pub price: Option<f64>,
+    pub value: Option<f64>,
+    pub currency: String,
+    pub coin_ids: String,
+    pub change: Option<String>,
+    pub change_coin_ids: String,
+    pub counterparty: Option<String>,
+    pub label: Option<String>,
+    pub tags: Option<String>,
+    pub evidence: Option<String>,
+    /// Which of the --per-asset reports the row goes to, which is the asset's own except
+    /// for NFTs, since each of them is an asset of its own.
+    #[serde(skip)]
+    pub asset_file: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    #[default]
+    Csv,
+    Json,
+    /// A Beancount journal, using the account mapping from the config.
+    Beancount,
+    /// A ledger-cli journal, using the account mapping from the config.
+    Ledger,
+    /// A bank feed CSV for QuickBooks Online, in fiat with categories from the config.
+    #[value(name = "quickbooks")]
+    QuickBooks,
+    /// A bank statement CSV for Xero, in fiat with account codes from the config.
+    Xero,
+}
+
+impl ReportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+            Self::Beancount => "beancount",
+            Self::Ledger => "ledger",
+            Self::QuickBooks | Self::Xero => "csv",
+        }
+    }
+
+    /// The writer for the format, which finishes the output after its own last rows.
+    pub fn create_writer(
+        self,
+        output: ReportOutput,
+        config: &Config,
+    ) -> anyhow::Result<Box<dyn ReportWriter>> {
+        let writer = self.format_writer(Box::new(output.clone()), config)?;
+        Ok(Box::new(FinishingWriter { writer, output }))
+    }
+
+    fn format_writer(
+        self,
+        output: Box<dyn Write>,
+        config: &Config,
+    ) -> anyhow::Result<Box<dyn ReportWriter>> {
+        let accounts = config.accounts.clone();
+        Ok(match self {
+            Self::Csv => Box::new(CsvReportWriter::new(output)),
+            Self::Json => Box::new(JsonReportWriter::new(output)?),
+            Self::Beancount => Box::new(JournalWriter::new(
+                output,
+                JournalStyle::Beancount,
+                accounts,
+            )),
+            Self::Ledger => Box::new(JournalWriter::new(output, JournalStyle::Ledger, accounts)),
+            Self::QuickBooks => Box::new(BankFeedWriter::new(
+                output,
+                BankFeedStyle::QuickBooks,
+                config.categories.clone(),
+            )?),
+            Self::Xero => Box::new(BankFeedWriter::new(
+                output,
+                BankFeedStyle::Xero,
+                config.categories.clone(),
+            )?),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportCompression {
+    Gz,
+    Zst,
+}
+
+impl ReportCompression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gz => "gz",
+            Self::Zst => "zst",
+        }
+    }
+
+    /// Compresses everything written to the output, until it's finished.
+    pub fn wrap(self, output: Box<dyn Write>) -> anyhow::Result<ReportOutput> {
+        let stream = match self {
+            Self::Gz => Stream::Gz(GzEncoder::new(output, Compression::default())),
+            Self::Zst => Stream::Zst(zstd::Encoder::new(output, 0)?),
+        };
+        Ok(ReportOutput(Rc::new(RefCell::new(Some(stream)))))
+    }
+}
+
+/// Where a report is written, which has to be finished for a compressed stream to be written
+/// to the end. Finishing reports what dropping the stream would ignore, like a full disk.
+///
+/// The report's writer and whatever finishes it share the output.
+#[derive(Clone)]
+pub struct ReportOutput(Rc<RefCell<Option<Stream>>>);
+
+enum Stream {
+    Plain(Box<dyn Write>),
+    Gz(GzEncoder<Box<dyn Write>>),
+    Zst(zstd::Encoder<'static, Box<dyn Write>>),
+}
+
+impl ReportOutput {
+    pub fn plain(output: Box<dyn Write>) -> Self {
+        Self(Rc::new(RefCell::new(Some(Stream::Plain(output)))))
+    }
+
+    /// Ends the stream and flushes it. Nothing can be written after.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let mut output = match self.0.borrow_mut().take() {
+            Some(Stream::Plain(output)) => output,
+            Some(Stream::Gz(encoder)) => encoder.finish()?,
+            Some(Stream::Zst(encoder)) => encoder.finish()?,
+            None => return Ok(()),
+        };
+        output.flush()?;
+        Ok(())
+    }
+
+    fn with_stream<T>(&self, f: impl FnOnce(&mut dyn Write) -> io::Result<T>) -> io::Result<T> {
+        match self.0.borrow_mut().as_mut() {
+            Some(Stream::Plain(output)) => f(output),
+            Some(Stream::Gz(encoder)) => f(encoder),
+            Some(Stream::Zst(encoder)) => f(encoder),
+            None => Err(io::Error::other("The report was already finished")),
+        }
+    }
+}
+
+impl Write for ReportOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_stream(|output| output.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_stream(|output| output.flush())
+    }
+}
+
+/// Finishes the output once the format's writer has written its end.
+struct FinishingWriter {
+    writer: Box<dyn ReportWriter>,
+    output: ReportOutput,
+}
+
+impl ReportWriter for FinishingWriter {
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        self.writer.write_row(row)
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.finish()?;
+        self.output.finish()
+    }
+}
+
+/// Fills in a report file name template such as `report-{fingerprint}-{year}.{format}`.
+pub fn report_file_name(
+    template: &str,
+    fingerprint: u32,
+    year: i32,
+    entity: Option<&str>,
+    currency: &str,
+    format: ReportFormat,
+) -> anyhow::Result<String> {
+    let mut name = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed placeholder in file name template `{template}`");
+        };
+        match &rest[start + 1..start + end] {
+            "fingerprint" => name.push_str(&fingerprint.to_string()),
+            "year" => name.push_str(&year.to_string()),
+            "entity" => name.push_str(entity.unwrap_or("wallet")),
+            "currency" => name.push_str(&currency.to_uppercase()),
+            "format" => name.push_str(format.extension()),
+            placeholder => bail!(
+                "Unknown placeholder `{{{placeholder}}}` in file name template, \
+                 expected {{fingerprint}}, {{year}}, {{entity}}, {{currency}} or {{format}}"
+            ),
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// The longest range of days whose prices are fetched with one request. Longer ones are
+/// fetched a day at a time, since the free API plan only goes a year back for ranges.
+const MAX_RANGE_DAYS: i64 = 365;
+
+/// The number of rows formatted in parallel before being written out.
+const CHUNK_SIZE: usize = 4096;
+
+/// A hash of every coin spent or created in a transaction, sorted and counted once, so it
+/// doesn't depend on the order the coins were cached in.
+fn transaction_id<'a>(coin_ids: impl Iterator<Item = &'a Bytes32>) -> Bytes32 {
+    let mut coin_ids = coin_ids.collect::<Vec<_>>();
+    coin_ids.sort();
+    coin_ids.dedup();
+
+    let mut hasher = Sha256::new();
+    for coin_id in coin_ids {
+        hasher.update(coin_id);
+    }
+    Bytes32::new(hasher.finalize())
+}
+
+pub type DailyPrices = IndexMap<(String, NaiveDate), f64>;
+
+/// Writes report rows to an output format one at a time, so reports don't have to fit in memory.
+pub trait ReportWriter {
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()>;
+
+    fn finish(&mut self) -> anyhow::Result<()>;
+}
+
+pub struct CsvReportWriter {
+    writer: csv::Writer<Box<dyn Write>>,
+}
+
+impl CsvReportWriter {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(output),
+        }
+    }
+}
+
+impl ReportWriter for CsvReportWriter {
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        self.writer.serialize(row)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the report as a single JSON document for programmatic consumers.
+///
+/// The document has the following shape, and `schema_version` is bumped on breaking changes:
+///
+/// ```json
+/// {
+///   "schema_version": 2,
+///   "transactions": [
+///     {
+///       "date": "2024-03-01 12:00:00",
+///       "height": 5000000,
+///       "type": "receive",
+///       "asset": "XCH",
+///       "amount": "1.000000000000",
+///       "price": 30.5,
+///       "value": 30.5,
+///       "currency": "USD",
+///       "coin_ids": ["9f8e..."],
+///       "change": null,
+///       "change_coin_ids": [],
+///       "counterparty": "cluster-1a2b3c4d",
+///       "label": "Invoice 42",
+///       "tags": "project-a",
+///       "evidence": "invoices/42.pdf"
+///     }
+///   ],
+///   "summary": {
+///     "transactions": 1,
+///     "assets": {
+///       "XCH": {
+///         "receives": 1,
+///         "sends": 0,
+///         "fees": 0,
+///         "received_value": 30.5,
+///         "sent_value": 0.0,
+///         "fee_value": 0.0,
+///         "unpriced": 0
+///       }
+///     }
+///   }
+/// }
+/// ```
+///
+/// Dates are in local time, amounts are decimal strings in the asset's display unit so no precision
+/// is lost, and `price` and `value` are `null` when no price was available. `label` is the purpose
+/// recorded for the receiving address with `thyme address`, or `null`, and `tags` are the tags from
+/// the config joined by `; `, or `null`, as are the `evidence` files from the config. Sends and fees list the coins that returned to the wallet
+/// as change separately, with their total as `change`. `counterparty` is only looked up with
+/// `--counterparties`.
+pub struct JsonReportWriter {
+    writer: BufWriter<Box<dyn Write>>,
+    summary: ReportSummary,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+struct ReportSummary {
+    transactions: usize,
+    assets: IndexMap<String, AssetSummary>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+struct AssetSummary {
+    receives: usize,
+    sends: usize,
+    fees: usize,
+    received_value: f64,
+    sent_value: f64,
+    fee_value: f64,
+    unpriced: usize,
+}
+
+/// Bumped whenever the JSON report changes in a way that could break consumers.
+const JSON_SCHEMA_VERSION: u32 = 2;
+
+impl JsonReportWriter {
+    pub fn new(output: Box<dyn Write>) -> anyhow::Result<Self> {
+        let mut writer = BufWriter::new(output);
+        write!(
+            writer,
+            "{{\"schema_version\":{JSON_SCHEMA_VERSION},\"transactions\":["
+        )?;
+        Ok(Self {
+            writer,
+            summary: ReportSummary::default(),
+        })
+    }
+}
+
+impl ReportWriter for JsonReportWriter {
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        if self.summary.transactions > 0 {
+            self.writer.write_all(b",")?;
+        }
+
+        let mut value = serde_json::to_value(row)?;
+        value["coin_ids"] = row.coin_ids.split_whitespace().map(Value::from).collect();
+        value["change_coin_ids"] = row
+            .change_coin_ids
+            .split_whitespace()
+            .map(Value::from)
+            .collect();
+        serde_json::to_writer(&mut self.writer, &value)?;
+
+        self.summary.transactions += 1;
+        let asset = self.summary.assets.entry(row.asset.clone()).or_default();
+        match row.kind {
+            TransactionKind::Receive => {
+                asset.receives += 1;
+                asset.received_value += row.value.unwrap_or_default();
+            }
+            TransactionKind::Send => {
+                asset.sends += 1;
+                asset.sent_value += row.value.unwrap_or_default();
+            }
+            TransactionKind::Fee => {
+                asset.fees += 1;
+                asset.fee_value += row.value.unwrap_or_default();
+            }
+        }
+        if row.value.is_none() {
+            asset.unpriced += 1;
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        write!(self.writer, "],\"summary\":")?;
+        serde_json::to_writer(&mut self.writer, &self.summary)?;
+        writeln!(self.writer, "}}")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes every row to a combined report, and to a separate report for the row's asset, with
+/// every NFT sharing one. The per-asset writers are created the first time each shows up.
+pub struct SplitReportWriter<F> {
+    combined: Box<dyn ReportWriter>,
+    per_asset: IndexMap<String, Box<dyn ReportWriter>>,
+    create: F,
+}
+
+impl<F> SplitReportWriter<F>
+where
+    F: FnMut(&str) -> anyhow::Result<Box<dyn ReportWriter>>,
+{
+    pub fn new(combined: Box<dyn ReportWriter>, create: F) -> Self {
+        Self {
+            combined,
+            per_asset: IndexMap::new(),
+            create,
+        }
+    }
+}
+
+impl<F> ReportWriter for SplitReportWriter<F>
+where
+    F: FnMut(&str) -> anyhow::Result<Box<dyn ReportWriter>>,
+{
+    fn write_row(&mut self, row: &ReportRow) -> anyhow::Result<()> {
+        self.combined.write_row(row)?;
+
+        if !self.per_asset.contains_key(&row.asset_file) {
+            let writer = (self.create)(&row.asset_file)?;
+            self.per_asset.insert(row.asset_file.clone(), writer);
+        }
+
+        self.per_asset[&row.asset_file].write_row(row)
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.combined.finish()?;
+        for writer in self.per_asset.values_mut() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a coin of the wallet isn't spendable yet, although it counts toward the holdings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Commitment {
+    /// Offered in an offer that hasn't been accepted or cancelled.
+    Offer,
+    /// Hinted to the wallet but locked to a puzzle outside it, like a clawback that can't be
+    /// claimed yet or hasn't been.
+    Unclaimed,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommittedCoin {
+    pub coin_id: Bytes32,
+    pub asset: Asset,
+    pub amount: u64,
+    pub commitment: Commitment,
+    created_height: u32,
+    spent_height: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Flow {
+    received: u64,
+    spent: u64,
+    coin_ids: Vec<Bytes32>,
+    /// The coins created at the height, which are change if the asset was sent.
+    created: IndexSet<Bytes32>,
+    /// The coins spent at the height.
+    spent_ids: IndexSet<Bytes32>,
+    /// How much of what each parent spend created came back to the wallet's puzzle hashes, by
+    /// parent coin id, with the spend's total if it was summarized.
+    returned: IndexMap<Bytes32, (u64, Option<u64>)>,
+    cost: Option<(Asset, u64)>,
+    labels: IndexSet<String>,
+}
+
+impl Flow {
+    /// Whether every coin created at the height came from a coin spent at it, and every
+    /// spend's outputs all came back to the wallet, so nothing was paid to anyone else.
+    fn is_self_spend(&self) -> bool {
+        self.returned
+            .keys()
+            .all(|parent| self.spent_ids.contains(parent))
+            && self.spent_ids.iter().all(|coin_id| {
+                self.returned
+                    .get(coin_id)
+                    .is_some_and(|(amount, created)| *created == Some(*amount))
+            })
+    }
+}
+
+/// Pairs the coins created and spent at each height into one transaction per asset.
+/// Coins spent and created at the same height are netted, so change doesn't show up as income.
+///
+/// Windows are added one at a time and only the amounts and coin ids are kept,
+/// so the full cache never needs to be in memory to build a report.
+#[derive(Debug, Default, Clone)]
+pub struct TransactionGrouper {
+    flows: IndexMap<(u32, Asset), Flow>,
+    nft_metadata_uris: IndexMap<Bytes32, Vec<String>>,
+    /// Labels for coins received to these puzzle hashes.
+    labels: IndexMap<Bytes32, String>,
+    /// Who sent each received coin, by coin id.
+    counterparties: IndexMap<Bytes32, String>,
+    /// The tags of each coin, by coin id.
+    tags: IndexMap<Bytes32, Vec<String>>,
+    /// The files backing up each coin, by coin id.
+    evidence: IndexMap<Bytes32, Vec<String>>,
+    /// The most XCH a spend sending everything back to the wallet can lose and be reported
+    /// as a fee.
+    max_fee: u64,
+    /// Coins offered in open offers, which are committed until they're spent.
+    offered: IndexSet<Bytes32>,
+    committed: Vec<CommittedCoin>,
+    /// The height and id of each coin received to each of the wallet's puzzle hashes.
+    receipts: IndexMap<Bytes32, Vec<(u32, Bytes32)>>,
+}
+
+impl TransactionGrouper {
+    pub fn with_labels(labels: IndexMap<Bytes32, String>) -> Self {
+        Self {
+            labels,
+            ..Default::default()
+        }
+    }
+
+    /// Names the sender of each of these received coins, by coin id.
+    pub fn set_counterparties(&mut self, counterparties: IndexMap<Bytes32, String>) {
+        self.counterparties = counterparties;
+    }
+
+    /// Reports XCH spends whose outputs all came back to the wallet and lose no more than
+    /// this as fees.
+    pub fn set_max_fee(&mut self, max_fee: u64) {
+        self.max_fee = max_fee;
+    }
+
+    /// Marks these coins as offered, which has to be done before their windows are added.
+    pub fn set_offered(&mut self, coin_ids: IndexSet<Bytes32>) {
+        self.offered = coin_ids;
+    }
+
+    /// Tags the transactions of these coins, by coin id.
+    pub fn set_tags(&mut self, tags: IndexMap<Bytes32, Vec<String>>) {
+        self.tags = tags;
+    }
+
+    /// Links the transactions of these coins to the files backing them up, by coin id.
+    pub fn set_evidence(&mut self, evidence: IndexMap<Bytes32, Vec<String>>) {
+        self.evidence = evidence;
+    }
+
+    /// Adds evidence to each of these coins, next to the files from the config.
+    pub fn add_evidence(&mut self, evidence: &IndexMap<Bytes32, Vec<String>>) {
+        for (coin_id, added) in evidence {
+            let coin_evidence = self.evidence.entry(*coin_id).or_default();
+            for file in added {
+                if !coin_evidence.contains(file) {
+                    coin_evidence.push(file.clone());
+                }
+            }
+        }
+    }
+
+    /// Adds tags to each of these coins, next to those from the config.
+    pub fn add_tags(&mut self, tags: &IndexMap<Bytes32, Vec<String>>) {
+        for (coin_id, added) in tags {
+            let coin_tags = self.tags.entry(*coin_id).or_default();
+            for tag in added {
+                if !coin_tags.contains(tag) {
+                    coin_tags.push(tag.clone());
+                }
+            }
+        }
+    }
+
+    pub fn add(&mut self, derivations: &Derivations) {
+        self.add_matching(derivations, |_, _| true);
+    }
+
+    /// Adds only the coins accepted by the filter, such as those belonging to one entity.
+    pub fn add_matching(
+        &mut self,
+        derivations: &Derivations,
+        filter: impl Fn(&[u8; 32], &CoinStateJson) -> bool,
+    ) {
+        for (coin_id, coin_state) in &derivations.coin_states {
+            if !filter(coin_id, coin_state) {
+                continue;
+            }
+
+            let Some(asset) = coin_state.asset() else {
+                continue;
+            };
+
+            if let Some(PuzzleInfo::Nft(nft)) = &coin_state.parent_puzzle {
+                if !nft.metadata_uris.is_empty() {
+                    self.nft_metadata_uris
+                        .insert(nft.launcher_id.into(), nft.metadata_uris.clone());
+                }
+            }
+
+            let p2_puzzle_hash = coin_state.p2_puzzle_hash();
+            let commitment = if self.offered.contains(&Bytes32::from(*coin_id)) {
+                Some(Commitment::Offer)
+            } else if !derivations
+                .puzzle_hashes
+                .contains(&p2_puzzle_hash.to_bytes())
+            {
+                Some(Commitment::Unclaimed)
+            } else {
+                None
+            };
+            if let (Some(commitment), Some(created_height)) =
+                (commitment, coin_state.created_height)
+            {
+                self.committed.push(CommittedCoin {
+                    coin_id: (*coin_id).into(),
+                    asset,
+                    amount: coin_state.coin.amount,
+                    commitment,
+                    created_height,
+                    spent_height: coin_state.spent_height,
+                });
+            }
+
+            if let (Some(height), None | Some(Commitment::Offer)) =
+                (coin_state.created_height, commitment)
+            {
+                self.receipts
+                    .entry(p2_puzzle_hash)
+                    .or_default()
+                    .push((height, (*coin_id).into()));
+            }
+
+            if let Some(height) = coin_state.created_height {
+                let flow = self.flows.entry((height, asset)).or_default();
+                flow.received += coin_state.coin.amount;
+                flow.coin_ids.push((*coin_id).into());
+                flow.created.insert((*coin_id).into());
+                if commitment != Some(Commitment::Unclaimed) {
+                    let created_amount = coin_state
+                        .parent_spend
+                        .as_ref()
+                        .map(|spend| spend.created_amount);
+                    let returned = flow
+                        .returned
+                        .entry(coin_state.coin.parent_coin_info.into())
+                        .or_insert((0, created_amount));
+                    returned.0 += coin_state.coin.amount;
+                }
+                if let Some(label) = self.labels.get(&p2_puzzle_hash) {
+                    flow.labels.insert(label.clone());
+                }
+            }
+
+            if let Some(height) = coin_state.spent_height {
+                let flow = self.flows.entry((height, asset)).or_default();
+                flow.spent += coin_state.coin.amount;
+                flow.coin_ids.push((*coin_id).into());
+                flow.spent_ids.insert((*coin_id).into());
+            }
+        }
+    }
+
+    /// The coins involved at each height where more of the asset was spent than received.
+    pub fn net_sends(&self, asset: Asset) -> impl Iterator<Item = (u32, &[Bytes32])> + '_ {
+        self.flows
+            .iter()
+            .filter_map(move |((height, flow_asset), flow)| {
+                (*flow_asset == asset && flow.spent > flow.received)
+                    .then_some((*height, flow.coin_ids.as_slice()))
+            })
+    }
+
+    /// Adds the NFTs launched at each height as received, with the XCH spent at that height
+    /// split between them as their cost.
+    pub fn add_mints(&mut self, mints: &IndexMap<u32, Vec<Bytes32>>) {
+        for (height, launcher_ids) in mints {
+            let Some(count) = u64::try_from(launcher_ids.len()).ok().filter(|n| *n > 0) else {
+                continue;
+            };
+
+            let spent = self
+                .flows
+                .get(&(*height, Asset::Xch))
+                .map_or(0, |flow| flow.spent.saturating_sub(flow.received));
+
+            for (i, launcher_id) in launcher_ids.iter().enumerate() {
+                // The first NFT absorbs the rounding remainder so the full cost is accounted for.
+                let share = spent / count + if i == 0 { spent % count } else { 0 };
+                let flow = self
+                    .flows
+                    .entry((*height, Asset::Nft(*launcher_id)))
+                    .or_default();
+                flow.received = 1;
+                flow.coin_ids = vec![*launcher_id];
+                flow.cost = Some((Asset::Xch, share));
+            }
+        }
+    }
+
+    /// The off-chain metadata URIs of each NFT that has passed through the wallet.
+    pub fn nft_metadata_uris(&self) -> &IndexMap<Bytes32, Vec<String>> {
+        &self.nft_metadata_uris
+    }
+
+    /// The committed coins that were unspent at the timestamp, in the order they were added.
+    pub fn committed_at<'a>(
+        &'a self,
+        timestamps: &'a IndexMap<u32, u64>,
+        end: i64,
+    ) -> impl Iterator<Item = &'a CommittedCoin> + 'a {
+        let before_end = move |height: u32| {
+            timestamps
+                .get(&height)
+                .is_some_and(|timestamp| (*timestamp as i64) < end)
+        };
+        self.committed.iter().filter(move |coin| {
+            before_end(coin.created_height) && !coin.spent_height.is_some_and(before_end)
+        })
+    }
+
+    /// The height and id of each coin received to each of the wallet's puzzle hashes.
+    pub fn receipts(&self) -> &IndexMap<Bytes32, Vec<(u32, Bytes32)>> {
+        &self.receipts
+    }
+
+    pub fn label(&self, puzzle_hash: &Bytes32) -> Option<&str> {
+        self.labels.get(puzzle_hash).map(String::as_str)
+    }
+
+    pub fn counterparty(&self, coin_id: &Bytes32) -> Option<&str> {
+        self.counterparties.get(coin_id).map(String::as_str)
+    }
+
+    /// Every height with activity, which all need timestamps before transactions can be dated.
+    pub fn heights(&self) -> IndexSet<u32> {
+        self.flows.keys().map(|(height, _)| *height).collect()
+    }
+
+    /// Produces the transactions lazily in height order.
+    pub fn transactions<'a>(
+        &'a mut self,
+        timestamps: &'a IndexMap<u32, u64>,
+    ) -> impl Iterator<Item = Transaction> + 'a {
+        // Heights are in time order, so this is by time, then by id within a block.
+        self.flows.sort_by_cached_key(|(height, _), flow| {
+            (*height, transaction_id(flow.coin_ids.iter()))
+        });
+
+        self.flows.iter().filter_map(|((height, asset), flow)| {
+            let (kind, amount) = if flow.spent > flow.received {
+                let amount = flow.spent - flow.received;
+                // Only the fee left the wallet if everything the spends created came back to it.
+                let kind = if *asset == Asset::Xch
+                    && flow.received > 0
+                    && amount <= self.max_fee
+                    && flow.is_self_spend()
+                {
+                    TransactionKind::Fee
+                } else {
+                    TransactionKind::Send
+                };
+                (kind, amount)
+            } else if flow.received > flow.spent {
+                (TransactionKind::Receive, flow.received - flow.spent)
+            } else {
+                return None;
+            };
+
+            // Coins created while sending returned to the wallet, so they're change.
+            let (mut coin_ids, mut change_coin_ids, change): (Vec<_>, Vec<_>, _) = match kind {
+                TransactionKind::Send | TransactionKind::Fee => (
+                    flow.coin_ids
+                        .iter()
+                        .filter(|coin_id| !flow.created.contains(*coin_id))
+                        .copied()
+                        .collect(),
+                    flow.created.iter().copied().collect(),
+                    flow.received,
+                ),
+                TransactionKind::Receive => (flow.coin_ids.clone(), Vec::new(), 0),
+            };
+
+            coin_ids.sort();
+            change_coin_ids.sort();
+
+            let counterparties = flow
+                .coin_ids
+                .iter()
+                .filter_map(|coin_id| self.counterparties.get(coin_id).cloned())
+                .collect::<IndexSet<_>>();
+
+            // Change isn't part of what was sent, so its tags aren't either.
+            let tags = coin_ids
+                .iter()
+                .filter_map(|coin_id| self.tags.get(coin_id))
+                .flatten()
+                .cloned()
+                .collect::<IndexSet<_>>();
+            let evidence = coin_ids
+                .iter()
+                .filter_map(|coin_id| self.evidence.get(coin_id))
+                .flatten()
+                .cloned()
+                .collect::<IndexSet<_>>();
+
+            Some(Transaction {
+                height: *height,
+                timestamp: *timestamps.get(height)?,
+                kind,
+                asset: *asset,
+                amount,
+                coin_ids,
+                change_coin_ids,
+                change,
+                cost: flow.cost,
+                counterparty: (!counterparties.is_empty())
+                    .then(|| counterparties.into_iter().collect::<Vec<_>>().join("; ")),
+                label: (!flow.labels.is_empty())
+                    .then(|| flow.labels.iter().cloned().collect::<Vec<_>>().join("; ")),
+                tags: tags.into_iter().collect(),
+                evidence: evidence.into_iter().collect(),
+            })
+        })
+    }
+}
+
+/// The prices needed to value the transactions, looking up each distinct asset and day once.
+pub async fn daily_prices(
+    transactions: impl Iterator<Item = Transaction>,
+    prices: &PriceProvider,
+    config: &Config,
+) -> anyhow::Result<DailyPrices> {
+    let keys = transactions
+        .filter_map(|tx| tx.price_key(config))
+        .collect::<IndexSet<_>>();
+
+    fetch_prices(keys, prices, config.concurrency).await
+}
+
+/// Looks up the price of each CoinGecko id on each day, with a bounded number of lookups in flight.
+///
+/// The days of an asset that aren't cached are fetched as one range where the API allows it,
+/// which is a single request instead of one a day. The requests left are counted up front, so
+/// a run that would go over the budget of the price API can be stopped before it starts.
+pub async fn fetch_prices(
+    keys: IndexSet<(String, NaiveDate)>,
+    prices: &PriceProvider,
+    concurrency: usize,
+) -> anyhow::Result<DailyPrices> {
+    let mut missing = IndexMap::<&str, Vec<NaiveDate>>::new();
+    for (id, date) in &keys {
+        if !prices.is_cached(id, *date) {
+            missing.entry(id).or_default().push(*date);
+        }
+    }
+
+    let ranges = missing
+        .iter()
+        .filter_map(|(id, dates)| {
+            let first = *dates.iter().min()?;
+            let last = *dates.iter().max()?;
+            (dates.len() > 1 && (last - first).num_days() < MAX_RANGE_DAYS)
+                .then_some((*id, first, last))
+        })
+        .collect::<Vec<_>>();
+    let requests = missing
+        .iter()
+        .map(|(id, dates)| {
+            if ranges.iter().any(|(range_id, _, _)| range_id == id) {
+                1
+            } else {
+                dates.len() as u64
+            }
+        })
+        .sum();
+    prices.confirm_requests(requests)?;
+
+    // A range that fails, like one further back than the plan allows, falls back to the days.
+    stream::iter(ranges)
+        .for_each_concurrent(concurrency.max(1), |(id, first, last)| async move {
+            if let Err(error) = prices.price_range(id, first, last).await {
+                eprintln!("Warning: couldn't fetch {id} prices from {first} to {last}: {error}");
+            }
+        })
+        .await;
+
+    stream::iter(keys)
+        .map(|(id, date)| async move {
+            let price = prices.historical_price(&id, date).await?;
+            anyhow::Ok(((id, date), price))
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await
+}
+
+/// Merges each run of self-spends on the same UTC day into one fee row if `collapse` is set,
+/// so a bot splitting and merging coins shows up as what it paid rather than a row per spend.
+/// Every other transaction passes through as it was.
+pub fn collapse_self_spends(
+    transactions: impl Iterator<Item = Transaction>,
+    collapse: bool,
+) -> impl Iterator<Item = Transaction> {
+    let mut transactions = transactions.peekable();
+    std::iter::from_fn(move || {
+        let mut tx = transactions.next()?;
+        while let Some(next) = transactions.next_if(|next| {
+            collapse
+                && tx.kind == TransactionKind::Fee
+                && next.kind == TransactionKind::Fee
+                && next.price_date() == tx.price_date()
+        }) {
+            tx.amount += next.amount;
+            tx.change += next.change;
+            tx.coin_ids.extend(next.coin_ids);
+            tx.change_coin_ids.extend(next.change_coin_ids);
+            for tag in next.tags {
+                if !tx.tags.contains(&tag) {
+                    tx.tags.push(tag);
+                }
+            }
+            for file in next.evidence {
+                if !tx.evidence.contains(&file) {
+                    tx.evidence.push(file);
+                }
+            }
+        }
+        tx.coin_ids.sort();
+        tx.change_coin_ids.sort();
+        Some(tx)
+    })
+}
+
+/// Converts the transactions to fiat and streams them to the writer.
+/// Rows are formatted in parallel a chunk at a time, which keeps memory bounded.
+pub fn write_report(
+    mut transactions: impl Iterator<Item = Transaction>,
+    daily_prices: &DailyPrices,
+    currency: &str,
+    config: &Config,
+    writer: &mut dyn ReportWriter,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+
+    loop {
+        let chunk = transactions.by_ref().take(CHUNK_SIZE).collect::<Vec<_>>();
+        if chunk.is_empty() {
+            break;
+        }
+
+        let rows = chunk
+            .par_iter()
+            .map(|tx| report_row(tx, daily_prices, currency, config))
+            .collect::<Vec<_>>();
+
+        for row in &rows {
+            writer.write_row(row)?;
+        }
+
+        count += rows.len();
+    }
+
+    writer.finish()?;
+
+    Ok(count)
+}
+
+pub fn report_row(
+    tx: &Transaction,
+    daily_prices: &DailyPrices,
+    currency: &str,
+    config: &Config,
+) -> ReportRow {
+    let price = tx.price(daily_prices, config);
+
+    ReportRow {
+        id: tx.id().to_string(),
+        date: Local
+            .timestamp_opt(tx.timestamp as i64, 0)
+            .single()
+            .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default(),
+        height: tx.height,
+        kind: tx.kind,
+        asset: tx.asset.name(config),
+        amount: tx.asset.format_amount(tx.amount),
+        price,
+        value: price.map(|price| price * tx.asset.display_amount(tx.amount)),
+        currency: currency.to_uppercase(),
+        coin_ids: tx
+            .coin_ids
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" "),
+        change: (tx.kind != TransactionKind::Receive).then(|| tx.asset.format_amount(tx.change)),
+        change_coin_ids: tx
+            .change_coin_ids
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" "),
+        counterparty: tx.counterparty.clone(),
+        label: tx.label.clone(),
+        tags: (!tx.tags.is_empty()).then(|| tx.tags.join("; ")),
+        evidence: (!tx.evidence.is_empty()).then(|| tx.evidence.join("; ")),
+        asset_file: match tx.asset {
+            Asset::Nft(_) => "NFT".to_string(),
+            _ => tx.asset.name(config),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CoinJson, SpendSummaryJson};
+
+    const PUZZLE_HASH: [u8; 32] = [1; 32];
+
+    fn coin_state(
+        parent: [u8; 32],
+        amount: u64,
+        created_height: u32,
+        spent_height: Option<u32>,
+        created_amount: Option<u64>,
+    ) -> CoinStateJson {
+        CoinStateJson {
+            coin: CoinJson {
+                parent_coin_info: parent,
+                puzzle_hash: PUZZLE_HASH,
+                amount,
+            },
+            parent_puzzle: None,
+            parent_spend: created_amount.map(|created_amount| SpendSummaryJson {
+                parent_amount: 0,
+                created_coins: 2,
+                created_amount,
+                change_amount: created_amount,
+                signers: Vec::new(),
+            }),
+            created_height: Some(created_height),
+            spent_height,
+        }
+    }
+
+    /// A 1000 mojo coin split into 600 and 390 back to the wallet, and whatever else the
+    /// spend created somewhere else.
+    fn split(created_amount: u64) -> Vec<Transaction> {
+        let mut derivations = Derivations::default();
+        derivations.puzzle_hashes.insert(PUZZLE_HASH);
+        let coins = [
+            ([2; 32], coin_state([9; 32], 1000, 1, Some(10), None)),
+            (
+                [3; 32],
+                coin_state([2; 32], 600, 10, None, Some(created_amount)),
+            ),
+            (
+                [4; 32],
+                coin_state([2; 32], 390, 10, None, Some(created_amount)),
+            ),
+        ];
+        derivations.coin_states.extend(coins);
+
+        let mut grouper = TransactionGrouper::default();
+        grouper.set_max_fee(100);
+        grouper.add(&derivations);
+        let timestamps = IndexMap::from([(1, 1_000), (10, 2_000)]);
+        grouper
+            .transactions(&timestamps)
+            .filter(|tx| tx.height == 10)
+            .collect()
+    }
+
+    #[test]
+    fn a_split_back_to_the_wallet_is_a_fee() {
+        let transactions = split(990);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].kind, TransactionKind::Fee);
+        assert_eq!(transactions[0].amount, 10);
+        assert_eq!(transactions[0].change, 990);
+    }
+
+    #[test]
+    fn a_split_that_pays_someone_else_is_a_send() {
+        let transactions = split(1000);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].kind, TransactionKind::Send);
+        assert_eq!(transactions[0].amount, 10);
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    future::Future,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use tokio::time::{sleep, timeout};
+
+use crate::config::{RateLimitConfig, RetryConfig};
+
+/// The limit shared by every request of the run, whichever peer it goes to.
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// A token bucket that refills at the configured rate, up to the burst size.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// A limiter of `rate` requests a second, which starts with `burst` available.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Waits until a request can be sent without going over the rate.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let (tokens, refilled) = &mut *state;
+                let now = Instant::now();
+                *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * self.rate)
+                    .min(self.burst);
+                *refilled = now;
+
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return;
+                }
+
+                Duration::from_secs_f64((1.0 - *tokens) / self.rate)
+            };
+
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Applies the rate limit to every request sent through [`with_retries`] from now on.
+/// The config is only read once a run, so later calls have no effect.
+pub fn set_rate_limit(config: &RateLimitConfig) {
+    let Some(rate) = config.requests_per_second.filter(|rate| *rate > 0.0) else {
+        return;
+    };
+
+    RATE_LIMITER.get_or_init(|| RateLimiter::new(rate, config.burst));
+}
+
+/// Whether a rejection might go away if the request is sent again, or is the peer's answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejections {
+    /// Retry rejections, such as a header request for a height the peer hasn't seen yet.
+    Retry,
+    /// Return rejections right away, such as a parent whose spend the peer doesn't have.
+    Return,
+}
+
+/// Sends a request to the peer until it's answered, waiting a jittered, exponentially growing
+/// backoff between attempts. Rejections are returned as `Ok(Err(rejection))`.
+/// Each attempt counts towards the rate limit, if there is one.
+///
+/// Every request thyme makes only reads chain state, so sending one again is always safe.
+/// Timeouts are retried, along with rejections if `rejections` says to. Errors from the
+/// connection itself aren't, since only reconnecting helps once the socket is gone.
+pub async fn with_retries<T, R, F, Fut>(
+    policy: &RetryConfig,
+    rejections: Rejections,
+    mut send: F,
+) -> anyhow::Result<Result<T, R>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, chia::client::Error<R>>>,
+    chia::client::Error<R>: std::error::Error + Send + Sync + 'static,
+{
+    let mut attempt = 1;
+
+    loop {
+        if let Some(limiter) = RATE_LIMITER.get() {
+            limiter.acquire().await;
+        }
+
+        let rejection = match timeout(Duration::from_secs(policy.timeout), send()).await {
+            Ok(Ok(response)) => return Ok(Ok(response)),
+            Ok(Err(chia::client::Error::Rejection(rejection))) => {
+                if rejections == Rejections::Return {
+                    return Ok(Err(rejection));
+                }
+                Some(rejection)
+            }
+            Ok(Err(chia::client::Error::InvalidResponse(message))) => bail!(
+                "Peer answered with an unexpected {:?} message",
+                message.msg_type
+            ),
+            Ok(Err(error)) => return Err(error.into()),
+            Err(_elapsed) => None,
+        };
+
+        if attempt >= policy.max_attempts {
+            match rejection {
+                Some(rejection) => return Ok(Err(rejection)),
+                None => bail!(
+                    "Peer didn't answer within {} seconds, after {attempt} attempts",
+                    policy.timeout
+                ),
+            }
+        }
+
+        sleep(backoff(policy, attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// A random delay between half and all of the initial backoff doubled for each attempt so far,
+/// so requests that failed together don't all retry at the same moment.
+fn backoff(policy: &RetryConfig, attempt: u32) -> Duration {
+    let ceiling = policy
+        .initial_backoff_ms
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(policy.max_backoff_ms);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64;
+
+    Duration::from_millis(ceiling / 2 + nanos % (ceiling / 2 + 1))
+}
@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use chia::protocol::Bytes32;
+use chrono::{Local, TimeZone};
+use indexmap::{IndexMap, IndexSet};
+use serde::Serialize;
+
+use crate::{addresses::address, config::Config, report::TransactionGrouper};
+
+/// An address that received more than one payment, which links those payments together
+/// for anyone watching the chain.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReuseRow {
+    pub derivation: u32,
+    pub address: String,
+    pub label: Option<String>,
+    /// The heights coins were received at, since the coins of one payment share a height.
+    pub payments: usize,
+    pub coins: usize,
+    pub first_received: String,
+    pub last_received: String,
+    /// The senders of the coins, by label or cluster id, if they were looked up.
+    pub counterparties: Option<String>,
+}
+
+/// Every address of the wallet that received payments at more than one height, by derivation.
+pub fn reuse_rows(
+    grouper: &TransactionGrouper,
+    derivation_indices: &IndexMap<Bytes32, u32>,
+    timestamps: &IndexMap<u32, u64>,
+    config: &Config,
+) -> anyhow::Result<Vec<ReuseRow>> {
+    let date = |height: u32| {
+        timestamps
+            .get(&height)
+            .and_then(|timestamp| Local.timestamp_opt(*timestamp as i64, 0).single())
+            .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    };
+
+    let mut rows = Vec::new();
+
+    for (puzzle_hash, receipts) in grouper.receipts() {
+        let heights = receipts
+            .iter()
+            .map(|(height, _)| *height)
+            .collect::<IndexSet<_>>();
+        if heights.len() < 2 {
+            continue;
+        }
+        let Some(derivation) = derivation_indices.get(puzzle_hash) else {
+            continue;
+        };
+
+        let counterparties = receipts
+            .iter()
+            .filter_map(|(_, coin_id)| grouper.counterparty(coin_id))
+            .collect::<IndexSet<_>>();
+
+        rows.push(ReuseRow {
+            derivation: *derivation,
+            address: address(config, *puzzle_hash)?,
+            label: grouper.label(puzzle_hash).map(str::to_string),
+            payments: heights.len(),
+            coins: receipts.len(),
+            first_received: date(heights.iter().copied().min().unwrap_or_default()),
+            last_received: date(heights.iter().copied().max().unwrap_or_default()),
+            counterparties: (!counterparties.is_empty())
+                .then(|| counterparties.into_iter().collect::<Vec<_>>().join("; ")),
+        });
+    }
+
+    rows.sort_by_key(|row| row.derivation);
+    Ok(rows)
+}
+
+pub fn write_reuse_report(path: &Path, rows: &[ReuseRow]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use anyhow::bail;
+use chia::{
+    clvm_utils::tree_hash_from_bytes,
+    protocol::{Bytes32, CoinState},
+};
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{cache::Derivations, parents::ParentSpend};
+
+/// The number of mojos in an XCH.
+const MOJOS_PER_XCH: u128 = 1_000_000_000_000;
+
+/// The XCH created at genesis, before any block was farmed.
+const PREFARM: u128 = 21_000_000 * MOJOS_PER_XCH;
+
+/// The number of blocks the reward stays the same for before it halves, which is three years.
+const BLOCKS_PER_HALVING: u128 = 3 * 1_681_920;
+
+/// Checks that the coin states a peer sent could have happened before they're cached, so a
+/// buggy or malicious peer can't feed reports coins spent before they were created, heights
+/// it hasn't reached, or amounts that there isn't enough XCH for.
+///
+/// Every coin is backed by XCH, CATs and NFTs included, so no coin can be worth more than the
+/// XCH there was when it was created, and the coins the wallet holds at once can't add up to
+/// more than there was by the highest height any of them reaches. The total is over the coins
+/// of the `cached` windows the peer didn't report spent, and those it sent that are unspent.
+/// Heights are only checked against the peak when the peer said where it is.
+pub fn check_coin_states(
+    uri: &str,
+    coin_states: &[CoinState],
+    peak_height: Option<u32>,
+    cached: &[Derivations],
+) -> anyhow::Result<()> {
+    let mut unspent = IndexMap::<[u8; 32], (u64, u32)>::new();
+    let mut spent = IndexSet::new();
+
+    for coin_state in coin_states {
+        let coin_id = coin_state.coin.coin_id();
+        let highest = coin_state.created_height.max(coin_state.spent_height);
+
+        let problem = match (coin_state.created_height, coin_state.spent_height) {
+            (None, Some(spent)) => Some(format!("was spent at height {spent} but never created")),
+            (Some(created), Some(spent)) if spent < created => Some(format!(
+                "was spent at height {spent}, before it was created at {created}"
+            )),
+            (Some(created), _) if u128::from(coin_state.coin.amount) > supply_at(created) => {
+                Some(format!(
+                    "is worth {} XCH, more than the {} XCH there was at height {created}",
+                    xch(coin_state.coin.amount.into()),
+                    xch(supply_at(created))
+                ))
+            }
+            _ => match (highest, peak_height) {
+                (Some(height), Some(peak)) if height > peak => Some(format!(
+                    "has height {height}, past the peer's peak of {peak}"
+                )),
+                _ => None,
+            },
+        };
+        if let Some(problem) = problem {
+            bail!("{uri} sent coin {coin_id}, which {problem}, so nothing it sent was cached");
+        }
+
+        let coin_id = coin_id.to_bytes();
+        match (coin_state.created_height, coin_state.spent_height) {
+            (Some(created), None) => {
+                unspent.insert(coin_id, (coin_state.coin.amount, created));
+            }
+            _ => {
+                spent.insert(coin_id);
+            }
+        }
+    }
+
+    for (coin_id, coin_state) in cached
+        .iter()
+        .flat_map(|derivations| &derivations.coin_states)
+    {
+        if spent.contains(coin_id) || coin_state.spent_height.is_some() {
+            continue;
+        }
+        if let Some(created) = coin_state.created_height {
+            unspent
+                .entry(*coin_id)
+                .or_insert((coin_state.coin.amount, created));
+        }
+    }
+
+    let Some(highest) = unspent.values().map(|(_, created)| *created).max() else {
+        return Ok(());
+    };
+    let total = unspent
+        .values()
+        .map(|(amount, _)| u128::from(*amount))
+        .sum::<u128>();
+    if total > supply_at(highest) {
+        bail!(
+            "{uri} sent coins that leave the wallet holding {} XCH, more than the {} XCH there \
+             was at height {highest}, so nothing it sent was cached",
+            xch(total),
+            xch(supply_at(highest))
+        );
+    }
+
+    Ok(())
+}
+
+/// The most XCH there can be by a height, in mojos, which is the prefarm and each block's
+/// reward since. The reward starts at 2 XCH and halves every three years until it's 1/8 XCH.
+fn supply_at(height: u32) -> u128 {
+    let mut remaining = u128::from(height);
+    let mut reward = 2 * MOJOS_PER_XCH;
+    let mut supply = PREFARM;
+
+    for _ in 0..4 {
+        let blocks = remaining.min(BLOCKS_PER_HALVING);
+        supply += blocks * reward;
+        remaining -= blocks;
+        reward /= 2;
+    }
+
+    supply + remaining * reward
+}
+
+fn xch(mojos: u128) -> u128 {
+    mojos / MOJOS_PER_XCH
+}
+
+/// Checks that every parent puzzle a peer revealed hashes to the parent's puzzle hash, since
+/// the asset of each child is decided by it.
+pub fn check_parent_spends(
+    uri: &str,
+    parent_spends: &IndexMap<Bytes32, Option<ParentSpend>>,
+) -> anyhow::Result<()> {
+    for (coin_id, parent_spend) in parent_spends {
+        let Some(parent_spend) = parent_spend else {
+            continue;
+        };
+
+        let matches = tree_hash_from_bytes(&parent_spend.puzzle)
+            .is_ok_and(|hash| hash.to_bytes() == parent_spend.coin.puzzle_hash.to_bytes());
+        if !matches {
+            bail!(
+                "{uri} sent a puzzle for coin {coin_id} that doesn't hash to its puzzle hash, \
+                 so nothing it sent was cached"
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::{
+    env,
+    io::{ErrorKind, Write},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+
+/// The service every secret is stored under in the keyring.
+const SERVICE: &str = "thyme";
+
+/// The values that can be kept in the OS keyring instead of `config.toml` or the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Secret {
+    /// Used when `coingecko_api_key` isn't set in the config.
+    #[value(name = "coingecko_api_key")]
+    CoingeckoApiKey,
+    /// Used when `--key` isn't passed.
+    #[value(name = "master_public_key")]
+    MasterPublicKey,
+    /// The Discord webhook notifications are posted to, if `THYME_DISCORD_WEBHOOK_URL` isn't set.
+    #[value(name = "discord_webhook_url")]
+    DiscordWebhookUrl,
+    /// The Telegram bot notifications are posted with, if `THYME_TELEGRAM_BOT_TOKEN` isn't set.
+    #[value(name = "telegram_bot_token")]
+    TelegramBotToken,
+}
+
+impl Secret {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CoingeckoApiKey => "coingecko_api_key",
+            Self::MasterPublicKey => "master_public_key",
+            Self::DiscordWebhookUrl => "discord_webhook_url",
+            Self::TelegramBotToken => "telegram_bot_token",
+        }
+    }
+
+    /// The environment variable that takes the place of the keyring, like
+    /// `THYME_DISCORD_WEBHOOK_URL`.
+    pub fn env_var(self) -> String {
+        format!("THYME_{}", self.name().to_uppercase())
+    }
+}
+
+/// Reads a secret from its environment variable, or else from the keyring.
+pub fn env_or_secret(secret: Secret) -> anyhow::Result<Option<String>> {
+    match env::var(secret.env_var()) {
+        Ok(value) if !value.trim().is_empty() => Ok(Some(value.trim().to_string())),
+        Ok(_) | Err(env::VarError::NotPresent) => get_secret(secret),
+        Err(env::VarError::NotUnicode(_)) => bail!("{} isn't valid text", secret.env_var()),
+    }
+}
+
+/// Reads a secret from the keyring, which is the login keychain on macOS and the Secret Service
+/// (through `secret-tool`) elsewhere. Returns `None` if it isn't stored, or there's no keyring.
+pub fn get_secret(secret: Secret) -> anyhow::Result<Option<String>> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args([
+            "find-generic-password",
+            "-s",
+            SERVICE,
+            "-a",
+            secret.name(),
+            "-w",
+        ]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["lookup", "service", SERVICE, "account", secret.name()]);
+        command
+    };
+
+    let output = match command.stderr(Stdio::null()).output() {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    // Both tools exit with an error when there's no such item.
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let value = String::from_utf8(output.stdout)?.trim().to_string();
+    Ok((!value.is_empty()).then_some(value))
+}
+
+/// Stores a secret in the keyring, replacing any previous value.
+pub fn set_secret(secret: Secret, value: &str) -> anyhow::Result<()> {
+    let status = if cfg!(target_os = "macos") {
+        // Any other process could read the password from the arguments, so the command is
+        // given to `security`'s interactive mode on stdin instead.
+        let command = format!(
+            "add-generic-password -U -s {SERVICE} -a {} -w \"{}\"\n",
+            secret.name(),
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        let status = run_with_input(
+            Command::new("security").arg("-i").stdout(Stdio::null()),
+            &command,
+        )?;
+
+        // Interactive mode exits successfully even if the command failed.
+        if status.success() && get_secret(secret)?.as_deref() != Some(value) {
+            bail!("Couldn't store {} in the keyring", secret.name());
+        }
+        status
+    } else {
+        run_with_input(
+            Command::new("secret-tool")
+                .args(["store", "--label", &format!("thyme {}", secret.name())])
+                .args(["service", SERVICE, "account", secret.name()]),
+            value,
+        )?
+    };
+
+    if !status.success() {
+        bail!("Couldn't store {} in the keyring", secret.name());
+    }
+
+    Ok(())
+}
+
+/// Removes a secret from the keyring. Returns whether it was stored.
+pub fn delete_secret(secret: Secret) -> anyhow::Result<bool> {
+    if get_secret(secret)?.is_none() {
+        return Ok(false);
+    }
+
+    let status = if cfg!(target_os = "macos") {
+        run(Command::new("security").args([
+            "delete-generic-password",
+            "-s",
+            SERVICE,
+            "-a",
+            secret.name(),
+        ]))?
+    } else {
+        run(Command::new("secret-tool").args([
+            "clear",
+            "service",
+            SERVICE,
+            "account",
+            secret.name(),
+        ]))?
+    };
+
+    if !status.success() {
+        bail!("Couldn't remove {} from the keyring", secret.name());
+    }
+
+    Ok(true)
+}
+
+fn run(command: &mut Command) -> anyhow::Result<std::process::ExitStatus> {
+    Ok(spawn(command)?.wait()?)
+}
+
+/// Runs the command with the input written to its stdin, which is closed after.
+fn run_with_input(command: &mut Command, input: &str) -> anyhow::Result<std::process::ExitStatus> {
+    let mut child = spawn(command.stdin(Stdio::piped()))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input.as_bytes())?;
+    Ok(child.wait()?)
+}
+
+fn spawn(command: &mut Command) -> anyhow::Result<std::process::Child> {
+    command.spawn().map_err(|error| {
+        if error.kind() == ErrorKind::NotFound {
+            anyhow!(
+                "No keyring available, since `{}` isn't installed",
+                command.get_program().to_string_lossy()
+            )
+        } else {
+            error.into()
+        }
+    })
+}
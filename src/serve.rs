@@ -0,0 +1,74 @@
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    Message,
+};
+
+/// Where clients connect to be sent transactions.
+pub const TRANSACTIONS_PATH: &str = "/transactions";
+
+/// Accepts WebSocket connections at [`TRANSACTIONS_PATH`] and sends each client every
+/// transaction published from then on, as one JSON report row per text message.
+pub async fn serve_transactions(
+    listener: TcpListener,
+    transactions: broadcast::Sender<String>,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let receiver = transactions.subscribe();
+
+        tokio::spawn(async move {
+            if let Err(error) = stream_transactions(stream, receiver).await {
+                eprintln!("WebSocket client {address} disconnected: {error}");
+            }
+        });
+    }
+}
+
+async fn stream_transactions(
+    stream: TcpStream,
+    mut transactions: broadcast::Receiver<String>,
+) -> anyhow::Result<()> {
+    let socket = tokio_tungstenite::accept_hdr_async(stream, check_path).await?;
+
+    let (mut outgoing, mut incoming) = socket.split();
+
+    loop {
+        tokio::select! {
+            transaction = transactions.recv() => match transaction {
+                Ok(json) => outgoing.send(Message::Text(json)).await?,
+                // A client that can't keep up misses transactions instead of holding up the rest.
+                Err(RecvError::Lagged(missed)) => {
+                    eprintln!("A WebSocket client fell behind and missed {missed} transactions");
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // Reading answers the client's pings, and nothing else it sends means anything.
+            message = incoming.next() => match message {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(error)) => return Err(error.into()),
+            },
+        }
+    }
+}
+
+/// Fails the handshake of clients connecting anywhere else with a 404.
+// The error type is tungstenite's, which is large because it carries the whole response.
+#[allow(clippy::result_large_err)]
+fn check_path(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    if request.uri().path() == TRANSACTIONS_PATH {
+        return Ok(response);
+    }
+
+    let mut error = ErrorResponse::new(Some(format!(
+        "Nothing is served here, connect to {TRANSACTIONS_PATH}"
+    )));
+    *error.status_mut() = StatusCode::NOT_FOUND;
+    Err(error)
+}
@@ -0,0 +1,229 @@
+use std::ops::Range;
+
+use anyhow::anyhow;
+use chrono::{Duration, Local, NaiveDate, NaiveTime, TimeZone};
+use indexmap::IndexMap;
+
+use crate::{
+    asset::Asset,
+    config::Config,
+    report::{DailyPrices, Transaction, TransactionKind},
+};
+
+/// Totals for a single tax year, which can be compared side by side with other years.
+#[derive(Debug, Default, Clone)]
+pub struct YearSummary {
+    pub year: i32,
+    pub receives: usize,
+    pub sends: usize,
+    /// The fiat value of everything received during the year.
+    pub income: f64,
+    /// The fiat value of everything sent during the year.
+    pub proceeds: f64,
+    /// The fiat value of the network fees paid during the year, which are expenses.
+    pub fees: f64,
+    /// Transactions during the year that couldn't be valued.
+    pub unpriced: usize,
+    /// Balances at the end of the year.
+    pub holdings: IndexMap<Asset, u64>,
+    /// The fiat value of the balances, using prices from the last day of the year.
+    pub holdings_value: f64,
+}
+
+/// The last day of the year, whose price is used to value holdings.
+pub fn year_end(year: i32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, 12, 31).unwrap()
+}
+
+/// The start of the day after the date in local time, which is where a snapshot of the date ends.
+/// Where the clocks go forward at midnight, the day starts at the first minute after the gap.
+pub fn day_end(date: NaiveDate) -> anyhow::Result<i64> {
+    let next = date.succ_opt().unwrap_or(date);
+    let midnight = next.and_time(NaiveTime::MIN);
+    (0..=24 * 60)
+        .find_map(|minutes| {
+            Local
+                .from_local_datetime(&(midnight + Duration::minutes(minutes)))
+                .earliest()
+        })
+        .map(|start| start.timestamp())
+        .ok_or_else(|| anyhow!("{next} has no start in the local timezone"))
+}
+
+/// Balances of each asset after every transaction before `end`.
+pub fn holdings_at(
+    transactions: impl Iterator<Item = Transaction>,
+    end: i64,
+) -> IndexMap<Asset, u64> {
+    let mut holdings = IndexMap::new();
+
+    for tx in transactions.filter(|tx| (tx.timestamp as i64) < end) {
+        let balance: &mut u64 = holdings.entry(tx.asset).or_default();
+        match tx.kind {
+            TransactionKind::Receive => *balance += tx.amount,
+            TransactionKind::Send | TransactionKind::Fee => {
+                *balance = balance.saturating_sub(tx.amount)
+            }
+        }
+    }
+
+    holdings.retain(|_, balance| *balance > 0);
+    holdings.sort_keys();
+    holdings
+}
+
+/// The balance of each asset at the end of each of the dates, which must be in order.
+/// The transactions must be in height order, like the grouper produces them.
+pub fn daily_balances(
+    transactions: impl Iterator<Item = Transaction>,
+    dates: &[NaiveDate],
+) -> anyhow::Result<IndexMap<Asset, Vec<u64>>> {
+    let mut balances = IndexMap::<Asset, Vec<u64>>::new();
+    let mut current = IndexMap::<Asset, u64>::new();
+    let mut transactions = transactions.peekable();
+
+    for (index, date) in dates.iter().enumerate() {
+        let end = day_end(*date)?;
+        while let Some(tx) = transactions.next_if(|tx| (tx.timestamp as i64) < end) {
+            let balance = current.entry(tx.asset).or_default();
+            match tx.kind {
+                TransactionKind::Receive => *balance += tx.amount,
+                TransactionKind::Send | TransactionKind::Fee => {
+                    *balance = balance.saturating_sub(tx.amount)
+                }
+            }
+        }
+
+        for (asset, balance) in &current {
+            balances
+                .entry(*asset)
+                .or_insert_with(|| vec![0; dates.len()])[index] = *balance;
+        }
+    }
+
+    balances.retain(|_, series| series.iter().any(|balance| *balance > 0));
+    balances.sort_keys();
+    Ok(balances)
+}
+
+pub fn summarize_year(
+    year: i32,
+    bounds: Range<i64>,
+    transactions: impl Iterator<Item = Transaction>,
+    holdings: IndexMap<Asset, u64>,
+    daily_prices: &DailyPrices,
+    config: &Config,
+) -> YearSummary {
+    let mut summary = YearSummary {
+        year,
+        ..Default::default()
+    };
+
+    for tx in transactions.filter(|tx| bounds.contains(&(tx.timestamp as i64))) {
+        let value = tx
+            .price(daily_prices, config)
+            .map(|price| price * tx.asset.display_amount(tx.amount));
+
+        match tx.kind {
+            // Something paid for, like a minted NFT, is an acquisition rather than income.
+            TransactionKind::Receive if tx.cost.is_some() => summary.receives += 1,
+            TransactionKind::Receive => {
+                summary.receives += 1;
+                summary.income += value.unwrap_or_default();
+            }
+            TransactionKind::Send => {
+                summary.sends += 1;
+                summary.proceeds += value.unwrap_or_default();
+            }
+            TransactionKind::Fee => summary.fees += value.unwrap_or_default(),
+        }
+
+        if value.is_none() {
+            summary.unpriced += 1;
+        }
+    }
+
+    summary.holdings_value = holdings
+        .iter()
+        .filter_map(|(asset, amount)| {
+            let id = asset.coingecko_id(config)?;
+            let price = daily_prices.get(&(id, year_end(year)))?;
+            Some(price * asset.display_amount(*amount))
+        })
+        .sum();
+    summary.holdings = holdings;
+
+    summary
+}
+
+/// What `report --group-by` splits the year's totals by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GroupBy {
+    /// The tags from the config, such as projects or cost centers.
+    Tag,
+    /// The purposes of the addresses the coins were received to.
+    Label,
+}
+
+impl GroupBy {
+    /// The group of transactions that have none.
+    pub fn ungrouped(self) -> &'static str {
+        match self {
+            Self::Tag => "untagged",
+            Self::Label => "unlabeled",
+        }
+    }
+
+    fn groups(self, tx: &Transaction) -> Vec<String> {
+        match self {
+            Self::Tag => tx.tags.clone(),
+            Self::Label => tx
+                .label
+                .iter()
+                .flat_map(|label| label.split("; "))
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+/// Totals the year separately for each group, in name order with the ungrouped last.
+/// A transaction in several groups counts toward each of them.
+pub fn summarize_groups(
+    year: i32,
+    bounds: Range<i64>,
+    transactions: impl Iterator<Item = Transaction>,
+    group_by: GroupBy,
+    daily_prices: &DailyPrices,
+    config: &Config,
+) -> IndexMap<String, YearSummary> {
+    let mut groups = IndexMap::<String, Vec<Transaction>>::new();
+
+    for tx in transactions.filter(|tx| bounds.contains(&(tx.timestamp as i64))) {
+        let mut names = group_by.groups(&tx);
+        if names.is_empty() {
+            names.push(group_by.ungrouped().to_string());
+        }
+        for name in names {
+            groups.entry(name).or_default().push(tx.clone());
+        }
+    }
+
+    let ungrouped = group_by.ungrouped();
+    groups.sort_by(|a, _, b, _| (a == ungrouped, a).cmp(&(b == ungrouped, b)));
+
+    groups
+        .into_iter()
+        .map(|(name, transactions)| {
+            let summary = summarize_year(
+                year,
+                bounds.clone(),
+                transactions.into_iter(),
+                IndexMap::new(),
+                daily_prices,
+                config,
+            );
+            (name, summary)
+        })
+        .collect()
+}
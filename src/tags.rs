@@ -0,0 +1,79 @@
+use anyhow::anyhow;
+use indexmap::IndexSet;
+
+use crate::{cache::CoinStateJson, config::Config, entity::parse_coin_id, query::Query};
+
+/// A tag from the config, such as a project name or cost center.
+#[derive(Debug, Clone)]
+struct Tag {
+    name: String,
+    /// Filters over coins, in the same syntax as `thyme cache query`.
+    rules: Vec<Query>,
+    /// Coins annotated with the tag.
+    coin_ids: IndexSet<[u8; 32]>,
+}
+
+/// The tags from the config, which every coin is matched against.
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+    tags: Vec<Tag>,
+}
+
+impl Tags {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let mut tags = Vec::new();
+
+        for (name, tag) in &config.tags {
+            let rules = tag
+                .rules
+                .iter()
+                .map(|rule| {
+                    let query = rule.parse::<Query>()?;
+                    query.validate()?;
+                    anyhow::Ok(query)
+                })
+                .collect::<anyhow::Result<_>>()
+                .map_err(|error| anyhow!("Tag `{name}`: {error}"))?;
+
+            let coin_ids = tag
+                .coins
+                .iter()
+                .map(|coin_id| {
+                    parse_coin_id(coin_id)
+                        .ok_or_else(|| anyhow!("Tag `{name}`: invalid coin id `{coin_id}`"))
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            tags.push(Tag {
+                name: name.clone(),
+                rules,
+                coin_ids,
+            });
+        }
+
+        Ok(Self { tags })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Every tag the coin is annotated with or matches a rule of, in config order.
+    pub fn tags_of<'a>(
+        &'a self,
+        config: &'a Config,
+        coin_id: &'a [u8; 32],
+        coin_state: &'a CoinStateJson,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.tags
+            .iter()
+            .filter(move |tag| {
+                tag.coin_ids.contains(coin_id)
+                    || tag
+                        .rules
+                        .iter()
+                        .any(|rule| rule.matches(config, coin_id, coin_state))
+            })
+            .map(|tag| tag.name.as_str())
+    }
+}
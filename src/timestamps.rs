@@ -0,0 +1,49 @@
+use anyhow::bail;
+use chia::client::Peer;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use indexmap::IndexMap;
+
+use crate::{
+    config::RetryConfig,
+    retry::{with_retries, Rejections},
+};
+
+/// Finds the timestamp of the block at the given height.
+/// Only transaction blocks carry a timestamp, so this walks back to the nearest one.
+pub async fn block_timestamp(peer: &Peer, height: u32, retry: &RetryConfig) -> anyhow::Result<u64> {
+    let mut current = height;
+
+    loop {
+        let Ok(header_block) = with_retries(retry, Rejections::Retry, || {
+            peer.request_block_header(current)
+        })
+        .await?
+        else {
+            bail!("Peer rejected the header request for height {current}");
+        };
+
+        if let Some(block) = header_block.foliage_transaction_block {
+            return Ok(block.timestamp);
+        }
+
+        if current == 0 {
+            bail!("No transaction block found at or before height {height}");
+        }
+
+        current -= 1;
+    }
+}
+
+/// Looks up the timestamps of many heights, with a bounded number of requests in flight.
+pub async fn resolve_timestamps(
+    peer: &Peer,
+    heights: impl IntoIterator<Item = u32>,
+    concurrency: usize,
+    retry: &RetryConfig,
+) -> anyhow::Result<IndexMap<u32, u64>> {
+    stream::iter(heights)
+        .map(|height| async move { Ok((height, block_timestamp(peer, height, retry).await?)) })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await
+}
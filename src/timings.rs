@@ -0,0 +1,71 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use indexmap::IndexMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Derivation,
+    CoinStatePaging,
+    ParentFetches,
+    MintDetection,
+    NftMetadata,
+    CacheIo,
+    Timestamps,
+    Pricing,
+    Export,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Derivation => "derivation",
+            Self::CoinStatePaging => "coin state paging",
+            Self::ParentFetches => "parent fetches",
+            Self::MintDetection => "mint detection",
+            Self::NftMetadata => "nft metadata",
+            Self::CacheIo => "cache i/o",
+            Self::Timestamps => "timestamps",
+            Self::Pricing => "pricing",
+            Self::Export => "export",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PhaseTiming {
+    elapsed: Duration,
+    requests: u64,
+}
+
+/// Accumulates wall-clock time and request counts per phase of a run.
+#[derive(Debug, Default)]
+pub struct Timings {
+    phases: Mutex<IndexMap<Phase, PhaseTiming>>,
+}
+
+impl Timings {
+    /// Adds the time since `started` and the number of requests made to the phase's totals.
+    pub fn record(&self, phase: Phase, started: Instant, requests: u64) {
+        let mut phases = self.phases.lock().unwrap();
+        let timing = phases.entry(phase).or_default();
+        timing.elapsed += started.elapsed();
+        timing.requests += requests;
+    }
+
+    pub fn print(&self) {
+        let phases = self.phases.lock().unwrap();
+        eprintln!("{:<20} {:>12} {:>10}", "phase", "seconds", "requests");
+        for (phase, timing) in phases.iter() {
+            eprintln!(
+                "{phase:<20} {:>12.3} {:>10}",
+                timing.elapsed.as_secs_f64(),
+                timing.requests
+            );
+        }
+    }
+}
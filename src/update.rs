@@ -0,0 +1,188 @@
+use std::{env, fs, path::Path};
+
+use anyhow::{anyhow, bail};
+use chia::bls::{verify, PublicKey, Signature};
+use clvmr::sha2::Sha256;
+use serde::Deserialize;
+
+use crate::read_only::check_writable;
+
+const RELEASES_API: &str = "https://api.github.com/repos/fredatgithub/thyme/releases/latest";
+
+/// The checksums of every binary in a release, as printed by `sha256sum`.
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+/// A BLS signature of the checksums file by the release key.
+const SIGNATURE_ASSET: &str = "SHA256SUMS.sig";
+
+/// The public key releases are signed with, as hex. It's set when release builds are made,
+/// so builds from source can't update themselves and should be updated the same way instead.
+const RELEASE_KEY: Option<&str> = option_env!("THYME_RELEASE_KEY");
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> anyhow::Result<&Asset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| anyhow!("Release {} has no {name}", self.tag_name))
+    }
+}
+
+/// Replaces the running binary with the latest GitHub release, if it's newer. The release's
+/// checksums file must be signed by the release key and the download must match its checksum,
+/// so a compromised download host can't swap in another binary.
+pub async fn self_update(check_only: bool) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("thyme/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+
+    let release: Release = client
+        .get(RELEASES_API)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let current = env!("CARGO_PKG_VERSION");
+    let latest = release.tag_name.trim_start_matches('v');
+
+    if !is_newer(latest, current) {
+        println!("thyme {current} is up to date");
+        return Ok(());
+    }
+
+    println!("thyme {latest} is available, this is {current}");
+    if check_only {
+        return Ok(());
+    }
+
+    let Some(release_key) = RELEASE_KEY else {
+        bail!("This build has no release key to verify updates with, so update it the way it was installed");
+    };
+    let release_key = PublicKey::from_bytes(
+        &hex::decode(release_key)?
+            .try_into()
+            .map_err(|_| anyhow!("The release key is not 48 bytes long"))?,
+    )?;
+
+    let binary_name = binary_name();
+    let download = |name: &str| {
+        let url = release
+            .asset(name)
+            .map(|asset| asset.browser_download_url.clone());
+        let client = client.clone();
+        async move {
+            let bytes = client
+                .get(url?)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            anyhow::Ok(bytes.to_vec())
+        }
+    };
+
+    let checksums = download(CHECKSUMS_ASSET).await?;
+    let signature = String::from_utf8(download(SIGNATURE_ASSET).await?)?;
+    let signature = Signature::from_bytes(
+        &hex::decode(signature.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("The release signature is not 96 bytes long"))?,
+    )?;
+
+    if !verify(&signature, &release_key, &checksums) {
+        bail!(
+            "The checksums of release {} aren't signed by the release key",
+            release.tag_name
+        );
+    }
+
+    let checksums = String::from_utf8(checksums)?;
+    let expected = checksums
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, name)| name.trim().trim_start_matches('*') == binary_name)
+        .map(|(checksum, _)| checksum.to_lowercase())
+        .ok_or_else(|| {
+            anyhow!(
+                "Release {} has no checksum for {binary_name}",
+                release.tag_name
+            )
+        })?;
+
+    println!("Downloading {binary_name}");
+    let binary = download(&binary_name).await?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(&binary);
+    if hex::encode(hasher.finalize()) != expected {
+        bail!("The download of {binary_name} doesn't match its checksum");
+    }
+
+    replace_binary(&env::current_exe()?, &binary)?;
+    println!("Updated thyme to {latest}");
+
+    Ok(())
+}
+
+/// The release asset for this platform, like `thyme-x86_64-linux`.
+fn binary_name() -> String {
+    format!(
+        "thyme-{}-{}{}",
+        env::consts::ARCH,
+        env::consts::OS,
+        env::consts::EXE_SUFFIX
+    )
+}
+
+/// Compares dotted version numbers, ignoring anything after a `-` like `-rc1`.
+fn is_newer(latest: &str, current: &str) -> bool {
+    let parse = |version: &str| {
+        version
+            .split('-')
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse::<u64>().unwrap_or(0))
+            .collect::<Vec<_>>()
+    };
+    parse(latest) > parse(current)
+}
+
+/// Writes the new binary next to the running one and moves it into place, which leaves the
+/// old binary untouched if anything fails before the move.
+fn replace_binary(exe: &Path, binary: &[u8]) -> anyhow::Result<()> {
+    check_writable(exe)?;
+    let new = exe.with_extension("new");
+    fs::write(&new, binary)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&new, fs::Permissions::from_mode(0o755))?;
+    }
+
+    // Windows can't replace a running binary, but it can rename it out of the way.
+    if cfg!(windows) {
+        let old = exe.with_extension("old");
+        let _ = fs::remove_file(&old);
+        fs::rename(exe, &old)?;
+    }
+
+    fs::rename(&new, exe)?;
+    Ok(())
+}
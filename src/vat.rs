@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use chrono::{Datelike, Local, Months, NaiveDate, TimeZone};
+use serde::Serialize;
+
+use crate::{
+    config::{Config, VatPeriod},
+    report::{DailyPrices, Transaction, TransactionKind},
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VatRow {
+    /// Like `2024`, `2024-Q1` or `2024-03`.
+    pub period: String,
+    pub start: String,
+    pub end: String,
+    pub sales: usize,
+    /// What customers paid, valued on the day it was received.
+    pub gross: f64,
+    pub net: f64,
+    pub vat: f64,
+    /// Sales that couldn't be valued, which are counted as zero.
+    pub unpriced: usize,
+    pub currency: String,
+}
+
+/// Whether the coins were received as a customer payment.
+pub fn is_sale(tx: &Transaction, config: &Config) -> bool {
+    if tx.kind != TransactionKind::Receive {
+        return false;
+    }
+    tx.label.as_deref().is_some_and(|label| {
+        label
+            .split("; ")
+            .any(|label| config.vat.sales_labels.iter().any(|sale| sale == label))
+    })
+}
+
+/// Totals the sales of the year by VAT period, in the local timezone. Every period of the year
+/// gets a row, since periods without sales are still filed.
+pub fn vat_rows(
+    year: i32,
+    transactions: impl Iterator<Item = Transaction>,
+    daily_prices: &DailyPrices,
+    currency: &str,
+    config: &Config,
+) -> Vec<VatRow> {
+    let months = match config.vat.period {
+        VatPeriod::Month => 1,
+        VatPeriod::Quarter => 3,
+        VatPeriod::Year => 12,
+    };
+
+    let mut rows = (0..12 / months)
+        .map(|index| {
+            let first_month = index * months + 1;
+            let start = NaiveDate::from_ymd_opt(year, first_month, 1).unwrap();
+            let end = (start + Months::new(months)).pred_opt().unwrap();
+            let period = match config.vat.period {
+                VatPeriod::Month => format!("{year}-{first_month:02}"),
+                VatPeriod::Quarter => format!("{year}-Q{}", index + 1),
+                VatPeriod::Year => year.to_string(),
+            };
+
+            VatRow {
+                period,
+                start: start.to_string(),
+                end: end.to_string(),
+                sales: 0,
+                gross: 0.0,
+                net: 0.0,
+                vat: 0.0,
+                unpriced: 0,
+                currency: currency.to_uppercase(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for tx in transactions.filter(|tx| is_sale(tx, config)) {
+        let Some(date) = Local.timestamp_opt(tx.timestamp as i64, 0).single() else {
+            continue;
+        };
+        if date.year() != year {
+            continue;
+        }
+
+        let row = &mut rows[(date.month0() / months) as usize];
+        row.sales += 1;
+        match tx.price(daily_prices, config) {
+            Some(price) => row.gross += price * tx.asset.display_amount(tx.amount),
+            None => row.unpriced += 1,
+        }
+    }
+
+    // Payments include the VAT, so it's the part of the gross above the net.
+    for row in &mut rows {
+        row.net = row.gross / (1.0 + config.vat.rate / 100.0);
+        row.vat = row.gross - row.net;
+    }
+
+    rows
+}
+
+pub fn write_vat_report(path: &Path, rows: &[VatRow]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use anyhow::bail;
+use chia::{client::Peer, protocol::Bytes32};
+use indexmap::IndexSet;
+
+use crate::{
+    cache::Derivations,
+    config::{Config, RetryConfig},
+    peers::{connect_many, ProbedPeer},
+    retry::{with_retries, Rejections},
+};
+
+/// Makes sure the primary peer agrees with the block each window was synced up to,
+/// since the windows of a round can come from different peers.
+pub async fn check_consistency(
+    peer: &ProbedPeer,
+    config: &Config,
+    windows: &[Derivations],
+) -> anyhow::Result<()> {
+    for (height, header_hash) in checkpoints(config, windows) {
+        // A primary that hasn't reached the height yet can't confirm or deny it.
+        let Some(expected) = header_hash_at(&peer.peer, height, &config.retry).await? else {
+            continue;
+        };
+
+        if expected != header_hash {
+            bail!(
+                "Peers disagree on the block at height {height}, so the cache wasn't updated. \
+                 {} has {expected}, but a window was synced up to {header_hash}",
+                peer.uri
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks a second peer for the block each window was synced up to, and warns if it has a
+/// different one at that height. A peer that lies about the chain has to fool both of them.
+///
+/// This only warns, since the cache is already saved and the report can still be useful,
+/// but a disagreement means one of the peers is on a fork or can't be trusted.
+pub async fn verify_headers(
+    primary: &ProbedPeer,
+    config: &Config,
+    windows: &[Derivations],
+) -> anyhow::Result<()> {
+    let Some(witness) = connect_many(config, usize::MAX)
+        .await?
+        .into_iter()
+        .find(|peer| peer.uri != primary.uri)
+    else {
+        eprintln!(
+            "Warning: couldn't verify the synced headers, since no other synced peer is configured"
+        );
+        return Ok(());
+    };
+
+    let checkpoints = checkpoints(config, windows);
+    eprintln!(
+        "Verifying {} synced headers against {}",
+        checkpoints.len(),
+        witness.uri
+    );
+
+    let mut disagreements = Vec::new();
+    let mut unconfirmed = 0;
+
+    for (height, header_hash) in checkpoints {
+        match header_hash_at(&witness.peer, height, &config.retry).await? {
+            Some(expected) if expected != header_hash => {
+                disagreements.push(format!(
+                    "  height {height}: {} has {header_hash}, {} has {expected}",
+                    primary.uri, witness.uri
+                ));
+            }
+            Some(_) => {}
+            None => unconfirmed += 1,
+        }
+    }
+
+    if unconfirmed > 0 {
+        eprintln!(
+            "{} doesn't have {unconfirmed} of the heights yet, so they weren't verified",
+            witness.uri
+        );
+    }
+
+    if !disagreements.is_empty() {
+        eprintln!();
+        eprintln!("!!! WARNING: the peers disagree on the chain !!!");
+        for disagreement in &disagreements {
+            eprintln!("{disagreement}");
+        }
+        eprintln!(
+            "!!! The report may be built from a fork or a dishonest peer. \
+             Reset the cache and sync from a peer you trust before relying on it. !!!"
+        );
+        eprintln!();
+    }
+
+    Ok(())
+}
+
+/// The header hash of the block at the height, or `None` if the peer hasn't seen it.
+pub async fn header_hash_at(
+    peer: &Peer,
+    height: u32,
+    retry: &RetryConfig,
+) -> anyhow::Result<Option<Bytes32>> {
+    let header_block = with_retries(retry, Rejections::Return, || {
+        peer.request_block_header(height)
+    })
+    .await?;
+
+    Ok(header_block
+        .ok()
+        .map(|header_block| header_block.header_hash()))
+}
+
+/// The distinct blocks the windows were synced up to. Windows without any activity yet
+/// still point at the genesis challenge, so they have nothing to check.
+fn checkpoints(config: &Config, windows: &[Derivations]) -> IndexSet<(u32, Bytes32)> {
+    windows
+        .iter()
+        .filter(|derivations| derivations.header_hash != config.genesis_challenge)
+        .filter_map(|derivations| {
+            Some((
+                derivations.previous_height?,
+                Bytes32::from(derivations.header_hash),
+            ))
+        })
+        .collect()
+}
@@ -0,0 +1,233 @@
+use std::{fs, path::Path};
+
+use anyhow::bail;
+use chia::protocol::{
+    Bytes32, CoinState, RejectCoinState, RejectStateReason, RequestCoinState, RespondCoinState,
+};
+use indexmap::{IndexMap, IndexSet};
+use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
+
+use crate::{
+    cache::{Cache, CoinStateJson},
+    config::Config,
+    peers::ProbedPeer,
+    read_only::check_writable,
+    retry::{with_retries, Rejections},
+};
+
+/// How many coin ids are subscribed to in each request.
+const COIN_BATCH_SIZE: usize = 1000;
+
+/// What a watch registered with its peer, so the next one can subscribe to the coins it was
+/// watching before paging anything and hear about a spend right away.
+#[serde_as]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Subscriptions {
+    #[serde_as(as = "IndexSet<Hex>")]
+    pub puzzle_hashes: IndexSet<[u8; 32]>,
+    /// Unspent coins received with a hint, which are sent to a puzzle hash that isn't the
+    /// wallet's, so they're subscribed to by id to hear when they're spent.
+    #[serde_as(as = "IndexSet<Hex>")]
+    pub coin_ids: IndexSet<[u8; 32]>,
+}
+
+impl Subscriptions {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        check_writable(&path)?;
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Everything a watch of the cache needs to hear about.
+    pub fn from_cache(cache: &Cache) -> Self {
+        let mut subscriptions = Self::default();
+
+        for derivations in &cache.derivations {
+            subscriptions
+                .puzzle_hashes
+                .extend(derivations.puzzle_hashes.iter().copied());
+            subscriptions.coin_ids.extend(
+                derivations
+                    .coin_states
+                    .iter()
+                    .filter(|(_, coin_state)| {
+                        coin_state.spent_height.is_none()
+                            && !derivations
+                                .puzzle_hashes
+                                .contains(&coin_state.coin.puzzle_hash)
+                    })
+                    .map(|(coin_id, _)| *coin_id),
+            );
+        }
+
+        subscriptions
+    }
+}
+
+/// Subscribes to the coins, returning their current states.
+pub async fn subscribe_coins(
+    peer: &ProbedPeer,
+    config: &Config,
+    coin_ids: &[[u8; 32]],
+) -> anyhow::Result<Vec<CoinState>> {
+    let mut coin_states = Vec::new();
+
+    for batch in coin_ids.chunks(COIN_BATCH_SIZE) {
+        let batch = batch.iter().copied().map(Bytes32::from).collect::<Vec<_>>();
+
+        if !peer.supports_puzzle_state {
+            let states = with_retries(&config.retry, Rejections::Retry, || {
+                peer.peer.register_for_coin_updates(batch.clone(), 0)
+            })
+            .await?
+            .map_err(|()| anyhow::anyhow!("Peer rejected RegisterForCoinUpdates"))?;
+            coin_states.extend(states);
+            continue;
+        }
+
+        let response = with_retries(&config.retry, Rejections::Return, || {
+            peer.peer
+                .request_or_reject::<RespondCoinState, RejectCoinState, _>(RequestCoinState {
+                    coin_ids: batch.clone(),
+                    previous_height: None,
+                    header_hash: config.genesis_challenge.into(),
+                    subscribe: true,
+                })
+        })
+        .await?;
+
+        match response {
+            Ok(response) => coin_states.extend(response.coin_states),
+            Err(rejection) => match rejection.reason {
+                RejectStateReason::ExceededSubscriptionLimit => bail!(
+                    "The peer won't subscribe to any more coins, raise its \
+                     max_subscribe_items or use a peer with a higher limit"
+                ),
+                RejectStateReason::Reorg => {
+                    bail!("Reorg detected but we didn't specify a previous height.")
+                }
+            },
+        }
+    }
+
+    Ok(coin_states)
+}
+
+/// The spent height of every coin in the cache, to tell what changed since.
+pub fn snapshot(cache: &Cache) -> IndexMap<[u8; 32], Option<u32>> {
+    cache
+        .derivations
+        .iter()
+        .flat_map(|derivations| &derivations.coin_states)
+        .map(|(coin_id, coin_state)| (*coin_id, coin_state.spent_height))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoinEventKind {
+    Received,
+    Spent,
+}
+
+/// A coin of the wallet that showed up or was spent while watching.
+#[derive(Debug, Clone)]
+pub struct CoinEvent {
+    pub kind: CoinEventKind,
+    pub coin_id: [u8; 32],
+    pub coin_state: CoinStateJson,
+    pub height: u32,
+}
+
+/// The coins created or spent since the snapshot, in height order.
+pub fn coin_events(before: &IndexMap<[u8; 32], Option<u32>>, cache: &Cache) -> Vec<CoinEvent> {
+    let mut events = Vec::new();
+
+    for (coin_id, coin_state) in cache
+        .derivations
+        .iter()
+        .flat_map(|derivations| &derivations.coin_states)
+    {
+        let (was_known, was_spent) = match before.get(coin_id) {
+            Some(spent_height) => (true, spent_height.is_some()),
+            None => (false, false),
+        };
+
+        if !was_known {
+            if let Some(height) = coin_state.created_height {
+                events.push(CoinEvent {
+                    kind: CoinEventKind::Received,
+                    coin_id: *coin_id,
+                    coin_state: coin_state.clone(),
+                    height,
+                });
+            }
+        }
+
+        if let Some(height) = coin_state.spent_height.filter(|_| !was_spent) {
+            events.push(CoinEvent {
+                kind: CoinEventKind::Spent,
+                coin_id: *coin_id,
+                coin_state: coin_state.clone(),
+                height,
+            });
+        }
+    }
+
+    events.sort_by_key(|event| event.height);
+    events
+}
+
+/// Records pushed coin states in the windows they belong to, returning the windows that
+/// changed. Coins the cache doesn't know of yet, such as a CAT received with a hint, can't be
+/// placed without a sync, so `None` is returned for those and nothing is changed.
+pub fn apply_coin_states(cache: &mut Cache, coin_states: &[CoinState]) -> Option<IndexSet<usize>> {
+    let mut placed = Vec::new();
+
+    for coin_state in coin_states {
+        let coin_id = coin_state.coin.coin_id().to_bytes();
+        let puzzle_hash = coin_state.coin.puzzle_hash.to_bytes();
+
+        let index = cache.derivations.iter().position(|derivations| {
+            derivations.puzzle_hashes.contains(&puzzle_hash)
+                || derivations.coin_states.contains_key(&coin_id)
+        })?;
+        placed.push((index, coin_id, coin_state));
+    }
+
+    let mut changed = IndexSet::new();
+
+    for (index, coin_id, coin_state) in placed {
+        let derivations = &mut cache.derivations[index];
+
+        // Pushes only change heights, so what the sync found about the parent stays.
+        let existing = derivations.coin_states.get(&coin_id);
+        let parent_puzzle = existing.and_then(|existing| existing.parent_puzzle.clone());
+        let parent_spend = existing.and_then(|existing| existing.parent_spend.clone());
+
+        derivations.coin_states.insert(
+            coin_id,
+            CoinStateJson {
+                coin: coin_state.coin.into(),
+                parent_puzzle,
+                parent_spend,
+                created_height: coin_state.created_height,
+                spent_height: coin_state.spent_height,
+            },
+        );
+        changed.insert(index);
+    }
+
+    Some(changed)
+}
@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use chia::protocol::Bytes32;
+use chrono::{DateTime, NaiveDateTime};
+use indexmap::IndexSet;
+
+use crate::{asset::Asset, config::ExchangeExportConfig};
+
+/// A withdrawal listed in an exchange's export, which is matched to the coin it created.
+#[derive(Debug, Clone)]
+pub struct ExportedWithdrawal {
+    /// The export it's from and its line there, which is listed as the coin's evidence.
+    pub file: String,
+    pub line: usize,
+    pub time: i64,
+    pub amount: String,
+    pub address: String,
+}
+
+/// A coin received from an exchange's hot wallet.
+#[derive(Debug, Clone)]
+pub struct Withdrawal {
+    pub coin_id: Bytes32,
+    pub asset: Asset,
+    pub amount: u64,
+    pub height: u32,
+    pub timestamp: u64,
+    pub address: String,
+    pub exchange: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct WithdrawalMatches {
+    pub matched: Vec<(Withdrawal, ExportedWithdrawal)>,
+    pub unmatched: Vec<Withdrawal>,
+    /// Withdrawals in the exports that no coin matched, such as those to other wallets.
+    pub unclaimed: usize,
+}
+
+/// Reads the withdrawals in an exchange export, from the columns set in the config.
+pub fn load_exchange_export(
+    path: impl AsRef<Path>,
+    config: &ExchangeExportConfig,
+) -> anyhow::Result<Vec<ExportedWithdrawal>> {
+    let path = path.as_ref();
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str, key: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} has no `{name}` column, set `exchange_export.{key}`",
+                    path.display()
+                )
+            })
+    };
+    let time_column = column(&config.time_column, "time_column")?;
+    let amount_column = column(&config.amount_column, "amount_column")?;
+    let address_column = column(&config.address_column, "address_column")?;
+
+    let mut withdrawals = Vec::new();
+
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        let line = line + 2;
+        let field = |index: usize| record.get(index).unwrap_or_default().trim();
+
+        let time = field(time_column);
+        let parsed = match &config.time_format {
+            Some(format) => {
+                NaiveDateTime::parse_from_str(time, format).map(|time| time.and_utc().timestamp())
+            }
+            None => DateTime::parse_from_rfc3339(time).map(|time| time.timestamp()),
+        };
+        let Ok(time) = parsed else {
+            bail!(
+                "Line {line} of {} has a time of `{time}`, which doesn't match \
+                 `exchange_export.time_format` of the config",
+                path.display()
+            );
+        };
+
+        withdrawals.push(ExportedWithdrawal {
+            file: path.display().to_string(),
+            line,
+            time,
+            amount: field(amount_column).trim_start_matches('-').to_string(),
+            address: field(address_column).to_lowercase(),
+        });
+    }
+
+    Ok(withdrawals)
+}
+
+/// Links each withdrawal to the exported one to the same address of the same amount that is
+/// closest in time, within the window. An exported withdrawal only matches a single coin.
+pub fn match_withdrawals(
+    withdrawals: Vec<Withdrawal>,
+    exported: &[ExportedWithdrawal],
+    window_minutes: u32,
+) -> WithdrawalMatches {
+    let mut matches = WithdrawalMatches::default();
+    let mut claimed = IndexSet::new();
+    let window = i64::from(window_minutes) * 60;
+
+    for withdrawal in withdrawals {
+        let closest = exported
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !claimed.contains(index))
+            .filter(|(_, row)| row.address == withdrawal.address)
+            .filter(|(_, row)| {
+                parse_amount(withdrawal.asset, &row.amount) == Some(withdrawal.amount)
+            })
+            .map(|(index, row)| (index, (row.time - withdrawal.timestamp as i64).abs()))
+            .filter(|(_, distance)| *distance <= window)
+            .min_by_key(|(_, distance)| *distance);
+
+        match closest {
+            Some((index, _)) => {
+                claimed.insert(index);
+                matches.matched.push((withdrawal, exported[index].clone()));
+            }
+            None => matches.unmatched.push(withdrawal),
+        }
+    }
+
+    matches.unclaimed = exported.len() - claimed.len();
+    matches
+}
+
+/// Exports often pad amounts with more decimal places than the asset has, which are zeros.
+fn parse_amount(asset: Asset, amount: &str) -> Option<u64> {
+    let amount = match amount.contains('.') {
+        true => amount.trim_end_matches('0').trim_end_matches('.'),
+        false => amount,
+    };
+    asset.parse_amount(amount)
+}